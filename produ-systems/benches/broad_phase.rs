@@ -0,0 +1,93 @@
+use cgmath::{Quaternion, Vector3};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+// The physics module only needs this from the engine
+pub type EntityIndex = u32;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/math/mod.rs"]
+mod math;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/physics/mod.rs"]
+mod physics;
+use physics::{Aabb, BroadPhase, ProxyId};
+
+struct MovingBox {
+    proxy: ProxyId,
+    position: Vector3<f32>,
+    velocity: Vector3<f32>,
+}
+
+const HALF_EXTENTS: Vector3<f32> = Vector3::new(0.5, 0.5, 0.5);
+
+/// Boxes on a grid inside a cube, moving in a pseudo random direction so every run is the same
+fn scene(count: usize) -> (BroadPhase<usize>, Vec<MovingBox>) {
+    let mut broad_phase = BroadPhase::new();
+    let side = (count as f32).cbrt().ceil() as usize;
+    let boxes = (0..count)
+        .map(|i| {
+            let position = Vector3::new(
+                (i % side) as f32 * 1.5,
+                (i / side % side) as f32 * 1.5,
+                (i / (side * side)) as f32 * 1.5,
+            );
+            let seed = (i as f32 * 12.9898).sin() * 43758.547;
+            let velocity = Vector3::new(seed.fract(), (seed * 1.7).fract(), (seed * 2.3).fract());
+            MovingBox {
+                proxy: broad_phase.create_proxy(Aabb::from_center(position, HALF_EXTENTS), i),
+                position,
+                velocity: velocity * 4.0,
+            }
+        })
+        .collect();
+    broad_phase.update_pairs();
+    (broad_phase, boxes)
+}
+
+fn step(broad_phase: &mut BroadPhase<usize>, boxes: &mut [MovingBox], bounds: f32) {
+    let dt = 1.0 / 60.0;
+    for b in boxes.iter_mut() {
+        b.position += b.velocity * dt;
+        for axis in 0..3 {
+            if b.position[axis] < 0.0 || b.position[axis] > bounds {
+                b.velocity[axis] = -b.velocity[axis];
+            }
+        }
+        let aabb = Aabb::from_oriented_box(
+            b.position,
+            Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            HALF_EXTENTS,
+        );
+        broad_phase.move_proxy(b.proxy, aabb, b.velocity * dt);
+    }
+    broad_phase.update_pairs();
+}
+
+fn moving_bodies(c: &mut Criterion) {
+    let mut group = c.benchmark_group("broad_phase_step");
+    for &count in &[1_000, 4_000, 16_000] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(count),
+            &count,
+            |bench, &count| {
+                let (mut broad_phase, mut boxes) = scene(count);
+                let bounds = (count as f32).cbrt().ceil() * 1.5;
+                bench.iter(|| {
+                    step(&mut broad_phase, &mut boxes, bounds);
+                    black_box(broad_phase.pair_count())
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+fn build_tree(c: &mut Criterion) {
+    c.bench_function("broad_phase_build_4000", |bench| {
+        bench.iter(|| black_box(scene(4_000).0.pair_count()))
+    });
+}
+
+criterion_group!(benches, moving_bodies, build_tree);
+criterion_main!(benches);
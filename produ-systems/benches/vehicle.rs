@@ -0,0 +1,64 @@
+use cgmath::Vector3;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+// The physics module only needs this from the engine
+pub type EntityIndex = u32;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/math/mod.rs"]
+mod math;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/physics/mod.rs"]
+mod physics;
+use physics::{
+    Collider, MassProperties, PhysicsWorld, RigidBody, Vehicle, VehicleConfig, VehicleControls,
+    VehicleHandle,
+};
+
+const DT: f32 = 1.0 / 60.0;
+
+const CHASSIS_HALF_EXTENTS: Vector3<f32> = Vector3::new(0.9, 0.4, 2.2);
+
+/// A long flat road with speed bumps across it every 100m, and a car at the start facing down it
+fn test_track() -> (PhysicsWorld, VehicleHandle) {
+    let mut world = PhysicsWorld::new();
+    let ground = world.add_body(RigidBody::fixed(Vector3::new(0.0, -0.5, -900.0)));
+    world.add_collider(ground, Collider::cuboid(Vector3::new(400.0, 0.5, 1000.0)));
+    for i in 1..10 {
+        let bump = world.add_body(RigidBody::fixed(Vector3::new(0.0, 0.0, i as f32 * -100.0)));
+        world.add_collider(bump, Collider::cuboid(Vector3::new(20.0, 0.04, 0.3)));
+    }
+
+    let position = Vector3::new(0.0, 1.0, 0.0);
+    let chassis = world.add_body(RigidBody::dynamic(
+        position,
+        MassProperties::cuboid(1_200.0, CHASSIS_HALF_EXTENTS),
+    ));
+    world.add_collider(chassis, Collider::cuboid(CHASSIS_HALF_EXTENTS));
+    let vehicle = Vehicle::new(chassis, VehicleConfig::car(1.6, 2.8, -0.2));
+    let handle = world.add_vehicle(vehicle);
+    (world, handle)
+}
+
+fn test_track_lap(c: &mut Criterion) {
+    c.bench_function("vehicle_step_at_speed", |bench| {
+        // Going round in circles keeps the car on the track however long the benchmark runs
+        let (mut world, handle) = test_track();
+        world.vehicle_mut(handle).unwrap().controls = VehicleControls {
+            throttle: 1.0,
+            steering: 0.3,
+            ..VehicleControls::default()
+        };
+        for _ in 0..(5.0 / DT) as usize {
+            world.step(DT);
+        }
+        bench.iter(|| {
+            world.step(DT);
+            black_box(world.vehicle(handle).unwrap().speed())
+        });
+    });
+}
+
+criterion_group!(benches, test_track_lap);
+criterion_main!(benches);
@@ -0,0 +1,22 @@
+#![no_main]
+//! Throws arbitrary bytes at the audio decoders, malformed files have to come back as errors
+//! rather than panics. Run with `cargo fuzz run decode` from `produ-systems`
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+#[allow(dead_code)]
+#[path = "../../src/audio/decode.rs"]
+mod decode;
+use decode::{Decoder, WavReader};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(wav) = WavReader::new(Cursor::new(data)) {
+        let mut decoder = Decoder::Wav(wav);
+        let _ = decoder.read_to_end();
+        let _ = decoder.rewind().and_then(|_| decoder.read_to_end());
+    }
+    if let Ok(mut vorbis) = Decoder::vorbis(Cursor::new(data)) {
+        let _ = vorbis.read_to_end();
+        let _ = vorbis.rewind().and_then(|_| vorbis.read_to_end());
+    }
+});
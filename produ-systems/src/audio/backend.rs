@@ -0,0 +1,316 @@
+use super::mixer::Mixer;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Frames mixed at a time by backends that pull from the mixer themselves
+const PULL_FRAMES: usize = 1024;
+
+/// Where the mixed audio goes. `audio_system` updates it once a frame, after the game's
+/// `Audio` has sent its commands
+pub trait AudioBackend {
+    fn sample_rate(&self) -> u32;
+
+    /// Backends playing on their own thread only check on it, the rest mix `dt` seconds of
+    /// audio there and then
+    fn update(&mut self, dt: f32) -> Result<(), BackendError>;
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    NoDevice,
+    /// The sound system would not play, or stopped playing
+    Device(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendError::NoDevice => write!(f, "no audio output device"),
+            BackendError::Device(e) => write!(f, "audio device failed: {}", e),
+            BackendError::Io(e) => write!(f, "failed to write audio: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+impl From<io::Error> for BackendError {
+    fn from(e: io::Error) -> Self {
+        BackendError::Io(e)
+    }
+}
+
+/// Turns update times into whole frames, carrying the fractions over so none get lost
+struct Clock {
+    sample_rate: u32,
+    owed: f64,
+}
+
+impl Clock {
+    fn new(sample_rate: u32) -> Clock {
+        Clock {
+            sample_rate,
+            owed: 0.0,
+        }
+    }
+
+    fn frames(&mut self, dt: f32) -> usize {
+        self.owed += dt.max(0.0) as f64 * self.sample_rate as f64;
+        let frames = self.owed.floor();
+        self.owed -= frames;
+        frames as usize
+    }
+}
+
+/// Mixes and throws the result away, for dedicated servers and machines without a sound card.
+/// Voices still play through and finish on time
+pub struct NullSink {
+    mixer: Mixer,
+    clock: Clock,
+    buffer: Vec<f32>,
+}
+
+impl NullSink {
+    pub fn new(mixer: Mixer) -> NullSink {
+        NullSink {
+            clock: Clock::new(mixer.sample_rate()),
+            mixer,
+            buffer: vec![0.0; PULL_FRAMES * 2],
+        }
+    }
+}
+
+impl AudioBackend for NullSink {
+    fn sample_rate(&self) -> u32 {
+        self.mixer.sample_rate()
+    }
+
+    fn update(&mut self, dt: f32) -> Result<(), BackendError> {
+        let mut frames = self.clock.frames(dt);
+        while frames > 0 {
+            let count = frames.min(PULL_FRAMES);
+            self.mixer.render(&mut self.buffer[..count * 2]);
+            frames -= count;
+        }
+        Ok(())
+    }
+}
+
+/// Offset of the RIFF size, patched in once the length is known
+const RIFF_SIZE_OFFSET: u64 = 4;
+/// Offset of the frame count in the fact chunk
+const FACT_FRAMES_OFFSET: u64 = 46;
+const DATA_SIZE_OFFSET: u64 = 54;
+const HEADER_BYTES: u32 = 58;
+
+/// Writes the mixed stream to a 32 bit float stereo `.wav` file, for recording and for audio
+/// regression tests. The file is finished off when the sink is dropped
+pub struct WavSink {
+    mixer: Mixer,
+    clock: Clock,
+    buffer: Vec<f32>,
+    /// `None` once finished
+    writer: Option<BufWriter<File>>,
+    frames: u64,
+}
+
+impl WavSink {
+    pub fn create<P: AsRef<Path>>(path: P, mixer: Mixer) -> Result<WavSink, BackendError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_header(&mut writer, mixer.sample_rate())?;
+        Ok(WavSink {
+            clock: Clock::new(mixer.sample_rate()),
+            mixer,
+            buffer: vec![0.0; PULL_FRAMES * 2],
+            writer: Some(writer),
+            frames: 0,
+        })
+    }
+
+    /// Mixes an exact number of frames into the file, whatever the frame times
+    pub fn render(&mut self, frames: usize) -> Result<(), BackendError> {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        let mut left = frames;
+        while left > 0 {
+            let count = left.min(PULL_FRAMES);
+            let buffer = &mut self.buffer[..count * 2];
+            self.mixer.render(buffer);
+            for sample in buffer.iter() {
+                writer.write_all(&sample.to_le_bytes())?;
+            }
+            left -= count;
+        }
+        self.frames += frames as u64;
+        Ok(())
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Fills in the header's sizes and closes the file
+    pub fn finish(mut self) -> Result<(), BackendError> {
+        self.close()
+    }
+
+    fn close(&mut self) -> Result<(), BackendError> {
+        let mut writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        let data_bytes = self.frames * 8;
+        let riff_bytes = (data_bytes + HEADER_BYTES as u64 - 8).min(u32::MAX as u64) as u32;
+        let patches = [
+            (RIFF_SIZE_OFFSET, riff_bytes),
+            (FACT_FRAMES_OFFSET, self.frames.min(u32::MAX as u64) as u32),
+            (DATA_SIZE_OFFSET, data_bytes.min(u32::MAX as u64) as u32),
+        ];
+        for &(offset, value) in patches.iter() {
+            writer.seek(SeekFrom::Start(offset))?;
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl AudioBackend for WavSink {
+    fn sample_rate(&self) -> u32 {
+        self.mixer.sample_rate()
+    }
+
+    fn update(&mut self, dt: f32) -> Result<(), BackendError> {
+        let frames = self.clock.frames(dt);
+        self.render(frames)
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// Header of a stereo float file with the sizes left at zero
+fn write_header<W: Write>(writer: &mut W, sample_rate: u32) -> io::Result<()> {
+    const CHANNELS: u16 = 2;
+    const BYTES_PER_SAMPLE: u16 = 4;
+    const FORMAT_FLOAT: u16 = 3;
+    let block_align = CHANNELS * BYTES_PER_SAMPLE;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&18u32.to_le_bytes())?;
+    writer.write_all(&FORMAT_FLOAT.to_le_bytes())?;
+    writer.write_all(&CHANNELS.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&(BYTES_PER_SAMPLE * 8).to_le_bytes())?;
+    // No extension to the format
+    writer.write_all(&0u16.to_le_bytes())?;
+    // Formats other than integer PCM need a fact chunk with the frame count
+    writer.write_all(b"fact")?;
+    writer.write_all(&4u32.to_le_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&0u32.to_le_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Audio, MixerConfig, PlayParams, Sound};
+    use super::*;
+    use std::f32::consts::TAU;
+    use std::fs;
+
+    const RATE: u32 = 48000;
+
+    fn offline() -> (Audio, Mixer) {
+        Audio::new(MixerConfig {
+            sample_rate: RATE,
+            max_voices: 4,
+            command_capacity: 16,
+        })
+    }
+
+    /// `seconds` of a 440 Hz sine
+    fn sine(seconds: f32) -> Sound {
+        let samples = (0..(RATE as f32 * seconds) as usize)
+            .map(|i| (i as f32 / RATE as f32 * 440.0 * TAU).sin())
+            .collect();
+        Sound::mono(RATE, samples)
+    }
+
+    #[test]
+    fn wav_sinks_write_what_was_mixed() {
+        let dir = std::env::temp_dir().join(format!("backend_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mix.wav");
+        let sound = sine(0.5);
+
+        let (mut audio, mixer) = offline();
+        let mut sink = WavSink::create(&path, mixer).unwrap();
+        assert_eq!(sink.sample_rate(), RATE);
+        audio.play(&sound, PlayParams::default());
+        for _ in 0..3 {
+            sink.update(0.1).unwrap();
+        }
+        sink.render(100).unwrap();
+        assert_eq!(sink.frames(), 14500);
+        sink.finish().unwrap();
+
+        let (mut audio, mut mixer) = offline();
+        audio.play(&sound, PlayParams::default());
+        let expected = mixer.render_frames(14500);
+        let written = Sound::from_file(&path).unwrap();
+        assert_eq!((written.sample_rate(), written.channels()), (RATE, 2));
+        assert_eq!(written.samples(), &expected[..]);
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), HEADER_BYTES as usize + 14500 * 8);
+        assert_eq!(bytes[4..8], (bytes.len() as u32 - 8).to_le_bytes());
+
+        // Dropping the sink finishes the file off too
+        let (_, mixer) = offline();
+        let mut sink = WavSink::create(&path, mixer).unwrap();
+        sink.render(10).unwrap();
+        drop(sink);
+        let written = Sound::from_file(&path).unwrap();
+        assert_eq!(written.frames(), 10);
+        assert!(written.samples().iter().all(|&s| s == 0.0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn null_sinks_play_voices_through_on_time() {
+        let (mut audio, mixer) = offline();
+        let mut sink = NullSink::new(mixer);
+        assert_eq!(sink.sample_rate(), RATE);
+        let voice = audio.play(&sine(0.1), PlayParams::default());
+        // A frame's time is 4.8 audio frames, the fractions add up rather than get lost
+        let dt = 1e-4;
+        for _ in 0..990 {
+            sink.update(dt).unwrap();
+        }
+        audio.update();
+        assert!(audio.is_playing(voice));
+        for _ in 0..20 {
+            sink.update(dt).unwrap();
+        }
+        audio.update();
+        assert!(!audio.is_playing(voice));
+
+        let voice = audio.play(&sine(0.1), PlayParams::default());
+        sink.update(-1.0).unwrap();
+        audio.update();
+        assert!(audio.is_playing(voice));
+    }
+}
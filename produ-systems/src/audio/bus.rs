@@ -0,0 +1,290 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Where voices get mixed. Every other bus feeds the master, which feeds the output
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Bus {
+    Master,
+    Music,
+    Sfx,
+    Voice,
+    Ui,
+}
+
+impl Bus {
+    pub const ALL: [Bus; 5] = [Bus::Master, Bus::Music, Bus::Sfx, Bus::Voice, Bus::Ui];
+
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Turns the target bus down while the key bus is playing something, like music under dialogue
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Ducking {
+    /// The bus listened to. It is heard after its volume, so muted dialogue ducks nothing
+    pub key: Bus,
+    /// Level in dB the key has to reach
+    pub threshold: f32,
+    /// How far in dB the target goes down
+    pub depth: f32,
+    /// Seconds to duck
+    pub attack: f32,
+    /// Seconds to come back up after the key goes quiet
+    pub release: f32,
+}
+
+impl Default for Ducking {
+    fn default() -> Ducking {
+        Ducking {
+            key: Bus::Voice,
+            threshold: -40.0,
+            depth: -12.0,
+            attack: 0.05,
+            release: 0.6,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BusSettings {
+    /// From `0.0` silent to `1.0` full
+    pub volume: f32,
+    #[serde(default)]
+    pub muted: bool,
+}
+
+impl Default for BusSettings {
+    fn default() -> BusSettings {
+        BusSettings {
+            volume: 1.0,
+            muted: false,
+        }
+    }
+}
+
+/// The volumes players pick in the options menu, kept in a `.ron` file between runs
+#[derive(Debug, Copy, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioSettings {
+    pub master: BusSettings,
+    pub music: BusSettings,
+    pub sfx: BusSettings,
+    pub voice: BusSettings,
+    pub ui: BusSettings,
+}
+
+#[derive(Debug)]
+pub enum AudioSettingsError {
+    Io(io::Error),
+    Ron(ron::Error),
+}
+
+impl fmt::Display for AudioSettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AudioSettingsError::Io(e) => write!(f, "failed to access audio settings: {}", e),
+            AudioSettingsError::Ron(e) => write!(f, "bad audio settings: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AudioSettingsError {}
+
+impl From<io::Error> for AudioSettingsError {
+    fn from(e: io::Error) -> Self {
+        AudioSettingsError::Io(e)
+    }
+}
+
+impl From<ron::Error> for AudioSettingsError {
+    fn from(e: ron::Error) -> Self {
+        AudioSettingsError::Ron(e)
+    }
+}
+
+impl FromStr for AudioSettings {
+    type Err = AudioSettingsError;
+
+    fn from_str(source: &str) -> Result<AudioSettings, AudioSettingsError> {
+        Ok(ron::de::from_str(source)?)
+    }
+}
+
+impl AudioSettings {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<AudioSettings, AudioSettingsError> {
+        let source = fs::read_to_string(path)?;
+        source.parse()
+    }
+
+    /// Saves the settings after the player changed them
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), AudioSettingsError> {
+        let source = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::new())?;
+        fs::write(path, source)?;
+        Ok(())
+    }
+
+    pub fn bus(&self, bus: Bus) -> &BusSettings {
+        match bus {
+            Bus::Master => &self.master,
+            Bus::Music => &self.music,
+            Bus::Sfx => &self.sfx,
+            Bus::Voice => &self.voice,
+            Bus::Ui => &self.ui,
+        }
+    }
+
+    pub fn bus_mut(&mut self, bus: Bus) -> &mut BusSettings {
+        match bus {
+            Bus::Master => &mut self.master,
+            Bus::Music => &mut self.music,
+            Bus::Sfx => &mut self.sfx,
+            Bus::Voice => &mut self.voice,
+            Bus::Ui => &mut self.ui,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        db_to_gain, gain_to_db, Audio, Limiter, Mixer, MixerConfig, PlayParams, Sound,
+    };
+    use super::*;
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    const RATE: u32 = 48000;
+
+    fn offline() -> (Audio, Mixer) {
+        Audio::new(MixerConfig {
+            sample_rate: RATE,
+            max_voices: 4,
+            command_capacity: 16,
+        })
+    }
+
+    /// A steady level, so what comes out is just the gains along the way
+    fn level(value: f32, seconds: f32) -> Sound {
+        Sound::mono(RATE, vec![value; (RATE as f32 * seconds) as usize])
+    }
+
+    /// The last frame rendered after `seconds`, long enough for any fades to finish
+    fn settle(mixer: &mut Mixer, seconds: f32) -> [f32; 2] {
+        let out = mixer.render_frames((RATE as f32 * seconds) as usize);
+        [out[out.len() - 2], out[out.len() - 1]]
+    }
+
+    #[test]
+    fn bus_volumes_chain_through_the_master() {
+        let (mut audio, mut mixer) = offline();
+        let looping = PlayParams {
+            looping: true,
+            ..PlayParams::default()
+        };
+        audio.play(&level(1.0, 0.1), looping);
+        assert!((settle(&mut mixer, 0.05)[0] - FRAC_1_SQRT_2).abs() < 1e-4);
+
+        audio.set_bus_volume(Bus::Sfx, 0.5);
+        audio.set_bus_volume(Bus::Master, 0.4);
+        assert!((settle(&mut mixer, 0.05)[0] - FRAC_1_SQRT_2 * 0.2).abs() < 1e-4);
+
+        // Other buses have no say
+        audio.set_bus_muted(Bus::Music, true);
+        audio.set_bus_volume(Bus::Voice, 0.0);
+        assert!((settle(&mut mixer, 0.05)[0] - FRAC_1_SQRT_2 * 0.2).abs() < 1e-4);
+
+        audio.set_bus_muted(Bus::Master, true);
+        assert_eq!(settle(&mut mixer, 0.05), [0.0, 0.0]);
+        audio.set_bus_muted(Bus::Master, false);
+        audio.set_bus_muted(Bus::Sfx, true);
+        assert_eq!(settle(&mut mixer, 0.05), [0.0, 0.0]);
+
+        // Muting keeps the volume for when it comes back
+        audio.set_bus_muted(Bus::Sfx, false);
+        assert!((settle(&mut mixer, 0.05)[0] - FRAC_1_SQRT_2 * 0.2).abs() < 1e-4);
+
+        let mut settings = AudioSettings::default();
+        settings.sfx.volume = 0.25;
+        audio.apply_settings(&settings);
+        assert!((settle(&mut mixer, 0.05)[0] - FRAC_1_SQRT_2 * 0.25).abs() < 1e-4);
+    }
+
+    #[test]
+    fn music_ducks_under_the_voice_and_comes_back() {
+        let (mut audio, mut mixer) = offline();
+        // Music all the way left and dialogue all the way right, to hear each on its own
+        audio.play(
+            &level(1.0, 0.1),
+            PlayParams {
+                looping: true,
+                pan: -1.0,
+                bus: Bus::Music,
+                ..PlayParams::default()
+            },
+        );
+        audio.set_ducking(Bus::Music, Some(Ducking::default()));
+        let line = PlayParams {
+            pan: 1.0,
+            bus: Bus::Voice,
+            ..PlayParams::default()
+        };
+        assert!((settle(&mut mixer, 0.1)[0] - 1.0).abs() < 1e-4);
+
+        audio.play(&level(0.5, 1.0), line);
+        let [music, voice] = settle(&mut mixer, 0.5);
+        assert!((music - db_to_gain(-12.0)).abs() < 1e-3, "{}", music);
+        assert!((voice - 0.5).abs() < 1e-4);
+
+        // The key's peak is held a moment, then the music is let up over the release
+        let [music, voice] = settle(&mut mixer, 4.0);
+        assert_eq!(voice, 0.0);
+        assert!((music - 1.0).abs() < 0.01, "{}", music);
+
+        // Too quiet to reach the threshold
+        audio.play(&level(0.005, 1.0), line);
+        assert!((settle(&mut mixer, 0.5)[0] - 1.0).abs() < 0.01);
+        // The key is heard after its volume
+        audio.set_bus_muted(Bus::Voice, true);
+        audio.play(&level(0.5, 1.0), line);
+        assert!((settle(&mut mixer, 0.5)[0] - 1.0).abs() < 0.01);
+
+        audio.set_bus_muted(Bus::Voice, false);
+        audio.set_ducking(Bus::Music, None);
+        audio.play(&level(0.5, 1.0), line);
+        assert!((settle(&mut mixer, 0.5)[0] - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn effects_on_a_bus_come_before_its_volume() {
+        let (mut audio, mut mixer) = offline();
+        audio.play(
+            &level(1.0, 0.1),
+            PlayParams {
+                looping: true,
+                ..PlayParams::default()
+            },
+        );
+        // Limited to half, then halved
+        let limiter = audio.add_effect(Bus::Sfx, Limiter::new(RATE, gain_to_db(0.5), 0.1));
+        audio.set_bus_volume(Bus::Sfx, 0.5);
+        assert!((settle(&mut mixer, 0.05)[0] - 0.25).abs() < 1e-4);
+
+        audio.remove_effect(limiter);
+        assert!((settle(&mut mixer, 0.05)[0] - FRAC_1_SQRT_2 * 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn settings_parse_with_missing_buses_left_at_default() {
+        let settings: AudioSettings = "(music: (volume: 0.3, muted: true))".parse().unwrap();
+        assert_eq!(settings.music.volume, 0.3);
+        assert!(settings.music.muted);
+        assert_eq!(settings.sfx, BusSettings::default());
+
+        let bad = "(music: 3)".parse::<AudioSettings>();
+        assert!(matches!(bad, Err(AudioSettingsError::Ron(_))));
+    }
+}
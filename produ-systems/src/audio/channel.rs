@@ -0,0 +1,126 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Fixed size single producer, single consumer queue. Neither end ever locks or allocates, so
+/// the audio thread can use it without risking a glitch
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    mask: usize,
+    /// Next slot to read, only the receiver moves it
+    head: AtomicUsize,
+    /// Next slot to write, only the sender moves it
+    tail: AtomicUsize,
+}
+
+// The sender only writes slots the receiver is done with and the receiver only reads slots the
+// sender has published, the head and tail hand slots over between them
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let (head, tail) = (*self.head.get_mut(), *self.tail.get_mut());
+        let mut at = head;
+        while at != tail {
+            unsafe {
+                (*self.slots[at & self.mask].get())
+                    .as_mut_ptr()
+                    .drop_in_place()
+            };
+            at = at.wrapping_add(1);
+        }
+    }
+}
+
+pub struct Sender<T> {
+    ring: Arc<Ring<T>>,
+}
+
+pub struct Receiver<T> {
+    ring: Arc<Ring<T>>,
+}
+
+/// Holds at least `capacity` values, rounded up to a power of two
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let capacity = capacity.max(1).next_power_of_two();
+    let slots = (0..capacity)
+        .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+        .collect();
+    let ring = Arc::new(Ring {
+        slots,
+        mask: capacity - 1,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (Sender { ring: ring.clone() }, Receiver { ring })
+}
+
+impl<T> Sender<T> {
+    /// Hands the value back if the queue is full
+    pub fn send(&mut self, value: T) -> Result<(), T> {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        let head = self.ring.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) > self.ring.mask {
+            return Err(value);
+        }
+        unsafe {
+            (*self.ring.slots[tail & self.ring.mask].get())
+                .as_mut_ptr()
+                .write(value)
+        };
+        self.ring
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Room left in the queue
+    pub fn free(&self) -> usize {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        let head = self.ring.head.load(Ordering::Acquire);
+        self.ring.mask + 1 - tail.wrapping_sub(head)
+    }
+}
+
+impl<T: Copy> Sender<T> {
+    /// Sends as many values from the front of `values` as fit in one go, returns how many
+    pub fn send_slice(&mut self, values: &[T]) -> usize {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        let count = self.free().min(values.len());
+        for (offset, &value) in values[..count].iter().enumerate() {
+            let slot = &self.ring.slots[tail.wrapping_add(offset) & self.ring.mask];
+            unsafe { (*slot.get()).as_mut_ptr().write(value) };
+        }
+        self.ring
+            .tail
+            .store(tail.wrapping_add(count), Ordering::Release);
+        count
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn recv(&mut self) -> Option<T> {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let tail = self.ring.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let value = unsafe {
+            (*self.ring.slots[head & self.ring.mask].get())
+                .as_ptr()
+                .read()
+        };
+        self.ring
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// Values waiting to be received
+    pub fn len(&self) -> usize {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let tail = self.ring.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+}
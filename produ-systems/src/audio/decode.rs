@@ -0,0 +1,654 @@
+use lewton::audio::AudioReadError;
+use lewton::inside_ogg::OggStreamReader;
+use lewton::samples::InterleavedSamples;
+use lewton::VorbisError;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Frames a WAV file hands out per `read`
+const WAV_BLOCK_FRAMES: usize = 4096;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Io(io::Error),
+    /// Only `.wav` and `.ogg` files are understood
+    UnknownFormat(String),
+    /// The file is cut short or its headers do not add up
+    Malformed(&'static str),
+    /// A WAV sample encoding other than 8 to 32 bit integers or 32 and 64 bit floats
+    UnsupportedEncoding {
+        format: u16,
+        bits: u16,
+    },
+    /// Sounds are mono or stereo
+    UnsupportedChannels(u16),
+    Vorbis(VorbisError),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Io(e) => write!(f, "failed to read audio: {}", e),
+            DecodeError::UnknownFormat(extension) => {
+                write!(f, "audio can not be decoded from \".{}\" files", extension)
+            }
+            DecodeError::Malformed(problem) => write!(f, "malformed audio file: {}", problem),
+            DecodeError::UnsupportedEncoding { format, bits } => write!(
+                f,
+                "unsupported WAV encoding {:#06x} with {} bits per sample",
+                format, bits
+            ),
+            DecodeError::UnsupportedChannels(channels) => write!(
+                f,
+                "audio has {} channels but only mono and stereo are supported",
+                channels
+            ),
+            DecodeError::Vorbis(e) => write!(f, "failed to decode Ogg Vorbis: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<io::Error> for DecodeError {
+    fn from(e: io::Error) -> Self {
+        DecodeError::Io(e)
+    }
+}
+
+impl From<VorbisError> for DecodeError {
+    fn from(e: VorbisError) -> Self {
+        match e {
+            VorbisError::OggError(lewton::OggReadError::ReadError(e)) => match e.kind() {
+                io::ErrorKind::UnexpectedEof => DecodeError::Malformed("Ogg stream cut short"),
+                _ => DecodeError::Io(e),
+            },
+            e => DecodeError::Vorbis(e),
+        }
+    }
+}
+
+/// Pulls samples out of an encoded file a block at a time, so music can stream
+pub(crate) enum Decoder<R: Read + Seek> {
+    Wav(WavReader<R>),
+    Vorbis(Box<OggStreamReader<R>>),
+}
+
+impl<R: Read + Seek> Decoder<R> {
+    /// Picks the decoder by file extension
+    pub(crate) fn for_path(path: &Path, reader: R) -> Result<Decoder<R>, DecodeError> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        match extension.as_str() {
+            "wav" | "wave" => Ok(Decoder::Wav(WavReader::new(reader)?)),
+            "ogg" | "oga" => Decoder::vorbis(reader),
+            _ => Err(DecodeError::UnknownFormat(extension)),
+        }
+    }
+
+    pub(crate) fn vorbis(reader: R) -> Result<Decoder<R>, DecodeError> {
+        let reader = OggStreamReader::new(reader)?;
+        let channels = reader.ident_hdr.audio_channels as u16;
+        if !(1..=2).contains(&channels) {
+            return Err(DecodeError::UnsupportedChannels(channels));
+        }
+        if reader.ident_hdr.audio_sample_rate == 0 {
+            return Err(DecodeError::Malformed("zero sample rate"));
+        }
+        Ok(Decoder::Vorbis(Box::new(reader)))
+    }
+
+    pub(crate) fn sample_rate(&self) -> u32 {
+        match self {
+            Decoder::Wav(wav) => wav.sample_rate,
+            Decoder::Vorbis(vorbis) => vorbis.ident_hdr.audio_sample_rate,
+        }
+    }
+
+    pub(crate) fn channels(&self) -> u16 {
+        match self {
+            Decoder::Wav(wav) => wav.channels,
+            Decoder::Vorbis(vorbis) => vorbis.ident_hdr.audio_channels as u16,
+        }
+    }
+
+    /// Appends the next block of interleaved samples to `out`, returns how many frames that
+    /// was. Zero means the end of the file
+    pub(crate) fn read(&mut self, out: &mut Vec<f32>) -> Result<usize, DecodeError> {
+        match self {
+            Decoder::Wav(wav) => wav.read(out, WAV_BLOCK_FRAMES),
+            Decoder::Vorbis(vorbis) => {
+                let channels = vorbis.ident_hdr.audio_channels as usize;
+                // Packets can decode to nothing, the first one only primes the decoder
+                while let Some(packet) =
+                    vorbis.read_dec_packet_generic::<InterleavedSamples<f32>>()?
+                {
+                    if packet.channel_count != channels {
+                        return Err(DecodeError::Malformed("channel count changed mid stream"));
+                    }
+                    if !packet.samples.is_empty() {
+                        out.extend_from_slice(&packet.samples);
+                        return Ok(packet.samples.len() / channels);
+                    }
+                }
+                Ok(0)
+            }
+        }
+    }
+
+    /// Goes back to the first frame
+    pub(crate) fn rewind(&mut self) -> Result<(), DecodeError> {
+        match self {
+            Decoder::Wav(wav) => wav.rewind(),
+            Decoder::Vorbis(vorbis) => {
+                vorbis.seek_absgp_pg(0)?;
+                // Header pages are at granule zero too, so that lands on them. Audio starts
+                // after the last one
+                loop {
+                    match vorbis.read_dec_packet_generic::<InterleavedSamples<f32>>() {
+                        Err(VorbisError::BadAudio(AudioReadError::AudioIsHeader)) => {}
+                        other => return other.map(|_| ()).map_err(DecodeError::from),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decodes everything that is left
+    pub(crate) fn read_to_end(&mut self) -> Result<Vec<f32>, DecodeError> {
+        let mut samples = vec![];
+        while self.read(&mut samples)? > 0 {}
+        Ok(samples)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Encoding {
+    /// Unsigned with the middle at 128
+    Pcm8,
+    Pcm16,
+    Pcm24,
+    Pcm32,
+    Float32,
+    Float64,
+}
+
+impl Encoding {
+    fn bytes(self) -> usize {
+        match self {
+            Encoding::Pcm8 => 1,
+            Encoding::Pcm16 => 2,
+            Encoding::Pcm24 => 3,
+            Encoding::Pcm32 | Encoding::Float32 => 4,
+            Encoding::Float64 => 8,
+        }
+    }
+
+    fn decode(self, bytes: &[u8]) -> f32 {
+        let sample = match self {
+            Encoding::Pcm8 => (bytes[0] as f32 - 128.0) / 128.0,
+            Encoding::Pcm16 => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+            Encoding::Pcm24 => {
+                // Into the top of an i32 so the sign comes along
+                i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as f32 / 2_147_483_648.0
+            }
+            Encoding::Pcm32 => {
+                i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32
+                    / 2_147_483_648.0
+            }
+            Encoding::Float32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            Encoding::Float64 => {
+                let mut raw = [0; 8];
+                raw.copy_from_slice(&bytes[..8]);
+                f64::from_le_bytes(raw) as f32
+            }
+        };
+        // Broken float files should not take the whole mix down with them
+        if sample.is_finite() {
+            sample
+        } else {
+            0.0
+        }
+    }
+}
+
+/// RIFF WAVE reader for PCM and floating point samples
+pub(crate) struct WavReader<R> {
+    reader: R,
+    sample_rate: u32,
+    channels: u16,
+    encoding: Encoding,
+    /// Where the samples start in the file
+    data_start: u64,
+    data_frames: usize,
+    frames_left: usize,
+}
+
+impl<R: Read + Seek> WavReader<R> {
+    pub(crate) fn new(mut reader: R) -> Result<WavReader<R>, DecodeError> {
+        let mut header = [0; 12];
+        read_header(&mut reader, &mut header, "not a WAV file")?;
+        if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+            return Err(DecodeError::Malformed("not a WAV file"));
+        }
+
+        let mut format: Option<(u16, u32, u16, Encoding)> = None;
+        loop {
+            let mut chunk = [0; 8];
+            read_header(&mut reader, &mut chunk, "no data chunk")?;
+            let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+            match &chunk[0..4] {
+                b"fmt " => {
+                    if !(16..=1024).contains(&size) {
+                        return Err(DecodeError::Malformed("bad fmt chunk size"));
+                    }
+                    let mut fmt = vec![0; size as usize];
+                    read_header(&mut reader, &mut fmt, "fmt chunk cut short")?;
+                    format = Some(parse_format(&fmt)?);
+                    if size % 2 == 1 {
+                        reader.seek(SeekFrom::Current(1))?;
+                    }
+                }
+                b"data" => {
+                    let (channels, sample_rate, block_align, encoding) =
+                        format.ok_or(DecodeError::Malformed("data chunk before fmt chunk"))?;
+                    let data_start = reader.stream_position()?;
+                    // Recorders that never finished writing leave the size too big or zero
+                    let available = reader.seek(SeekFrom::End(0))? - data_start;
+                    reader.seek(SeekFrom::Start(data_start))?;
+                    let size = if size == 0 {
+                        available
+                    } else {
+                        size.min(available)
+                    };
+                    let data_frames = (size / block_align as u64) as usize;
+                    return Ok(WavReader {
+                        reader,
+                        sample_rate,
+                        channels,
+                        encoding,
+                        data_start,
+                        data_frames,
+                        frames_left: data_frames,
+                    });
+                }
+                _ => {
+                    reader.seek(SeekFrom::Current((size + size % 2) as i64))?;
+                }
+            }
+        }
+    }
+
+    fn read(&mut self, out: &mut Vec<f32>, max_frames: usize) -> Result<usize, DecodeError> {
+        let frame_bytes = self.encoding.bytes() * self.channels as usize;
+        let wanted = max_frames.min(self.frames_left);
+        let mut bytes = Vec::with_capacity(wanted * frame_bytes);
+        (&mut self.reader)
+            .take((wanted * frame_bytes) as u64)
+            .read_to_end(&mut bytes)?;
+        let frames = bytes.len() / frame_bytes;
+        // A file shorter than its header claims just ends early
+        self.frames_left = if frames < wanted {
+            0
+        } else {
+            self.frames_left - frames
+        };
+        out.extend(
+            bytes[..frames * frame_bytes]
+                .chunks_exact(self.encoding.bytes())
+                .map(|sample| self.encoding.decode(sample)),
+        );
+        Ok(frames)
+    }
+
+    fn rewind(&mut self) -> Result<(), DecodeError> {
+        self.reader.seek(SeekFrom::Start(self.data_start))?;
+        self.frames_left = self.data_frames;
+        Ok(())
+    }
+}
+
+/// Channels, sample rate, block align and encoding out of a fmt chunk
+fn parse_format(fmt: &[u8]) -> Result<(u16, u32, u16, Encoding), DecodeError> {
+    let u16_at = |at: usize| u16::from_le_bytes([fmt[at], fmt[at + 1]]);
+    let mut format = u16_at(0);
+    let channels = u16_at(2);
+    let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+    let block_align = u16_at(12);
+    let bits = u16_at(14);
+    // WAVE_FORMAT_EXTENSIBLE keeps the real format at the front of its sub format GUID
+    if format == 0xFFFE {
+        if fmt.len() < 26 {
+            return Err(DecodeError::Malformed("extensible fmt chunk cut short"));
+        }
+        format = u16_at(24);
+    }
+
+    let encoding = match (format, bits) {
+        (1, 8) => Encoding::Pcm8,
+        (1, 16) => Encoding::Pcm16,
+        (1, 24) => Encoding::Pcm24,
+        (1, 32) => Encoding::Pcm32,
+        (3, 32) => Encoding::Float32,
+        (3, 64) => Encoding::Float64,
+        (format, bits) => return Err(DecodeError::UnsupportedEncoding { format, bits }),
+    };
+    if !(1..=2).contains(&channels) {
+        return Err(DecodeError::UnsupportedChannels(channels));
+    }
+    if sample_rate == 0 {
+        return Err(DecodeError::Malformed("zero sample rate"));
+    }
+    if block_align as usize != encoding.bytes() * channels as usize {
+        return Err(DecodeError::Malformed(
+            "block align does not match the sample size",
+        ));
+    }
+    Ok((channels, sample_rate, block_align, encoding))
+}
+
+/// `read_exact` that reports running out of file as a malformed header
+fn read_header<R: Read>(
+    reader: &mut R,
+    buffer: &mut [u8],
+    problem: &'static str,
+) -> Result<(), DecodeError> {
+    reader.read_exact(buffer).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => DecodeError::Malformed(problem),
+        _ => DecodeError::Io(e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A RIFF WAVE file with a fmt chunk and a data chunk, after a padded odd sized chunk the
+    /// reader has to skip
+    fn wav(format: u16, channels: u16, bits: u16, data: &[u8]) -> Vec<u8> {
+        let align = channels * bits / 8;
+        let mut fmt = vec![];
+        fmt.extend_from_slice(&format.to_le_bytes());
+        fmt.extend_from_slice(&channels.to_le_bytes());
+        fmt.extend_from_slice(&8000u32.to_le_bytes());
+        fmt.extend_from_slice(&(8000 * align as u32).to_le_bytes());
+        fmt.extend_from_slice(&align.to_le_bytes());
+        fmt.extend_from_slice(&bits.to_le_bytes());
+
+        let mut out = b"RIFF\0\0\0\0WAVE".to_vec();
+        out.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+        out.extend_from_slice(b"fmt ");
+        out.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+        out.extend_from_slice(&fmt);
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+        let size = (out.len() - 8) as u32;
+        out[4..8].copy_from_slice(&size.to_le_bytes());
+        out
+    }
+
+    /// 16 bit mono, counting up from zero
+    fn ramp_wav() -> Vec<u8> {
+        let data: Vec<u8> = (0..64i16)
+            .flat_map(|i| (i * 256).to_le_bytes().to_vec())
+            .collect();
+        wav(1, 1, 16, &data)
+    }
+
+    /// Vorbis packets are packed least significant bit first
+    #[derive(Default)]
+    struct Bits {
+        bytes: Vec<u8>,
+        used: usize,
+    }
+
+    impl Bits {
+        fn put(&mut self, value: u32, count: usize) {
+            for bit in 0..count {
+                if self.used % 8 == 0 {
+                    self.bytes.push(0);
+                }
+                let last = self.bytes.len() - 1;
+                self.bytes[last] |= (((value >> bit) & 1) as u8) << (self.used % 8);
+                self.used += 1;
+            }
+        }
+    }
+
+    fn ogg_crc(bytes: &[u8]) -> u32 {
+        bytes.iter().fold(0, |crc, &byte| {
+            (0..8).fold(crc ^ (u32::from(byte) << 24), |crc, _| {
+                if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ 0x04c1_1db7
+                } else {
+                    crc << 1
+                }
+            })
+        })
+    }
+
+    fn ogg_page(flags: u8, granule: u64, sequence: u32, packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut lacing = vec![];
+        for packet in packets {
+            lacing.resize(lacing.len() + packet.len() / 255, 255);
+            lacing.push((packet.len() % 255) as u8);
+        }
+        let mut page = b"OggS\0".to_vec();
+        page.push(flags);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&1u32.to_le_bytes());
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        for packet in packets {
+            page.extend_from_slice(packet);
+        }
+        let crc = ogg_crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        page
+    }
+
+    /// Mono Ogg Vorbis with 256 frame blocks, each audio packet 128 frames of silence. The
+    /// setup has the least the format allows: one codebook, a floor without partitions, an
+    /// empty residue and a single mode
+    fn silent_ogg(packets: usize) -> Vec<u8> {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend_from_slice(&0u32.to_le_bytes());
+        ident.push(1);
+        ident.extend_from_slice(&8000u32.to_le_bytes());
+        ident.extend_from_slice(&[0; 12]);
+        ident.extend_from_slice(&[0x88, 1]);
+
+        let mut comment = b"\x03vorbis".to_vec();
+        comment.extend_from_slice(&[0; 8]);
+        comment.push(1);
+
+        let mut setup = Bits::default();
+        for &byte in b"\x05vorbis" {
+            setup.put(u32::from(byte), 8);
+        }
+        // One codebook of two one bit entries
+        setup.put(0, 8);
+        setup.put(0x56_4342, 24);
+        setup.put(1, 16);
+        setup.put(2, 24);
+        setup.put(0, 2);
+        setup.put(0, 5);
+        setup.put(0, 5);
+        setup.put(0, 4);
+        // One unused time transform
+        setup.put(0, 6);
+        setup.put(0, 16);
+        // Floor type 1, no partitions, multiplier 1, 8 range bits
+        setup.put(0, 6);
+        setup.put(1, 16);
+        setup.put(0, 5);
+        setup.put(0, 2);
+        setup.put(8, 4);
+        // Residue type 0 covering nothing
+        setup.put(0, 6);
+        setup.put(0, 16);
+        setup.put(0, 24);
+        setup.put(0, 24);
+        setup.put(0, 24);
+        setup.put(0, 6);
+        setup.put(0, 8);
+        setup.put(0, 4);
+        // A mapping with one submap and no coupling
+        setup.put(0, 6);
+        setup.put(0, 16);
+        setup.put(0, 4);
+        setup.put(0, 24);
+        // A mode with short blocks, then the framing bit
+        setup.put(0, 6);
+        setup.put(0, 1);
+        setup.put(0, 32);
+        setup.put(0, 8);
+        setup.put(1, 1);
+
+        // An audio packet, mode zero, with the floor marked unused so the channel is silent
+        let audio = vec![vec![0]; packets];
+        let mut out = ogg_page(0x02, 0, 0, &[ident]);
+        out.extend(ogg_page(0, 0, 1, &[comment, setup.bytes]));
+        let granule = (packets.max(1) - 1) as u64 * 128;
+        out.extend(ogg_page(0x04, granule, 2, &audio));
+        out
+    }
+
+    fn decode_wav(bytes: &[u8]) -> Result<Vec<f32>, DecodeError> {
+        Decoder::Wav(WavReader::new(Cursor::new(bytes))?).read_to_end()
+    }
+
+    fn decode_vorbis(bytes: &[u8]) -> Result<Vec<f32>, DecodeError> {
+        Decoder::vorbis(Cursor::new(bytes))?.read_to_end()
+    }
+
+    #[test]
+    fn test_files_decode() {
+        let samples = decode_wav(&ramp_wav()).unwrap();
+        assert_eq!(samples.len(), 64);
+        assert_eq!(samples[1], 1.0 / 128.0);
+
+        let mut decoder = Decoder::vorbis(Cursor::new(silent_ogg(9))).unwrap();
+        assert_eq!((decoder.sample_rate(), decoder.channels()), (8000, 1));
+        let samples = decoder.read_to_end().unwrap();
+        assert_eq!(samples.len(), 8 * 128);
+        assert!(samples.iter().all(|&s| s == 0.0));
+        decoder.rewind().unwrap();
+        assert_eq!(decoder.read_to_end().unwrap().len(), 8 * 128);
+    }
+
+    #[test]
+    fn truncated_wavs_are_errors_or_end_early() {
+        let file = ramp_wav();
+        let data_start = file.len() - 128;
+        for cut in 0..data_start {
+            match decode_wav(&file[..cut]) {
+                Err(DecodeError::Malformed(_)) => {}
+                other => panic!("cut to {} bytes gave {:?}", cut, other),
+            }
+        }
+        // Recordings that stopped early keep what they have
+        for cut in data_start..file.len() {
+            let samples = decode_wav(&file[..cut]).unwrap();
+            assert_eq!(samples.len(), (cut - data_start) / 2);
+        }
+    }
+
+    #[test]
+    fn corrupt_wav_headers_are_errors() {
+        let file = ramp_wav();
+        // Where the fmt chunk's fields start, after the RIFF header and the skipped chunk
+        let fmt = 32;
+        let corrupt = |at: usize, bytes: &[u8]| {
+            let mut file = file.clone();
+            file[at..at + bytes.len()].copy_from_slice(bytes);
+            decode_wav(&file)
+        };
+        assert!(matches!(
+            corrupt(0, b"RIFX"),
+            Err(DecodeError::Malformed(_))
+        ));
+        assert!(matches!(
+            corrupt(8, b"WAVF"),
+            Err(DecodeError::Malformed(_))
+        ));
+        // The fmt chunk's size, then its fields
+        assert!(matches!(
+            corrupt(fmt - 4, &[8]),
+            Err(DecodeError::Malformed(_))
+        ));
+        assert!(matches!(
+            corrupt(fmt, &[2, 0]),
+            Err(DecodeError::UnsupportedEncoding {
+                format: 2,
+                bits: 16
+            })
+        ));
+        assert!(matches!(
+            corrupt(fmt + 2, &[0, 0]),
+            Err(DecodeError::UnsupportedChannels(0))
+        ));
+        assert!(matches!(
+            corrupt(fmt + 2, &[3, 0]),
+            Err(DecodeError::UnsupportedChannels(3))
+        ));
+        assert!(matches!(
+            corrupt(fmt + 4, &[0, 0, 0, 0]),
+            Err(DecodeError::Malformed(_))
+        ));
+        assert!(matches!(
+            corrupt(fmt + 12, &[4]),
+            Err(DecodeError::Malformed(_))
+        ));
+        assert!(matches!(
+            corrupt(fmt + 14, &[12]),
+            Err(DecodeError::UnsupportedEncoding {
+                format: 1,
+                bits: 12
+            })
+        ));
+        // A data chunk where the fmt chunk should be
+        assert!(matches!(
+            corrupt(fmt - 8, b"data"),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn truncated_oggs_are_errors_or_end_early() {
+        let file = silent_ogg(9);
+        let audio_start = file.len() - 9 - 9 - 27;
+        for cut in 0..file.len() {
+            match decode_vorbis(&file[..cut]) {
+                Ok(_) => assert!(cut >= audio_start, "cut to {} bytes decoded", cut),
+                Err(DecodeError::Malformed(_)) | Err(DecodeError::Vorbis(_)) => {}
+                Err(e) => panic!("cut to {} bytes gave {:?}", cut, e),
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_oggs_are_errors() {
+        let file = silent_ogg(9);
+        for at in 0..file.len() {
+            for &flip in [0x01, 0x80, 0xff].iter() {
+                let mut corrupt = file.clone();
+                corrupt[at] ^= flip;
+                assert!(
+                    decode_vorbis(&corrupt).is_err(),
+                    "byte {} xor {:#x} decoded",
+                    at,
+                    flip
+                );
+            }
+        }
+    }
+}
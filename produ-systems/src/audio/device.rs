@@ -0,0 +1,173 @@
+use super::backend::{AudioBackend, BackendError};
+use super::mixer::{Mixer, MixerConfig};
+use super::Audio;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{Sample, SampleFormat, SampleRate, StreamConfig};
+use std::sync::{Arc, Mutex};
+
+/// Seconds between attempts to get a lost device back
+const REOPEN_INTERVAL: f32 = 1.0;
+
+/// The system's default output device. It pulls from the mixer on its own thread, as fast as
+/// the sound card wants samples
+pub struct DeviceOutput {
+    /// Shared with the device's callback, which only ever `try_lock`s it. Keeping it here lets
+    /// a new stream take over when the device goes away
+    mixer: Arc<Mutex<Mixer>>,
+    sample_rate: u32,
+    stream: Option<cpal::Stream>,
+    /// Set from the device's error callback
+    failure: Arc<Mutex<Option<String>>>,
+    reopen_in: f32,
+}
+
+impl DeviceOutput {
+    /// Opens the default device at the rate it likes best, or `config`'s if it can do that,
+    /// and the `Audio` to go with it
+    pub fn open(config: MixerConfig) -> Result<(Audio, DeviceOutput), BackendError> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or(BackendError::NoDevice)?;
+        let sample_rate = choose_sample_rate(&device, config.sample_rate)?;
+        let (audio, mixer) = Audio::new(MixerConfig {
+            sample_rate,
+            ..config
+        });
+        let mut output = DeviceOutput {
+            mixer: Arc::new(Mutex::new(mixer)),
+            sample_rate,
+            stream: None,
+            failure: Arc::new(Mutex::new(None)),
+            reopen_in: 0.0,
+        };
+        output.stream = Some(output.start(&device)?);
+        Ok((audio, output))
+    }
+
+    fn start(&self, device: &cpal::Device) -> Result<cpal::Stream, BackendError> {
+        let supported = device
+            .supported_output_configs()
+            .map_err(device_error)?
+            .filter(|range| {
+                range.min_sample_rate().0 <= self.sample_rate
+                    && self.sample_rate <= range.max_sample_rate().0
+            })
+            // Stereo float if there is one, or whatever is closest
+            .min_by_key(|range| {
+                (
+                    range.channels() != 2,
+                    range.sample_format() != SampleFormat::F32,
+                )
+            })
+            .ok_or_else(|| {
+                BackendError::Device(format!("{} Hz is not supported", self.sample_rate))
+            })?
+            .with_sample_rate(SampleRate(self.sample_rate));
+        let format = supported.sample_format();
+        let config = supported.config();
+        let stream = match format {
+            SampleFormat::F32 => self.build::<f32>(device, &config),
+            SampleFormat::I16 => self.build::<i16>(device, &config),
+            SampleFormat::U16 => self.build::<u16>(device, &config),
+        }?;
+        stream.play().map_err(device_error)?;
+        Ok(stream)
+    }
+
+    fn build<T: Sample>(
+        &self,
+        device: &cpal::Device,
+        config: &StreamConfig,
+    ) -> Result<cpal::Stream, BackendError> {
+        let mixer = self.mixer.clone();
+        let failure = self.failure.clone();
+        let channels = config.channels as usize;
+        let mut stereo = vec![];
+        let stream = device
+            .build_output_stream(
+                config,
+                move |data: &mut [T], _| {
+                    let frames = data.len() / channels;
+                    // Only grows for the first few callbacks, until it fits the device's buffer
+                    if stereo.len() < frames * 2 {
+                        stereo.resize(frames * 2, 0.0);
+                    }
+                    let stereo = &mut stereo[..frames * 2];
+                    match mixer.try_lock() {
+                        Ok(mut mixer) => mixer.render(stereo),
+                        // Only while a new stream takes over
+                        Err(_) => stereo.iter_mut().for_each(|s| *s = 0.0),
+                    }
+                    for (out, frame) in data.chunks_exact_mut(channels).zip(stereo.chunks_exact(2))
+                    {
+                        match out.len() {
+                            1 => out[0] = Sample::from(&((frame[0] + frame[1]) * 0.5)),
+                            _ => {
+                                out[0] = Sample::from(&frame[0]);
+                                out[1] = Sample::from(&frame[1]);
+                                for extra in out[2..].iter_mut() {
+                                    *extra = Sample::from(&0.0f32);
+                                }
+                            }
+                        }
+                    }
+                },
+                move |error| {
+                    if let Ok(mut failure) = failure.lock() {
+                        *failure = Some(error.to_string());
+                    }
+                },
+            )
+            .map_err(device_error)?;
+        Ok(stream)
+    }
+}
+
+impl AudioBackend for DeviceOutput {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Checks the device is still there, and tries the default device again every so often
+    /// once it is not
+    fn update(&mut self, dt: f32) -> Result<(), BackendError> {
+        let failure = self.failure.lock().ok().and_then(|mut f| f.take());
+        if let Some(failure) = failure {
+            self.stream = None;
+            self.reopen_in = REOPEN_INTERVAL;
+            return Err(BackendError::Device(failure));
+        }
+        if self.stream.is_some() {
+            return Ok(());
+        }
+        self.reopen_in -= dt;
+        if self.reopen_in > 0.0 {
+            return Ok(());
+        }
+        self.reopen_in = REOPEN_INTERVAL;
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or(BackendError::NoDevice)?;
+        self.stream = Some(self.start(&device)?);
+        Ok(())
+    }
+}
+
+/// `preferred` if the device can play at that rate, otherwise its own default
+fn choose_sample_rate(device: &cpal::Device, preferred: u32) -> Result<u32, BackendError> {
+    let supports_preferred = device
+        .supported_output_configs()
+        .map_err(device_error)?
+        .any(|range| {
+            range.min_sample_rate().0 <= preferred && preferred <= range.max_sample_rate().0
+        });
+    if supports_preferred {
+        return Ok(preferred);
+    }
+    let default = device.default_output_config().map_err(device_error)?;
+    Ok(default.sample_rate().0)
+}
+
+fn device_error<E: std::error::Error>(error: E) -> BackendError {
+    BackendError::Device(error.to_string())
+}
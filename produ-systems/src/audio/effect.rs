@@ -0,0 +1,595 @@
+use std::f32::consts::PI;
+
+/// Q of a Butterworth filter, as flat as a filter gets without a bump at the cutoff
+pub const BUTTERWORTH_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+pub fn db_to_gain(db: f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+pub fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.max(1e-9).log10()
+}
+
+/// Per sample factor of an envelope that covers most of the way in `seconds`, `0.0` for
+/// instant
+pub(crate) fn smoothing(seconds: f32, sample_rate: u32) -> f32 {
+    if seconds <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / (seconds * sample_rate as f32)).exp()
+    }
+}
+
+/// Something that reworks a bus's interleaved stereo as it plays. Effects run on the audio
+/// thread, they are built on the game thread so any memory they need is already there
+pub enum Effect {
+    Filter(Biquad),
+    Reverb(Box<Reverb>),
+    Delay(Delay),
+    Compressor(Compressor),
+    Limiter(Limiter),
+}
+
+impl Effect {
+    pub(crate) fn process(&mut self, buffer: &mut [f32]) {
+        match self {
+            Effect::Filter(filter) => filter.process(buffer),
+            Effect::Reverb(reverb) => reverb.process(buffer),
+            Effect::Delay(delay) => delay.process(buffer),
+            Effect::Compressor(compressor) => compressor.process(buffer),
+            Effect::Limiter(limiter) => limiter.process(buffer),
+        }
+    }
+}
+
+impl From<Biquad> for Effect {
+    fn from(filter: Biquad) -> Effect {
+        Effect::Filter(filter)
+    }
+}
+
+impl From<Reverb> for Effect {
+    fn from(reverb: Reverb) -> Effect {
+        Effect::Reverb(Box::new(reverb))
+    }
+}
+
+impl From<Delay> for Effect {
+    fn from(delay: Delay) -> Effect {
+        Effect::Delay(delay)
+    }
+}
+
+impl From<Compressor> for Effect {
+    fn from(compressor: Compressor) -> Effect {
+        Effect::Compressor(compressor)
+    }
+}
+
+impl From<Limiter> for Effect {
+    fn from(limiter: Limiter) -> Effect {
+        Effect::Limiter(limiter)
+    }
+}
+
+/// Two pole filter from the Audio EQ Cookbook, on both channels
+#[derive(Debug, Clone)]
+pub struct Biquad {
+    sample_rate: u32,
+    b: [f32; 3],
+    a: [f32; 2],
+    /// Transposed direct form II state, per channel
+    state: [[f32; 2]; 2],
+}
+
+impl Biquad {
+    /// Cuts what is above `cutoff` Hz
+    pub fn low_pass(sample_rate: u32, cutoff: f32, q: f32) -> Biquad {
+        let mut filter = Biquad::pass_through(sample_rate);
+        filter.set_low_pass(cutoff, q);
+        filter
+    }
+
+    /// Cuts what is below `cutoff` Hz
+    pub fn high_pass(sample_rate: u32, cutoff: f32, q: f32) -> Biquad {
+        let mut filter = Biquad::pass_through(sample_rate);
+        filter.set_high_pass(cutoff, q);
+        filter
+    }
+
+    fn pass_through(sample_rate: u32) -> Biquad {
+        Biquad {
+            sample_rate: sample_rate.max(1),
+            b: [1.0, 0.0, 0.0],
+            a: [0.0, 0.0],
+            state: [[0.0; 2]; 2],
+        }
+    }
+
+    /// Retunes the filter without clearing what it is holding, so it can sweep while playing
+    pub fn set_low_pass(&mut self, cutoff: f32, q: f32) {
+        let (cos, alpha) = self.shape(cutoff, q);
+        let b1 = 1.0 - cos;
+        self.set_coefficients(
+            [b1 * 0.5, b1, b1 * 0.5],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        );
+    }
+
+    pub fn set_high_pass(&mut self, cutoff: f32, q: f32) {
+        let (cos, alpha) = self.shape(cutoff, q);
+        let b1 = -(1.0 + cos);
+        self.set_coefficients(
+            [-b1 * 0.5, b1, -b1 * 0.5],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        );
+    }
+
+    fn shape(&self, cutoff: f32, q: f32) -> (f32, f32) {
+        let nyquist = self.sample_rate as f32 * 0.5;
+        let cutoff = cutoff.clamp(10.0, nyquist * 0.99);
+        let omega = 2.0 * PI * cutoff / self.sample_rate as f32;
+        (omega.cos(), omega.sin() / (2.0 * q.max(0.01)))
+    }
+
+    fn set_coefficients(&mut self, b: [f32; 3], a: [f32; 3]) {
+        self.b = [b[0] / a[0], b[1] / a[0], b[2] / a[0]];
+        self.a = [a[1] / a[0], a[2] / a[0]];
+    }
+
+    pub fn reset(&mut self) {
+        self.state = [[0.0; 2]; 2];
+    }
+
+    pub(crate) fn process_frame(&mut self, frame: [f32; 2]) -> [f32; 2] {
+        let mut out = [0.0; 2];
+        for (channel, state) in self.state.iter_mut().enumerate() {
+            let x = frame[channel];
+            let y = self.b[0] * x + state[0];
+            state[0] = self.b[1] * x - self.a[0] * y + state[1];
+            state[1] = self.b[2] * x - self.a[1] * y;
+            out[channel] = y;
+        }
+        out
+    }
+
+    pub(crate) fn process(&mut self, buffer: &mut [f32]) {
+        for frame in buffer.chunks_exact_mut(2) {
+            let out = self.process_frame([frame[0], frame[1]]);
+            frame.copy_from_slice(&out);
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ReverbParams {
+    /// From `0.0` a small room to `1.0` a long hall
+    pub room_size: f32,
+    /// How quickly the highs die away, `0.0` to `1.0`
+    pub damping: f32,
+    pub wet: f32,
+    pub dry: f32,
+    /// Stereo spread of the tail, `0.0` is mono
+    pub width: f32,
+}
+
+impl Default for ReverbParams {
+    fn default() -> ReverbParams {
+        ReverbParams {
+            room_size: 0.5,
+            damping: 0.5,
+            wet: 0.3,
+            dry: 1.0,
+            width: 1.0,
+        }
+    }
+}
+
+/// Delay line lengths in samples at 44.1 kHz, Freeverb's tuning
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+/// The right channel's delay lines are this much longer, so the two sides decorrelate
+const STEREO_SPREAD: usize = 23;
+
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    /// The one pole low pass in the feedback path that does the damping
+    filtered: f32,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.filtered = output * (1.0 - damping) + self.filtered * damping;
+        self.buffer[self.index] = input + self.filtered * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer[self.index];
+        self.buffer[self.index] = input + delayed * 0.5;
+        self.index = (self.index + 1) % self.buffer.len();
+        delayed - input
+    }
+}
+
+/// Freeverb, parallel combs into allpasses for each channel
+pub struct Reverb {
+    params: ReverbParams,
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+}
+
+impl Reverb {
+    pub fn new(sample_rate: u32, params: ReverbParams) -> Reverb {
+        let scale = sample_rate as f32 / 44100.0;
+        let length =
+            |samples: usize, spread: usize| (((samples + spread) as f32 * scale) as usize).max(1);
+        let combs = |spread| {
+            COMB_TUNING
+                .iter()
+                .map(|&samples| Comb {
+                    buffer: vec![0.0; length(samples, spread)],
+                    index: 0,
+                    filtered: 0.0,
+                })
+                .collect()
+        };
+        let allpasses = |spread| {
+            ALLPASS_TUNING
+                .iter()
+                .map(|&samples| Allpass {
+                    buffer: vec![0.0; length(samples, spread)],
+                    index: 0,
+                })
+                .collect()
+        };
+        Reverb {
+            params,
+            combs: [combs(0), combs(STEREO_SPREAD)],
+            allpasses: [allpasses(0), allpasses(STEREO_SPREAD)],
+        }
+    }
+
+    pub fn params(&self) -> &ReverbParams {
+        &self.params
+    }
+
+    pub(crate) fn process(&mut self, buffer: &mut [f32]) {
+        let params = self.params;
+        let feedback = params.room_size.clamp(0.0, 1.0) * 0.28 + 0.7;
+        let damping = params.damping.clamp(0.0, 1.0) * 0.4;
+        let wet1 = params.wet * (params.width * 0.5 + 0.5);
+        let wet2 = params.wet * (0.5 - params.width * 0.5);
+        for frame in buffer.chunks_exact_mut(2) {
+            // Both sides hear the same mono input, quietly so the combs have headroom
+            let input = (frame[0] + frame[1]) * 0.015;
+            let mut tails = [0.0; 2];
+            for (tail, (combs, allpasses)) in tails
+                .iter_mut()
+                .zip(self.combs.iter_mut().zip(self.allpasses.iter_mut()))
+            {
+                let mut sum: f32 = combs
+                    .iter_mut()
+                    .map(|comb| comb.process(input, feedback, damping))
+                    .sum();
+                for allpass in allpasses.iter_mut() {
+                    sum = allpass.process(sum);
+                }
+                *tail = sum;
+            }
+            let (left, right) = (frame[0], frame[1]);
+            frame[0] = tails[0] * wet1 + tails[1] * wet2 + left * params.dry;
+            frame[1] = tails[1] * wet1 + tails[0] * wet2 + right * params.dry;
+        }
+    }
+}
+
+/// Echoes, each one `feedback` times the last
+pub struct Delay {
+    buffer: Vec<[f32; 2]>,
+    index: usize,
+    pub feedback: f32,
+    /// Level of the echoes, the dry signal passes through untouched
+    pub wet: f32,
+}
+
+impl Delay {
+    pub fn new(sample_rate: u32, seconds: f32, feedback: f32, wet: f32) -> Delay {
+        let length = ((seconds * sample_rate as f32) as usize).max(1);
+        Delay {
+            buffer: vec![[0.0; 2]; length],
+            index: 0,
+            feedback,
+            wet,
+        }
+    }
+
+    pub(crate) fn process(&mut self, buffer: &mut [f32]) {
+        // Past 1 the echoes would grow forever
+        let feedback = self.feedback.clamp(0.0, 0.99);
+        for frame in buffer.chunks_exact_mut(2) {
+            let delayed = self.buffer[self.index];
+            self.buffer[self.index] = [
+                frame[0] + delayed[0] * feedback,
+                frame[1] + delayed[1] * feedback,
+            ];
+            self.index = (self.index + 1) % self.buffer.len();
+            frame[0] += delayed[0] * self.wet;
+            frame[1] += delayed[1] * self.wet;
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CompressorParams {
+    /// Level in dB above which the compressor turns things down
+    pub threshold: f32,
+    /// How many dB over the threshold in make 1 dB over it out
+    pub ratio: f32,
+    /// Seconds to react to getting louder
+    pub attack: f32,
+    /// Seconds to let go once it gets quieter
+    pub release: f32,
+    /// Gain in dB added back after compressing
+    pub makeup: f32,
+}
+
+impl Default for CompressorParams {
+    fn default() -> CompressorParams {
+        CompressorParams {
+            threshold: -18.0,
+            ratio: 4.0,
+            attack: 0.01,
+            release: 0.1,
+            makeup: 0.0,
+        }
+    }
+}
+
+/// Evens out loudness by turning down whatever goes over the threshold
+pub struct Compressor {
+    params: CompressorParams,
+    attack: f32,
+    release: f32,
+    envelope: f32,
+}
+
+impl Compressor {
+    pub fn new(sample_rate: u32, params: CompressorParams) -> Compressor {
+        Compressor {
+            params,
+            attack: smoothing(params.attack, sample_rate),
+            release: smoothing(params.release, sample_rate),
+            envelope: 0.0,
+        }
+    }
+
+    pub fn params(&self) -> &CompressorParams {
+        &self.params
+    }
+
+    pub(crate) fn process(&mut self, buffer: &mut [f32]) {
+        let slope = 1.0 - 1.0 / self.params.ratio.max(1.0);
+        for frame in buffer.chunks_exact_mut(2) {
+            let level = frame[0].abs().max(frame[1].abs());
+            let coefficient = if level > self.envelope {
+                self.attack
+            } else {
+                self.release
+            };
+            self.envelope = level + (self.envelope - level) * coefficient;
+            let over = (gain_to_db(self.envelope) - self.params.threshold).max(0.0);
+            let gain = db_to_gain(self.params.makeup - over * slope);
+            frame[0] *= gain;
+            frame[1] *= gain;
+        }
+    }
+}
+
+/// Keeps peaks under a ceiling, clamping down on them instantly and letting go smoothly. Goes
+/// last on the master bus so nothing clips
+pub struct Limiter {
+    ceiling: f32,
+    release: f32,
+    gain: f32,
+}
+
+impl Limiter {
+    /// `ceiling` in dB, `release` in seconds
+    pub fn new(sample_rate: u32, ceiling: f32, release: f32) -> Limiter {
+        Limiter {
+            ceiling: db_to_gain(ceiling),
+            release: smoothing(release, sample_rate),
+            gain: 1.0,
+        }
+    }
+
+    pub(crate) fn process(&mut self, buffer: &mut [f32]) {
+        for frame in buffer.chunks_exact_mut(2) {
+            let peak = frame[0].abs().max(frame[1].abs());
+            let target = if peak > self.ceiling {
+                self.ceiling / peak
+            } else {
+                1.0
+            };
+            self.gain = if target < self.gain {
+                target
+            } else {
+                target + (self.gain - target) * self.release
+            };
+            frame[0] *= self.gain;
+            frame[1] *= self.gain;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::{FRAC_1_SQRT_2, TAU};
+
+    const RATE: u32 = 48000;
+
+    /// Interleaved stereo sine, the same on both sides
+    fn sine(frequency: f32, amplitude: f32, seconds: f32) -> Vec<f32> {
+        (0..(RATE as f32 * seconds) as usize)
+            .flat_map(|i| {
+                let s = (i as f32 / RATE as f32 * frequency * TAU).sin() * amplitude;
+                vec![s, s]
+            })
+            .collect()
+    }
+
+    /// One full scale click at the start of `seconds` of silence
+    fn impulse(seconds: f32) -> Vec<f32> {
+        let mut buffer = vec![0.0; (RATE as f32 * seconds) as usize * 2];
+        buffer[0] = 1.0;
+        buffer[1] = 1.0;
+        buffer
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, s| peak.max(s.abs()))
+    }
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|s| s * s).sum()
+    }
+
+    /// Peak of a sine through `effect`, once it has settled
+    fn response<E: Into<Effect>>(effect: E, frequency: f32) -> f32 {
+        let mut buffer = sine(frequency, 1.0, 0.5);
+        effect.into().process(&mut buffer);
+        peak(&buffer[buffer.len() / 2..])
+    }
+
+    #[test]
+    fn decibels_convert_both_ways() {
+        assert!((db_to_gain(0.0) - 1.0).abs() < 1e-6);
+        assert!((db_to_gain(-6.0) - 0.501).abs() < 1e-3);
+        assert!((db_to_gain(20.0) - 10.0).abs() < 1e-4);
+        assert!((gain_to_db(0.1) + 20.0).abs() < 1e-4);
+        for &db in [-60.0, -12.0, -1.0, 0.0, 6.0].iter() {
+            assert!((gain_to_db(db_to_gain(db)) - db).abs() < 1e-3);
+        }
+        // Silence is very quiet rather than minus infinity
+        assert!((gain_to_db(0.0) + 180.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn filters_pass_their_band_and_cut_the_other() {
+        let low = || Biquad::low_pass(RATE, 1000.0, BUTTERWORTH_Q);
+        let high = || Biquad::high_pass(RATE, 1000.0, BUTTERWORTH_Q);
+        assert!((response(low(), 100.0) - 1.0).abs() < 0.01);
+        assert!((response(low(), 1000.0) - FRAC_1_SQRT_2).abs() < 0.01);
+        // Two poles, 40 dB down a decade past the cutoff
+        assert!(response(low(), 10000.0) < db_to_gain(-38.0));
+        assert!((response(high(), 10000.0) - 1.0).abs() < 0.01);
+        assert!((response(high(), 1000.0) - FRAC_1_SQRT_2).abs() < 0.01);
+        assert!(response(high(), 100.0) < db_to_gain(-38.0));
+
+        // Retuned on the fly
+        let mut filter = low();
+        filter.set_high_pass(1000.0, BUTTERWORTH_Q);
+        assert!(response(filter, 100.0) < db_to_gain(-38.0));
+    }
+
+    #[test]
+    fn delays_echo_later_and_quieter() {
+        let mut buffer = impulse(0.35);
+        Delay::new(RATE, 0.1, 0.5, 0.8).process(&mut buffer);
+        let frame = (RATE / 10) as usize * 2;
+        assert_eq!(buffer[0], 1.0);
+        assert!((buffer[frame] - 0.8).abs() < 1e-6);
+        assert!((buffer[frame * 2] - 0.4).abs() < 1e-6);
+        assert!((buffer[frame * 3 + 1] - 0.2).abs() < 1e-6);
+        let echoes = [0, 1, frame, frame + 1, frame * 2, frame * 2 + 1];
+        let silent = buffer[..frame * 3]
+            .iter()
+            .enumerate()
+            .all(|(i, &s)| echoes.contains(&i) || s == 0.0);
+        assert!(silent);
+    }
+
+    #[test]
+    fn reverb_leaves_a_tail_that_dies_away() {
+        let mut buffer = impulse(2.0);
+        Reverb::new(RATE, ReverbParams::default()).process(&mut buffer);
+        let second = RATE as usize * 2;
+        let early = energy(&buffer[2..second / 2]);
+        let late = energy(&buffer[second..second * 3 / 2]);
+        assert!(early > 1e-4);
+        assert!(late > 0.0 && late < early * 0.1);
+        assert!(peak(&buffer[2..]) < 0.5);
+        // Spread between the sides
+        assert_ne!(buffer[second / 4], buffer[second / 4 + 1]);
+
+        let mut mono = impulse(0.5);
+        let params = ReverbParams {
+            width: 0.0,
+            ..ReverbParams::default()
+        };
+        Reverb::new(RATE, params).process(&mut mono);
+        assert!(mono.chunks_exact(2).all(|frame| frame[0] == frame[1]));
+
+        let mut dry = impulse(0.5);
+        let params = ReverbParams {
+            wet: 0.0,
+            ..ReverbParams::default()
+        };
+        Reverb::new(RATE, params).process(&mut dry);
+        assert_eq!(dry, impulse(0.5));
+    }
+
+    #[test]
+    fn compressors_turn_down_only_what_goes_over() {
+        // A steady level, so the envelope settles on it
+        let compress = |params, level: f32| {
+            let mut buffer = vec![level; RATE as usize];
+            Compressor::new(RATE, params).process(&mut buffer);
+            buffer[buffer.len() - 1]
+        };
+        let params = CompressorParams::default();
+        // 18 dB over at 4:1 comes out 4.5 dB over
+        let loud = compress(params, 1.0);
+        assert!(
+            (gain_to_db(loud) + 13.5).abs() < 0.01,
+            "{}",
+            gain_to_db(loud)
+        );
+        let quiet = db_to_gain(-30.0);
+        assert!((compress(params, quiet) - quiet).abs() < 1e-6);
+
+        let params = CompressorParams {
+            makeup: 6.0,
+            ..params
+        };
+        assert!((gain_to_db(compress(params, 1.0)) - gain_to_db(loud) - 6.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn limiters_hold_peaks_under_the_ceiling_then_let_go() {
+        let mut limiter = Limiter::new(RATE, -1.0, 0.1);
+        let mut loud = sine(440.0, 2.0, 0.25);
+        limiter.process(&mut loud);
+        assert!(peak(&loud) <= db_to_gain(-1.0) + 1e-6);
+        assert!(peak(&loud) > db_to_gain(-1.5));
+
+        let mut quiet = sine(440.0, 0.5, 1.0);
+        limiter.process(&mut quiet);
+        // Still held down at first
+        assert!(peak(&quiet[..200]) < 0.25);
+        assert!((peak(&quiet[quiet.len() / 2..]) - 0.5).abs() < 1e-3);
+    }
+}
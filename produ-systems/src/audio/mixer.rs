@@ -0,0 +1,790 @@
+use super::bus::{Bus, Ducking};
+use super::channel::{Receiver, Sender};
+use super::effect::{self, Biquad, Effect, BUTTERWORTH_Q};
+use super::music::{MusicCommand, MusicEvent, MusicPlayer};
+use super::sound::Sound;
+use super::spatial::{Spatial, OPEN_CUTOFF};
+use super::stream::Stream;
+use std::f32::consts::FRAC_PI_4;
+
+/// How long voices take to fade in and out when they start, stop, pause or change volume
+const FADE_SECONDS: f32 = 0.005;
+
+/// Frames mixed at a time, the size of each bus's buffer
+const BLOCK_FRAMES: usize = 512;
+
+/// Room reserved for effects on each bus, so adding one does not allocate on the audio thread
+const EFFECTS_PER_BUS: usize = 8;
+
+/// How quickly a ducking key's level falls away, long enough to bridge the gaps between words
+const KEY_DECAY_SECONDS: f32 = 0.1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct VoiceHandle(pub(crate) u64);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct EffectHandle(pub(crate) u64);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PlayParams {
+    pub volume: f32,
+    /// Playback speed, `2.0` plays an octave up in half the time
+    pub pitch: f32,
+    /// From `-1.0` fully left to `1.0` fully right
+    pub pan: f32,
+    pub looping: bool,
+    /// Starts the voice paused, ready to `resume`
+    pub paused: bool,
+    pub bus: Bus,
+}
+
+impl Default for PlayParams {
+    fn default() -> PlayParams {
+        PlayParams {
+            volume: 1.0,
+            pitch: 1.0,
+            pan: 0.0,
+            looping: false,
+            paused: false,
+            bus: Bus::Sfx,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MixerConfig {
+    /// Output frames per second
+    pub sample_rate: u32,
+    /// Voices playing at once, sounds played past this are dropped
+    pub max_voices: usize,
+    /// Commands the game can queue up between two mixer renders
+    pub command_capacity: usize,
+}
+
+impl Default for MixerConfig {
+    fn default() -> MixerConfig {
+        MixerConfig {
+            sample_rate: 48000,
+            max_voices: 64,
+            command_capacity: 1024,
+        }
+    }
+}
+
+/// What a voice plays
+pub(crate) enum Source {
+    Sound {
+        sound: Sound,
+        /// In frames of the sound, between two frames when resampling
+        position: f64,
+    },
+    Stream {
+        stream: Stream,
+        /// How far between `frames` the voice is
+        fraction: f64,
+        /// The two frames around the position, pulled from the stream as it moves on
+        frames: [[f32; 2]; 2],
+        started: bool,
+        /// The stream ran out, the last frame is `frames[0]`
+        ended: bool,
+    },
+}
+
+impl Source {
+    pub(crate) fn sound(sound: Sound) -> Source {
+        Source::Sound {
+            sound,
+            position: 0.0,
+        }
+    }
+
+    pub(crate) fn stream(stream: Stream) -> Source {
+        Source::Stream {
+            stream,
+            fraction: 0.0,
+            frames: [[0.0; 2]; 2],
+            started: false,
+            ended: false,
+        }
+    }
+
+    pub(crate) fn sample_rate(&self) -> u32 {
+        match self {
+            Source::Sound { sound, .. } => sound.sample_rate(),
+            Source::Stream { stream, .. } => stream.sample_rate(),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        match self {
+            Source::Sound { sound, .. } => sound.channels(),
+            Source::Stream { stream, .. } => stream.channels(),
+        }
+    }
+
+    /// Linearly interpolated frame at the current position. Silence follows the last frame of
+    /// a sound unless it loops
+    pub(crate) fn sample(&mut self, looping: bool) -> [f32; 2] {
+        let (a, b, fraction) = match self {
+            Source::Sound { sound, position } => {
+                let index = *position as usize;
+                let next = if index + 1 < sound.frames() {
+                    sound.frame(index + 1)
+                } else if looping {
+                    sound.frame(0)
+                } else {
+                    [0.0, 0.0]
+                };
+                (sound.frame(index), next, (*position - index as f64) as f32)
+            }
+            Source::Stream {
+                stream,
+                fraction,
+                frames,
+                started,
+                ..
+            } => {
+                if !*started {
+                    *started = true;
+                    frames[0] = stream.next_frame().unwrap_or([0.0; 2]);
+                    frames[1] = stream.next_frame().unwrap_or([0.0; 2]);
+                }
+                (frames[0], frames[1], *fraction as f32)
+            }
+        };
+        [
+            a[0] + (b[0] - a[0]) * fraction,
+            a[1] + (b[1] - a[1]) * fraction,
+        ]
+    }
+
+    /// Moves on by `step` frames. Returns `false` at the end
+    pub(crate) fn advance(&mut self, step: f64, looping: bool) -> bool {
+        match self {
+            Source::Sound { sound, position } => {
+                let frames = sound.frames() as f64;
+                *position += step;
+                if *position >= frames {
+                    if !looping {
+                        return false;
+                    }
+                    *position %= frames;
+                }
+                true
+            }
+            Source::Stream {
+                stream,
+                fraction,
+                frames,
+                ended,
+                ..
+            } => {
+                *fraction += step;
+                while *fraction >= 1.0 {
+                    if *ended {
+                        return false;
+                    }
+                    *fraction -= 1.0;
+                    frames[0] = frames[1];
+                    frames[1] = match stream.next_frame() {
+                        Some(frame) => frame,
+                        None if stream.is_finished() => {
+                            *ended = true;
+                            [0.0, 0.0]
+                        }
+                        // The decoder fell behind, play silence rather than wait for it
+                        None => [0.0, 0.0],
+                    };
+                }
+                true
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Source::Sound { sound, .. } => sound.frames() == 0,
+            Source::Stream { .. } => false,
+        }
+    }
+}
+
+/// What the game thread asks of the mixer
+pub(crate) enum Command {
+    Play {
+        voice: VoiceHandle,
+        source: Source,
+        params: PlayParams,
+    },
+    Stop(VoiceHandle),
+    Pause(VoiceHandle),
+    Resume(VoiceHandle),
+    SetVolume(VoiceHandle, f32),
+    SetPitch(VoiceHandle, f32),
+    SetPan(VoiceHandle, f32),
+    SetLooping(VoiceHandle, bool),
+    SetSpatial(VoiceHandle, Spatial),
+    StopAll,
+    SetBusVolume(Bus, f32),
+    SetBusMuted(Bus, bool),
+    AddEffect(Bus, EffectHandle, Effect),
+    RemoveEffect(EffectHandle),
+    SetDucking(Bus, Option<Ducking>),
+    Music(MusicCommand),
+}
+
+struct Voice {
+    handle: VoiceHandle,
+    source: Source,
+    params: PlayParams,
+    /// Where the world puts the voice, left alone for voices without an emitter
+    spatial: Spatial,
+    /// Muffles the voice while something is in the way, `None` while nothing is
+    filter: Option<Biquad>,
+    /// Left and right gains being played, they move towards the targets a little every frame
+    gains: [f32; 2],
+    stopping: bool,
+}
+
+impl Voice {
+    fn set_spatial(&mut self, spatial: Spatial, output_rate: u32) {
+        self.spatial = spatial;
+        if spatial.cutoff >= OPEN_CUTOFF || spatial.cutoff >= output_rate as f32 * 0.5 {
+            self.filter = None;
+            return;
+        }
+        match &mut self.filter {
+            Some(filter) => filter.set_low_pass(spatial.cutoff, BUTTERWORTH_Q),
+            None => {
+                self.filter = Some(Biquad::low_pass(output_rate, spatial.cutoff, BUTTERWORTH_Q))
+            }
+        }
+    }
+
+    fn target_gains(&self) -> [f32; 2] {
+        if self.stopping || self.params.paused {
+            return [0.0, 0.0];
+        }
+        let volume = (self.params.volume * self.spatial.gain).max(0.0);
+        let pan = (self.params.pan + self.spatial.pan).clamp(-1.0, 1.0);
+        if self.source.channels() == 1 {
+            // Constant power, so a sound keeps its loudness as it moves across
+            let angle = (pan + 1.0) * FRAC_PI_4;
+            [volume * angle.cos(), volume * angle.sin()]
+        } else {
+            // Stereo sounds are balanced instead, turning one side down
+            [volume * (1.0 - pan).min(1.0), volume * (1.0 + pan).min(1.0)]
+        }
+    }
+
+    /// Adds the voice into interleaved stereo `out`. Returns `false` once the voice is done
+    fn mix(&mut self, out: &mut [f32], output_rate: u32, fade: f32) -> bool {
+        if self.source.is_empty() {
+            return false;
+        }
+        let pitch = self.params.pitch * self.spatial.pitch;
+        let step = pitch.max(0.0) as f64 * self.source.sample_rate() as f64 / output_rate as f64;
+        let target = self.target_gains();
+        for frame in out.chunks_exact_mut(2) {
+            for (gain, target) in self.gains.iter_mut().zip(target.iter()) {
+                *gain += (target - *gain).clamp(-fade, fade);
+            }
+            if self.gains == [0.0, 0.0] && (self.stopping || self.params.paused) {
+                // Faded out, paused voices keep their place
+                return !self.stopping;
+            }
+            let mut sample = self.source.sample(self.params.looping);
+            if let Some(filter) = &mut self.filter {
+                sample = filter.process_frame(sample);
+            }
+            frame[0] += sample[0] * self.gains[0];
+            frame[1] += sample[1] * self.gains[1];
+
+            if !self.source.advance(step, self.params.looping) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// A ducked bus's state, following how loud its key is
+struct Ducker {
+    params: Ducking,
+    threshold: f32,
+    depth: f32,
+    attack: f32,
+    release: f32,
+    decay: f32,
+    /// Peak level of the key
+    envelope: f32,
+    gain: f32,
+}
+
+impl Ducker {
+    fn new(params: Ducking, sample_rate: u32) -> Ducker {
+        Ducker {
+            params,
+            threshold: effect::db_to_gain(params.threshold),
+            depth: effect::db_to_gain(params.depth.min(0.0)),
+            attack: effect::smoothing(params.attack, sample_rate),
+            release: effect::smoothing(params.release, sample_rate),
+            decay: effect::smoothing(KEY_DECAY_SECONDS, sample_rate),
+            envelope: 0.0,
+            gain: 1.0,
+        }
+    }
+
+    /// Works out the ducking gain of each frame in a block from the key bus's samples
+    fn follow(&mut self, key: &[f32], key_gain: f32, gains: &mut [f32]) {
+        for (gain, frame) in gains.iter_mut().zip(key.chunks_exact(2)) {
+            let level = frame[0].abs().max(frame[1].abs()) * key_gain;
+            self.envelope = level.max(self.envelope * self.decay);
+            let (target, coefficient) = if self.envelope > self.threshold {
+                (self.depth, self.attack)
+            } else {
+                (1.0, self.release)
+            };
+            self.gain = target + (self.gain - target) * coefficient;
+            *gain = self.gain;
+        }
+    }
+}
+
+struct BusState {
+    volume: f32,
+    muted: bool,
+    /// The gain being played, it moves towards the volume a little every frame
+    gain: f32,
+    /// Run in order on the bus's mix before it goes to the master
+    effects: Vec<(EffectHandle, Effect)>,
+    ducker: Option<Ducker>,
+    /// This block's ducking gain for every frame, while ducked
+    ducking: Vec<f32>,
+    buffer: Vec<f32>,
+}
+
+impl BusState {
+    fn target_gain(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume.max(0.0)
+        }
+    }
+}
+
+/// Mixes voices into interleaved stereo. It lives on the audio thread and takes commands from
+/// the game's `Audio` through a lock free queue, or renders straight into a buffer offline
+pub struct Mixer {
+    sample_rate: u32,
+    voices: Vec<Option<Voice>>,
+    commands: Receiver<Command>,
+    finished: Sender<VoiceHandle>,
+    /// Indexed by `Bus::index`
+    buses: Vec<BusState>,
+    /// Plays into the music bus
+    music: MusicPlayer,
+    /// Largest gain change per frame
+    fade: f32,
+}
+
+impl Mixer {
+    pub(crate) fn new(
+        config: &MixerConfig,
+        commands: Receiver<Command>,
+        finished: Sender<VoiceHandle>,
+        music_events: Sender<MusicEvent>,
+    ) -> Mixer {
+        let sample_rate = config.sample_rate.max(1);
+        let fade = 1.0 / (FADE_SECONDS * sample_rate as f32).max(1.0);
+        Mixer {
+            sample_rate,
+            voices: (0..config.max_voices).map(|_| None).collect(),
+            commands,
+            finished,
+            buses: Bus::ALL
+                .iter()
+                .map(|_| BusState {
+                    volume: 1.0,
+                    muted: false,
+                    gain: 1.0,
+                    effects: Vec::with_capacity(EFFECTS_PER_BUS),
+                    ducker: None,
+                    ducking: vec![1.0; BLOCK_FRAMES],
+                    buffer: vec![0.0; BLOCK_FRAMES * 2],
+                })
+                .collect(),
+            music: MusicPlayer::new(sample_rate, fade, music_events),
+            fade,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Voices playing or paused
+    pub fn active_voices(&self) -> usize {
+        self.voices.iter().filter(|v| v.is_some()).count()
+    }
+
+    /// Applies the queued commands, then fills interleaved stereo `out` with the next
+    /// `out.len() / 2` frames
+    pub fn render(&mut self, out: &mut [f32]) {
+        self.apply_commands();
+        for block in out.chunks_mut(BLOCK_FRAMES * 2) {
+            self.render_block(block);
+        }
+    }
+
+    /// Voices into their buses, buses through their effects and into the master, the master
+    /// through its effects into `out`
+    fn render_block(&mut self, out: &mut [f32]) {
+        let length = out.len();
+        for bus in self.buses.iter_mut() {
+            for sample in bus.buffer[..length].iter_mut() {
+                *sample = 0.0;
+            }
+        }
+        for slot in self.voices.iter_mut() {
+            let done = match slot {
+                Some(voice) => {
+                    let buffer = &mut self.buses[voice.params.bus.index()].buffer[..length];
+                    !voice.mix(buffer, self.sample_rate, self.fade)
+                }
+                None => continue,
+            };
+            if done {
+                let voice = slot.take().expect("the slot was just mixed");
+                // A full queue means the game stopped listening, nobody misses the event
+                let _ = self.finished.send(voice.handle);
+            }
+        }
+
+        self.music
+            .mix(&mut self.buses[Bus::Music.index()].buffer[..length]);
+
+        let master = Bus::Master.index();
+        for bus in self.buses.iter_mut().skip(1) {
+            for (_, effect) in bus.effects.iter_mut() {
+                effect.process(&mut bus.buffer[..length]);
+            }
+        }
+        // Every key has been through its effects before any bus gets ducked
+        for index in 1..self.buses.len() {
+            if let Some(mut ducker) = self.buses[index].ducker.take() {
+                let mut ducking = std::mem::take(&mut self.buses[index].ducking);
+                let key = &self.buses[ducker.params.key.index()];
+                ducker.follow(&key.buffer[..length], key.target_gain(), &mut ducking);
+                self.buses[index].ducker = Some(ducker);
+                self.buses[index].ducking = ducking;
+            }
+        }
+        let (master_bus, children) = self.buses.split_at_mut(master + 1);
+        let master_bus = &mut master_bus[master];
+        for bus in children.iter_mut() {
+            let target = bus.target_gain();
+            for (frame, (mixed, sample)) in master_bus.buffer[..length]
+                .chunks_exact_mut(2)
+                .zip(bus.buffer[..length].chunks_exact(2))
+                .enumerate()
+            {
+                bus.gain += (target - bus.gain).clamp(-self.fade, self.fade);
+                let ducking = if bus.ducker.is_some() {
+                    bus.ducking[frame]
+                } else {
+                    1.0
+                };
+                mixed[0] += sample[0] * bus.gain * ducking;
+                mixed[1] += sample[1] * bus.gain * ducking;
+            }
+        }
+
+        for (_, effect) in master_bus.effects.iter_mut() {
+            effect.process(&mut master_bus.buffer[..length]);
+        }
+        let target = master_bus.target_gain();
+        for (sample, mixed) in out
+            .chunks_exact_mut(2)
+            .zip(master_bus.buffer[..length].chunks_exact(2))
+        {
+            master_bus.gain += (target - master_bus.gain).clamp(-self.fade, self.fade);
+            sample[0] = mixed[0] * master_bus.gain;
+            sample[1] = mixed[1] * master_bus.gain;
+        }
+    }
+
+    /// Renders `frames` frames into a new buffer, for tests and tools that have no sound card
+    pub fn render_frames(&mut self, frames: usize) -> Vec<f32> {
+        let mut out = vec![0.0; frames * 2];
+        self.render(&mut out);
+        out
+    }
+
+    fn voice_mut(&mut self, handle: VoiceHandle) -> Option<&mut Voice> {
+        self.voices
+            .iter_mut()
+            .flatten()
+            .find(|voice| voice.handle == handle)
+    }
+
+    fn apply_commands(&mut self) {
+        while let Some(command) = self.commands.recv() {
+            match command {
+                Command::Play {
+                    voice,
+                    source,
+                    params,
+                } => match self.voices.iter_mut().find(|slot| slot.is_none()) {
+                    Some(slot) => {
+                        *slot = Some(Voice {
+                            handle: voice,
+                            source,
+                            params,
+                            spatial: Spatial::default(),
+                            filter: None,
+                            gains: [0.0, 0.0],
+                            stopping: false,
+                        })
+                    }
+                    None => {
+                        let _ = self.finished.send(voice);
+                    }
+                },
+                Command::Stop(handle) => {
+                    if let Some(voice) = self.voice_mut(handle) {
+                        voice.stopping = true;
+                    }
+                }
+                Command::Pause(handle) => {
+                    if let Some(voice) = self.voice_mut(handle) {
+                        voice.params.paused = true;
+                    }
+                }
+                Command::Resume(handle) => {
+                    if let Some(voice) = self.voice_mut(handle) {
+                        voice.params.paused = false;
+                    }
+                }
+                Command::SetVolume(handle, volume) => {
+                    if let Some(voice) = self.voice_mut(handle) {
+                        voice.params.volume = volume;
+                    }
+                }
+                Command::SetPitch(handle, pitch) => {
+                    if let Some(voice) = self.voice_mut(handle) {
+                        voice.params.pitch = pitch;
+                    }
+                }
+                Command::SetPan(handle, pan) => {
+                    if let Some(voice) = self.voice_mut(handle) {
+                        voice.params.pan = pan;
+                    }
+                }
+                Command::SetLooping(handle, looping) => {
+                    if let Some(voice) = self.voice_mut(handle) {
+                        voice.params.looping = looping;
+                        if let Source::Stream { stream, .. } = &voice.source {
+                            stream.set_looping(looping);
+                        }
+                    }
+                }
+                Command::SetSpatial(handle, spatial) => {
+                    let output_rate = self.sample_rate;
+                    if let Some(voice) = self.voice_mut(handle) {
+                        voice.set_spatial(spatial, output_rate);
+                    }
+                }
+                Command::StopAll => {
+                    for voice in self.voices.iter_mut().flatten() {
+                        voice.stopping = true;
+                    }
+                }
+                Command::SetBusVolume(bus, volume) => self.buses[bus.index()].volume = volume,
+                Command::SetBusMuted(bus, muted) => self.buses[bus.index()].muted = muted,
+                Command::AddEffect(bus, handle, effect) => {
+                    self.buses[bus.index()].effects.push((handle, effect))
+                }
+                Command::RemoveEffect(handle) => {
+                    for bus in self.buses.iter_mut() {
+                        bus.effects.retain(|(h, _)| *h != handle);
+                    }
+                }
+                Command::SetDucking(bus, ducking) => {
+                    // The master mixes after everything else, it can neither duck nor be ducked
+                    let valid =
+                        |d: &Ducking| bus != Bus::Master && d.key != Bus::Master && d.key != bus;
+                    self.buses[bus.index()].ducker = ducking
+                        .filter(valid)
+                        .map(|d| Ducker::new(d, self.sample_rate));
+                }
+                Command::Music(command) => self.music.apply(command),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Audio, Sound};
+    use super::*;
+    use std::f32::consts::{FRAC_1_SQRT_2, TAU};
+
+    const RATE: u32 = 48000;
+
+    fn offline() -> (Audio, Mixer) {
+        Audio::new(MixerConfig {
+            sample_rate: RATE,
+            max_voices: 4,
+            command_capacity: 16,
+        })
+    }
+
+    /// One second of a 440 Hz sine at `sample_rate`
+    fn sine(sample_rate: u32) -> Sound {
+        let samples = (0..sample_rate)
+            .map(|i| (i as f32 / sample_rate as f32 * 440.0 * TAU).sin())
+            .collect();
+        Sound::mono(sample_rate, samples)
+    }
+
+    fn left(out: &[f32]) -> Vec<f32> {
+        out.iter().step_by(2).cloned().collect()
+    }
+
+    fn right(out: &[f32]) -> Vec<f32> {
+        out.iter().skip(1).step_by(2).cloned().collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, s| peak.max(s.abs()))
+    }
+
+    /// Times a channel goes from negative to positive, once per cycle
+    fn upward_crossings(samples: &[f32]) -> usize {
+        samples
+            .windows(2)
+            .filter(|w| w[0] <= 0.0 && w[1] > 0.0)
+            .count()
+    }
+
+    #[test]
+    fn played_sounds_are_mixed_in_then_finish() {
+        let (mut audio, mut mixer) = offline();
+        let voice = audio.play(&sine(RATE), PlayParams::default());
+        let out = mixer.render_frames(RATE as usize);
+        // Centred, both sides 3 dB down
+        assert!((peak(&left(&out)) - FRAC_1_SQRT_2).abs() < 0.01);
+        assert_eq!(left(&out), right(&out));
+        assert_eq!(upward_crossings(&left(&out)), 440);
+
+        let tail = mixer.render_frames(10);
+        assert!(tail.iter().all(|&s| s == 0.0));
+        audio.update();
+        assert!(!audio.is_playing(voice));
+        assert_eq!(mixer.active_voices(), 0);
+    }
+
+    #[test]
+    fn sounds_are_resampled_to_the_output_rate() {
+        let (mut audio, mut mixer) = offline();
+        audio.play(&sine(RATE / 2), PlayParams::default());
+        let out = mixer.render_frames(RATE as usize);
+        let left = left(&out);
+        assert!((peak(&left) - FRAC_1_SQRT_2).abs() < 0.01);
+        assert!((439..=441).contains(&upward_crossings(&left)));
+        // Interpolated between the sound's samples, so there are no steps every other frame
+        assert!(left.windows(2).all(|w| (w[0] - w[1]).abs() < 0.06));
+    }
+
+    #[test]
+    fn pitch_plays_faster_and_higher() {
+        let (mut audio, mut mixer) = offline();
+        let voice = audio.play(
+            &sine(RATE),
+            PlayParams {
+                pitch: 2.0,
+                ..PlayParams::default()
+            },
+        );
+        // All of it in half a second at 880 Hz
+        let half = RATE as usize / 2;
+        let out = mixer.render_frames(half + 10);
+        assert!((439..=440).contains(&upward_crossings(&left(&out)[..half])));
+        assert!(out[2 * half..].iter().all(|&s| s == 0.0));
+        audio.update();
+        assert!(!audio.is_playing(voice));
+    }
+
+    #[test]
+    fn pan_moves_mono_sounds_between_the_sides() {
+        let (mut audio, mut mixer) = offline();
+        let voice = audio.play(
+            &sine(RATE),
+            PlayParams {
+                pan: -1.0,
+                ..PlayParams::default()
+            },
+        );
+        let out = mixer.render_frames(4800);
+        assert!((peak(&left(&out)) - 1.0).abs() < 0.01);
+        assert!(peak(&right(&out)) < 1e-6);
+
+        audio.set_pan(voice, 1.0);
+        mixer.render_frames(4800);
+        let out = mixer.render_frames(4800);
+        assert!(peak(&left(&out)) < 1e-6);
+        assert!((peak(&right(&out)) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn looping_sounds_keep_playing_until_stopped() {
+        let (mut audio, mut mixer) = offline();
+        let constant = Sound::new(RATE, 2, vec![0.5; 200]).unwrap();
+        let voice = audio.play(
+            &constant,
+            PlayParams {
+                looping: true,
+                ..PlayParams::default()
+            },
+        );
+        // Fades in rather than clicking
+        let out = mixer.render_frames(1000);
+        assert!(out[0] < 0.01);
+        let out = mixer.render_frames(RATE as usize);
+        assert!(out.iter().all(|&s| (s - 0.5).abs() < 1e-6));
+        audio.update();
+        assert!(audio.is_playing(voice));
+
+        // And fades out
+        audio.stop(voice);
+        let out = mixer.render_frames(1000);
+        let left = left(&out);
+        assert!(left.windows(2).all(|w| (w[0] - w[1]).abs() < 0.003));
+        assert_eq!(*left.last().unwrap(), 0.0);
+        audio.update();
+        assert!(!audio.is_playing(voice));
+        assert_eq!(mixer.active_voices(), 0);
+    }
+
+    #[test]
+    fn paused_sounds_go_quiet_and_resume_where_they_were() {
+        let (mut audio, mut mixer) = offline();
+        let ramp = Sound::mono(RATE, (0..RATE).map(|i| i as f32 / RATE as f32).collect());
+        let voice = audio.play(&ramp, PlayParams::default());
+        mixer.render_frames(4800);
+        audio.pause(voice);
+        mixer.render_frames(4800);
+        let out = mixer.render_frames(4800);
+        assert!(out.iter().all(|&s| s == 0.0));
+        audio.update();
+        assert!(audio.is_playing(voice));
+
+        // About a tenth of the way up the ramp, not the fifth it would be without the pause
+        audio.resume(voice);
+        let out = mixer.render_frames(1000);
+        let resumed_at = out[2 * 999] / FRAC_1_SQRT_2;
+        assert!(resumed_at > 0.1 && resumed_at < 0.13, "{}", resumed_at);
+    }
+}
@@ -0,0 +1,387 @@
+mod backend;
+mod bus;
+mod channel;
+mod decode;
+#[cfg(feature = "device")]
+mod device;
+mod effect;
+mod mixer;
+mod music;
+mod sound;
+mod spatial;
+mod stream;
+mod synth;
+
+pub use backend::{AudioBackend, BackendError, NullSink, WavSink};
+pub use bus::{AudioSettings, AudioSettingsError, Bus, BusSettings, Ducking};
+use channel::{Receiver, Sender};
+pub use decode::DecodeError;
+#[cfg(feature = "device")]
+pub use device::DeviceOutput;
+pub use effect::{
+    db_to_gain, gain_to_db, Biquad, Compressor, CompressorParams, Delay, Effect, Limiter, Reverb,
+    ReverbParams, BUTTERWORTH_Q,
+};
+use mixer::{Command, Source};
+pub use mixer::{EffectHandle, Mixer, MixerConfig, PlayParams, VoiceHandle};
+use music::MusicCommand;
+pub use music::{Boundary, MusicEvent, Section, SectionKind, Stem, Track, Transition};
+pub use sound::Sound;
+use spatial::Placement;
+pub use spatial::{
+    AcousticMaterial, Attachment, Attenuation, Cone, Emitter, Occlusion, OPEN_CUTOFF,
+    SPEED_OF_SOUND,
+};
+pub use stream::Stream;
+pub use synth::{Adsr, SfxParams, Waveform};
+
+use crate::EntityIndex;
+use cgmath::{One, Quaternion, Vector3, Zero};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// An emitter and what the game thread tracks about it between updates
+struct EmitterState {
+    emitter: Emitter,
+    last_position: Option<Vector3<f32>>,
+    /// Smoothed over time, `None` until the first `update_spatial` sets it outright
+    occlusion: Option<Occlusion>,
+    /// Spatial voices start on the next `update_spatial`, once it is known where they are
+    pending: Option<(Source, PlayParams)>,
+}
+
+/// The game thread's side of the audio engine. It hands commands to the `Mixer` on the audio
+/// thread without ever blocking, and hears back when voices finish
+pub struct Audio {
+    sample_rate: u32,
+    commands: Sender<Command>,
+    finished: Receiver<VoiceHandle>,
+    music_events: Receiver<MusicEvent>,
+    /// What the music did before the last `update`
+    music_log: Vec<MusicEvent>,
+    /// Beats per minute of the track playing, as of the last `update`
+    music_tempo: Option<f32>,
+    /// Commands that did not fit in the queue, they go first on the next `update`
+    backlog: VecDeque<Command>,
+    next_voice: u64,
+    next_effect: u64,
+    playing: HashSet<VoiceHandle>,
+    listener: Placement,
+    listener_last_position: Option<Vector3<f32>>,
+    emitters: HashMap<VoiceHandle, EmitterState>,
+}
+
+impl Audio {
+    /// The mixer goes to whatever pulls samples, usually the audio thread
+    pub fn new(config: MixerConfig) -> (Audio, Mixer) {
+        let (commands, command_receiver) = channel::channel(config.command_capacity);
+        // Every voice finishes once, so this only fills up if the game stops calling `update`
+        let (finished_sender, finished) =
+            channel::channel(config.max_voices + config.command_capacity);
+        // Plenty for the beats of a few seconds of music between updates
+        let (music_sender, music_events) = channel::channel(256);
+        let audio = Audio {
+            sample_rate: config.sample_rate.max(1),
+            commands,
+            finished,
+            music_events,
+            music_log: vec![],
+            music_tempo: None,
+            backlog: VecDeque::new(),
+            next_voice: 0,
+            next_effect: 0,
+            playing: HashSet::new(),
+            listener: Placement {
+                position: Vector3::zero(),
+                rotation: Quaternion::one(),
+                velocity: Vector3::zero(),
+            },
+            listener_last_position: None,
+            emitters: HashMap::new(),
+        };
+        (
+            audio,
+            Mixer::new(&config, command_receiver, finished_sender, music_sender),
+        )
+    }
+
+    /// The mixer's, for building effects
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn play(&mut self, sound: &Sound, params: PlayParams) -> VoiceHandle {
+        self.start(Source::sound(sound.clone()), params)
+    }
+
+    /// Plays music or anything else streaming from disk, the stream goes away with the voice
+    pub fn play_stream(&mut self, stream: Stream, params: PlayParams) -> VoiceHandle {
+        // Before the decoding thread can reach the end of a short file and stop
+        stream.set_looping(params.looping);
+        self.start(Source::stream(stream), params)
+    }
+
+    /// Plays a sound out in the world. It starts on the next `update_spatial`, which works out
+    /// how loud it is and where it comes from
+    pub fn play_spatial(
+        &mut self,
+        sound: &Sound,
+        emitter: Emitter,
+        params: PlayParams,
+    ) -> VoiceHandle {
+        let voice = VoiceHandle(self.next_voice);
+        self.next_voice += 1;
+        self.playing.insert(voice);
+        let state = EmitterState {
+            emitter,
+            last_position: None,
+            occlusion: None,
+            pending: Some((Source::sound(sound.clone()), params)),
+        };
+        self.emitters.insert(voice, state);
+        voice
+    }
+
+    pub fn emitter_mut(&mut self, voice: VoiceHandle) -> Option<&mut Emitter> {
+        self.emitters
+            .get_mut(&voice)
+            .map(|state| &mut state.emitter)
+    }
+
+    /// Moves the listener, usually to the camera. Its velocity comes from how far it moved
+    /// between calls to `update_spatial`
+    pub fn set_listener(&mut self, position: Vector3<f32>, rotation: Quaternion<f32>) {
+        self.listener.position = position;
+        self.listener.rotation = rotation;
+    }
+
+    /// Pans, attenuates and Doppler shifts every emitter's voice for where it is relative to
+    /// the listener. `transform` gives the position and rotation of an entity, the voices of
+    /// entities it no longer knows get stopped. `occlusion` says what is in the way between
+    /// an emitter's position and the listener's, voices get there gradually
+    pub fn update_spatial<F, O>(&mut self, dt: f32, transform: F, mut occlusion: O)
+    where
+        F: Fn(EntityIndex) -> Option<(Vector3<f32>, Quaternion<f32>)>,
+        O: FnMut(Vector3<f32>, Vector3<f32>) -> Occlusion,
+    {
+        let velocity = |position: Vector3<f32>, last: Option<Vector3<f32>>| match last {
+            Some(last) if dt > 0.0 => (position - last) / dt,
+            _ => Vector3::zero(),
+        };
+        self.listener.velocity = velocity(self.listener.position, self.listener_last_position);
+        self.listener_last_position = Some(self.listener.position);
+
+        let mut commands = vec![];
+        let mut gone = vec![];
+        for (&voice, state) in self.emitters.iter_mut() {
+            let placement = match state.emitter.attachment {
+                Attachment::Entity(entity) => transform(entity),
+                Attachment::Point(position) => Some((position, Quaternion::one())),
+            };
+            let (position, rotation) = match placement {
+                Some(placement) => placement,
+                None => {
+                    gone.push(voice);
+                    continue;
+                }
+            };
+            let source = Placement {
+                position,
+                rotation,
+                velocity: velocity(position, state.last_position),
+            };
+            state.last_position = Some(position);
+
+            if let Some((source, params)) = state.pending.take() {
+                commands.push(Command::Play {
+                    voice,
+                    source,
+                    params,
+                });
+            }
+            let mut spatial = spatial::spatialize(&state.emitter, &source, &self.listener);
+            let target = if state.emitter.occlusion {
+                occlusion(position, self.listener.position)
+            } else {
+                Occlusion::CLEAR
+            };
+            let smoothed = match &mut state.occlusion {
+                Some(smoothed) => {
+                    smoothed.approach(target, dt);
+                    *smoothed
+                }
+                None => *state.occlusion.insert(target),
+            };
+            spatial.gain *= smoothed.gain;
+            spatial.cutoff = smoothed.cutoff;
+            commands.push(Command::SetSpatial(voice, spatial));
+        }
+        for command in commands {
+            self.send(command);
+        }
+        for voice in gone {
+            if let Some(state) = self.emitters.remove(&voice) {
+                if state.pending.is_some() {
+                    self.playing.remove(&voice);
+                } else {
+                    self.send(Command::Stop(voice));
+                }
+            }
+        }
+    }
+
+    /// Fades the voice out and drops it
+    pub fn stop(&mut self, voice: VoiceHandle) {
+        if let Some(state) = self.emitters.get(&voice) {
+            if state.pending.is_some() {
+                // Never reached the mixer
+                self.emitters.remove(&voice);
+                self.playing.remove(&voice);
+                return;
+            }
+        }
+        self.send(Command::Stop(voice));
+    }
+
+    pub fn stop_all(&mut self) {
+        self.send(Command::StopAll);
+    }
+
+    pub fn pause(&mut self, voice: VoiceHandle) {
+        self.send(Command::Pause(voice));
+    }
+
+    pub fn resume(&mut self, voice: VoiceHandle) {
+        self.send(Command::Resume(voice));
+    }
+
+    pub fn set_volume(&mut self, voice: VoiceHandle, volume: f32) {
+        self.send(Command::SetVolume(voice, volume));
+    }
+
+    pub fn set_pitch(&mut self, voice: VoiceHandle, pitch: f32) {
+        self.send(Command::SetPitch(voice, pitch));
+    }
+
+    pub fn set_pan(&mut self, voice: VoiceHandle, pan: f32) {
+        self.send(Command::SetPan(voice, pan));
+    }
+
+    pub fn set_looping(&mut self, voice: VoiceHandle, looping: bool) {
+        self.send(Command::SetLooping(voice, looping));
+    }
+
+    pub fn set_bus_volume(&mut self, bus: Bus, volume: f32) {
+        self.send(Command::SetBusVolume(bus, volume));
+    }
+
+    pub fn set_bus_muted(&mut self, bus: Bus, muted: bool) {
+        self.send(Command::SetBusMuted(bus, muted));
+    }
+
+    /// Sets every bus's volume and mute from the player's settings
+    pub fn apply_settings(&mut self, settings: &AudioSettings) {
+        for &bus in Bus::ALL.iter() {
+            let bus_settings = settings.bus(bus);
+            self.set_bus_volume(bus, bus_settings.volume);
+            self.set_bus_muted(bus, bus_settings.muted);
+        }
+    }
+
+    /// Adds an effect after those already on the bus. Build it with `sample_rate`
+    pub fn add_effect<E: Into<Effect>>(&mut self, bus: Bus, effect: E) -> EffectHandle {
+        let handle = EffectHandle(self.next_effect);
+        self.next_effect += 1;
+        self.send(Command::AddEffect(bus, handle, effect.into()));
+        handle
+    }
+
+    pub fn remove_effect(&mut self, effect: EffectHandle) {
+        self.send(Command::RemoveEffect(effect));
+    }
+
+    /// Ducks `bus` under another, or stops ducking it with `None`. The master bus can neither
+    /// duck nor be ducked
+    pub fn set_ducking(&mut self, bus: Bus, ducking: Option<Ducking>) {
+        self.send(Command::SetDucking(bus, ducking));
+    }
+
+    /// Plays a track on the music bus. If music is already playing the new track takes over
+    /// at the transition's boundary, crossfading with the old one
+    pub fn play_music(&mut self, track: Track, transition: Transition) {
+        // Before the decoding threads can reach the end of a short stem and stop
+        track.loop_streams();
+        self.send(Command::Music(MusicCommand::Play(
+            Box::new(track),
+            transition,
+        )));
+    }
+
+    /// Plays the outro at the boundary, or fades out over the crossfade if there is none
+    pub fn stop_music(&mut self, transition: Transition) {
+        self.send(Command::Music(MusicCommand::Stop(transition)));
+    }
+
+    /// Drives which stems play, usually from how intense the fighting is
+    pub fn set_music_intensity(&mut self, intensity: f32) {
+        self.send(Command::Music(MusicCommand::SetIntensity(intensity)));
+    }
+
+    pub fn music_tempo(&self) -> Option<f32> {
+        self.music_tempo
+    }
+
+    /// Beats, sections and track changes the music went through, as heard by the last
+    /// `update`
+    pub fn music_events(&self) -> &[MusicEvent] {
+        &self.music_log
+    }
+
+    /// Whether the voice is still playing or paused, as of the last `update`
+    pub fn is_playing(&self, voice: VoiceHandle) -> bool {
+        self.playing.contains(&voice)
+    }
+
+    /// Sends commands the queue had no room for and collects the voices that finished
+    pub fn update(&mut self) {
+        while let Some(command) = self.backlog.pop_front() {
+            if let Err(command) = self.commands.send(command) {
+                self.backlog.push_front(command);
+                break;
+            }
+        }
+        while let Some(voice) = self.finished.recv() {
+            self.playing.remove(&voice);
+            self.emitters.remove(&voice);
+        }
+        self.music_log.clear();
+        while let Some(event) = self.music_events.recv() {
+            match event {
+                MusicEvent::Started { tempo, .. } => self.music_tempo = Some(tempo),
+                MusicEvent::Stopped => self.music_tempo = None,
+                _ => {}
+            }
+            self.music_log.push(event);
+        }
+    }
+
+    fn start(&mut self, source: Source, params: PlayParams) -> VoiceHandle {
+        let voice = VoiceHandle(self.next_voice);
+        self.next_voice += 1;
+        self.playing.insert(voice);
+        self.send(Command::Play {
+            voice,
+            source,
+            params,
+        });
+        voice
+    }
+
+    /// Keeps commands in order, nothing jumps ahead of the backlog
+    fn send(&mut self, command: Command) {
+        if !self.backlog.is_empty() {
+            self.backlog.push_back(command);
+        } else if let Err(command) = self.commands.send(command) {
+            self.backlog.push_back(command);
+        }
+    }
+}
@@ -0,0 +1,752 @@
+use super::channel::Sender;
+use super::mixer::Source;
+use super::sound::Sound;
+use super::stream::Stream;
+use std::fmt;
+
+/// Tracks replaced while others are still fading out get cut short past this many
+const MAX_FADING: usize = 4;
+
+/// One layer of a section. Layers above the base come in as the music's intensity rises
+pub struct Stem {
+    source: Source,
+    /// Intensity at which the stem fades in, `0.0` always plays
+    pub threshold: f32,
+}
+
+impl Stem {
+    pub fn new(sound: Sound) -> Stem {
+        Stem::layer(sound, 0.0)
+    }
+
+    pub fn layer(sound: Sound, threshold: f32) -> Stem {
+        Stem {
+            source: Source::sound(sound),
+            threshold,
+        }
+    }
+
+    /// A layer decoded from disk as it plays, for stems too long to keep in memory. A stream
+    /// only goes forwards, so it keeps its own time and should be as long as the section's
+    /// other stems. In the main section it loops on the decoding thread
+    pub fn streamed(stream: Stream, threshold: f32) -> Stem {
+        Stem {
+            source: Source::stream(stream),
+            threshold,
+        }
+    }
+
+    fn stream(&self) -> Option<&Stream> {
+        match &self.source {
+            Source::Stream { stream, .. } => Some(stream),
+            Source::Sound { .. } => None,
+        }
+    }
+}
+
+impl fmt::Debug for Stem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stem")
+            .field("streamed", &self.stream().is_some())
+            .field("threshold", &self.threshold)
+            .finish()
+    }
+}
+
+/// Stems that play together from the same start. Sections should last a whole number of bars
+#[derive(Debug)]
+pub struct Section {
+    pub stems: Vec<Stem>,
+}
+
+impl Section {
+    pub fn new(stems: Vec<Stem>) -> Section {
+        Section { stems }
+    }
+
+    pub fn single(sound: Sound) -> Section {
+        Section::new(vec![Stem::new(sound)])
+    }
+
+    /// As long as its longest sound, in output frames. Streams are left out, their length is
+    /// not known ahead, so a section of nothing but streams has none and lasts until they run
+    /// out
+    fn length(&self, sample_rate: u32) -> Option<u64> {
+        let longest = self
+            .stems
+            .iter()
+            .filter_map(|stem| match &stem.source {
+                Source::Sound { sound, .. } => {
+                    let scale = sample_rate as f64 / sound.sample_rate() as f64;
+                    Some((sound.frames() as f64 * scale).ceil() as u64)
+                }
+                Source::Stream { .. } => None,
+            })
+            .max();
+        match longest {
+            None if !self.stems.is_empty() => None,
+            longest => Some(longest.unwrap_or(0).max(1)),
+        }
+    }
+}
+
+/// A piece of music that plays its intro once, loops its main section, and ends with its
+/// outro when stopped
+#[derive(Debug)]
+pub struct Track {
+    /// Beats per minute
+    pub tempo: f32,
+    pub beats_per_bar: u32,
+    pub intro: Option<Section>,
+    pub main: Section,
+    pub outro: Option<Section>,
+    /// Seconds stems take to fade in or out as the intensity changes
+    pub stem_fade: f32,
+}
+
+impl Track {
+    pub fn new(tempo: f32, beats_per_bar: u32, main: Section) -> Track {
+        Track {
+            tempo,
+            beats_per_bar,
+            intro: None,
+            main,
+            outro: None,
+            stem_fade: 1.0,
+        }
+    }
+
+    fn section(&self, kind: SectionKind) -> Option<&Section> {
+        match kind {
+            SectionKind::Intro => self.intro.as_ref(),
+            SectionKind::Main => Some(&self.main),
+            SectionKind::Outro => self.outro.as_ref(),
+        }
+    }
+
+    /// Streamed stems loop in the main section and play once in the others
+    pub(crate) fn loop_streams(&self) {
+        let kinds = [SectionKind::Intro, SectionKind::Main, SectionKind::Outro];
+        for &kind in kinds.iter() {
+            let stems = self
+                .section(kind)
+                .map_or(&[][..], |section| &section.stems[..]);
+            for stream in stems.iter().filter_map(Stem::stream) {
+                stream.set_looping(kind == SectionKind::Main);
+            }
+        }
+    }
+
+    fn section_mut(&mut self, kind: SectionKind) -> Option<&mut Section> {
+        match kind {
+            SectionKind::Intro => self.intro.as_mut(),
+            SectionKind::Main => Some(&mut self.main),
+            SectionKind::Outro => self.outro.as_mut(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SectionKind {
+    Intro,
+    Main,
+    Outro,
+}
+
+/// Where in the music a change can happen. Beats and bars count from the start of each section
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Boundary {
+    Now,
+    Beat,
+    Bar,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transition {
+    pub boundary: Boundary,
+    /// Seconds the old track takes to fade out under the new one
+    pub crossfade: f32,
+}
+
+impl Default for Transition {
+    fn default() -> Transition {
+        Transition {
+            boundary: Boundary::Bar,
+            crossfade: 2.0,
+        }
+    }
+}
+
+/// What the music did, sent back from the audio thread for gameplay to keep time with
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MusicEvent {
+    /// A track took over, `tempo` in beats per minute
+    Started {
+        tempo: f32,
+        beats_per_bar: u32,
+    },
+    Section(SectionKind),
+    /// `bar` counts from the start of the track, `beat` from the start of the bar
+    Beat {
+        bar: u64,
+        beat: u32,
+    },
+    /// The music ran out after an outro or a fade
+    Stopped,
+}
+
+pub(crate) enum MusicCommand {
+    Play(Box<Track>, Transition),
+    /// Goes to the outro at the boundary, or fades out if there is none
+    Stop(Transition),
+    SetIntensity(f32),
+}
+
+/// A track as it plays on the audio thread
+struct Playback {
+    track: Box<Track>,
+    section: SectionKind,
+    /// Output frames into the section
+    frame: u64,
+    length: Option<u64>,
+    frames_per_beat: f64,
+    /// Last beat of the section sent out, so asking twice about a frame sends nothing new
+    announced: Option<u64>,
+    /// Bars since the track started, the first one is bar 0
+    bar: u64,
+    gain: f32,
+    target: f32,
+    /// Largest gain change per frame
+    fade: f32,
+    stem_gains: Vec<f32>,
+    /// Streamed stems of the section that have run out
+    stems_ended: Vec<bool>,
+}
+
+impl Playback {
+    fn new(track: Box<Track>, sample_rate: u32, intensity: f32) -> Playback {
+        let section = if track.intro.is_some() {
+            SectionKind::Intro
+        } else {
+            SectionKind::Main
+        };
+        let frames_per_beat = sample_rate as f64 * 60.0 / track.tempo.max(1.0) as f64;
+        let mut playback = Playback {
+            track,
+            section,
+            frame: 0,
+            length: Some(1),
+            frames_per_beat,
+            announced: None,
+            bar: 0,
+            gain: 1.0,
+            target: 1.0,
+            fade: 1.0,
+            stem_gains: vec![],
+            stems_ended: vec![],
+        };
+        playback.enter(section, sample_rate, intensity);
+        playback
+    }
+
+    /// Starts a section, layers carry on at the level they were at and new ones start where
+    /// the intensity says
+    fn enter(&mut self, kind: SectionKind, sample_rate: u32, intensity: f32) {
+        let section = match self.track.section(kind) {
+            Some(section) => section,
+            None => return,
+        };
+        let previous = std::mem::take(&mut self.stem_gains);
+        self.stem_gains = section
+            .stems
+            .iter()
+            .enumerate()
+            .map(|(i, stem)| match previous.get(i) {
+                Some(&gain) => gain,
+                None if intensity >= stem.threshold => 1.0,
+                None => 0.0,
+            })
+            .collect();
+        self.stems_ended = vec![false; section.stems.len()];
+        self.length = section.length(sample_rate);
+        self.section = kind;
+        self.frame = 0;
+        self.announced = None;
+    }
+
+    /// Never quicker than `minimum` per frame
+    fn fade_to(&mut self, target: f32, seconds: f32, sample_rate: u32, minimum: f32) {
+        self.target = target;
+        self.fade = (1.0 / (seconds * sample_rate as f32).max(1.0)).min(minimum);
+    }
+
+    fn is_silent(&self) -> bool {
+        self.gain == 0.0 && self.target == 0.0
+    }
+
+    /// Every sound has played through, or in a section of nothing but streams, every stream
+    /// ran out
+    fn section_ended(&self) -> bool {
+        match self.length {
+            Some(length) => self.frame >= length,
+            None => self.stems_ended.iter().all(|&ended| ended),
+        }
+    }
+
+    /// Moves into the next section at the end of this one. Returns `false` once the track is
+    /// over
+    fn advance(
+        &mut self,
+        sample_rate: u32,
+        intensity: f32,
+        events: Option<&mut Sender<MusicEvent>>,
+    ) -> bool {
+        if !self.section_ended() {
+            return true;
+        }
+        let next = match self.section {
+            SectionKind::Outro => return false,
+            // Its streams were looping, if they ran out anyway there is nothing left to play
+            SectionKind::Main if self.length.is_none() => return false,
+            _ => SectionKind::Main,
+        };
+        if next != self.section {
+            if let Some(events) = events {
+                let _ = events.send(MusicEvent::Section(next));
+            }
+        }
+        // Sections are whole bars, so one starting also starts a bar
+        self.bar += 1;
+        self.enter(next, sample_rate, intensity);
+        true
+    }
+
+    /// The beat the current frame starts, if any, and which boundary that is
+    fn beat(&mut self) -> Option<(Boundary, MusicEvent)> {
+        let beat = (self.frame as f64 / self.frames_per_beat) as u64;
+        let on_beat =
+            self.frame == 0 || ((self.frame - 1) as f64 / self.frames_per_beat) as u64 != beat;
+        if !on_beat || self.announced == Some(beat) {
+            return None;
+        }
+        self.announced = Some(beat);
+        let beats_per_bar = self.track.beats_per_bar.max(1) as u64;
+        let beat_in_bar = (beat % beats_per_bar) as u32;
+        if beat_in_bar == 0 && beat > 0 {
+            self.bar += 1;
+        }
+        let boundary = if beat_in_bar == 0 {
+            Boundary::Bar
+        } else {
+            Boundary::Beat
+        };
+        let event = MusicEvent::Beat {
+            bar: self.bar,
+            beat: beat_in_bar,
+        };
+        Some((boundary, event))
+    }
+
+    /// The frame at the current position, then moves on
+    fn next_frame(&mut self, sample_rate: u32, intensity: f32) -> [f32; 2] {
+        self.gain += (self.target - self.gain).clamp(-self.fade, self.fade);
+        let stem_fade = 1.0 / (self.track.stem_fade * sample_rate as f32).max(1.0);
+        let section = match self.track.section_mut(self.section) {
+            Some(section) => section,
+            None => return [0.0; 2],
+        };
+        let mut out = [0.0; 2];
+        let stems = section
+            .stems
+            .iter_mut()
+            .zip(self.stem_gains.iter_mut().zip(self.stems_ended.iter_mut()));
+        for (stem, (gain, ended)) in stems {
+            let target = if intensity >= stem.threshold {
+                1.0
+            } else {
+                0.0
+            };
+            *gain += (target - *gain).clamp(-stem_fade, stem_fade);
+            let sound = match &mut stem.source {
+                Source::Sound { sound, .. } => sound,
+                source => {
+                    // Played on while faded out too, to stay in time with the rest
+                    if !*ended {
+                        let step = source.sample_rate() as f64 / sample_rate as f64;
+                        let sample = source.sample(false);
+                        *ended = !source.advance(step, false);
+                        out[0] += sample[0] * *gain;
+                        out[1] += sample[1] * *gain;
+                    }
+                    continue;
+                }
+            };
+            let position = self.frame as f64 * sound.sample_rate() as f64 / sample_rate as f64;
+            let index = position as usize;
+            if *gain == 0.0 || index >= sound.frames() {
+                continue;
+            }
+            let a = sound.frame(index);
+            let b = if index + 1 < sound.frames() {
+                sound.frame(index + 1)
+            } else {
+                [0.0; 2]
+            };
+            let t = (position - index as f64) as f32;
+            out[0] += (a[0] + (b[0] - a[0]) * t) * *gain;
+            out[1] += (a[1] + (b[1] - a[1]) * t) * *gain;
+        }
+        self.frame += 1;
+        [out[0] * self.gain, out[1] * self.gain]
+    }
+}
+
+/// Plays music on the audio thread, so sections follow each other and changes land on the
+/// beat down to the frame
+pub(crate) struct MusicPlayer {
+    sample_rate: u32,
+    current: Option<Playback>,
+    /// Tracks fading out under the current one
+    fading: Vec<Playback>,
+    /// Waiting for the current track to reach a boundary
+    pending: Option<MusicCommand>,
+    intensity: f32,
+    events: Sender<MusicEvent>,
+    /// Quickest fade, so nothing clicks
+    fade: f32,
+}
+
+impl MusicPlayer {
+    pub(crate) fn new(sample_rate: u32, fade: f32, events: Sender<MusicEvent>) -> MusicPlayer {
+        MusicPlayer {
+            sample_rate,
+            current: None,
+            fading: Vec::with_capacity(MAX_FADING),
+            pending: None,
+            intensity: 0.0,
+            events,
+            fade,
+        }
+    }
+
+    pub(crate) fn apply(&mut self, command: MusicCommand) {
+        match command {
+            MusicCommand::SetIntensity(intensity) => self.intensity = intensity,
+            // With nothing playing there is no beat to wait for
+            MusicCommand::Play(track, transition) if self.current.is_none() => {
+                self.start(track, transition)
+            }
+            MusicCommand::Stop(_) if self.current.is_none() => self.pending = None,
+            command => self.pending = Some(command),
+        }
+    }
+
+    /// Adds the music into interleaved stereo `out`
+    pub(crate) fn mix(&mut self, out: &mut [f32]) {
+        let (sample_rate, intensity) = (self.sample_rate, self.intensity);
+        for frame in out.chunks_exact_mut(2) {
+            let beat = self.begin_frame();
+            // Every frame is a boundary for changes that happen now
+            let boundary = beat.map_or(Boundary::Now, |(boundary, _)| boundary);
+            // A change on the beat starts a beat of its own instead
+            let beat = if self.reached(boundary) {
+                self.begin_frame()
+            } else {
+                beat
+            };
+            if let Some((_, beat)) = beat {
+                let _ = self.events.send(beat);
+            }
+
+            let mut mixed = [0.0; 2];
+            if let Some(current) = &mut self.current {
+                mixed = current.next_frame(sample_rate, intensity);
+            }
+            for playback in self.fading.iter_mut() {
+                if playback.advance(sample_rate, intensity, None) {
+                    let sample = playback.next_frame(sample_rate, intensity);
+                    mixed[0] += sample[0];
+                    mixed[1] += sample[1];
+                } else {
+                    playback.gain = 0.0;
+                    playback.target = 0.0;
+                }
+            }
+            self.fading.retain(|playback| !playback.is_silent());
+            frame[0] += mixed[0];
+            frame[1] += mixed[1];
+        }
+    }
+
+    /// Gets the current track ready to play a frame. Returns the beat the frame starts, if any
+    fn begin_frame(&mut self) -> Option<(Boundary, MusicEvent)> {
+        let current = self.current.as_mut()?;
+        if !current.advance(self.sample_rate, self.intensity, Some(&mut self.events)) {
+            self.current = None;
+            let _ = self.events.send(MusicEvent::Stopped);
+            return None;
+        }
+        current.beat()
+    }
+
+    /// Carries out the pending change if the frame is on a late enough boundary, or the track
+    /// it was waiting on ended. Returns whether it did
+    fn reached(&mut self, boundary: Boundary) -> bool {
+        let ready = match &self.pending {
+            Some(MusicCommand::Play(_, transition)) | Some(MusicCommand::Stop(transition)) => {
+                self.current.is_none() || boundary >= transition.boundary
+            }
+            _ => false,
+        };
+        if !ready {
+            return false;
+        }
+        match self.pending.take() {
+            Some(MusicCommand::Play(track, transition)) => self.start(track, transition),
+            Some(MusicCommand::Stop(transition)) => self.stop(transition),
+            _ => {}
+        }
+        true
+    }
+
+    fn start(&mut self, track: Box<Track>, transition: Transition) {
+        let _ = self.events.send(MusicEvent::Started {
+            tempo: track.tempo,
+            beats_per_bar: track.beats_per_bar,
+        });
+        let mut playback = Playback::new(track, self.sample_rate, self.intensity);
+        let _ = self.events.send(MusicEvent::Section(playback.section));
+        playback.gain = 0.0;
+        match self.current.take() {
+            Some(mut old) => {
+                old.fade_to(0.0, transition.crossfade, self.sample_rate, self.fade);
+                playback.fade_to(1.0, transition.crossfade, self.sample_rate, self.fade);
+                self.fade_out(old);
+            }
+            None => playback.fade_to(1.0, 0.0, self.sample_rate, self.fade),
+        }
+        self.current = Some(playback);
+    }
+
+    fn stop(&mut self, transition: Transition) {
+        let mut current = match self.current.take() {
+            Some(current) => current,
+            None => return,
+        };
+        if current.track.outro.is_some() && current.section != SectionKind::Outro {
+            current.enter(SectionKind::Outro, self.sample_rate, self.intensity);
+            let _ = self.events.send(MusicEvent::Section(SectionKind::Outro));
+            self.current = Some(current);
+        } else {
+            current.fade_to(0.0, transition.crossfade, self.sample_rate, self.fade);
+            self.fade_out(current);
+            let _ = self.events.send(MusicEvent::Stopped);
+        }
+    }
+
+    fn fade_out(&mut self, playback: Playback) {
+        if self.fading.len() == MAX_FADING {
+            self.fading.remove(0);
+        }
+        self.fading.push(playback);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Audio, Mixer, MixerConfig, PlayParams, WavSink};
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    const RATE: u32 = 48000;
+    /// At 240 beats per minute a beat is a quarter of a second, and a bar of four a second
+    const TEMPO: f32 = 240.0;
+    const BEAT: usize = RATE as usize / 4;
+    const BAR: usize = BEAT * 4;
+
+    fn offline() -> (Audio, Mixer) {
+        Audio::new(MixerConfig {
+            sample_rate: RATE,
+            max_voices: 4,
+            command_capacity: 16,
+        })
+    }
+
+    /// A bar of a steady stereo level, so what comes out says which section is playing
+    fn bar_of(level: f32) -> Sound {
+        Sound::new(RATE, 2, vec![level; BAR * 2]).unwrap()
+    }
+
+    fn track(level: f32) -> Track {
+        Track::new(TEMPO, 4, Section::single(bar_of(level)))
+    }
+
+    /// A bar of a steady level written out and opened again as a stream, with room to decode
+    /// all of it ahead
+    fn streamed_bar(level: f32, path: &Path) -> Stream {
+        let (mut audio, mixer) = offline();
+        let mut sink = WavSink::create(path, mixer).unwrap();
+        audio.play(&bar_of(level), PlayParams::default());
+        sink.render(BAR).unwrap();
+        sink.finish().unwrap();
+        Stream::open_buffered(path, 2.0).unwrap()
+    }
+
+    /// Mixes `frames` more, returning what the music did and the left side of the last frame
+    fn play(audio: &mut Audio, mixer: &mut Mixer, frames: usize) -> (Vec<MusicEvent>, f32) {
+        let out = mixer.render_frames(frames);
+        audio.update();
+        (audio.music_events().to_vec(), out[out.len() - 2])
+    }
+
+    fn beat(bar: u64, beat: u32) -> MusicEvent {
+        MusicEvent::Beat { bar, beat }
+    }
+
+    fn started() -> MusicEvent {
+        MusicEvent::Started {
+            tempo: TEMPO,
+            beats_per_bar: 4,
+        }
+    }
+
+    #[test]
+    fn beats_land_on_their_frames() {
+        let (mut audio, mut mixer) = offline();
+        audio.play_music(track(0.5), Transition::default());
+        let (events, _) = play(&mut audio, &mut mixer, 1);
+        let section = MusicEvent::Section(SectionKind::Main);
+        assert_eq!(events, vec![started(), section, beat(0, 0)]);
+        assert_eq!(audio.music_tempo(), Some(TEMPO));
+
+        for n in 1..4 {
+            let (events, level) = play(&mut audio, &mut mixer, BEAT - 1);
+            assert_eq!(events, vec![]);
+            assert_eq!(level, 0.5);
+            assert_eq!(play(&mut audio, &mut mixer, 1).0, vec![beat(0, n)]);
+        }
+        // The main section starts over without announcing itself again
+        play(&mut audio, &mut mixer, BEAT - 1);
+        assert_eq!(play(&mut audio, &mut mixer, 1), (vec![beat(1, 0)], 0.5));
+        let (events, _) = play(&mut audio, &mut mixer, BAR);
+        assert_eq!(events, vec![beat(1, 1), beat(1, 2), beat(1, 3), beat(2, 0)]);
+    }
+
+    #[test]
+    fn sections_play_through_to_the_outro() {
+        let (mut audio, mut mixer) = offline();
+        let mut music = track(0.5);
+        music.intro = Some(Section::single(bar_of(0.25)));
+        music.outro = Some(Section::single(bar_of(0.75)));
+        audio.play_music(music, Transition::default());
+        let (events, _) = play(&mut audio, &mut mixer, 1);
+        let intro = MusicEvent::Section(SectionKind::Intro);
+        assert_eq!(events, vec![started(), intro, beat(0, 0)]);
+        assert_eq!(play(&mut audio, &mut mixer, BAR - 1).1, 0.25);
+
+        let main = MusicEvent::Section(SectionKind::Main);
+        assert_eq!(
+            play(&mut audio, &mut mixer, 1),
+            (vec![main, beat(1, 0)], 0.5)
+        );
+        // Stopping waits for the bar, then plays the outro instead of fading
+        play(&mut audio, &mut mixer, BEAT);
+        audio.stop_music(Transition::default());
+        assert_eq!(play(&mut audio, &mut mixer, BAR - BEAT - 1).1, 0.5);
+        let (events, level) = play(&mut audio, &mut mixer, 1);
+        assert_eq!(events[0], MusicEvent::Section(SectionKind::Outro));
+        assert_eq!(level, 0.75);
+        assert_eq!(play(&mut audio, &mut mixer, BAR - 1).1, 0.75);
+
+        assert_eq!(
+            play(&mut audio, &mut mixer, 1),
+            (vec![MusicEvent::Stopped], 0.0)
+        );
+        assert_eq!(audio.music_tempo(), None);
+    }
+
+    #[test]
+    fn new_tracks_take_over_on_their_boundary() {
+        let waits = [
+            (Boundary::Now, 0),
+            (Boundary::Beat, BEAT / 2),
+            (Boundary::Bar, BAR - BEAT * 3 / 2),
+        ];
+        for &(boundary, wait) in waits.iter() {
+            let (mut audio, mut mixer) = offline();
+            audio.play_music(track(0.5), Transition::default());
+            play(&mut audio, &mut mixer, BAR + BEAT + BEAT / 2);
+            let transition = Transition {
+                boundary,
+                crossfade: 0.5,
+            };
+            audio.play_music(track(0.25), transition);
+            if wait > 0 {
+                let (events, level) = play(&mut audio, &mut mixer, wait);
+                assert!(!events.contains(&started()), "{:?}", boundary);
+                assert_eq!(level, 0.5);
+            }
+            let (events, _) = play(&mut audio, &mut mixer, 1);
+            assert_eq!(events[0], started(), "{:?}", boundary);
+            // Halfway through the crossfade each track is at half
+            let (_, level) = play(&mut audio, &mut mixer, RATE as usize / 4 - 1);
+            assert!((level - 0.375).abs() < 1e-3, "{:?} {}", boundary, level);
+            let (_, level) = play(&mut audio, &mut mixer, RATE as usize / 4);
+            assert!((level - 0.25).abs() < 1e-4, "{:?} {}", boundary, level);
+        }
+    }
+
+    #[test]
+    fn stems_follow_the_intensity() {
+        let (mut audio, mut mixer) = offline();
+        let mut music = track(0.25);
+        music.main.stems.push(Stem::layer(bar_of(0.5), 0.5));
+        music.stem_fade = 0.5;
+        audio.play_music(music, Transition::default());
+        assert_eq!(play(&mut audio, &mut mixer, BEAT).1, 0.25);
+
+        audio.set_music_intensity(0.5);
+        let (_, level) = play(&mut audio, &mut mixer, RATE as usize / 4);
+        assert!((level - 0.5).abs() < 1e-3, "{}", level);
+        let (_, level) = play(&mut audio, &mut mixer, RATE as usize / 4);
+        assert!((level - 0.75).abs() < 1e-4, "{}", level);
+        // Layers carry on into the next time round
+        let (_, level) = play(&mut audio, &mut mixer, BAR);
+        assert!((level - 0.75).abs() < 1e-4, "{}", level);
+
+        audio.set_music_intensity(0.0);
+        let (_, level) = play(&mut audio, &mut mixer, RATE as usize / 2);
+        assert!((level - 0.25).abs() < 1e-4, "{}", level);
+    }
+
+    #[test]
+    fn streamed_stems_keep_time_with_the_sections() {
+        let dir = std::env::temp_dir().join(format!("music_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let intro = streamed_bar(0.25, &dir.join("intro.wav"));
+        let main = streamed_bar(0.5, &dir.join("main.wav"));
+
+        let mut music = track(0.25);
+        music.intro = Some(Section::new(vec![Stem::streamed(intro, 0.0)]));
+        music.main.stems.push(Stem::streamed(main, 0.0));
+        let (mut audio, mut mixer) = offline();
+        audio.play_music(music, Transition::default());
+        let (events, _) = play(&mut audio, &mut mixer, 1);
+        assert_eq!(events[1], MusicEvent::Section(SectionKind::Intro));
+        // A section of nothing but streams lasts as long as they do
+        assert_eq!(play(&mut audio, &mut mixer, BAR - 1).1, 0.25);
+        let (events, _) = play(&mut audio, &mut mixer, 1);
+        assert_eq!(events[0], MusicEvent::Section(SectionKind::Main));
+        // Past where the recordings fade in
+        assert_eq!(play(&mut audio, &mut mixer, BEAT).1, 0.75);
+
+        // Give the decoding thread time to go round the main stem again
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(play(&mut audio, &mut mixer, BAR / 2 - BEAT).1, 0.75);
+        let (events, level) = play(&mut audio, &mut mixer, BAR);
+        assert_eq!(events.last(), Some(&beat(2, 2)));
+        assert_eq!(level, 0.75);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
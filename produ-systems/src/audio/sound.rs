@@ -0,0 +1,101 @@
+use super::decode::{DecodeError, Decoder, WavReader};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Decoded audio held in memory, shared between every voice playing it
+#[derive(Clone)]
+pub struct Sound {
+    sample_rate: u32,
+    channels: u16,
+    /// Interleaved when there are two channels, from -1 to 1
+    samples: Arc<[f32]>,
+}
+
+impl Sound {
+    /// Only mono and stereo are supported. Returns `None` for other channel counts, a zero
+    /// sample rate, or samples that do not fill whole frames
+    pub fn new(sample_rate: u32, channels: u16, samples: Vec<f32>) -> Option<Sound> {
+        if sample_rate == 0 || !(1..=2).contains(&channels) {
+            return None;
+        }
+        if channels == 2 && samples.len() % 2 == 1 {
+            return None;
+        }
+        Some(Sound {
+            sample_rate,
+            channels,
+            samples: samples.into(),
+        })
+    }
+
+    /// Decodes a whole `.wav` or `.ogg` file into memory, for short sound effects. Music is
+    /// better off as a `Stream`
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Sound, DecodeError> {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path)?);
+        Sound::decode(Decoder::for_path(path, reader)?)
+    }
+
+    /// Decodes a PCM or floating point WAV file
+    pub fn from_wav(bytes: &[u8]) -> Result<Sound, DecodeError> {
+        Sound::decode(Decoder::Wav(WavReader::new(Cursor::new(bytes))?))
+    }
+
+    pub fn from_vorbis(bytes: &[u8]) -> Result<Sound, DecodeError> {
+        Sound::decode(Decoder::vorbis(Cursor::new(bytes))?)
+    }
+
+    fn decode<R: Read + Seek>(mut decoder: Decoder<R>) -> Result<Sound, DecodeError> {
+        let (sample_rate, channels) = (decoder.sample_rate(), decoder.channels());
+        let samples = decoder.read_to_end()?;
+        Ok(Sound::new(sample_rate, channels, samples).expect("decoders only give whole frames"))
+    }
+
+    pub fn mono(sample_rate: u32, samples: Vec<f32>) -> Sound {
+        Sound::new(sample_rate.max(1), 1, samples).expect("mono sounds always fill whole frames")
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+
+    /// Length in seconds at the sound's own sample rate
+    pub fn duration(&self) -> f32 {
+        self.frames() as f32 / self.sample_rate as f32
+    }
+
+    /// Left and right of a frame, mono sounds play the same on both sides
+    pub(crate) fn frame(&self, index: usize) -> [f32; 2] {
+        if self.channels == 1 {
+            let sample = self.samples[index];
+            [sample, sample]
+        } else {
+            [self.samples[index * 2], self.samples[index * 2 + 1]]
+        }
+    }
+}
+
+impl fmt::Debug for Sound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sound")
+            .field("sample_rate", &self.sample_rate)
+            .field("channels", &self.channels)
+            .field("frames", &self.frames())
+            .finish()
+    }
+}
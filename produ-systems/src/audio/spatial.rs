@@ -0,0 +1,478 @@
+use crate::EntityIndex;
+use cgmath::{InnerSpace, Quaternion, Rotation, Vector3};
+
+/// In world units per second, for Doppler shifts
+pub const SPEED_OF_SOUND: f32 = 343.0;
+
+/// Doppler never shifts pitch further than this either way
+const MAX_DOPPLER_SHIFT: f32 = 2.0;
+
+/// Low pass cutoffs at or above this leave a voice unfiltered
+pub const OPEN_CUTOFF: f32 = 20_000.0;
+
+/// Lowest cutoff occlusion muffles a voice down to
+const MIN_CUTOFF: f32 = 50.0;
+
+/// Seconds occlusion takes to get most of the way to a new value, so walls passing between
+/// an emitter and the listener fade in and out instead of popping
+const OCCLUSION_SMOOTHING: f32 = 0.15;
+
+/// How a sound gets quieter with distance, between the emitter's reference and max distances
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Attenuation {
+    /// `reference / (reference + rolloff * (distance - reference))`, like real sound
+    Inverse,
+    /// Straight down to silence at the max distance, scaled by rolloff
+    Linear,
+    /// `(distance / reference) ^ -rolloff`
+    Exponential,
+}
+
+impl Attenuation {
+    pub fn gain(self, distance: f32, reference: f32, max_distance: f32, rolloff: f32) -> f32 {
+        let reference = reference.max(1e-3);
+        let max_distance = max_distance.max(reference);
+        let distance = distance.clamp(reference, max_distance);
+        let gain = match self {
+            Attenuation::Inverse => reference / (reference + rolloff * (distance - reference)),
+            Attenuation::Linear => {
+                if max_distance > reference {
+                    1.0 - rolloff * (distance - reference) / (max_distance - reference)
+                } else {
+                    1.0
+                }
+            }
+            Attenuation::Exponential => (distance / reference).powf(-rolloff),
+        };
+        gain.clamp(0.0, 1.0)
+    }
+}
+
+/// Directional emitters are full volume inside the inner cone and `outer_gain` outside the
+/// outer one, angles are the full width of the cone in radians
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Cone {
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    pub outer_gain: f32,
+}
+
+impl Cone {
+    /// `angle` is between the emitter's forward and the direction to the listener
+    pub fn gain(&self, angle: f32) -> f32 {
+        let (inner, outer) = (
+            self.inner_angle * 0.5,
+            self.outer_angle.max(self.inner_angle) * 0.5,
+        );
+        if angle <= inner {
+            1.0
+        } else if angle >= outer {
+            self.outer_gain
+        } else {
+            let t = (angle - inner) / (outer - inner);
+            1.0 + (self.outer_gain - 1.0) * t
+        }
+    }
+}
+
+/// What an emitter follows around
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Attachment {
+    Entity(EntityIndex),
+    Point(Vector3<f32>),
+}
+
+/// Places a voice in the world, so it pans, fades with distance and shifts pitch as it moves
+/// relative to the listener
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Emitter {
+    pub attachment: Attachment,
+    pub attenuation: Attenuation,
+    /// Full volume up to this distance
+    pub reference_distance: f32,
+    /// It gets no quieter past this distance
+    pub max_distance: f32,
+    pub rolloff: f32,
+    /// Pointing along the emitter's forward, -Z, when set
+    pub cone: Option<Cone>,
+    /// Scales the Doppler shift, `0.0` turns it off
+    pub doppler: f32,
+    /// Muffled by whatever stands between it and the listener, when set
+    pub occlusion: bool,
+}
+
+impl Emitter {
+    pub fn entity(entity: EntityIndex) -> Emitter {
+        Emitter::new(Attachment::Entity(entity))
+    }
+
+    pub fn point(position: Vector3<f32>) -> Emitter {
+        Emitter::new(Attachment::Point(position))
+    }
+
+    fn new(attachment: Attachment) -> Emitter {
+        Emitter {
+            attachment,
+            attenuation: Attenuation::Inverse,
+            reference_distance: 1.0,
+            max_distance: 100.0,
+            rolloff: 1.0,
+            cone: None,
+            doppler: 1.0,
+            occlusion: true,
+        }
+    }
+}
+
+/// How a surface changes the sound passing through it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AcousticMaterial {
+    /// Fraction of the volume that gets through, `0.0` blocks everything
+    pub transmission: f32,
+    /// Low pass cutoff of what gets through, in Hz
+    pub cutoff: f32,
+}
+
+impl AcousticMaterial {
+    pub const FOLIAGE: AcousticMaterial = AcousticMaterial {
+        transmission: 0.8,
+        cutoff: 8000.0,
+    };
+    pub const GLASS: AcousticMaterial = AcousticMaterial {
+        transmission: 0.5,
+        cutoff: 4000.0,
+    };
+    pub const WOOD: AcousticMaterial = AcousticMaterial {
+        transmission: 0.3,
+        cutoff: 1500.0,
+    };
+    pub const STONE: AcousticMaterial = AcousticMaterial {
+        transmission: 0.1,
+        cutoff: 600.0,
+    };
+    pub const EARTH: AcousticMaterial = AcousticMaterial {
+        transmission: 0.05,
+        cutoff: 300.0,
+    };
+}
+
+/// Solid walls are the safest guess for surfaces nobody gave a material
+impl Default for AcousticMaterial {
+    fn default() -> AcousticMaterial {
+        AcousticMaterial::STONE
+    }
+}
+
+/// What the surfaces between an emitter and the listener do to its voice
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Occlusion {
+    pub gain: f32,
+    /// Low pass cutoff in Hz, `OPEN_CUTOFF` for none
+    pub cutoff: f32,
+}
+
+impl Occlusion {
+    /// Nothing in the way
+    pub const CLEAR: Occlusion = Occlusion {
+        gain: 1.0,
+        cutoff: OPEN_CUTOFF,
+    };
+
+    /// Sound that passed through each of `materials` in turn
+    pub fn through<'a, I>(materials: I) -> Occlusion
+    where
+        I: IntoIterator<Item = &'a AcousticMaterial>,
+    {
+        materials
+            .into_iter()
+            .fold(Occlusion::CLEAR, |occlusion, material| Occlusion {
+                gain: occlusion.gain * material.transmission.clamp(0.0, 1.0),
+                cutoff: occlusion.cutoff.min(material.cutoff),
+            })
+    }
+
+    /// Moves towards `target` as much as `dt` seconds of smoothing allow. The cutoff moves
+    /// in octaves, so it sweeps evenly to the ear
+    pub(crate) fn approach(&mut self, target: Occlusion, dt: f32) {
+        let keep = (-dt.max(0.0) / OCCLUSION_SMOOTHING).exp();
+        self.gain = target.gain + (self.gain - target.gain) * keep;
+        let from = self.cutoff.clamp(MIN_CUTOFF, OPEN_CUTOFF);
+        let to = target.cutoff.clamp(MIN_CUTOFF, OPEN_CUTOFF);
+        // A power of the ratio rather than a round trip through logarithms, so no time passing
+        // leaves the cutoff exactly where it was
+        let cutoff = from * (to / from).powf(1.0 - keep);
+        // Land exactly on the target once it is inaudibly close, so clearing up really
+        // turns the filter off
+        self.cutoff = if (cutoff / to).log2().abs() < 0.01 {
+            to
+        } else {
+            cutoff
+        };
+    }
+}
+
+/// Where and how something is moving, for the listener and emitters alike
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Placement {
+    pub position: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub velocity: Vector3<f32>,
+}
+
+/// How the world changes a voice, on top of its own volume, pan and pitch
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Spatial {
+    pub gain: f32,
+    pub pan: f32,
+    pub pitch: f32,
+    /// Low pass cutoff from occlusion, `OPEN_CUTOFF` leaves the voice unfiltered
+    pub cutoff: f32,
+}
+
+impl Default for Spatial {
+    fn default() -> Spatial {
+        Spatial {
+            gain: 1.0,
+            pan: 0.0,
+            pitch: 1.0,
+            cutoff: OPEN_CUTOFF,
+        }
+    }
+}
+
+/// How `emitter` at `source` sounds to `listener`. The listener looks down -Z with +X to its
+/// right, like the camera
+pub fn spatialize(emitter: &Emitter, source: &Placement, listener: &Placement) -> Spatial {
+    let offset = source.position - listener.position;
+    let distance = offset.magnitude();
+    if distance < 1e-4 {
+        return Spatial::default();
+    }
+    let to_source = offset / distance;
+
+    let mut gain = emitter.attenuation.gain(
+        distance,
+        emitter.reference_distance,
+        emitter.max_distance,
+        emitter.rolloff,
+    );
+    if let Some(cone) = &emitter.cone {
+        let forward = source.rotation.rotate_vector(-Vector3::unit_z());
+        let angle = forward.dot(-to_source).clamp(-1.0, 1.0).acos();
+        gain *= cone.gain(angle);
+    }
+
+    let local = listener.rotation.invert().rotate_vector(to_source);
+    let pan = local.x.clamp(-1.0, 1.0);
+
+    // Speeds along the line from the source to the listener, capped below the speed of sound
+    let limit = SPEED_OF_SOUND * 0.99;
+    let listener_speed = (-to_source).dot(listener.velocity) * emitter.doppler;
+    let source_speed = (-to_source).dot(source.velocity) * emitter.doppler;
+    let pitch = (SPEED_OF_SOUND - listener_speed.clamp(-limit, limit))
+        / (SPEED_OF_SOUND - source_speed.clamp(-limit, limit));
+
+    Spatial {
+        gain,
+        pan,
+        pitch: pitch.clamp(1.0 / MAX_DOPPLER_SHIFT, MAX_DOPPLER_SHIFT),
+        cutoff: OPEN_CUTOFF,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{One, Rad, Rotation3, Zero};
+    use std::f32::consts::PI;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    fn still(position: Vector3<f32>) -> Placement {
+        Placement {
+            position,
+            rotation: Quaternion::one(),
+            velocity: Vector3::zero(),
+        }
+    }
+
+    fn moving(position: Vector3<f32>, velocity: Vector3<f32>) -> Placement {
+        Placement {
+            velocity,
+            ..still(position)
+        }
+    }
+
+    #[test]
+    fn attenuation_follows_each_curve() {
+        let inverse = |distance| Attenuation::Inverse.gain(distance, 2.0, 10.0, 1.0);
+        assert_eq!(inverse(0.5), 1.0);
+        assert!(close(inverse(6.0), 1.0 / 3.0));
+        // No quieter past the max distance
+        assert!(close(inverse(50.0), 0.2));
+
+        let linear = |distance, rolloff| Attenuation::Linear.gain(distance, 2.0, 10.0, rolloff);
+        assert!(close(linear(6.0, 1.0), 0.5));
+        assert_eq!(linear(10.0, 1.0), 0.0);
+        assert_eq!(linear(6.0, 3.0), 0.0);
+
+        let exponential = |distance| Attenuation::Exponential.gain(distance, 2.0, 100.0, 2.0);
+        assert!(close(exponential(4.0), 0.25));
+        assert!(close(exponential(20.0), 0.01));
+    }
+
+    #[test]
+    fn cones_fade_between_their_angles() {
+        let cone = Cone {
+            inner_angle: PI / 2.0,
+            outer_angle: PI,
+            outer_gain: 0.2,
+        };
+        assert_eq!(cone.gain(0.0), 1.0);
+        assert_eq!(cone.gain(PI / 4.0), 1.0);
+        assert!(close(cone.gain(3.0 * PI / 8.0), 0.6));
+        assert_eq!(cone.gain(PI / 2.0), 0.2);
+        assert_eq!(cone.gain(PI), 0.2);
+
+        // Pointing away from the listener
+        let emitter = Emitter {
+            cone: Some(cone),
+            ..Emitter::point(Vector3::zero())
+        };
+        let listener = still(Vector3::zero());
+        let away = spatialize(&emitter, &still(Vector3::new(0.0, 0.0, -1.0)), &listener);
+        assert!(close(away.gain, 0.2));
+        let facing = Placement {
+            rotation: Quaternion::from_angle_y(Rad(PI)),
+            ..still(Vector3::new(0.0, 0.0, -1.0))
+        };
+        assert!(close(spatialize(&emitter, &facing, &listener).gain, 1.0));
+    }
+
+    #[test]
+    fn sounds_pan_to_the_side_they_are_on() {
+        let emitter = Emitter::point(Vector3::zero());
+        let listener = still(Vector3::zero());
+        let right = still(Vector3::new(5.0, 0.0, 0.0));
+        assert!(close(spatialize(&emitter, &right, &listener).pan, 1.0));
+        let ahead = still(Vector3::new(0.0, 0.0, -5.0));
+        assert!(close(spatialize(&emitter, &ahead, &listener).pan, 0.0));
+
+        // Turned round, the right is on the left
+        let turned = Placement {
+            rotation: Quaternion::from_angle_y(Rad(PI)),
+            ..listener
+        };
+        assert!(close(spatialize(&emitter, &right, &turned).pan, -1.0));
+
+        // Right on top of the listener there is no direction to pan to
+        let here = spatialize(&emitter, &listener, &listener);
+        assert_eq!(here, Spatial::default());
+    }
+
+    #[test]
+    fn doppler_raises_approaching_sounds_and_lowers_receding_ones() {
+        let emitter = Emitter::point(Vector3::zero());
+        let listener = still(Vector3::zero());
+        let ahead = Vector3::new(0.0, 0.0, -10.0);
+        let pitch =
+            |source: Placement, listener: Placement| spatialize(&emitter, &source, &listener).pitch;
+        let towards = Vector3::new(0.0, 0.0, SPEED_OF_SOUND * 0.1);
+
+        assert!(close(pitch(moving(ahead, towards), listener), 1.0 / 0.9));
+        assert!(close(pitch(moving(ahead, -towards), listener), 1.0 / 1.1));
+        // The listener moving in works out a little differently to the source moving in
+        assert!(close(
+            pitch(still(ahead), moving(Vector3::zero(), -towards)),
+            1.1
+        ));
+        assert!(close(
+            pitch(still(ahead), moving(Vector3::zero(), towards)),
+            0.9
+        ));
+        // Passing by sideways at the closest point leaves the pitch alone
+        let sideways = Vector3::new(30.0, 0.0, 0.0);
+        assert!(close(pitch(moving(ahead, sideways), listener), 1.0));
+
+        // Faster than sound is capped, coming in at the largest shift and going away at just
+        // under the speed of sound
+        let supersonic = moving(ahead, towards * 20.0);
+        assert_eq!(pitch(supersonic, listener), MAX_DOPPLER_SHIFT);
+        let receding = pitch(moving(ahead, -towards * 20.0), listener);
+        assert!(close(receding, 1.0 / 1.99));
+
+        let off = Emitter {
+            doppler: 0.0,
+            ..emitter
+        };
+        assert_eq!(
+            spatialize(&off, &moving(ahead, towards), &listener).pitch,
+            1.0
+        );
+    }
+
+    #[test]
+    fn occlusion_stacks_every_material_passed_through() {
+        let none: [AcousticMaterial; 0] = [];
+        assert_eq!(Occlusion::through(&none), Occlusion::CLEAR);
+
+        let wall = Occlusion::through(&[AcousticMaterial::WOOD, AcousticMaterial::GLASS]);
+        assert!(close(wall.gain, 0.15));
+        assert_eq!(wall.cutoff, 1500.0);
+        let thicker = Occlusion::through(&[
+            AcousticMaterial::WOOD,
+            AcousticMaterial::GLASS,
+            AcousticMaterial::STONE,
+        ]);
+        assert!(close(thicker.gain, 0.015));
+        assert_eq!(thicker.cutoff, 600.0);
+
+        // Nothing gets louder for passing through something
+        let odd = AcousticMaterial {
+            transmission: 2.0,
+            cutoff: 30_000.0,
+        };
+        assert_eq!(Occlusion::through(&[odd]), Occlusion::CLEAR);
+    }
+
+    #[test]
+    fn occlusion_glides_to_its_target() {
+        let stone = Occlusion::through(&[AcousticMaterial::STONE]);
+        let mut occlusion = Occlusion::CLEAR;
+        occlusion.approach(stone, 0.0);
+        assert_eq!(occlusion, Occlusion::CLEAR);
+        occlusion.approach(stone, -1.0);
+        assert_eq!(occlusion, Occlusion::CLEAR);
+
+        // One smoothing time in, all but 1/e of the way there, the cutoff measured in octaves
+        let keep = (-1.0f32).exp();
+        occlusion.approach(stone, OCCLUSION_SMOOTHING);
+        assert!(close(occlusion.gain, 0.1 + 0.9 * keep));
+        let (from, to) = (OPEN_CUTOFF.log2(), 600.0f32.log2());
+        let cutoff = (to + (from - to) * keep).exp2();
+        assert!(
+            (occlusion.cutoff - cutoff).abs() < 0.1,
+            "{}",
+            occlusion.cutoff
+        );
+
+        occlusion.approach(stone, 2.0);
+        assert!(close(occlusion.gain, 0.1));
+        assert_eq!(occlusion.cutoff, 600.0);
+
+        // Clearing up lands on no filter at all rather than somewhere just under it
+        occlusion.approach(Occlusion::CLEAR, 2.0);
+        assert!(close(occlusion.gain, 1.0));
+        assert_eq!(occlusion.cutoff, OPEN_CUTOFF);
+
+        // And muffling stops at the lowest cutoff
+        let buried = Occlusion {
+            gain: 0.0,
+            cutoff: 10.0,
+        };
+        occlusion.approach(buried, 10.0);
+        assert_eq!(occlusion.cutoff, MIN_CUTOFF);
+    }
+}
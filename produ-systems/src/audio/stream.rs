@@ -0,0 +1,181 @@
+use super::channel::{self, Receiver, Sender};
+use super::decode::{DecodeError, Decoder};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often the decoding thread tops the ring buffer up
+const REFILL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Flags the voice and the decoding thread share
+struct Shared {
+    looping: AtomicBool,
+    /// The decoder ran out, whatever is left in the ring is the end of the stream
+    finished: AtomicBool,
+    /// The stream was dropped, the decoding thread can stop
+    closed: AtomicBool,
+}
+
+/// Audio decoded from disk bit by bit on a background thread, for music and other sounds too
+/// long to keep in memory. The thread keeps a ring buffer of samples topped up ahead of the
+/// mixer, which plays from it without ever waiting
+pub struct Stream {
+    samples: Receiver<f32>,
+    shared: Arc<Shared>,
+    sample_rate: u32,
+    channels: u16,
+}
+
+impl Stream {
+    /// Opens a `.wav` or `.ogg` file, keeping half a second decoded ahead
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Stream, DecodeError> {
+        Stream::open_buffered(path, 0.5)
+    }
+
+    /// Opens a `.wav` or `.ogg` file, keeping `seconds` decoded ahead. The buffer gets filled
+    /// before this returns so playback can start straight away
+    pub fn open_buffered<P: AsRef<Path>>(path: P, seconds: f32) -> Result<Stream, DecodeError> {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path)?);
+        let decoder = Decoder::for_path(path, reader)?;
+        Ok(Stream::spawn(decoder, seconds))
+    }
+
+    fn spawn(decoder: Decoder<BufReader<File>>, seconds: f32) -> Stream {
+        let (sample_rate, channels) = (decoder.sample_rate(), decoder.channels());
+        let capacity = (sample_rate as f32 * seconds.max(0.01)) as usize * channels as usize;
+        let (sender, samples) = channel::channel(capacity);
+        let shared = Arc::new(Shared {
+            looping: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+        });
+        let mut feeder = Feeder {
+            decoder,
+            sender,
+            shared: shared.clone(),
+            pending: vec![],
+            sent: 0,
+            at_end: false,
+            broken: false,
+        };
+        feeder.fill();
+        // The thread stays around after the end of the file in case the voice starts looping
+        thread::spawn(move || {
+            while !feeder.shared.closed.load(Ordering::Acquire) {
+                thread::sleep(REFILL_INTERVAL);
+                feeder.fill();
+            }
+        });
+        Stream {
+            samples,
+            shared,
+            sample_rate,
+            channels,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// The decoding thread does the looping, starting the file over when it reaches the end
+    pub(crate) fn set_looping(&self, looping: bool) {
+        self.shared.looping.store(looping, Ordering::Release);
+    }
+
+    /// The next frame, or `None` if the decoder has not kept up
+    pub(crate) fn next_frame(&mut self) -> Option<[f32; 2]> {
+        if self.samples.len() < self.channels as usize {
+            return None;
+        }
+        let left = self.samples.recv()?;
+        if self.channels == 1 {
+            return Some([left, left]);
+        }
+        Some([left, self.samples.recv()?])
+    }
+
+    /// Everything has been decoded and played
+    pub(crate) fn is_finished(&self) -> bool {
+        // The finished flag goes up after the last samples went in, so check it first
+        self.shared.finished.load(Ordering::Acquire) && self.samples.len() < self.channels as usize
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+    }
+}
+
+/// The decoding thread's end of a stream
+struct Feeder {
+    decoder: Decoder<BufReader<File>>,
+    sender: Sender<f32>,
+    shared: Arc<Shared>,
+    /// Decoded samples that did not fit in the ring yet
+    pending: Vec<f32>,
+    sent: usize,
+    at_end: bool,
+    /// The file failed to decode partway, it just ends there
+    broken: bool,
+}
+
+impl Feeder {
+    /// Decodes until the ring is full or the file runs out
+    fn fill(&mut self) {
+        let channels = self.decoder.channels() as usize;
+        loop {
+            if self.sent == self.pending.len() {
+                self.pending.clear();
+                self.sent = 0;
+                let more = self.decode_block();
+                self.shared.finished.store(!more, Ordering::Release);
+                if !more {
+                    return;
+                }
+            }
+            // Whole frames only, so the voice never sees half of one
+            let room = self.sender.free() / channels * channels;
+            let count = room.min(self.pending.len() - self.sent);
+            if count == 0 {
+                return;
+            }
+            self.sent += self
+                .sender
+                .send_slice(&self.pending[self.sent..self.sent + count]);
+        }
+    }
+
+    /// Refills `pending`, starting over at the end of a looping stream. Tries the start once
+    /// at most, a file with no audio in it would otherwise loop forever
+    fn decode_block(&mut self) -> bool {
+        for _ in 0..2 {
+            if self.at_end {
+                let looping = self.shared.looping.load(Ordering::Acquire);
+                if self.broken || !looping || self.decoder.rewind().is_err() {
+                    return false;
+                }
+                self.at_end = false;
+            }
+            match self.decoder.read(&mut self.pending) {
+                Ok(0) => self.at_end = true,
+                Ok(_) => return true,
+                Err(_) => {
+                    self.at_end = true;
+                    self.broken = true;
+                }
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,433 @@
+use super::effect::Biquad;
+use super::sound::Sound;
+use std::f32::consts::TAU;
+
+/// Noise waveforms hold this many random values per period, so they still have a pitch
+const NOISE_STEPS: usize = 32;
+
+/// Filter sweeps retune this often, in samples
+const SWEEP_INTERVAL: usize = 16;
+
+/// Resonance of `1.0` gives this filter Q
+const MAX_RESONANCE_Q: f32 = 8.0;
+
+/// Small xorshift rng, the same seed always makes the same sound
+#[derive(Debug, Clone)]
+struct SynthRng {
+    state: u64,
+}
+
+impl SynthRng {
+    fn new(seed: u64) -> SynthRng {
+        // xorshift gets stuck on zero
+        SynthRng {
+            state: seed ^ 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A number in `0.0..1.0`
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn range(&mut self, low: f32, high: f32) -> f32 {
+        low + (high - low) * self.next_f32()
+    }
+
+    /// A number in `-1.0..1.0`
+    fn signed(&mut self) -> f32 {
+        self.next_f32() * 2.0 - 1.0
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        self.next_f32() < probability
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Waveform {
+    /// High for `SfxParams::duty` of each period
+    Square,
+    Sawtooth,
+    Sine,
+    Triangle,
+    /// Random steps, pitched by the frequency
+    Noise,
+}
+
+/// Seconds for each stage, and the level held between decay and release
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Adsr {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    /// How long the sustain level is held
+    pub hold: f32,
+    pub release: f32,
+}
+
+impl Adsr {
+    pub fn duration(&self) -> f32 {
+        self.attack.max(0.0) + self.decay.max(0.0) + self.hold.max(0.0) + self.release.max(0.0)
+    }
+
+    fn level(&self, time: f32) -> f32 {
+        let stages = [
+            (self.attack, 0.0, 1.0),
+            (self.decay, 1.0, self.sustain),
+            (self.hold, self.sustain, self.sustain),
+            (self.release, self.sustain, 0.0),
+        ];
+        let mut start = 0.0;
+        for &(length, from, to) in stages.iter() {
+            let length = length.max(0.0);
+            if time < start + length {
+                return from + (to - from) * (time - start) / length;
+            }
+            start += length;
+        }
+        0.0
+    }
+}
+
+/// Everything an sfxr style sound effect is made from. Frequencies are in Hz, slides and
+/// sweeps in octaves per second
+#[derive(Debug, Clone, PartialEq)]
+pub struct SfxParams {
+    pub waveform: Waveform,
+    /// Fraction of the period a square wave is high
+    pub duty: f32,
+    /// Change in duty per second
+    pub duty_sweep: f32,
+    pub envelope: Adsr,
+    pub frequency: f32,
+    /// The sound cuts off if the slide takes it below this, `0.0` never cuts off
+    pub min_frequency: f32,
+    pub slide: f32,
+    /// Change in slide per second
+    pub delta_slide: f32,
+    /// Fraction of the frequency the vibrato swings by
+    pub vibrato_depth: f32,
+    /// Vibrato cycles per second
+    pub vibrato_speed: f32,
+    /// The frequency jumps by this factor once, `1.0` for no jump
+    pub arpeggio: f32,
+    /// Seconds into the sound the jump happens
+    pub arpeggio_time: f32,
+    /// Low pass cutoff, at or above half the sample rate it lets everything through. Off by
+    /// default
+    pub low_pass: f32,
+    pub low_pass_sweep: f32,
+    /// From `0.0` flat to `1.0` ringing at the low pass cutoff
+    pub resonance: f32,
+    /// High pass cutoff, `0.0` for none
+    pub high_pass: f32,
+    pub high_pass_sweep: f32,
+    /// White noise mixed over the waveform, from `0.0` to `1.0`
+    pub noise: f32,
+    pub volume: f32,
+    /// For the noise, the same seed makes the same sound
+    pub seed: u64,
+}
+
+impl Default for SfxParams {
+    fn default() -> SfxParams {
+        SfxParams {
+            waveform: Waveform::Square,
+            duty: 0.5,
+            duty_sweep: 0.0,
+            envelope: Adsr {
+                attack: 0.0,
+                decay: 0.0,
+                sustain: 1.0,
+                hold: 0.2,
+                release: 0.2,
+            },
+            frequency: 440.0,
+            min_frequency: 0.0,
+            slide: 0.0,
+            delta_slide: 0.0,
+            vibrato_depth: 0.0,
+            vibrato_speed: 0.0,
+            arpeggio: 1.0,
+            arpeggio_time: 0.0,
+            low_pass: f32::INFINITY,
+            low_pass_sweep: 0.0,
+            resonance: 0.0,
+            high_pass: 0.0,
+            high_pass_sweep: 0.0,
+            noise: 0.0,
+            volume: 0.5,
+            seed: 0,
+        }
+    }
+}
+
+impl SfxParams {
+    /// A bright blip that jumps up, for coins and power ups
+    pub fn pickup(seed: u64) -> SfxParams {
+        let mut rng = SynthRng::new(seed);
+        let mut params = SfxParams {
+            waveform: if rng.chance(0.5) {
+                Waveform::Square
+            } else {
+                Waveform::Sawtooth
+            },
+            frequency: rng.range(600.0, 1500.0),
+            seed,
+            ..SfxParams::default()
+        };
+        params.envelope = Adsr {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            hold: rng.range(0.03, 0.12),
+            release: rng.range(0.1, 0.3),
+        };
+        if rng.chance(0.7) {
+            params.arpeggio = rng.range(1.25, 1.8);
+            params.arpeggio_time = rng.range(0.03, 0.09);
+        }
+        params
+    }
+
+    /// A square wave sliding up
+    pub fn jump(seed: u64) -> SfxParams {
+        let mut rng = SynthRng::new(seed);
+        let mut params = SfxParams {
+            waveform: Waveform::Square,
+            duty: rng.range(0.2, 0.5),
+            frequency: rng.range(250.0, 600.0),
+            slide: rng.range(1.5, 4.0),
+            seed,
+            ..SfxParams::default()
+        };
+        params.envelope = Adsr {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            hold: rng.range(0.05, 0.15),
+            release: rng.range(0.1, 0.2),
+        };
+        if rng.chance(0.5) {
+            params.low_pass = rng.range(2000.0, 8000.0);
+        }
+        if rng.chance(0.5) {
+            params.high_pass = rng.range(100.0, 400.0);
+        }
+        params
+    }
+
+    /// A short crunch sliding down, for taking damage
+    pub fn hit(seed: u64) -> SfxParams {
+        let mut rng = SynthRng::new(seed);
+        let waveform = match rng.next_u64() % 3 {
+            0 => Waveform::Square,
+            1 => Waveform::Sawtooth,
+            _ => Waveform::Noise,
+        };
+        let mut params = SfxParams {
+            waveform,
+            duty: rng.range(0.2, 0.5),
+            frequency: rng.range(150.0, 700.0),
+            slide: rng.range(-5.0, -2.0),
+            noise: rng.range(0.0, 0.3),
+            seed,
+            ..SfxParams::default()
+        };
+        params.envelope = Adsr {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            hold: rng.range(0.0, 0.05),
+            release: rng.range(0.08, 0.25),
+        };
+        if rng.chance(0.5) {
+            params.high_pass = rng.range(100.0, 600.0);
+        }
+        params
+    }
+
+    /// A low rumble of noise that dies away
+    pub fn explosion(seed: u64) -> SfxParams {
+        let mut rng = SynthRng::new(seed);
+        let mut params = SfxParams {
+            waveform: Waveform::Noise,
+            frequency: rng.range(40.0, 250.0),
+            slide: rng.range(-1.5, 0.3),
+            low_pass: rng.range(2000.0, 10000.0),
+            low_pass_sweep: rng.range(-2.0, -0.5),
+            resonance: rng.range(0.0, 0.3),
+            volume: 0.6,
+            seed,
+            ..SfxParams::default()
+        };
+        params.envelope = Adsr {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            hold: rng.range(0.05, 0.3),
+            release: rng.range(0.4, 1.0),
+        };
+        if rng.chance(0.5) {
+            params.vibrato_depth = rng.range(0.05, 0.3);
+            params.vibrato_speed = rng.range(5.0, 20.0);
+        }
+        params
+    }
+
+    /// Renders the sound at `sample_rate`, ready to `play` like a decoded one
+    pub fn generate(&self, sample_rate: u32) -> Sound {
+        let sample_rate = sample_rate.max(1);
+        let rate = sample_rate as f32;
+        let dt = 1.0 / rate;
+        let nyquist = rate * 0.5;
+        let frames = (self.envelope.duration() * rate).ceil() as usize;
+
+        let mut rng = SynthRng::new(self.seed);
+        let mut noise_steps = [0.0; NOISE_STEPS];
+        for step in noise_steps.iter_mut() {
+            *step = rng.signed();
+        }
+        let q = std::f32::consts::FRAC_1_SQRT_2 + self.resonance.clamp(0.0, 1.0) * MAX_RESONANCE_Q;
+        let mut low_pass = Biquad::low_pass(sample_rate, self.low_pass, q);
+        let mut high_pass = Biquad::high_pass(sample_rate, self.high_pass, q);
+        let (mut low_cutoff, mut high_cutoff) = (self.low_pass, self.high_pass);
+
+        let mut samples = Vec::with_capacity(frames);
+        let mut frequency = self.frequency.max(0.0);
+        let mut slide = self.slide;
+        let mut duty = self.duty;
+        let mut phase = 0.0f32;
+        let mut arpeggio_done = self.arpeggio == 1.0 || self.arpeggio_time <= 0.0;
+        for i in 0..frames {
+            let time = i as f32 * dt;
+            if !arpeggio_done && time >= self.arpeggio_time {
+                frequency *= self.arpeggio;
+                arpeggio_done = true;
+            }
+            frequency *= (slide * dt).exp2();
+            slide += self.delta_slide * dt;
+            if frequency < self.min_frequency {
+                break;
+            }
+            let vibrato = 1.0 + self.vibrato_depth * (TAU * self.vibrato_speed * time).sin();
+            phase += (frequency * vibrato).min(nyquist) * dt;
+            if phase >= 1.0 {
+                phase %= 1.0;
+                if self.waveform == Waveform::Noise {
+                    for step in noise_steps.iter_mut() {
+                        *step = rng.signed();
+                    }
+                }
+            }
+            duty = (duty + self.duty_sweep * dt).clamp(0.0, 1.0);
+
+            let wave = match self.waveform {
+                Waveform::Square => {
+                    if phase < duty {
+                        1.0
+                    } else {
+                        -1.0
+                    }
+                }
+                Waveform::Sawtooth => 1.0 - 2.0 * phase,
+                Waveform::Sine => (TAU * phase).sin(),
+                Waveform::Triangle => 4.0 * (phase - 0.5).abs() - 1.0,
+                Waveform::Noise => noise_steps[(phase * NOISE_STEPS as f32) as usize % NOISE_STEPS],
+            };
+            let noise = self.noise.clamp(0.0, 1.0);
+            let mut sample = wave * (1.0 - noise) + rng.signed() * noise;
+
+            if i % SWEEP_INTERVAL == 0 {
+                if self.low_pass_sweep != 0.0 {
+                    low_cutoff = self.low_pass * (self.low_pass_sweep * time).exp2();
+                    low_pass.set_low_pass(low_cutoff, q);
+                }
+                if self.high_pass_sweep != 0.0 {
+                    high_cutoff = self.high_pass * (self.high_pass_sweep * time).exp2();
+                    high_pass.set_high_pass(high_cutoff, q);
+                }
+            }
+            // Filters only run while their cutoff is in range, so off really is off
+            if low_cutoff < nyquist {
+                sample = low_pass.process_frame([sample, sample])[0];
+            }
+            if high_cutoff > 0.0 {
+                sample = high_pass.process_frame([sample, sample])[0];
+            }
+
+            let level = self.envelope.level(time) * self.volume;
+            samples.push((sample * level).clamp(-1.0, 1.0));
+        }
+        Sound::mono(sample_rate, samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44100;
+
+    type Preset = fn(u64) -> SfxParams;
+
+    /// Every preset, by name for the failure messages
+    const PRESETS: [(&str, Preset); 4] = [
+        ("pickup", SfxParams::pickup),
+        ("jump", SfxParams::jump),
+        ("hit", SfxParams::hit),
+        ("explosion", SfxParams::explosion),
+    ];
+
+    #[test]
+    fn the_same_seed_makes_the_same_sound() {
+        for &(name, preset) in PRESETS.iter() {
+            for &seed in [0, 1, 7, u64::MAX].iter() {
+                assert_eq!(preset(seed), preset(seed), "{} {}", name, seed);
+                let sound = preset(seed).generate(RATE);
+                assert_eq!(sound.samples(), preset(seed).generate(RATE).samples());
+                assert!(!sound.samples().is_empty(), "{} {}", name, seed);
+                assert!(
+                    sound.samples().iter().any(|&s| s != 0.0),
+                    "{} {}",
+                    name,
+                    seed
+                );
+                assert!(sound.samples().iter().all(|s| s.abs() <= 1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn different_seeds_make_different_sounds() {
+        for &(name, preset) in PRESETS.iter() {
+            let sounds: Vec<_> = (0..4).map(|seed| preset(seed).generate(RATE)).collect();
+            for (i, a) in sounds.iter().enumerate() {
+                for b in &sounds[i + 1..] {
+                    assert_ne!(a.samples(), b.samples(), "{}", name);
+                }
+            }
+        }
+
+        // With everything else the same the seed still changes the noise
+        let noise = |seed| SfxParams {
+            waveform: Waveform::Noise,
+            noise: 0.5,
+            seed,
+            ..SfxParams::default()
+        };
+        let a = noise(1).generate(RATE);
+        assert_eq!(a.samples(), noise(1).generate(RATE).samples());
+        assert_ne!(a.samples(), noise(2).generate(RATE).samples());
+    }
+}
@@ -3,10 +3,57 @@
 // use vulkan::create_vulkan_instance;
 #[path = "./renderer/vulkan.rs"]
 mod vulkan;
-use vulkan::create_vulkan_instance;
+use vulkan::Renderer;
 
-struct Player {}
-struct Monster {}
+#[path = "./audio/mod.rs"]
+mod audio;
+#[cfg(feature = "device")]
+use audio::DeviceOutput;
+use audio::{
+    AcousticMaterial, Audio, AudioBackend, AudioSettings, Bus, Ducking, Emitter, Limiter,
+    MixerConfig, NullSink, Occlusion, PlayParams, SfxParams, Sound,
+};
+
+#[path = "./spawner/mod.rs"]
+mod spawner;
+use spawner::{PlayerView, Spawner};
+
+#[path = "./math/mod.rs"]
+mod math;
+
+#[path = "./physics/mod.rs"]
+mod physics;
+use physics::{
+    CharacterConfig, CharacterController, ColliderHandle, CollisionEvent, CollisionEventKind,
+    PhysicsWorld, QueryFilter, Ray, VehicleControls, VehicleHandle,
+};
+
+#[path = "./terrain/mod.rs"]
+mod terrain;
+use terrain::{Terrain, TerrainConfig};
+
+use cgmath::{InnerSpace, One, Quaternion, Vector3};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::window::WindowBuilder;
+
+struct Player {
+    health: f32,
+    max_health: f32,
+    controller: CharacterController,
+    /// Speed along the up axis, only gravity and jumps change it
+    vertical_speed: f32,
+    /// The vehicle the player is driving, input steers it instead of walking
+    vehicle: Option<VehicleHandle>,
+}
+struct Monster {
+    archetype: String,
+    /// The player the monster has spotted
+    target: Option<EntityIndex>,
+}
 struct Npc {}
 
 enum Entity {
@@ -17,43 +64,572 @@ enum Entity {
 
 pub type EntityIndex = u32;
 
+/// Collision layer for level geometry, colliders start out on it
+const LAYER_WORLD: u32 = 1;
+const LAYER_PLAYER: u32 = 1 << 1;
+
+/// How fast players walk, in units per second
+const PLAYER_SPEED: f32 = 5.0;
+
+/// How far away a monster can spot a player
+const MONSTER_SIGHT_RANGE: f32 = 40.0;
+
+/// How close a monster gets to a player before it stops to attack
+const MONSTER_REACH: f32 = 1.0;
+
+/// Monsters hunting players for the fighting to count as fully intense
+const MONSTERS_FOR_FULL_INTENSITY: f32 = 8.0;
+
+/// Sight lines run this far above entity positions, so the floor under them does not get in the way
+const EYE_HEIGHT: f32 = 1.6;
+
+/// Longest step the game takes in one frame, so a stall such as dragging the window around
+/// does not fast forward the world
+const MAX_FRAME_TIME: f32 = 0.25;
+
+/// Occlusion rays leave emitters this far up, so the ground a sound stands on does not muffle it
+const OCCLUSION_RAY_LIFT: f32 = 0.25;
+
+/// Contacts that begin softer than this make no sound, so resting bodies settling stay quiet
+const QUIET_IMPACT_IMPULSE: f32 = 1.0;
+
+/// Contacts that begin this hard or harder play the impact sound at full volume
+const LOUD_IMPACT_IMPULSE: f32 = 20.0;
+
+/// Where an entity sits in the world, indexed by `EntityIndex` alongside `GameState::entities`
+#[derive(Debug, Copy, Clone)]
+pub struct Transform {
+    pub position: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Transform {
+    pub fn from_position(position: Vector3<f32>) -> Transform {
+        Transform {
+            position,
+            rotation: Quaternion::one(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
 pub struct GameState {
     entities: Vec<Option<Entity>>,
+    transforms: Vec<Option<Transform>>,
     players: Vec<EntityIndex>,
+    spawner: Spawner,
+    physics: PhysicsWorld,
+    terrain: Terrain,
+    audio: Audio,
+    audio_output: Box<dyn AudioBackend>,
+    /// How level geometry muffles sounds behind it, colliders missing here count as stone
+    acoustic_materials: HashMap<ColliderHandle, AcousticMaterial>,
+    /// Played where bodies hit each other
+    impact_sound: Sound,
+    /// Trigger and contact events from the last physics update, for gameplay and audio to react to
+    collision_events: Vec<CollisionEvent>,
+    /// From `0.0` calm to `1.0` with a pack of monsters hunting the players, drives the music
+    combat_intensity: f32,
+    /// Seconds the game has been running for
     counter: f64,
 }
+
+impl GameState {
+    /// Puts the entity in the first free slot, so indices of dead entities get reused
+    fn spawn(&mut self, entity: Entity, transform: Transform) -> EntityIndex {
+        let index = match self.entities.iter().position(|e| e.is_none()) {
+            Some(index) => index,
+            None => {
+                self.entities.push(None);
+                self.transforms.push(None);
+                self.entities.len() - 1
+            }
+        };
+        self.entities[index] = Some(entity);
+        self.transforms[index] = Some(transform);
+        index as EntityIndex
+    }
+
+    fn spawn_player(&mut self, position: Vector3<f32>) -> EntityIndex {
+        let config = CharacterConfig {
+            layers: LAYER_PLAYER,
+            ..CharacterConfig::default()
+        };
+        let controller = CharacterController::new(&mut self.physics, position, config);
+        let body = controller.body();
+        let player = Player {
+            health: 100.0,
+            max_health: 100.0,
+            controller,
+            vertical_speed: 0.0,
+            vehicle: None,
+        };
+        let index = self.spawn(Entity::Player(player), Transform::from_position(position));
+        if let Some(body) = self.physics.body_mut(body) {
+            body.entity = Some(index);
+        }
+        self.players.push(index);
+        index
+    }
+}
+
 enum Input {
+    NONE,
     UP,
     DOWN,
     LEFT,
     RIGHT,
 }
 
+impl Input {
+    /// Which way to walk on the ground, up on the stick is away from the camera down -Z
+    fn direction(&self) -> Vector3<f32> {
+        match self {
+            Input::NONE => Vector3::new(0.0, 0.0, 0.0),
+            Input::UP => -Vector3::unit_z(),
+            Input::DOWN => Vector3::unit_z(),
+            Input::LEFT => -Vector3::unit_x(),
+            Input::RIGHT => Vector3::unit_x(),
+        }
+    }
+
+    /// Up is the throttle, down brakes and then reverses, left and right steer
+    fn vehicle_controls(&self) -> VehicleControls {
+        let (throttle, brake, steering) = match self {
+            Input::NONE => (0.0, 0.0, 0.0),
+            Input::UP => (1.0, 0.0, 0.0),
+            Input::DOWN => (0.0, 1.0, 0.0),
+            Input::LEFT => (0.0, 0.0, -1.0),
+            Input::RIGHT => (0.0, 0.0, 1.0),
+        };
+        VehicleControls {
+            throttle,
+            brake,
+            steering,
+            handbrake: false,
+        }
+    }
+}
+
 fn main() {
+    let spawner =
+        Spawner::from_file("assets/spawner/waves.ron").expect("Failed to load the spawner config");
+
+    let terrain_config = TerrainConfig {
+        origin: Vector3::new(-64.0, -8.0, -64.0),
+        ..TerrainConfig::default()
+    };
+    let terrain = Terrain::from_file("assets/terrain/hills.pgm", terrain_config)
+        .expect("Failed to load the terrain heightmap");
+    let mut physics = PhysicsWorld::new();
+    let (_, terrain_collider) = terrain.add_to(&mut physics);
+    let mut acoustic_materials = HashMap::new();
+    acoustic_materials.insert(terrain_collider, AcousticMaterial::EARTH);
+
+    let (mut audio, audio_output) = open_audio(MixerConfig::default());
+    // Defaults until the player has saved some
+    let audio_settings = AudioSettings::from_file("assets/audio/settings.ron").unwrap_or_default();
+    audio.apply_settings(&audio_settings);
+    audio.set_ducking(Bus::Music, Some(Ducking::default()));
+    let limiter = Limiter::new(audio.sample_rate(), -1.0, 0.1);
+    audio.add_effect(Bus::Master, limiter);
+    let impact_sound = SfxParams::hit(7).generate(audio.sample_rate());
+
     let mut game_state = GameState {
         entities: vec![],
+        transforms: vec![],
         players: vec![],
+        spawner,
+        physics,
+        terrain,
+        audio,
+        audio_output,
+        acoustic_materials,
+        impact_sound,
+        collision_events: vec![],
+        combat_intensity: 0.0,
         counter: 0.0,
     };
+    let ground = game_state.terrain.height_at(0.0, 0.0).unwrap_or(0.0);
+    game_state.spawn_player(Vector3::new(0.0, ground + 1.0, 0.0));
+
+    let event_loop = EventLoop::new();
+    let window = WindowBuilder::new()
+        .with_title("produ")
+        .build(&event_loop)
+        .expect("Failed to open a window");
+    let window = Arc::new(window);
+    let mut renderer = Renderer::new(window).expect("Failed to start the renderer");
+
+    let mut keyboard = Keyboard::default();
+    let mut last_frame = Instant::now();
+    event_loop.run(move |event, _, control_flow| match event {
+        Event::WindowEvent { event, .. } => match event {
+            WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+            WindowEvent::Resized(size) => renderer.resize(size.into()),
+            WindowEvent::KeyboardInput { input, .. } => keyboard.handle(&input),
+            // Keys let go of in another window never send their release
+            WindowEvent::Focused(false) => keyboard.held.clear(),
+            _ => {}
+        },
+        Event::MainEventsCleared => {
+            let now = Instant::now();
+            let dt = (now - last_frame).as_secs_f32().min(MAX_FRAME_TIME);
+            last_frame = now;
+            let input_state = capture_input_state(&keyboard);
+            update(&mut game_state, &input_state, dt);
+        }
+        Event::RedrawEventsCleared => render_system(&game_state, &mut renderer),
+        Event::LoopDestroyed => renderer.shutdown(),
+        _ => {}
+    });
+}
 
-    render_system(&mut game_state);
+/// Runs every system but rendering for one frame
+fn update(game_state: &mut GameState, input_state: &Input, dt: f32) {
+    game_state.counter += dt as f64;
+    player_controller_system(game_state, input_state, dt);
+    vehicle_controller_system(game_state, input_state);
+    npc_behaviour_system(game_state, input_state);
+    physics_system(game_state, dt);
+    monster_behaviour_system(game_state, dt);
+    spawner_system(game_state, dt);
+    audio_system(game_state, dt);
+}
+
+/// The sound card if there is one, otherwise a sink that keeps the mixer going silently
+fn open_audio(config: MixerConfig) -> (Audio, Box<dyn AudioBackend>) {
+    #[cfg(feature = "device")]
+    {
+        if let Ok((audio, output)) = DeviceOutput::open(config) {
+            return (audio, Box::new(output));
+        }
+    }
+    let (audio, mixer) = Audio::new(config);
+    (audio, Box::new(NullSink::new(mixer)))
 }
 
 fn npc_behaviour_system(game_state: &mut GameState, input_state: &Input) {}
 
-fn monster_behaviour_system(game_state: &mut GameState) {}
+fn player_controller_system(game_state: &mut GameState, input_state: &Input, dt: f32) {
+    let gravity = game_state.physics.gravity.y;
+    let walk = input_state.direction() * PLAYER_SPEED * dt;
+    for &index in &game_state.players {
+        let player = match &mut game_state.entities[index as usize] {
+            Some(Entity::Player(player)) if player.vehicle.is_none() => player,
+            _ => continue,
+        };
+        if player.controller.is_grounded() {
+            player.vertical_speed = 0.0;
+        }
+        player.vertical_speed += gravity * dt;
+
+        let displacement = walk + Vector3::unit_y() * player.vertical_speed * dt;
+        player
+            .controller
+            .move_by(&mut game_state.physics, displacement, dt);
+        // Hitting a ceiling ends a jump early
+        if player
+            .controller
+            .contact_normals()
+            .iter()
+            .any(|n| n.y < -0.5)
+        {
+            player.vertical_speed = player.vertical_speed.min(0.0);
+        }
+
+        let body = game_state.physics.body(player.controller.body());
+        if let (Some(body), Some(Some(transform))) =
+            (body, game_state.transforms.get_mut(index as usize))
+        {
+            transform.position = body.position;
+        }
+    }
+}
+
+fn vehicle_controller_system(game_state: &mut GameState, input_state: &Input) {
+    let controls = input_state.vehicle_controls();
+    let physics = &mut game_state.physics;
+    for &index in &game_state.players {
+        let vehicle = match &game_state.entities[index as usize] {
+            Some(Entity::Player(player)) => player.vehicle,
+            _ => None,
+        };
+        if let Some(vehicle) = vehicle.and_then(|v| physics.vehicle_mut(v)) {
+            vehicle.controls = controls;
+        }
+    }
+}
+
+/// Monsters pick the closest player they can see and walk over the ground towards them,
+/// wearing down their health once they catch up
+fn monster_behaviour_system(game_state: &mut GameState, dt: f32) {
+    let physics = &game_state.physics;
+    let transforms = &game_state.transforms;
+    let players = &game_state.players;
+    for (index, entity) in game_state.entities.iter_mut().enumerate() {
+        let monster = match entity {
+            Some(Entity::Monster(monster)) => monster,
+            _ => continue,
+        };
+        let eye = match &transforms[index] {
+            Some(transform) => transform.position,
+            None => continue,
+        };
+        monster.target = players
+            .iter()
+            .filter_map(|&player| {
+                let position = transforms[player as usize].as_ref()?.position;
+                let distance = (position - eye).magnitude();
+                if distance <= MONSTER_SIGHT_RANGE && line_of_sight(physics, eye, position) {
+                    Some((player, distance))
+                } else {
+                    None
+                }
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(player, _)| player);
+    }
 
-fn physics_system(game_state: &mut GameState) {}
+    let mut bites = vec![];
+    for index in 0..game_state.entities.len() {
+        let (archetype, target) = match &game_state.entities[index] {
+            Some(Entity::Monster(monster)) => (&monster.archetype, monster.target),
+            _ => continue,
+        };
+        let goal = match target.and_then(|t| game_state.transforms[t as usize]) {
+            Some(transform) => transform.position,
+            None => continue,
+        };
+        let archetype = game_state.spawner.archetype(archetype);
+        let transform = match &mut game_state.transforms[index] {
+            Some(transform) => transform,
+            None => continue,
+        };
+        let mut offset = goal - transform.position;
+        offset.y = 0.0;
+        let distance = offset.magnitude();
+        if distance <= MONSTER_REACH {
+            bites.push((target, archetype.damage * dt));
+            continue;
+        }
+        let step = (archetype.speed * dt).min(distance - MONSTER_REACH);
+        let mut position = transform.position + offset / distance * step;
+        if let Some(height) = game_state.terrain.height_at(position.x, position.z) {
+            position.y = height;
+        }
+        transform.position = position;
+    }
+    for (target, damage) in bites {
+        if let Some(Some(Entity::Player(player))) =
+            target.and_then(|t| game_state.entities.get_mut(t as usize))
+        {
+            player.health = (player.health - damage).max(0.0);
+        }
+    }
+    let hunting = game_state
+        .entities
+        .iter()
+        .filter(|entity| matches!(entity, Some(Entity::Monster(m)) if m.target.is_some()))
+        .count();
+    game_state.combat_intensity = (hunting as f32 / MONSTERS_FOR_FULL_INTENSITY).min(1.0);
+}
 
-fn render_system(game_state: &mut GameState) {
-    // create_vulkan_instance()
-    create_vulkan_instance()
+/// True unless level geometry blocks the line between two entities standing at `from` and `to`
+fn line_of_sight(physics: &PhysicsWorld, from: Vector3<f32>, to: Vector3<f32>) -> bool {
+    let eye = from + Vector3::unit_y() * EYE_HEIGHT;
+    let offset = to - from;
+    let distance = offset.magnitude();
+    if distance < 1e-6 {
+        return true;
+    }
+    let filter = QueryFilter::new().with_mask(LAYER_WORLD);
+    physics
+        .cast_ray(&Ray::new(eye, offset), distance, &filter)
+        .is_none()
 }
 
-fn audio_system(game_state: &mut GameState) {}
+fn spawner_system(game_state: &mut GameState, dt: f32) {
+    let entities = &game_state.entities;
+    let transforms = &game_state.transforms;
+    game_state.spawner.retain_alive(|index| {
+        matches!(entities.get(index as usize), Some(Some(Entity::Monster(_))))
+    });
+
+    let players: Vec<PlayerView> = game_state
+        .players
+        .iter()
+        .filter_map(
+            |&index| match (&entities[index as usize], &transforms[index as usize]) {
+                (Some(Entity::Player(player)), Some(transform)) => Some(PlayerView {
+                    position: transform.position,
+                    health_fraction: player.health / player.max_health,
+                }),
+                _ => None,
+            },
+        )
+        .collect();
 
-fn wait_vsync() {}
+    let physics = &game_state.physics;
+    let terrain = &game_state.terrain;
+    let visible = |eye: Vector3<f32>, target: Vector3<f32>| line_of_sight(physics, eye, target);
+    let ground = |x: f32, z: f32| terrain.height_at(x, z);
+    let requests = game_state.spawner.update(dt, &players, &visible, &ground);
+
+    for request in requests {
+        let monster = Monster {
+            archetype: request.archetype,
+            target: None,
+        };
+        let index = game_state.spawn(
+            Entity::Monster(monster),
+            Transform::from_position(request.position),
+        );
+        game_state.spawner.track(index);
+    }
+}
+
+fn physics_system(game_state: &mut GameState, dt: f32) {
+    let steps = game_state.physics.update(dt);
+    game_state.collision_events = game_state.physics.take_events();
+    if steps == 0 {
+        return;
+    }
+
+    for (_, body) in game_state.physics.bodies() {
+        let entity = match body.entity {
+            Some(entity) => entity as usize,
+            None => continue,
+        };
+        if let Some(Some(transform)) = game_state.transforms.get_mut(entity) {
+            transform.position = body.position;
+            transform.rotation = body.rotation;
+        }
+    }
+}
+
+fn render_system(game_state: &GameState, renderer: &mut Renderer) {
+    // A dropped frame is not worth stopping the game for, the next one tries again
+    let _ = renderer.render_frame(game_state);
+}
+
+fn audio_system(game_state: &mut GameState, dt: f32) {
+    // Hear the world from the first player's eyes
+    let listener = game_state
+        .players
+        .first()
+        .and_then(|&index| game_state.transforms[index as usize]);
+    if let Some(transform) = listener {
+        let eye = transform.position + Vector3::unit_y() * EYE_HEIGHT;
+        game_state.audio.set_listener(eye, transform.rotation);
+    }
+    impact_sounds(game_state);
+    let transforms = &game_state.transforms;
+    let physics = &game_state.physics;
+    let materials = &game_state.acoustic_materials;
+    game_state.audio.update_spatial(
+        dt,
+        |entity| {
+            transforms
+                .get(entity as usize)
+                .and_then(|transform| transform.as_ref())
+                .map(|transform| (transform.position, transform.rotation))
+        },
+        |source, listener| occlusion(physics, materials, source, listener),
+    );
+    game_state
+        .audio
+        .set_music_intensity(game_state.combat_intensity);
+    // A lost device is retried in the background, the game carries on silent meanwhile
+    let _ = game_state.audio_output.update(dt);
+    game_state.audio.update();
+}
+
+/// Plays the impact sound where bodies started touching during the last physics update, louder
+/// the harder they hit
+fn impact_sounds(game_state: &mut GameState) {
+    let physics = &game_state.physics;
+    for event in &game_state.collision_events {
+        if event.kind != CollisionEventKind::ContactBegin || event.impulse < QUIET_IMPACT_IMPULSE {
+            continue;
+        }
+        // The body that moved into the other, static ground sits wherever it was built from
+        let position = [event.collider_a, event.collider_b]
+            .iter()
+            .filter_map(|&collider| physics.collider(collider))
+            .filter_map(|collider| physics.body(collider.body))
+            .find(|body| body.is_dynamic())
+            .map(|body| body.position);
+        if let Some(position) = position {
+            let params = PlayParams {
+                volume: (event.impulse / LOUD_IMPACT_IMPULSE).min(1.0),
+                ..PlayParams::default()
+            };
+            game_state.audio.play_spatial(
+                &game_state.impact_sound,
+                Emitter::point(position),
+                params,
+            );
+        }
+    }
+}
+
+/// What the level geometry between a sound at `source` and the listener does to it, every
+/// surface the sound passes through adds its material
+fn occlusion(
+    physics: &PhysicsWorld,
+    materials: &HashMap<ColliderHandle, AcousticMaterial>,
+    source: Vector3<f32>,
+    listener: Vector3<f32>,
+) -> Occlusion {
+    let from = source + Vector3::unit_y() * OCCLUSION_RAY_LIFT;
+    let offset = listener - from;
+    let distance = offset.magnitude();
+    if distance < 1e-6 {
+        return Occlusion::CLEAR;
+    }
+    let filter = QueryFilter::new().with_mask(LAYER_WORLD);
+    let hits = physics.cast_ray_all(&Ray::new(from, offset), distance, &filter);
+    let stone = AcousticMaterial::default();
+    Occlusion::through(
+        hits.iter()
+            .map(|hit| materials.get(&hit.collider).unwrap_or(&stone)),
+    )
+}
+
+/// Keys held down, kept up to date from the window's keyboard events
+#[derive(Default)]
+struct Keyboard {
+    /// In the order they went down
+    held: Vec<VirtualKeyCode>,
+}
+
+impl Keyboard {
+    fn handle(&mut self, input: &KeyboardInput) {
+        let key = match input.virtual_keycode {
+            Some(key) => key,
+            None => return,
+        };
+        self.held.retain(|&held| held != key);
+        if input.state == ElementState::Pressed {
+            self.held.push(key);
+        }
+    }
+}
 
-fn capture_input_state() -> Input {
-    return Input::DOWN;
+/// The arrow or WASD key pressed last among those still held
+fn capture_input_state(keyboard: &Keyboard) -> Input {
+    keyboard
+        .held
+        .iter()
+        .rev()
+        .find_map(|key| match key {
+            VirtualKeyCode::W | VirtualKeyCode::Up => Some(Input::UP),
+            VirtualKeyCode::S | VirtualKeyCode::Down => Some(Input::DOWN),
+            VirtualKeyCode::A | VirtualKeyCode::Left => Some(Input::LEFT),
+            VirtualKeyCode::D | VirtualKeyCode::Right => Some(Input::RIGHT),
+            _ => None,
+        })
+        .unwrap_or(Input::NONE)
 }
@@ -0,0 +1,230 @@
+use std::convert::TryFrom;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+const FRAC_BITS: u32 = 32;
+
+/// Signed 32.32 fixed point number. Every operation is plain integer maths, so it comes out bit
+/// for bit the same on every machine. Adding and subtracting past the range panics in debug
+/// builds like any integer would, multiplying and dividing past it always panics
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fixed(i64);
+
+impl Fixed {
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(1 << FRAC_BITS);
+    pub const PI: Fixed = Fixed(13_493_037_705);
+    pub const FRAC_PI_2: Fixed = Fixed(6_746_518_852);
+    pub const TWO_PI: Fixed = Fixed(26_986_075_409);
+
+    pub const fn from_int(value: i32) -> Fixed {
+        Fixed((value as i64) << FRAC_BITS)
+    }
+
+    /// Rounds to the nearest fixed point number. Only the conversion has to match between peers,
+    /// so convert inputs once and keep them fixed from then on
+    pub fn from_f32(value: f32) -> Fixed {
+        Fixed((f64::from(value) * (1u64 << FRAC_BITS) as f64).round() as i64)
+    }
+
+    pub fn to_f32(self) -> f32 {
+        (self.0 as f64 / (1u64 << FRAC_BITS) as f64) as f32
+    }
+
+    pub fn abs(self) -> Fixed {
+        Fixed(self.0.abs())
+    }
+
+    /// Zero for negative numbers
+    pub fn sqrt(self) -> Fixed {
+        if self.0 <= 0 {
+            return Fixed::ZERO;
+        }
+        let value = (self.0 as u128) << FRAC_BITS;
+        // Newton's method on integers, starting above the root so it only ever comes down
+        let mut root = 1u128 << (64 - value.leading_zeros() / 2);
+        loop {
+            let next = (root + value / root) / 2;
+            if next >= root {
+                return Fixed(root as i64);
+            }
+            root = next;
+        }
+    }
+
+    pub fn sin(self) -> Fixed {
+        // Bring the angle into -pi/2..pi/2, where the series converges quickly
+        let mut x = Fixed(self.0 % Fixed::TWO_PI.0);
+        if x > Fixed::PI {
+            x -= Fixed::TWO_PI;
+        } else if x < -Fixed::PI {
+            x += Fixed::TWO_PI;
+        }
+        if x > Fixed::FRAC_PI_2 {
+            x = Fixed::PI - x;
+        } else if x < -Fixed::FRAC_PI_2 {
+            x = -Fixed::PI - x;
+        }
+
+        let x2 = x * x;
+        let mut term = x;
+        let mut sum = x;
+        for n in 1..7 {
+            term = -term * x2 / Fixed::from_int((2 * n) * (2 * n + 1));
+            sum += term;
+        }
+        sum
+    }
+
+    pub fn cos(self) -> Fixed {
+        (self + Fixed::FRAC_PI_2).sin()
+    }
+
+    /// Angle of the point (x, y) from the x axis, between -pi and pi
+    pub fn atan2(self, x: Fixed) -> Fixed {
+        let y = self;
+        if x == Fixed::ZERO && y == Fixed::ZERO {
+            return Fixed::ZERO;
+        }
+        // atan of the smaller over the larger stays within 0..1, the rest follows from symmetry
+        let (ay, ax) = (y.abs(), x.abs());
+        let mut angle = if ay <= ax {
+            atan_unit(ay / ax)
+        } else {
+            Fixed::FRAC_PI_2 - atan_unit(ax / ay)
+        };
+        if x < Fixed::ZERO {
+            angle = Fixed::PI - angle;
+        }
+        if y < Fixed::ZERO {
+            -angle
+        } else {
+            angle
+        }
+    }
+}
+
+/// atan for 0..1. Halving the angle twice brings it under tan(pi/16), where a short series is
+/// accurate to the last few bits
+fn atan_unit(z: Fixed) -> Fixed {
+    let mut z = z;
+    for _ in 0..2 {
+        z = z / (Fixed::ONE + (Fixed::ONE + z * z).sqrt());
+    }
+    let z2 = z * z;
+    let mut power = z;
+    let mut sum = z;
+    for n in 1..8 {
+        power = -power * z2;
+        sum += power / Fixed::from_int(2 * n + 1);
+    }
+    sum * Fixed::from_int(4)
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+    fn add(self, other: Fixed) -> Fixed {
+        Fixed(self.0 + other.0)
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+    fn sub(self, other: Fixed) -> Fixed {
+        Fixed(self.0 - other.0)
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+    fn mul(self, other: Fixed) -> Fixed {
+        let product = (self.0 as i128 * other.0 as i128) >> FRAC_BITS;
+        Fixed(i64::try_from(product).expect("fixed point multiply overflowed"))
+    }
+}
+
+/// Panics on division by zero
+impl Div for Fixed {
+    type Output = Fixed;
+    fn div(self, other: Fixed) -> Fixed {
+        let quotient = ((self.0 as i128) << FRAC_BITS) / other.0 as i128;
+        Fixed(i64::try_from(quotient).expect("fixed point divide overflowed"))
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+    fn neg(self) -> Fixed {
+        Fixed(-self.0)
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, other: Fixed) {
+        *self = *self + other;
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, other: Fixed) {
+        *self = *self - other;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Worst difference from the double precision result over a sweep of -20..20
+    fn worst_error<F, G>(fixed: F, float: G) -> f64
+    where
+        F: Fn(Fixed) -> Fixed,
+        G: Fn(f64) -> f64,
+    {
+        (-2000..2000)
+            .map(|i| {
+                let x = i as f32 * 0.01;
+                (fixed(Fixed::from_f32(x)).to_f32() as f64 - float(x as f64)).abs()
+            })
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn trig_is_close_to_the_float_versions() {
+        assert!(worst_error(Fixed::sin, f64::sin) < 1e-6);
+        assert!(worst_error(Fixed::cos, f64::cos) < 1e-6);
+        let y = Fixed::from_f32(-1.3);
+        assert!(worst_error(|x| y.atan2(x), |x| (-1.3f32 as f64).atan2(x)) < 1e-6);
+        assert!(worst_error(|x| x.atan2(y), |x| x.atan2(-1.3f32 as f64)) < 1e-6);
+        assert_eq!(Fixed::ZERO.atan2(Fixed::ZERO), Fixed::ZERO);
+    }
+
+    #[test]
+    fn sqrt_is_close_to_the_float_version() {
+        assert!(worst_error(Fixed::sqrt, |x| x.max(0.0).sqrt()) < 1e-6);
+        assert_eq!(Fixed::from_int(1_000_000).sqrt(), Fixed::from_int(1000));
+    }
+
+    #[test]
+    fn products_in_range_are_exact() {
+        let big = Fixed::from_int(40_000);
+        assert_eq!(big * big, Fixed::from_int(1_600_000_000));
+        assert_eq!(-big * big, Fixed::from_int(-1_600_000_000));
+        assert_eq!(
+            Fixed::from_int(-7) / Fixed::from_int(2),
+            Fixed::from_f32(-3.5)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "fixed point multiply overflowed")]
+    fn multiplying_out_of_range_panics() {
+        let big = Fixed::from_int(70_000);
+        let _ = big * big;
+    }
+
+    #[test]
+    #[should_panic(expected = "fixed point divide overflowed")]
+    fn dividing_out_of_range_panics() {
+        let _ = Fixed::from_int(1_000_000) / Fixed::from_f32(1e-4);
+    }
+}
@@ -0,0 +1,92 @@
+// Adding, multiplying, dividing and square roots of floats round the same everywhere, but sin,
+// cos and friends come from the platform's maths library and may differ in the last bit. With
+// the `deterministic` feature simulation code gets them from `Fixed` instead, so lockstep peers
+// on different machines stay in step. Physics and gameplay call these rather than the float
+// methods, or cgmath functions built on them
+
+use cgmath::{Quaternion, Vector3};
+
+#[cfg(feature = "deterministic")]
+mod fixed;
+
+#[cfg(feature = "deterministic")]
+use fixed::Fixed;
+
+#[cfg(feature = "deterministic")]
+pub fn sin(x: f32) -> f32 {
+    Fixed::from_f32(x).sin().to_f32()
+}
+
+#[cfg(not(feature = "deterministic"))]
+pub fn sin(x: f32) -> f32 {
+    x.sin()
+}
+
+#[cfg(feature = "deterministic")]
+pub fn cos(x: f32) -> f32 {
+    Fixed::from_f32(x).cos().to_f32()
+}
+
+#[cfg(not(feature = "deterministic"))]
+pub fn cos(x: f32) -> f32 {
+    x.cos()
+}
+
+#[cfg(feature = "deterministic")]
+pub fn atan2(y: f32, x: f32) -> f32 {
+    Fixed::from_f32(y).atan2(Fixed::from_f32(x)).to_f32()
+}
+
+#[cfg(not(feature = "deterministic"))]
+pub fn atan2(y: f32, x: f32) -> f32 {
+    y.atan2(x)
+}
+
+/// Turns `angle` radians about the unit vector `axis`, like `Quaternion::from_axis_angle`
+pub fn axis_angle(axis: Vector3<f32>, angle: f32) -> Quaternion<f32> {
+    let half = angle * 0.5;
+    Quaternion::from_sv(cos(half), axis * sin(half))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trig_matches_the_float_versions() {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-5;
+        // Halfway between steps, so atan2 never lands on its branch cut with a signed zero
+        for i in -200..200 {
+            let x = (i as f32 + 0.5) * 0.1;
+            assert!(close(sin(x), x.sin()), "sin({})", x);
+            assert!(close(cos(x), x.cos()), "cos({})", x);
+            assert!(close(atan2(x, 0.7), x.atan2(0.7)), "atan2({}, 0.7)", x);
+            assert!(
+                close(atan2(-x, -0.7), (-x).atan2(-0.7)),
+                "atan2({}, -0.7)",
+                -x
+            );
+        }
+    }
+
+    #[test]
+    fn axis_angle_matches_cgmath() {
+        use cgmath::{InnerSpace, Rad, Rotation3};
+        let axis = Vector3::new(1.0, -2.0, 0.5).normalize();
+        for &angle in [-3.0, -0.4, 0.0, 1.2, 2.9].iter() {
+            let ours = axis_angle(axis, angle);
+            let theirs = Quaternion::from_axis_angle(axis, Rad(angle));
+            assert!((ours - theirs).magnitude() < 1e-5, "{} radians", angle);
+        }
+    }
+
+    /// Worked out once, any machine giving other bits would fall out of step with its peers
+    #[cfg(feature = "deterministic")]
+    #[test]
+    fn trig_gives_the_same_bits_everywhere() {
+        assert_eq!(sin(0.5).to_bits(), 0x3ef5_7744);
+        assert_eq!(cos(-2.0).to_bits(), 0xbed5_1133);
+        assert_eq!(atan2(1.0, -3.0).to_bits(), 0x4034_784b);
+        assert_eq!(sin(100.0).to_bits(), 0xbf01_a12e);
+    }
+}
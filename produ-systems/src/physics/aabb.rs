@@ -0,0 +1,147 @@
+use cgmath::{Matrix3, Quaternion, Vector3};
+
+/// Axis aligned bounding box in world space
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Vector3<f32>, max: Vector3<f32>) -> Aabb {
+        Aabb { min, max }
+    }
+
+    pub fn from_center(center: Vector3<f32>, half_extents: Vector3<f32>) -> Aabb {
+        Aabb {
+            min: center - half_extents,
+            max: center + half_extents,
+        }
+    }
+
+    /// Bounds of a box with `half_extents` after rotating it and moving it to `position`
+    pub fn from_oriented_box(
+        position: Vector3<f32>,
+        rotation: Quaternion<f32>,
+        half_extents: Vector3<f32>,
+    ) -> Aabb {
+        let basis = Matrix3::from(rotation);
+        let extents = Vector3::new(
+            basis.x.x.abs() * half_extents.x
+                + basis.y.x.abs() * half_extents.y
+                + basis.z.x.abs() * half_extents.z,
+            basis.x.y.abs() * half_extents.x
+                + basis.y.y.abs() * half_extents.y
+                + basis.z.y.abs() * half_extents.z,
+            basis.x.z.abs() * half_extents.x
+                + basis.y.z.abs() * half_extents.y
+                + basis.z.z.abs() * half_extents.z,
+        );
+        Aabb::from_center(position, extents)
+    }
+
+    pub fn center(&self) -> Vector3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn half_extents(&self) -> Vector3<f32> {
+        (self.max - self.min) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    pub fn overlaps(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x
+            && self.max.x >= other.min.x
+            && self.min.y <= other.max.y
+            && self.max.y >= other.min.y
+            && self.min.z <= other.max.z
+            && self.max.z >= other.min.z
+    }
+
+    pub fn contains(&self, other: &Aabb) -> bool {
+        self.min.x <= other.min.x
+            && self.min.y <= other.min.y
+            && self.min.z <= other.min.z
+            && self.max.x >= other.max.x
+            && self.max.y >= other.max.y
+            && self.max.z >= other.max.z
+    }
+
+    pub fn contains_point(&self, point: Vector3<f32>) -> bool {
+        point.x >= self.min.x
+            && point.y >= self.min.y
+            && point.z >= self.min.z
+            && point.x <= self.max.x
+            && point.y <= self.max.y
+            && point.z <= self.max.z
+    }
+
+    /// How far along the ray it enters the box, `0.0` if it starts inside.
+    /// Returns `None` if it misses or only gets there after `max_distance`
+    pub fn ray_distance(
+        &self,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        max_distance: f32,
+    ) -> Option<f32> {
+        let mut near = 0.0f32;
+        let mut far = max_distance;
+        for axis in 0..3 {
+            if direction[axis].abs() < 1e-12 {
+                if origin[axis] < self.min[axis] || origin[axis] > self.max[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let inv = 1.0 / direction[axis];
+            let t0 = (self.min[axis] - origin[axis]) * inv;
+            let t1 = (self.max[axis] - origin[axis]) * inv;
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+            if near > far {
+                return None;
+            }
+        }
+        Some(near)
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Vector3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            max: Vector3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        }
+    }
+
+    pub fn expanded(&self, margin: f32) -> Aabb {
+        let margin = Vector3::new(margin, margin, margin);
+        Aabb {
+            min: self.min - margin,
+            max: self.max + margin,
+        }
+    }
+
+    /// Grows the box in the direction it is moving, so it stays valid for a few steps
+    pub fn swept(&self, displacement: Vector3<f32>) -> Aabb {
+        let mut aabb = *self;
+        for axis in 0..3 {
+            if displacement[axis] < 0.0 {
+                aabb.min[axis] += displacement[axis];
+            } else {
+                aabb.max[axis] += displacement[axis];
+            }
+        }
+        aabb
+    }
+}
@@ -0,0 +1,385 @@
+use super::shape::Isometry;
+use crate::EntityIndex;
+use cgmath::{InnerSpace, Matrix, Matrix3, One, Quaternion, SquareMatrix, Vector3, Zero};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct BodyHandle(pub(crate) usize);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BodyType {
+    /// Moved by forces, gravity and collisions
+    Dynamic,
+    /// Moved only by its velocity, which gameplay sets, pushes dynamic bodies but is never pushed back
+    Kinematic,
+    /// Never moves
+    Static,
+}
+
+/// Mass and inertia tensor of a body, the tensor is in body space
+#[derive(Debug, Copy, Clone)]
+pub struct MassProperties {
+    pub mass: f32,
+    pub inertia: Matrix3<f32>,
+}
+
+impl MassProperties {
+    pub fn sphere(mass: f32, radius: f32) -> MassProperties {
+        let i = 0.4 * mass * radius * radius;
+        MassProperties {
+            mass,
+            inertia: Matrix3::from_diagonal(Vector3::new(i, i, i)),
+        }
+    }
+
+    pub fn cuboid(mass: f32, half_extents: Vector3<f32>) -> MassProperties {
+        let size = half_extents * 2.0;
+        let (x2, y2, z2) = (size.x * size.x, size.y * size.y, size.z * size.z);
+        MassProperties {
+            mass,
+            inertia: Matrix3::from_diagonal(Vector3::new(
+                mass * (y2 + z2) / 12.0,
+                mass * (x2 + z2) / 12.0,
+                mass * (x2 + y2) / 12.0,
+            )),
+        }
+    }
+
+    /// Capsule along the body's Y axis, `half_height` is the half length of the cylinder part
+    pub fn capsule(mass: f32, radius: f32, half_height: f32) -> MassProperties {
+        let r2 = radius * radius;
+        let height = half_height * 2.0;
+        let cylinder_volume = std::f32::consts::PI * r2 * height;
+        let sphere_volume = 4.0 / 3.0 * std::f32::consts::PI * r2 * radius;
+        let cylinder_mass = mass * cylinder_volume / (cylinder_volume + sphere_volume);
+        let sphere_mass = mass - cylinder_mass;
+
+        let axial = cylinder_mass * r2 / 2.0 + sphere_mass * 2.0 * r2 / 5.0;
+        let lateral = cylinder_mass * (r2 / 4.0 + height * height / 12.0)
+            + sphere_mass
+                * (2.0 * r2 / 5.0 + half_height * half_height + 3.0 * half_height * radius / 4.0);
+        MassProperties {
+            mass,
+            inertia: Matrix3::from_diagonal(Vector3::new(lateral, axial, lateral)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RigidBody {
+    /// The entity whose transform this body drives
+    pub entity: Option<EntityIndex>,
+    pub body_type: BodyType,
+    pub position: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub linear_velocity: Vector3<f32>,
+    pub angular_velocity: Vector3<f32>,
+    /// Fraction of velocity lost per second
+    pub linear_damping: f32,
+    pub angular_damping: f32,
+    pub gravity_scale: f32,
+    /// Sweeps the body along its motion every step so it can not tunnel through thin colliders.
+    /// Costs a shape cast per collider per step, meant for small fast things like projectiles
+    pub ccd: bool,
+    /// Lets the world put the body to sleep once it and everything touching it comes to rest
+    pub can_sleep: bool,
+    sleeping: bool,
+    /// How long the body has been moving slower than the sleep thresholds
+    pub(crate) sleep_time: f32,
+    inv_mass: f32,
+    inv_inertia_local: Matrix3<f32>,
+    force: Vector3<f32>,
+    torque: Vector3<f32>,
+}
+
+impl RigidBody {
+    pub fn new(body_type: BodyType, position: Vector3<f32>) -> RigidBody {
+        let mut body = RigidBody {
+            entity: None,
+            body_type,
+            position,
+            rotation: Quaternion::one(),
+            linear_velocity: Vector3::zero(),
+            angular_velocity: Vector3::zero(),
+            linear_damping: 0.01,
+            angular_damping: 0.05,
+            gravity_scale: 1.0,
+            ccd: false,
+            can_sleep: true,
+            sleeping: false,
+            sleep_time: 0.0,
+            inv_mass: 0.0,
+            inv_inertia_local: Matrix3::zero(),
+            force: Vector3::zero(),
+            torque: Vector3::zero(),
+        };
+        if body_type == BodyType::Dynamic {
+            body.set_mass_properties(MassProperties::sphere(1.0, 0.5));
+        }
+        body
+    }
+
+    pub fn dynamic(position: Vector3<f32>, mass_properties: MassProperties) -> RigidBody {
+        let mut body = RigidBody::new(BodyType::Dynamic, position);
+        body.set_mass_properties(mass_properties);
+        body
+    }
+
+    pub fn kinematic(position: Vector3<f32>) -> RigidBody {
+        RigidBody::new(BodyType::Kinematic, position)
+    }
+
+    pub fn fixed(position: Vector3<f32>) -> RigidBody {
+        RigidBody::new(BodyType::Static, position)
+    }
+
+    pub fn with_entity(mut self, entity: EntityIndex) -> RigidBody {
+        self.entity = Some(entity);
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: Quaternion<f32>) -> RigidBody {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_ccd(mut self, ccd: bool) -> RigidBody {
+        self.ccd = ccd;
+        self
+    }
+
+    pub fn with_can_sleep(mut self, can_sleep: bool) -> RigidBody {
+        self.can_sleep = can_sleep;
+        self
+    }
+
+    /// Only dynamic bodies have mass, kinematic and static bodies act as if it were infinite
+    pub fn set_mass_properties(&mut self, mass_properties: MassProperties) {
+        if self.body_type != BodyType::Dynamic {
+            return;
+        }
+        self.inv_mass = if mass_properties.mass > 0.0 {
+            1.0 / mass_properties.mass
+        } else {
+            0.0
+        };
+        self.inv_inertia_local = mass_properties
+            .inertia
+            .invert()
+            .unwrap_or_else(Matrix3::zero);
+    }
+
+    pub fn set_body_type(&mut self, body_type: BodyType, mass_properties: MassProperties) {
+        self.body_type = body_type;
+        self.wake_up();
+        if body_type == BodyType::Dynamic {
+            self.set_mass_properties(mass_properties);
+        } else {
+            self.inv_mass = 0.0;
+            self.inv_inertia_local = Matrix3::zero();
+        }
+        if body_type == BodyType::Static {
+            self.linear_velocity = Vector3::zero();
+            self.angular_velocity = Vector3::zero();
+        }
+    }
+
+    pub fn is_dynamic(&self) -> bool {
+        self.body_type == BodyType::Dynamic
+    }
+
+    /// A sleeping body keeps still and costs nothing until a contact, joint or force wakes it.
+    /// Setting its velocity directly does not, call `wake_up` as well
+    pub fn is_sleeping(&self) -> bool {
+        self.sleeping
+    }
+
+    /// A dynamic body that is awake, the only kind the solver moves
+    pub fn is_active(&self) -> bool {
+        self.is_dynamic() && !self.sleeping
+    }
+
+    pub fn wake_up(&mut self) {
+        self.sleeping = false;
+        self.sleep_time = 0.0;
+    }
+
+    pub(crate) fn sleep(&mut self) {
+        self.sleeping = true;
+        self.linear_velocity = Vector3::zero();
+        self.angular_velocity = Vector3::zero();
+        self.clear_forces();
+    }
+
+    pub fn mass(&self) -> f32 {
+        if self.inv_mass > 0.0 {
+            1.0 / self.inv_mass
+        } else {
+            f32::INFINITY
+        }
+    }
+
+    pub fn inv_mass(&self) -> f32 {
+        self.inv_mass
+    }
+
+    /// Inverse inertia tensor rotated into world space
+    pub fn inv_inertia_world(&self) -> Matrix3<f32> {
+        let rotation = Matrix3::from(self.rotation);
+        rotation * self.inv_inertia_local * rotation.transpose()
+    }
+
+    pub fn isometry(&self) -> Isometry {
+        Isometry::new(self.position, self.rotation)
+    }
+
+    pub fn velocity_at_point(&self, point: Vector3<f32>) -> Vector3<f32> {
+        self.linear_velocity + self.angular_velocity.cross(point - self.position)
+    }
+
+    /// Transforms a point from body space into world space
+    pub fn to_world(&self, local_point: Vector3<f32>) -> Vector3<f32> {
+        self.position + self.rotation * local_point
+    }
+
+    /// Transforms a point from world space into body space
+    pub fn to_local(&self, world_point: Vector3<f32>) -> Vector3<f32> {
+        self.rotation.conjugate() * (world_point - self.position)
+    }
+
+    /// Applied over the next step, then cleared
+    pub fn apply_force(&mut self, force: Vector3<f32>) {
+        self.wake_up();
+        self.force += force;
+    }
+
+    pub fn apply_force_at_point(&mut self, force: Vector3<f32>, point: Vector3<f32>) {
+        self.wake_up();
+        self.force += force;
+        self.torque += (point - self.position).cross(force);
+    }
+
+    pub fn apply_torque(&mut self, torque: Vector3<f32>) {
+        self.wake_up();
+        self.torque += torque;
+    }
+
+    /// Changes the velocity right away
+    pub fn apply_impulse(&mut self, impulse: Vector3<f32>) {
+        self.wake_up();
+        self.linear_velocity += impulse * self.inv_mass;
+    }
+
+    pub fn apply_impulse_at_point(&mut self, impulse: Vector3<f32>, point: Vector3<f32>) {
+        self.wake_up();
+        self.linear_velocity += impulse * self.inv_mass;
+        self.angular_velocity += self.inv_inertia_world() * (point - self.position).cross(impulse);
+    }
+
+    pub fn apply_angular_impulse(&mut self, impulse: Vector3<f32>) {
+        self.wake_up();
+        self.angular_velocity += self.inv_inertia_world() * impulse;
+    }
+
+    pub fn clear_forces(&mut self) {
+        self.force = Vector3::zero();
+        self.torque = Vector3::zero();
+    }
+
+    /// First half of the semi-implicit euler step, forces and gravity change the velocity
+    pub(crate) fn integrate_velocity(&mut self, gravity: Vector3<f32>, dt: f32) {
+        if !self.is_active() {
+            return;
+        }
+        let acceleration = self.force * self.inv_mass + gravity * self.gravity_scale;
+        self.linear_velocity += acceleration * dt;
+        self.angular_velocity += self.inv_inertia_world() * self.torque * dt;
+
+        self.linear_velocity *= 1.0 / (1.0 + dt * self.linear_damping);
+        self.angular_velocity *= 1.0 / (1.0 + dt * self.angular_damping);
+    }
+
+    /// Second half of the step, the new velocity moves the body. `pseudo` is the solver's split
+    /// impulse velocity, which moves the body this step without being kept
+    pub(crate) fn integrate_position(
+        &mut self,
+        dt: f32,
+        pseudo: Option<(Vector3<f32>, Vector3<f32>)>,
+    ) {
+        if self.body_type == BodyType::Static || self.sleeping {
+            return;
+        }
+        let (pseudo_linear, pseudo_angular) = pseudo.unwrap_or((Vector3::zero(), Vector3::zero()));
+        self.position += (self.linear_velocity + pseudo_linear) * dt;
+
+        let w = self.angular_velocity + pseudo_angular;
+        let spin = Quaternion::new(0.0, w.x, w.y, w.z) * self.rotation * (0.5 * dt);
+        self.rotation = (self.rotation + spin).normalize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capsule_inertia_matches_known_values() {
+        let diagonal = |m: MassProperties| [m.inertia.x.x, m.inertia.y.y, m.inertia.z.z];
+        let close = |got: [f32; 3], want: [f32; 3]| {
+            for (g, w) in got.iter().zip(want.iter()) {
+                assert!((g - w).abs() < 1e-4, "{:?} vs {:?}", got, want);
+            }
+        };
+
+        // With no cylinder part a capsule is a solid sphere, 2/5 m r^2 about every axis
+        close(
+            diagonal(MassProperties::capsule(5.0, 0.5, 0.0)),
+            [0.5, 0.5, 0.5],
+        );
+
+        // Worked by hand for m = 3, r = 0.4, half height 0.9: the cylinder takes 0.288/0.3733
+        // of the mass and each hemisphere sits 0.9 + 3r/8 from the centre
+        close(
+            diagonal(MassProperties::capsule(3.0, 0.4, 0.9)),
+            [1.501_886, 0.229_029, 1.501_886],
+        );
+    }
+
+    #[test]
+    fn capsule_inertia_matches_integrating_its_volume() {
+        let (mass, radius, half_height) = (2.0f32, 0.5f32, 0.75f32);
+        let capsule = MassProperties::capsule(mass, radius, half_height);
+
+        // Point masses on a grid filling the capsule
+        let steps = 120;
+        let extent = [radius, half_height + radius, radius];
+        let mut count = 0u32;
+        let mut sum = Vector3::new(0.0f64, 0.0, 0.0);
+        for i in 0..steps {
+            for j in 0..steps {
+                for k in 0..steps {
+                    let at = |n: u32, e: f32| ((n as f32 + 0.5) / steps as f32 * 2.0 - 1.0) * e;
+                    let p = Vector3::new(at(i, extent[0]), at(j, extent[1]), at(k, extent[2]));
+                    let axis_y = p.y.max(-half_height).min(half_height);
+                    if (p - Vector3::new(0.0, axis_y, 0.0)).magnitude2() > radius * radius {
+                        continue;
+                    }
+                    let (x2, y2, z2) = ((p.x * p.x) as f64, (p.y * p.y) as f64, (p.z * p.z) as f64);
+                    sum += Vector3::new(y2 + z2, x2 + z2, x2 + y2);
+                    count += 1;
+                }
+            }
+        }
+        let integrated = sum * (mass as f64 / count as f64);
+
+        let diagonal = [
+            capsule.inertia.x.x,
+            capsule.inertia.y.y,
+            capsule.inertia.z.z,
+        ];
+        let expected = [integrated.x, integrated.y, integrated.z];
+        for (&got, &want) in diagonal.iter().zip(expected.iter()) {
+            let error = (got as f64 - want).abs() / want;
+            assert!(error < 0.01, "{:?} vs {:?}", diagonal, expected);
+        }
+    }
+}
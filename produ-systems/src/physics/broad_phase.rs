@@ -0,0 +1,610 @@
+use super::aabb::Aabb;
+use cgmath::Vector3;
+use std::collections::BTreeSet;
+
+const NULL_NODE: usize = usize::MAX;
+
+/// Fat boxes are this much bigger than the real bounds, so small movements do not touch the tree
+const AABB_MARGIN: f32 = 0.1;
+
+/// How many steps of movement a fat box is stretched to cover
+const DISPLACEMENT_MULTIPLIER: f32 = 4.0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ProxyId(usize);
+
+#[derive(Debug, Clone)]
+struct Node<T> {
+    aabb: Aabb,
+    parent: usize,
+    child1: usize,
+    child2: usize,
+    /// Leaves are 0, free nodes are -1
+    height: i32,
+    data: Option<T>,
+}
+
+impl<T> Node<T> {
+    fn is_leaf(&self) -> bool {
+        self.child1 == NULL_NODE
+    }
+}
+
+/// Bounding volume hierarchy of fat AABBs, kept balanced with tree rotations as leaves come and go.
+/// This follows the dynamic tree from Box2D
+#[derive(Debug, Clone)]
+pub struct DynamicTree<T> {
+    nodes: Vec<Node<T>>,
+    root: usize,
+    free: Vec<usize>,
+}
+
+impl<T: Copy> Default for DynamicTree<T> {
+    fn default() -> Self {
+        DynamicTree::new()
+    }
+}
+
+impl<T: Copy> DynamicTree<T> {
+    pub fn new() -> DynamicTree<T> {
+        DynamicTree {
+            nodes: vec![],
+            root: NULL_NODE,
+            free: vec![],
+        }
+    }
+
+    pub fn create_proxy(&mut self, aabb: Aabb, data: T) -> ProxyId {
+        let id = self.allocate_node();
+        self.nodes[id].aabb = aabb.expanded(AABB_MARGIN);
+        self.nodes[id].data = Some(data);
+        self.nodes[id].height = 0;
+        self.insert_leaf(id);
+        ProxyId(id)
+    }
+
+    pub fn destroy_proxy(&mut self, proxy: ProxyId) -> Option<T> {
+        let data = self.nodes[proxy.0].data;
+        self.remove_leaf(proxy.0);
+        self.free_node(proxy.0);
+        data
+    }
+
+    /// Refits the proxy if `aabb` has left its fat box, returns whether it had to
+    pub fn move_proxy(&mut self, proxy: ProxyId, aabb: Aabb, displacement: Vector3<f32>) -> bool {
+        if self.nodes[proxy.0].aabb.contains(&aabb) {
+            return false;
+        }
+
+        self.remove_leaf(proxy.0);
+        self.nodes[proxy.0].aabb = aabb
+            .expanded(AABB_MARGIN)
+            .swept(displacement * DISPLACEMENT_MULTIPLIER);
+        self.insert_leaf(proxy.0);
+        true
+    }
+
+    pub fn fat_aabb(&self, proxy: ProxyId) -> Aabb {
+        self.nodes[proxy.0].aabb
+    }
+
+    pub fn data(&self, proxy: ProxyId) -> Option<T> {
+        self.nodes[proxy.0].data
+    }
+
+    pub fn height(&self) -> i32 {
+        if self.root == NULL_NODE {
+            0
+        } else {
+            self.nodes[self.root].height
+        }
+    }
+
+    /// Calls `callback` for each proxy whose fat box overlaps `aabb`, until it returns false
+    pub fn query<F>(&self, aabb: &Aabb, mut callback: F)
+    where
+        F: FnMut(ProxyId) -> bool,
+    {
+        if self.root == NULL_NODE {
+            return;
+        }
+        let mut stack = vec![self.root];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !node.aabb.overlaps(aabb) {
+                continue;
+            }
+            if node.is_leaf() {
+                if !callback(ProxyId(index)) {
+                    return;
+                }
+            } else {
+                stack.push(node.child1);
+                stack.push(node.child2);
+            }
+        }
+    }
+
+    /// Calls `callback` with each proxy whose fat box, grown by `extents`, the ray enters before
+    /// `max_distance`. The callback gets the distance to the box and returns the new limit,
+    /// so a query after the closest hit can shrink the search as it goes
+    pub fn query_ray<F>(
+        &self,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        max_distance: f32,
+        extents: Vector3<f32>,
+        mut callback: F,
+    ) where
+        F: FnMut(ProxyId, f32) -> f32,
+    {
+        if self.root == NULL_NODE {
+            return;
+        }
+        let mut max_distance = max_distance;
+        let mut stack = vec![self.root];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let aabb = Aabb::new(node.aabb.min - extents, node.aabb.max + extents);
+            let distance = match aabb.ray_distance(origin, direction, max_distance) {
+                Some(distance) => distance,
+                None => continue,
+            };
+            if node.is_leaf() {
+                max_distance = callback(ProxyId(index), distance);
+                if max_distance < 0.0 {
+                    return;
+                }
+            } else {
+                stack.push(node.child1);
+                stack.push(node.child2);
+            }
+        }
+    }
+
+    fn allocate_node(&mut self) -> usize {
+        let node = Node {
+            aabb: Aabb::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 0.0)),
+            parent: NULL_NODE,
+            child1: NULL_NODE,
+            child2: NULL_NODE,
+            height: 0,
+            data: None,
+        };
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn free_node(&mut self, index: usize) {
+        self.nodes[index].height = -1;
+        self.nodes[index].data = None;
+        self.free.push(index);
+    }
+
+    fn insert_leaf(&mut self, leaf: usize) {
+        if self.root == NULL_NODE {
+            self.root = leaf;
+            self.nodes[leaf].parent = NULL_NODE;
+            return;
+        }
+
+        // Walk down to the sibling that grows the total surface area the least
+        let leaf_aabb = self.nodes[leaf].aabb;
+        let mut index = self.root;
+        while !self.nodes[index].is_leaf() {
+            let node = &self.nodes[index];
+            let area = node.aabb.surface_area();
+            let combined_area = node.aabb.union(&leaf_aabb).surface_area();
+
+            // Cost of making a new parent for this node and the leaf
+            let cost = 2.0 * combined_area;
+            // Minimum cost of pushing the leaf further down the tree
+            let inheritance_cost = 2.0 * (combined_area - area);
+
+            let child_cost = |child: usize| {
+                let child = &self.nodes[child];
+                let grown = child.aabb.union(&leaf_aabb).surface_area();
+                if child.is_leaf() {
+                    grown + inheritance_cost
+                } else {
+                    grown - child.aabb.surface_area() + inheritance_cost
+                }
+            };
+            let cost1 = child_cost(node.child1);
+            let cost2 = child_cost(node.child2);
+
+            if cost < cost1 && cost < cost2 {
+                break;
+            }
+            index = if cost1 < cost2 {
+                node.child1
+            } else {
+                node.child2
+            };
+        }
+        let sibling = index;
+
+        let old_parent = self.nodes[sibling].parent;
+        let new_parent = self.allocate_node();
+        self.nodes[new_parent].parent = old_parent;
+        self.nodes[new_parent].aabb = leaf_aabb.union(&self.nodes[sibling].aabb);
+        self.nodes[new_parent].height = self.nodes[sibling].height + 1;
+        self.nodes[new_parent].child1 = sibling;
+        self.nodes[new_parent].child2 = leaf;
+        self.nodes[sibling].parent = new_parent;
+        self.nodes[leaf].parent = new_parent;
+
+        if old_parent == NULL_NODE {
+            self.root = new_parent;
+        } else if self.nodes[old_parent].child1 == sibling {
+            self.nodes[old_parent].child1 = new_parent;
+        } else {
+            self.nodes[old_parent].child2 = new_parent;
+        }
+
+        self.refit_from(self.nodes[leaf].parent);
+    }
+
+    fn remove_leaf(&mut self, leaf: usize) {
+        if leaf == self.root {
+            self.root = NULL_NODE;
+            return;
+        }
+
+        let parent = self.nodes[leaf].parent;
+        let grand_parent = self.nodes[parent].parent;
+        let sibling = if self.nodes[parent].child1 == leaf {
+            self.nodes[parent].child2
+        } else {
+            self.nodes[parent].child1
+        };
+
+        if grand_parent == NULL_NODE {
+            self.root = sibling;
+            self.nodes[sibling].parent = NULL_NODE;
+            self.free_node(parent);
+            return;
+        }
+
+        if self.nodes[grand_parent].child1 == parent {
+            self.nodes[grand_parent].child1 = sibling;
+        } else {
+            self.nodes[grand_parent].child2 = sibling;
+        }
+        self.nodes[sibling].parent = grand_parent;
+        self.free_node(parent);
+
+        self.refit_from(grand_parent);
+    }
+
+    /// Rebalances and refits every node from `index` up to the root
+    fn refit_from(&mut self, mut index: usize) {
+        while index != NULL_NODE {
+            index = self.balance(index);
+
+            let child1 = self.nodes[index].child1;
+            let child2 = self.nodes[index].child2;
+            self.nodes[index].height = 1 + self.nodes[child1].height.max(self.nodes[child2].height);
+            self.nodes[index].aabb = self.nodes[child1].aabb.union(&self.nodes[child2].aabb);
+
+            index = self.nodes[index].parent;
+        }
+    }
+
+    /// Rotates the taller child of `a` up if the tree is leaning, returns the new root of the subtree
+    fn balance(&mut self, a: usize) -> usize {
+        if self.nodes[a].is_leaf() || self.nodes[a].height < 2 {
+            return a;
+        }
+
+        let b = self.nodes[a].child1;
+        let c = self.nodes[a].child2;
+        let balance = self.nodes[c].height - self.nodes[b].height;
+
+        if balance > 1 {
+            self.rotate_up(a, c, b, false)
+        } else if balance < -1 {
+            self.rotate_up(a, b, c, true)
+        } else {
+            a
+        }
+    }
+
+    /// Makes `up` the parent of `a`, `a` keeps `other` and the shorter child of `up`.
+    /// `up_is_child1` says which slot of `a` the `up` node came from
+    fn rotate_up(&mut self, a: usize, up: usize, other: usize, up_is_child1: bool) -> usize {
+        let f = self.nodes[up].child1;
+        let g = self.nodes[up].child2;
+
+        self.nodes[up].child1 = a;
+        self.nodes[up].parent = self.nodes[a].parent;
+        self.nodes[a].parent = up;
+
+        let up_parent = self.nodes[up].parent;
+        if up_parent == NULL_NODE {
+            self.root = up;
+        } else if self.nodes[up_parent].child1 == a {
+            self.nodes[up_parent].child1 = up;
+        } else {
+            self.nodes[up_parent].child2 = up;
+        }
+
+        let (taller, shorter) = if self.nodes[f].height > self.nodes[g].height {
+            (f, g)
+        } else {
+            (g, f)
+        };
+        self.nodes[up].child2 = taller;
+        if up_is_child1 {
+            self.nodes[a].child1 = shorter;
+        } else {
+            self.nodes[a].child2 = shorter;
+        }
+        self.nodes[shorter].parent = a;
+
+        self.nodes[a].aabb = self.nodes[other].aabb.union(&self.nodes[shorter].aabb);
+        self.nodes[up].aabb = self.nodes[a].aabb.union(&self.nodes[taller].aabb);
+        self.nodes[a].height = 1 + self.nodes[other].height.max(self.nodes[shorter].height);
+        self.nodes[up].height = 1 + self.nodes[a].height.max(self.nodes[taller].height);
+        up
+    }
+}
+
+/// Keeps track of which proxies have fat boxes that overlap, only looking again at proxies that moved
+#[derive(Debug, Clone)]
+pub struct BroadPhase<T> {
+    tree: DynamicTree<T>,
+    moved: Vec<ProxyId>,
+    pairs: BTreeSet<(ProxyId, ProxyId)>,
+}
+
+impl<T: Copy> Default for BroadPhase<T> {
+    fn default() -> Self {
+        BroadPhase::new()
+    }
+}
+
+impl<T: Copy> BroadPhase<T> {
+    pub fn new() -> BroadPhase<T> {
+        BroadPhase {
+            tree: DynamicTree::new(),
+            moved: vec![],
+            pairs: BTreeSet::new(),
+        }
+    }
+
+    pub fn create_proxy(&mut self, aabb: Aabb, data: T) -> ProxyId {
+        let proxy = self.tree.create_proxy(aabb, data);
+        self.moved.push(proxy);
+        proxy
+    }
+
+    pub fn destroy_proxy(&mut self, proxy: ProxyId) -> Option<T> {
+        self.moved.retain(|&p| p != proxy);
+        self.pairs.retain(|&(a, b)| a != proxy && b != proxy);
+        self.tree.destroy_proxy(proxy)
+    }
+
+    pub fn move_proxy(&mut self, proxy: ProxyId, aabb: Aabb, displacement: Vector3<f32>) {
+        if self.tree.move_proxy(proxy, aabb, displacement) {
+            self.moved.push(proxy);
+        }
+    }
+
+    pub fn tree(&self) -> &DynamicTree<T> {
+        &self.tree
+    }
+
+    /// Finds pairs for everything that moved since the last call and drops pairs that separated
+    pub fn update_pairs(&mut self) {
+        let tree = &self.tree;
+        let pairs = &mut self.pairs;
+        for &proxy in &self.moved {
+            tree.query(&tree.fat_aabb(proxy), |other| {
+                if other != proxy {
+                    pairs.insert((proxy.min(other), proxy.max(other)));
+                }
+                true
+            });
+        }
+        self.moved.clear();
+
+        self.pairs
+            .retain(|&(a, b)| tree.fat_aabb(a).overlaps(&tree.fat_aabb(b)));
+    }
+
+    /// Candidate pairs in a stable order, the narrow phase decides if they really touch
+    pub fn pairs(&self) -> impl Iterator<Item = (T, T)> + '_ {
+        self.pairs.iter().filter_map(
+            move |&(a, b)| match (self.tree.data(a), self.tree.data(b)) {
+                (Some(a), Some(b)) => Some((a, b)),
+                _ => None,
+            },
+        )
+    }
+
+    pub fn pair_count(&self) -> usize {
+        self.pairs.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Repeatable pseudo random number in [0, 1)
+    fn noise(i: usize) -> f32 {
+        ((i as f32 * 12.9898).sin() * 43758.547).fract().abs()
+    }
+
+    fn random_box(i: usize) -> Aabb {
+        let center = Vector3::new(noise(3 * i), noise(3 * i + 1), noise(3 * i + 2)) * 20.0;
+        let half_extents = Vector3::new(0.2, 0.2, 0.2) + Vector3::new(1.0, 1.0, 1.0) * noise(i + 7);
+        Aabb::from_center(center, half_extents)
+    }
+
+    /// Every overlapping pair of live fat boxes, found by checking all of them against each other
+    fn brute_force(
+        broad_phase: &BroadPhase<usize>,
+        proxies: &[ProxyId],
+    ) -> BTreeSet<(usize, usize)> {
+        let tree = broad_phase.tree();
+        let mut pairs = BTreeSet::new();
+        for (i, &a) in proxies.iter().enumerate() {
+            for &b in &proxies[i + 1..] {
+                if tree.fat_aabb(a).overlaps(&tree.fat_aabb(b)) {
+                    let (a, b) = (tree.data(a).unwrap(), tree.data(b).unwrap());
+                    pairs.insert((a.min(b), a.max(b)));
+                }
+            }
+        }
+        pairs
+    }
+
+    fn found(broad_phase: &BroadPhase<usize>) -> BTreeSet<(usize, usize)> {
+        broad_phase
+            .pairs()
+            .map(|(a, b)| (a.min(b), a.max(b)))
+            .collect()
+    }
+
+    #[test]
+    fn pairs_match_brute_force() {
+        let mut broad_phase = BroadPhase::new();
+        let proxies: Vec<_> = (0..300)
+            .map(|i| broad_phase.create_proxy(random_box(i), i))
+            .collect();
+        broad_phase.update_pairs();
+
+        let expected = brute_force(&broad_phase, &proxies);
+        assert!(!expected.is_empty());
+        assert_eq!(found(&broad_phase), expected);
+
+        // A balanced tree of 300 leaves is far shorter than a list of them
+        assert!(
+            broad_phase.tree().height() < 20,
+            "{}",
+            broad_phase.tree().height()
+        );
+
+        let region = Aabb::new(Vector3::new(5.0, 5.0, 5.0), Vector3::new(12.0, 9.0, 15.0));
+        let mut hits = BTreeSet::new();
+        broad_phase.tree().query(&region, |proxy| {
+            hits.insert(proxy);
+            true
+        });
+        let overlapping: BTreeSet<_> = proxies
+            .iter()
+            .copied()
+            .filter(|&proxy| broad_phase.tree().fat_aabb(proxy).overlaps(&region))
+            .collect();
+        assert_eq!(hits, overlapping);
+    }
+
+    #[test]
+    fn moving_proxies_keeps_pairs_in_step() {
+        let mut broad_phase = BroadPhase::new();
+        let mut proxies: Vec<_> = (0..100)
+            .map(|i| broad_phase.create_proxy(random_box(i), i))
+            .collect();
+        broad_phase.update_pairs();
+
+        // Shuffle every box to a new place a few times, the pairs must follow
+        for round in 1..4 {
+            for (i, &proxy) in proxies.iter().enumerate() {
+                let aabb = random_box(i + 1000 * round);
+                let displacement = aabb.center() - broad_phase.tree().fat_aabb(proxy).center();
+                broad_phase.move_proxy(proxy, aabb, displacement * 0.01);
+            }
+            broad_phase.update_pairs();
+            assert_eq!(found(&broad_phase), brute_force(&broad_phase, &proxies));
+        }
+
+        // Wobbling inside the fat box leaves the tree alone
+        let first = proxies[0];
+        let fat = broad_phase.tree().fat_aabb(first);
+        let inside = Aabb::new(fat.min + Vector3::new(0.05, 0.05, 0.05), fat.max);
+        assert!(!broad_phase
+            .tree
+            .move_proxy(first, inside, Vector3::new(0.0, 0.0, 0.0)));
+        assert_eq!(broad_phase.tree().fat_aabb(first), fat);
+
+        // Two boxes teleported together make a pair, and lose it again when they part
+        let far = Vector3::new(100.0, 100.0, 100.0);
+        let still = Vector3::new(0.0, 0.0, 0.0);
+        let a = proxies.pop().unwrap();
+        let b = proxies.pop().unwrap();
+        let half = Vector3::new(0.5, 0.5, 0.5);
+        broad_phase.move_proxy(a, Aabb::from_center(far, half), still);
+        broad_phase.move_proxy(
+            b,
+            Aabb::from_center(far + Vector3::new(0.8, 0.0, 0.0), half),
+            still,
+        );
+        broad_phase.update_pairs();
+        assert!(found(&broad_phase).contains(&(98, 99)));
+
+        let further = far + Vector3::new(50.0, 0.0, 0.0);
+        broad_phase.move_proxy(b, Aabb::from_center(further, half), still);
+        broad_phase.update_pairs();
+        assert!(!found(&broad_phase).iter().any(|&(a, b)| a == 99 || b == 99));
+    }
+
+    #[test]
+    fn removed_proxies_leave_no_pairs_behind() {
+        let mut broad_phase = BroadPhase::new();
+        let mut proxies: Vec<_> = (0..200)
+            .map(|i| broad_phase.create_proxy(random_box(i), i))
+            .collect();
+        broad_phase.update_pairs();
+
+        let removed: Vec<_> = (0..proxies.len()).step_by(3).collect();
+        for &i in removed.iter().rev() {
+            let proxy = proxies.remove(i);
+            assert_eq!(broad_phase.destroy_proxy(proxy), Some(i));
+        }
+        broad_phase.update_pairs();
+        let pairs = found(&broad_phase);
+        assert!(!pairs
+            .iter()
+            .any(|(a, b)| removed.contains(a) || removed.contains(b)));
+        assert_eq!(pairs, brute_force(&broad_phase, &proxies));
+
+        let mut seen = 0;
+        broad_phase.tree().query(
+            &Aabb::new(
+                Vector3::new(-10.0, -10.0, -10.0),
+                Vector3::new(30.0, 30.0, 30.0),
+            ),
+            |_| {
+                seen += 1;
+                true
+            },
+        );
+        assert_eq!(seen, proxies.len());
+
+        // Freed nodes are reused, so new proxies do not grow the tree's storage
+        let nodes = broad_phase.tree().nodes.len();
+        for i in 0..removed.len() {
+            proxies.push(broad_phase.create_proxy(random_box(i + 5000), 1000 + i));
+        }
+        broad_phase.update_pairs();
+        assert_eq!(broad_phase.tree().nodes.len(), nodes);
+        assert_eq!(found(&broad_phase), brute_force(&broad_phase, &proxies));
+
+        for proxy in proxies {
+            broad_phase.destroy_proxy(proxy);
+        }
+        broad_phase.update_pairs();
+        assert_eq!(broad_phase.pair_count(), 0);
+        assert_eq!(broad_phase.tree().height(), 0);
+    }
+}
@@ -0,0 +1,624 @@
+use super::body::{BodyHandle, RigidBody};
+use super::collider::Collider;
+use super::query::{QueryFilter, QueryHit, Ray};
+use super::shape::{Isometry, Shape};
+use super::PhysicsWorld;
+use crate::math;
+use crate::EntityIndex;
+use cgmath::{InnerSpace, One, Quaternion, Rad, Vector3, Zero};
+
+/// How far below the capsule the ground is looked for after a move
+const GROUND_PROBE: f32 = 0.05;
+
+/// How far out past where the capsule touches the ground its surface is probed, so the rounded
+/// bottom resting on an edge finds the top of the ledge rather than the edge itself
+const LEDGE_PROBE: f32 = 0.01;
+
+/// Overlaps left over from the last move are pushed out in at most this many goes
+const MAX_DEPENETRATION: usize = 4;
+
+#[derive(Debug, Copy, Clone)]
+pub struct CharacterConfig {
+    pub radius: f32,
+    /// Half the length of the straight part of the capsule
+    pub half_height: f32,
+    /// Steepest ground the character can stand on, anything steeper blocks like a wall
+    pub max_slope: Rad<f32>,
+    /// Ledges up to this high are stepped onto
+    pub step_height: f32,
+    /// Walking down a drop shorter than this keeps the character on the ground
+    pub snap_distance: f32,
+    /// Gap kept between the capsule and everything around it
+    pub skin_width: f32,
+    /// How many surfaces one move can slide along before it gives up
+    pub max_slides: u32,
+    /// Layers the character's collider sits on
+    pub layers: u32,
+    /// Layers that block the character
+    pub mask: u32,
+}
+
+impl Default for CharacterConfig {
+    fn default() -> Self {
+        CharacterConfig {
+            radius: 0.4,
+            half_height: 0.5,
+            max_slope: Rad(std::f32::consts::FRAC_PI_4),
+            step_height: 0.3,
+            snap_distance: 0.3,
+            skin_width: 0.01,
+            max_slides: 4,
+            layers: 1,
+            mask: u32::MAX,
+        }
+    }
+}
+
+/// What the character is standing on
+#[derive(Debug, Copy, Clone)]
+pub struct Ground {
+    pub body: BodyHandle,
+    pub entity: Option<EntityIndex>,
+    pub position: Vector3<f32>,
+    pub normal: Vector3<f32>,
+}
+
+impl From<QueryHit> for Ground {
+    fn from(hit: QueryHit) -> Ground {
+        Ground {
+            body: hit.body,
+            entity: hit.entity,
+            position: hit.position,
+            normal: hit.normal,
+        }
+    }
+}
+
+/// Moves a kinematic capsule by whatever displacement gameplay asks for, sliding along what it
+/// hits instead of being pushed around by the solver
+#[derive(Debug, Clone)]
+pub struct CharacterController {
+    pub config: CharacterConfig,
+    body: BodyHandle,
+    ground: Option<Ground>,
+    contact_normals: Vec<Vector3<f32>>,
+}
+
+impl CharacterController {
+    /// Adds a kinematic body for the character to `world`, with its capsule centred on `position`
+    pub fn new(
+        world: &mut PhysicsWorld,
+        position: Vector3<f32>,
+        config: CharacterConfig,
+    ) -> CharacterController {
+        let body = world.add_body(RigidBody::kinematic(position));
+        let collider =
+            Collider::capsule(config.radius, config.half_height).with_layers(config.layers);
+        world.add_collider(body, collider);
+        CharacterController {
+            config,
+            body,
+            ground: None,
+            contact_normals: vec![],
+        }
+    }
+
+    pub fn body(&self) -> BodyHandle {
+        self.body
+    }
+
+    pub fn is_grounded(&self) -> bool {
+        self.ground.is_some()
+    }
+
+    pub fn ground(&self) -> Option<&Ground> {
+        self.ground.as_ref()
+    }
+
+    /// Normals of everything the last move ran into
+    pub fn contact_normals(&self) -> &[Vector3<f32>] {
+        &self.contact_normals
+    }
+
+    /// Moves the character by `displacement`, or as much of it as fits, and returns how far it
+    /// really went. Whatever it stands on carries it along for `dt` on top of that
+    pub fn move_by(
+        &mut self,
+        world: &mut PhysicsWorld,
+        displacement: Vector3<f32>,
+        dt: f32,
+    ) -> Vector3<f32> {
+        let start = match world.body(self.body) {
+            Some(body) => body.position,
+            None => return Vector3::zero(),
+        };
+        let exclude = [self.body];
+        let filter = QueryFilter::new()
+            .with_mask(self.config.mask)
+            .excluding(&exclude);
+
+        let mut displacement = displacement;
+        if let Some(ground) = self.ground {
+            if let Some(platform) = world.body(ground.body) {
+                displacement += platform.velocity_at_point(ground.position) * dt;
+            }
+        }
+
+        let up = Vector3::unit_y();
+        let vertical = up * displacement.dot(up);
+        let horizontal = displacement - vertical;
+        let was_grounded = self.ground.is_some();
+
+        let mut normals = vec![];
+        let mut position = self.depenetrate(world, start, &filter);
+        let flat = self.slide(world, position, horizontal, &filter, &mut normals);
+        let stepped = if was_grounded {
+            self.step_up(world, position, horizontal, flat, &filter)
+        } else {
+            None
+        };
+        position = match stepped {
+            Some(stepped) => {
+                normals.clear();
+                stepped
+            }
+            None => flat,
+        };
+        position = self.slide(world, position, vertical, &filter, &mut normals);
+        self.contact_normals = normals;
+
+        self.ground = self
+            .find_ground(world, position, GROUND_PROBE, &filter)
+            .map(Ground::from);
+        if self.ground.is_none() && was_grounded && vertical.dot(up) <= 0.0 {
+            let snap = self.find_ground(world, position, self.config.snap_distance, &filter);
+            if let Some(hit) = snap {
+                position -= up * (hit.distance - self.config.skin_width).max(0.0);
+                self.ground = Some(Ground::from(hit));
+            }
+        }
+
+        if let Some(body) = world.body_mut(self.body) {
+            body.position = position;
+        }
+        position - start
+    }
+
+    fn shape(&self) -> Shape {
+        Shape::Capsule {
+            radius: self.config.radius,
+            half_height: self.config.half_height,
+        }
+    }
+
+    fn is_walkable(&self, normal: Vector3<f32>) -> bool {
+        normal.dot(Vector3::unit_y()) >= math::cos(self.config.max_slope.0)
+    }
+
+    /// Pushes the capsule out of anything it ended up inside, such as a platform that moved into it
+    fn depenetrate(
+        &self,
+        world: &PhysicsWorld,
+        mut position: Vector3<f32>,
+        filter: &QueryFilter,
+    ) -> Vector3<f32> {
+        for _ in 0..MAX_DEPENETRATION {
+            let iso = Isometry::new(position, Quaternion::one());
+            let deepest = world
+                .overlap_shape(&self.shape(), &iso, filter)
+                .into_iter()
+                .min_by(|a, b| a.distance.total_cmp(&b.distance));
+            match deepest {
+                Some(hit) => position += hit.normal * (self.config.skin_width - hit.distance),
+                None => break,
+            }
+        }
+        position
+    }
+
+    /// Collide and slide: moves until something is hit, then carries on with what is left of the
+    /// displacement along the surface
+    fn slide(
+        &self,
+        world: &PhysicsWorld,
+        mut position: Vector3<f32>,
+        displacement: Vector3<f32>,
+        filter: &QueryFilter,
+        normals: &mut Vec<Vector3<f32>>,
+    ) -> Vector3<f32> {
+        let skin = self.config.skin_width;
+        let horizontal = displacement.y.abs() < 1e-6;
+        let mut remaining = displacement;
+        for _ in 0..self.config.max_slides {
+            let length = remaining.magnitude();
+            if length < 1e-5 {
+                break;
+            }
+            let direction = remaining / length;
+            let iso = Isometry::new(position, Quaternion::one());
+            let hit = match world.cast_shape(&self.shape(), &iso, direction, length + skin, filter)
+            {
+                Some(hit) => hit,
+                None => {
+                    position += remaining;
+                    break;
+                }
+            };
+            let travel = (hit.distance - skin).max(0.0).min(length);
+            position += direction * travel;
+            remaining -= direction * travel;
+            normals.push(hit.normal);
+
+            // A slope too steep to climb stops a walk like a wall would, instead of lifting it
+            let mut normal = hit.normal;
+            if horizontal && normal.y > 0.0 && !self.is_walkable(normal) {
+                normal.y = 0.0;
+                if normal.magnitude2() < 1e-8 {
+                    break;
+                }
+                normal = normal.normalize();
+            }
+            let into = remaining.dot(normal);
+            if into < 0.0 {
+                remaining -= normal * into;
+            }
+        }
+        position
+    }
+
+    /// Tries the horizontal move again from `step_height` up, then drops back down. Returns where
+    /// that lands if it is walkable ground and gets further than `flat`, the plain move
+    fn step_up(
+        &self,
+        world: &PhysicsWorld,
+        position: Vector3<f32>,
+        horizontal: Vector3<f32>,
+        flat: Vector3<f32>,
+        filter: &QueryFilter,
+    ) -> Option<Vector3<f32>> {
+        let progress = |p: Vector3<f32>| {
+            let offset = p - position;
+            (offset - Vector3::unit_y() * offset.y).magnitude()
+        };
+        let wanted = horizontal.magnitude();
+        if self.config.step_height <= 0.0 || wanted - progress(flat) < 1e-4 {
+            return None;
+        }
+
+        let mut ignored = vec![];
+        let up = Vector3::unit_y() * self.config.step_height;
+        let raised = self.slide(world, position, up, filter, &mut ignored);
+        let stepped = self.slide(world, raised, horizontal, filter, &mut ignored);
+        let rise = raised.y - position.y;
+        let hit = self.cast_down(world, stepped, rise, filter)?;
+        // The top of the ledge has to be in reach, not just an edge the capsule's side caught on
+        let ledge = self.ground_under(world, stepped, &hit, filter)?;
+        let feet =
+            position.y - self.config.half_height - self.config.radius - self.config.skin_width;
+        if ledge.position.y - feet > self.config.step_height {
+            return None;
+        }
+        let landed = stepped - Vector3::unit_y() * (hit.distance - self.config.skin_width).max(0.0);
+        if progress(landed) > progress(flat) + 1e-4 {
+            Some(landed)
+        } else {
+            None
+        }
+    }
+
+    fn cast_down(
+        &self,
+        world: &PhysicsWorld,
+        position: Vector3<f32>,
+        distance: f32,
+        filter: &QueryFilter,
+    ) -> Option<QueryHit> {
+        let iso = Isometry::new(position, Quaternion::one());
+        let down = -Vector3::unit_y();
+        world.cast_shape(
+            &self.shape(),
+            &iso,
+            down,
+            distance + self.config.skin_width,
+            filter,
+        )
+    }
+
+    /// Walkable ground within `distance` below the capsule, with the normal of the surface it
+    /// stands on
+    fn find_ground(
+        &self,
+        world: &PhysicsWorld,
+        position: Vector3<f32>,
+        distance: f32,
+        filter: &QueryFilter,
+    ) -> Option<QueryHit> {
+        let mut hit = self.cast_down(world, position, distance, filter)?;
+        hit.normal = self.ground_under(world, position, &hit, filter)?.normal;
+        Some(hit)
+    }
+
+    /// The surface under where a downward cast from `position` touched, if it can be stood on.
+    /// On an edge the cast's normal leans out over it, so a short ray just past the contact,
+    /// away from the capsule, looks at the top of the ledge instead
+    fn ground_under(
+        &self,
+        world: &PhysicsWorld,
+        position: Vector3<f32>,
+        hit: &QueryHit,
+        filter: &QueryFilter,
+    ) -> Option<QueryHit> {
+        let up = Vector3::unit_y();
+        let mut outward = hit.position - position;
+        outward -= up * outward.dot(up);
+        if outward.magnitude2() > 1e-8 {
+            outward = outward.normalize() * LEDGE_PROBE;
+        }
+        let ray = Ray::new(hit.position + outward + up * GROUND_PROBE, -up);
+        world
+            .cast_ray(&ray, 2.0 * GROUND_PROBE, filter)
+            .filter(|surface| self.is_walkable(surface.normal))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::ground;
+    use super::super::FIXED_TIMESTEP;
+    use super::*;
+    use cgmath::Rotation3;
+
+    const GRAVITY: f32 = -9.81;
+
+    /// Standing on the ground at the origin, with the default capsule
+    fn character(world: &mut PhysicsWorld) -> CharacterController {
+        let config = CharacterConfig::default();
+        let height = config.half_height + config.radius + config.skin_width;
+        let mut controller =
+            CharacterController::new(world, Vector3::new(0.0, height, 0.0), config);
+        controller.move_by(world, Vector3::new(0.0, -0.1, 0.0), FIXED_TIMESTEP);
+        assert!(controller.is_grounded());
+        controller
+    }
+
+    /// Feet of the capsule, with the skin taken off
+    fn feet(world: &PhysicsWorld, controller: &CharacterController) -> Vector3<f32> {
+        let config = &controller.config;
+        let position = world.body(controller.body()).unwrap().position;
+        position - Vector3::unit_y() * (config.half_height + config.radius + config.skin_width)
+    }
+
+    /// Walks `step` a tick for `ticks` ticks under gravity, the way the game moves players.
+    /// Returns whether the character stayed on the ground the whole way
+    fn walk(
+        world: &mut PhysicsWorld,
+        controller: &mut CharacterController,
+        step: Vector3<f32>,
+        ticks: usize,
+    ) -> bool {
+        let mut vertical_speed = 0.0;
+        let mut grounded = true;
+        for _ in 0..ticks {
+            if controller.is_grounded() {
+                vertical_speed = 0.0;
+            }
+            vertical_speed += GRAVITY * FIXED_TIMESTEP;
+            let displacement = step + Vector3::unit_y() * vertical_speed * FIXED_TIMESTEP;
+            controller.move_by(world, displacement, FIXED_TIMESTEP);
+            grounded &= controller.is_grounded();
+        }
+        grounded
+    }
+
+    fn block(world: &mut PhysicsWorld, center: Vector3<f32>, half_extents: Vector3<f32>) {
+        let body = world.add_body(RigidBody::fixed(center));
+        world
+            .add_collider(body, Collider::cuboid(half_extents))
+            .unwrap();
+    }
+
+    #[test]
+    fn low_ledges_are_stepped_onto() {
+        for &height in [0.15, 0.2, 0.25].iter() {
+            for &speed in [0.02, 0.05].iter() {
+                let mut world = PhysicsWorld::new();
+                ground(&mut world);
+                // Starts a metre ahead and runs on for ten
+                let half = Vector3::new(5.0, height / 2.0, 5.0);
+                block(&mut world, Vector3::new(6.0, height / 2.0, 0.0), half);
+                let mut controller = character(&mut world);
+
+                walk(
+                    &mut world,
+                    &mut controller,
+                    Vector3::new(speed, 0.0, 0.0),
+                    (2.0 / speed) as usize,
+                );
+                let at = feet(&world, &controller);
+                assert!(
+                    at.x > 1.9,
+                    "{} m at {} m/tick stopped at {:?}",
+                    height,
+                    speed,
+                    at
+                );
+                assert!(
+                    (at.y - height).abs() < 0.01,
+                    "{} m at {} m/tick: {:?}",
+                    height,
+                    speed,
+                    at
+                );
+                assert!(controller.is_grounded());
+            }
+        }
+    }
+
+    #[test]
+    fn ledges_above_the_step_height_block() {
+        let mut world = PhysicsWorld::new();
+        ground(&mut world);
+        block(
+            &mut world,
+            Vector3::new(6.0, 0.25, 0.0),
+            Vector3::new(5.0, 0.25, 5.0),
+        );
+        let mut controller = character(&mut world);
+
+        walk(
+            &mut world,
+            &mut controller,
+            Vector3::new(0.05, 0.0, 0.0),
+            40,
+        );
+        let at = feet(&world, &controller);
+        assert!(at.x < 1.0 - controller.config.radius + 0.01, "{:?}", at);
+        assert!(at.y.abs() < 0.01, "{:?}", at);
+    }
+
+    /// A ramp tilted `degrees` up towards +X whose foot is at x = 1
+    fn ramp(world: &mut PhysicsWorld, degrees: f32) {
+        let angle = degrees.to_radians();
+        let (half_length, half_thickness) = (10.0, 0.5);
+        // Pivot the slab about its bottom corner so the top surface meets the ground at x = 1
+        let center = Vector3::new(
+            1.0 + half_length * angle.cos() - half_thickness * angle.sin(),
+            half_length * angle.sin() - half_thickness * angle.cos(),
+            0.0,
+        );
+        let body = world
+            .add_body(RigidBody::fixed(center).with_rotation(Quaternion::from_angle_z(Rad(angle))));
+        world
+            .add_collider(
+                body,
+                Collider::cuboid(Vector3::new(half_length, half_thickness, 5.0)),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn slopes_steeper_than_the_limit_stop_the_walk() {
+        let mut world = PhysicsWorld::new();
+        ground(&mut world);
+        ramp(&mut world, 30.0);
+        let mut controller = character(&mut world);
+        walk(
+            &mut world,
+            &mut controller,
+            Vector3::new(0.05, 0.0, 0.0),
+            100,
+        );
+        let at = feet(&world, &controller);
+        assert!(at.y > 1.0, "should have walked up the ramp: {:?}", at);
+        assert!(controller.is_grounded());
+
+        let mut world = PhysicsWorld::new();
+        ground(&mut world);
+        ramp(&mut world, 60.0);
+        let mut controller = character(&mut world);
+        walk(
+            &mut world,
+            &mut controller,
+            Vector3::new(0.05, 0.0, 0.0),
+            100,
+        );
+        let at = feet(&world, &controller);
+        assert!(
+            at.y < controller.config.step_height,
+            "climbed a wall: {:?}",
+            at
+        );
+        assert!(at.x < 1.0, "{:?}", at);
+    }
+
+    #[test]
+    fn walls_are_slid_along() {
+        let mut world = PhysicsWorld::new();
+        ground(&mut world);
+        block(
+            &mut world,
+            Vector3::new(2.0, 2.0, 0.0),
+            Vector3::new(0.5, 2.0, 10.0),
+        );
+        let mut controller = character(&mut world);
+
+        walk(
+            &mut world,
+            &mut controller,
+            Vector3::new(0.05, 0.0, 0.05),
+            100,
+        );
+        let at = feet(&world, &controller);
+        // Stopped at the wall but carried on along it the whole way
+        assert!(
+            (at.x - (1.5 - controller.config.radius)).abs() < 0.02,
+            "{:?}",
+            at
+        );
+        assert!((at.z - 5.0).abs() < 0.01, "{:?}", at);
+        assert!(controller.contact_normals().iter().any(|n| n.x < -0.99));
+    }
+
+    #[test]
+    fn walking_down_a_slope_snaps_to_it() {
+        for &degrees in [20.0, 40.0].iter() {
+            let mut world = PhysicsWorld::new();
+            ground(&mut world);
+            ramp(&mut world, degrees);
+            let mut controller = character(&mut world);
+            // Onto the ramp and up it, then turn round and walk straight back out
+            walk(
+                &mut world,
+                &mut controller,
+                Vector3::new(0.05, 0.0, 0.0),
+                60,
+            );
+            assert!(feet(&world, &controller).y > 0.5);
+
+            let grounded = walk(
+                &mut world,
+                &mut controller,
+                Vector3::new(-0.05, 0.0, 0.0),
+                80,
+            );
+            assert!(grounded, "left a {} degree slope", degrees);
+            let at = feet(&world, &controller);
+            assert!(at.y.abs() < 0.01, "{} degrees: {:?}", degrees, at);
+        }
+    }
+
+    #[test]
+    fn small_drops_are_snapped_down_and_big_ones_walked_off() {
+        let mut world = PhysicsWorld::new();
+        ground(&mut world);
+        // A 0.2 m platform, inside the snap distance
+        block(
+            &mut world,
+            Vector3::new(-4.0, 0.1, 0.0),
+            Vector3::new(5.0, 0.1, 5.0),
+        );
+        let mut controller = character(&mut world);
+        assert!((feet(&world, &controller).y - 0.2).abs() < 0.01);
+        for _ in 0..40 {
+            controller.move_by(&mut world, Vector3::new(0.05, 0.0, 0.0), FIXED_TIMESTEP);
+            assert!(controller.is_grounded(), "{:?}", feet(&world, &controller));
+        }
+        assert!(feet(&world, &controller).y.abs() < 0.01);
+
+        // A metre is too far, the capsule rolls over the edge and then walks out on air
+        let mut world = PhysicsWorld::new();
+        ground(&mut world);
+        block(
+            &mut world,
+            Vector3::new(-4.0, 0.5, 0.0),
+            Vector3::new(5.0, 0.5, 5.0),
+        );
+        let mut controller = character(&mut world);
+        for _ in 0..40 {
+            controller.move_by(&mut world, Vector3::new(0.05, 0.0, 0.0), FIXED_TIMESTEP);
+        }
+        assert!(!controller.is_grounded());
+        let at = feet(&world, &controller);
+        assert!(at.x > 1.5 && at.y > 0.5, "{:?}", at);
+    }
+}
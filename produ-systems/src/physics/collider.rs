@@ -0,0 +1,98 @@
+use super::aabb::Aabb;
+use super::body::{BodyHandle, RigidBody};
+use super::broad_phase::ProxyId;
+use super::shape::{ConvexHull, HeightField, Shape, TriMesh};
+use cgmath::Vector3;
+use std::sync::Arc;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ColliderHandle(pub(crate) usize);
+
+/// The part of a body that takes up space, attached to a body with `PhysicsWorld::add_collider`
+#[derive(Debug, Clone)]
+pub struct Collider {
+    pub shape: Shape,
+    /// Coulomb friction, the pair uses the geometric mean of both colliders
+    pub friction: f32,
+    /// Bounciness from 0 to 1, the pair uses the larger of both colliders
+    pub restitution: f32,
+    /// Bit set of the layers the collider sits on, queries only see it if their mask shares a bit
+    pub layers: u32,
+    /// Sensors report what overlaps them as trigger events but never push anything
+    pub sensor: bool,
+    pub(crate) body: BodyHandle,
+    pub(crate) proxy: Option<ProxyId>,
+}
+
+impl Collider {
+    pub fn new(shape: Shape) -> Collider {
+        Collider {
+            shape,
+            friction: 0.5,
+            restitution: 0.0,
+            layers: 1,
+            sensor: false,
+            body: BodyHandle(usize::MAX),
+            proxy: None,
+        }
+    }
+
+    pub fn sphere(radius: f32) -> Collider {
+        Collider::new(Shape::Sphere { radius })
+    }
+
+    pub fn cuboid(half_extents: Vector3<f32>) -> Collider {
+        Collider::new(Shape::Cuboid { half_extents })
+    }
+
+    pub fn capsule(radius: f32, half_height: f32) -> Collider {
+        Collider::new(Shape::Capsule {
+            radius,
+            half_height,
+        })
+    }
+
+    pub fn convex_hull(points: Vec<Vector3<f32>>) -> Option<Collider> {
+        Some(Collider::new(Shape::ConvexHull(Arc::new(ConvexHull::new(
+            points,
+        )?))))
+    }
+
+    /// Meshes only collide with convex shapes, so keep them on static or kinematic bodies
+    pub fn trimesh(mesh: Arc<TriMesh>) -> Collider {
+        Collider::new(Shape::TriMesh(mesh))
+    }
+
+    /// Terrain, like meshes it only collides with convex shapes
+    pub fn heightfield(field: Arc<HeightField>) -> Collider {
+        Collider::new(Shape::HeightField(field))
+    }
+
+    pub fn with_friction(mut self, friction: f32) -> Collider {
+        self.friction = friction;
+        self
+    }
+
+    pub fn with_restitution(mut self, restitution: f32) -> Collider {
+        self.restitution = restitution;
+        self
+    }
+
+    pub fn with_layers(mut self, layers: u32) -> Collider {
+        self.layers = layers;
+        self
+    }
+
+    pub fn with_sensor(mut self, sensor: bool) -> Collider {
+        self.sensor = sensor;
+        self
+    }
+
+    pub fn body(&self) -> BodyHandle {
+        self.body
+    }
+
+    pub fn aabb(&self, body: &RigidBody) -> Aabb {
+        self.shape.aabb(&body.isometry())
+    }
+}
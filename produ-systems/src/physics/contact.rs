@@ -0,0 +1,1116 @@
+use super::body::BodyHandle;
+use super::collider::ColliderHandle;
+use super::gjk::{epa, gjk, GjkResult, Inflated, ShapeCore, SupportMap};
+use super::shape::{Isometry, Shape, Triangle, TriangleSource};
+use cgmath::{InnerSpace, Matrix3, Vector3, Zero};
+
+/// Contacts are kept until the surfaces are this far apart, so resting bodies do not flicker
+pub const CONTACT_MARGIN: f32 = 0.02;
+
+/// An old contact point is dropped once its two halves slide this far apart
+const CONTACT_DRIFT: f32 = 0.04;
+
+const MAX_MANIFOLD_POINTS: usize = 4;
+
+/// How much deeper a point has to be before manifold reduction keeps it over a wider spread
+const DEPTH_TOLERANCE: f32 = 0.01;
+
+#[derive(Debug, Copy, Clone)]
+pub struct ContactPoint {
+    /// Halfway between the two surfaces, in world space
+    pub position: Vector3<f32>,
+    /// Points from the first collider into the second
+    pub normal: Vector3<f32>,
+    /// How deep the shapes overlap, negative while they are just inside the contact margin
+    pub penetration: f32,
+    /// Impulse the solver pushed the bodies apart with during the last step
+    pub normal_impulse: f32,
+    /// Where the contact sits on each body, in that body's space
+    pub(crate) local_a: Vector3<f32>,
+    pub(crate) local_b: Vector3<f32>,
+}
+
+/// All points where two colliders touch
+#[derive(Debug, Clone)]
+pub struct ContactManifold {
+    pub collider_a: ColliderHandle,
+    pub collider_b: ColliderHandle,
+    pub body_a: BodyHandle,
+    pub body_b: BodyHandle,
+    pub points: Vec<ContactPoint>,
+    /// Combined from both colliders
+    pub friction: f32,
+    pub restitution: f32,
+    /// Friction is solved once for the whole manifold, at the centre of its points
+    pub(crate) friction_impulse: Vector3<f32>,
+    pub(crate) twist_impulse: f32,
+    /// Whether the colliders pushed on each other last step, speculative contacts may not have
+    pub(crate) touching: bool,
+}
+
+impl ContactManifold {
+    pub(crate) fn new(
+        colliders: (ColliderHandle, ColliderHandle),
+        bodies: (BodyHandle, BodyHandle),
+        raw: &[RawContact],
+        iso_a: &Isometry,
+        iso_b: &Isometry,
+    ) -> ContactManifold {
+        ContactManifold {
+            collider_a: colliders.0,
+            collider_b: colliders.1,
+            body_a: bodies.0,
+            body_b: bodies.1,
+            points: raw
+                .iter()
+                .map(|c| ContactPoint {
+                    position: (c.point_a + c.point_b) * 0.5,
+                    normal: c.normal,
+                    penetration: (c.point_a - c.point_b).dot(c.normal),
+                    normal_impulse: 0.0,
+                    local_a: iso_a.inverse_transform_point(c.point_a),
+                    local_b: iso_b.inverse_transform_point(c.point_b),
+                })
+                .collect(),
+            friction: 0.0,
+            restitution: 0.0,
+            friction_impulse: Vector3::zero(),
+            twist_impulse: 0.0,
+            touching: false,
+        }
+    }
+
+    /// Gives points that were already touching last step their old impulses for warm starting
+    pub(crate) fn carry_impulses(&mut self, previous: &ContactManifold) {
+        for point in &mut self.points {
+            let old = previous.points.iter().find(|old| {
+                (old.local_a - point.local_a).magnitude2() < CONTACT_DRIFT * CONTACT_DRIFT
+                    && (old.local_b - point.local_b).magnitude2() < CONTACT_DRIFT * CONTACT_DRIFT
+            });
+            if let Some(old) = old {
+                point.normal_impulse = old.normal_impulse;
+            }
+        }
+        self.friction_impulse = previous.friction_impulse;
+        self.twist_impulse = previous.twist_impulse;
+        self.touching = previous.touching;
+    }
+
+    /// Keeps points from last step that still hold, so shapes that only produce one point a
+    /// step still build up a full manifold over a few steps
+    pub(crate) fn merge(&mut self, previous: &ContactManifold, iso_a: &Isometry, iso_b: &Isometry) {
+        let normal = match self.points.first() {
+            Some(point) => point.normal,
+            None => return,
+        };
+        for old in &previous.points {
+            let point_a = iso_a.transform_point(old.local_a);
+            let point_b = iso_b.transform_point(old.local_b);
+            let separation = point_a - point_b;
+            let penetration = separation.dot(normal);
+            if penetration < -CONTACT_MARGIN {
+                continue;
+            }
+            if (separation - normal * penetration).magnitude2() > CONTACT_DRIFT * CONTACT_DRIFT {
+                continue;
+            }
+            let position = (point_a + point_b) * 0.5;
+            if self
+                .points
+                .iter()
+                .any(|p| (p.position - position).magnitude2() < CONTACT_DRIFT * CONTACT_DRIFT)
+            {
+                continue;
+            }
+            self.points.push(ContactPoint {
+                position,
+                normal,
+                penetration,
+                ..*old
+            });
+        }
+        reduce(&mut self.points);
+    }
+
+    /// Sum of the impulses at every point, how hard the colliders pushed on each other
+    pub fn total_impulse(&self) -> f32 {
+        self.points.iter().map(|p| p.normal_impulse).sum()
+    }
+
+    pub fn deepest(&self) -> Option<&ContactPoint> {
+        self.points
+            .iter()
+            .max_by(|a, b| a.penetration.partial_cmp(&b.penetration).unwrap())
+    }
+}
+
+/// A contact before it is attached to bodies, `point_a` is on the surface of the first shape
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct RawContact {
+    pub point_a: Vector3<f32>,
+    pub point_b: Vector3<f32>,
+    pub normal: Vector3<f32>,
+}
+
+impl RawContact {
+    fn flipped(self) -> RawContact {
+        RawContact {
+            point_a: self.point_b,
+            point_b: self.point_a,
+            normal: -self.normal,
+        }
+    }
+
+    pub(crate) fn penetration(&self) -> f32 {
+        (self.point_a - self.point_b).dot(self.normal)
+    }
+}
+
+/// Contacts between two shapes that are closer than `margin`
+pub(crate) fn collide(
+    a: &Shape,
+    iso_a: &Isometry,
+    b: &Shape,
+    iso_b: &Isometry,
+    margin: f32,
+) -> Vec<RawContact> {
+    let flip = |contacts: Vec<RawContact>| contacts.into_iter().map(RawContact::flipped).collect();
+
+    let mut contacts = match (a, b) {
+        (_, _) if !a.is_convex() && !b.is_convex() => vec![],
+        (Shape::TriMesh(mesh), _) => flip(mesh_contacts(&**mesh, iso_a, b, iso_b, margin)),
+        (_, Shape::TriMesh(mesh)) => mesh_contacts(&**mesh, iso_b, a, iso_a, margin),
+        (Shape::HeightField(field), _) => flip(mesh_contacts(&**field, iso_a, b, iso_b, margin)),
+        (_, Shape::HeightField(field)) => mesh_contacts(&**field, iso_b, a, iso_a, margin),
+
+        (Shape::Cuboid { half_extents: ha }, Shape::Cuboid { half_extents: hb }) => {
+            box_box(*ha, iso_a, *hb, iso_b, margin)
+        }
+        (Shape::Sphere { radius }, Shape::Cuboid { half_extents }) => {
+            sphere_box(iso_a.translation, *radius, *half_extents, iso_b, margin)
+                .into_iter()
+                .collect()
+        }
+        (Shape::Cuboid { half_extents }, Shape::Sphere { radius }) => flip(
+            sphere_box(iso_b.translation, *radius, *half_extents, iso_a, margin)
+                .into_iter()
+                .collect(),
+        ),
+        (Shape::Sphere { .. }, _) | (Shape::Capsule { .. }, _)
+            if matches!(b, Shape::Sphere { .. } | Shape::Capsule { .. }) =>
+        {
+            let (a0, a1) = core_segment(a, iso_a);
+            let (b0, b1) = core_segment(b, iso_b);
+            let (pa, pb) = closest_segment_points(a0, a1, b0, b1);
+            rounded_contact(pa, a.radius(), pb, b.radius(), margin)
+                .into_iter()
+                .collect()
+        }
+
+        _ => {
+            let core_a = ShapeCore {
+                shape: a,
+                iso: iso_a,
+            };
+            let core_b = ShapeCore {
+                shape: b,
+                iso: iso_b,
+            };
+            convex_contacts(
+                &core_a,
+                Rounding::of(a, iso_a),
+                &core_b,
+                Rounding::of(b, iso_b),
+                margin,
+            )
+        }
+    };
+
+    reduce(&mut contacts);
+    contacts
+}
+
+/// The segment at the heart of a sphere or capsule, in world space
+fn core_segment(shape: &Shape, iso: &Isometry) -> (Vector3<f32>, Vector3<f32>) {
+    match shape {
+        Shape::Capsule { half_height, .. } => {
+            let axis = iso.transform_vector(Vector3::new(0.0, *half_height, 0.0));
+            (iso.translation - axis, iso.translation + axis)
+        }
+        _ => (iso.translation, iso.translation),
+    }
+}
+
+fn rounded_contact(
+    center_a: Vector3<f32>,
+    radius_a: f32,
+    center_b: Vector3<f32>,
+    radius_b: f32,
+    margin: f32,
+) -> Option<RawContact> {
+    let offset = center_b - center_a;
+    let distance = offset.magnitude();
+    if distance > radius_a + radius_b + margin {
+        return None;
+    }
+    let normal = if distance > 1e-6 {
+        offset / distance
+    } else {
+        Vector3::unit_y()
+    };
+    Some(RawContact {
+        point_a: center_a + normal * radius_a,
+        point_b: center_b - normal * radius_b,
+        normal,
+    })
+}
+
+/// Closest points between segments `p0 p1` and `q0 q1`, from Real-Time Collision Detection 5.1.9
+pub(crate) fn closest_segment_points(
+    p0: Vector3<f32>,
+    p1: Vector3<f32>,
+    q0: Vector3<f32>,
+    q1: Vector3<f32>,
+) -> (Vector3<f32>, Vector3<f32>) {
+    let d1 = p1 - p0;
+    let d2 = q1 - q0;
+    let r = p0 - q0;
+    let a = d1.dot(d1);
+    let e = d2.dot(d2);
+    let f = d2.dot(r);
+
+    let (s, t) = if a <= 1e-12 && e <= 1e-12 {
+        (0.0, 0.0)
+    } else if a <= 1e-12 {
+        (0.0, (f / e).clamp(0.0, 1.0))
+    } else {
+        let c = d1.dot(r);
+        if e <= 1e-12 {
+            ((-c / a).clamp(0.0, 1.0), 0.0)
+        } else {
+            let b = d1.dot(d2);
+            let denom = a * e - b * b;
+            let mut s = if denom > 1e-12 {
+                ((b * f - c * e) / denom).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let mut t = (b * s + f) / e;
+            if t < 0.0 {
+                t = 0.0;
+                s = (-c / a).clamp(0.0, 1.0);
+            } else if t > 1.0 {
+                t = 1.0;
+                s = ((b - c) / a).clamp(0.0, 1.0);
+            }
+            (s, t)
+        }
+    };
+    (p0 + d1 * s, q0 + d2 * t)
+}
+
+fn sphere_box(
+    center: Vector3<f32>,
+    radius: f32,
+    half_extents: Vector3<f32>,
+    iso_box: &Isometry,
+    margin: f32,
+) -> Option<RawContact> {
+    let local = iso_box.inverse_transform_point(center);
+    let clamped = Vector3::new(
+        local.x.clamp(-half_extents.x, half_extents.x),
+        local.y.clamp(-half_extents.y, half_extents.y),
+        local.z.clamp(-half_extents.z, half_extents.z),
+    );
+
+    let (box_point, normal) = if clamped != local {
+        let offset = local - clamped;
+        let distance = offset.magnitude();
+        if distance > radius + margin {
+            return None;
+        }
+        (clamped, offset / distance)
+    } else {
+        // The centre is inside the box, push out through the nearest face
+        let mut axis = 0;
+        let mut best = f32::INFINITY;
+        for i in 0..3 {
+            let depth = half_extents[i] - local[i].abs();
+            if depth < best {
+                best = depth;
+                axis = i;
+            }
+        }
+        let mut normal = Vector3::new(0.0, 0.0, 0.0);
+        normal[axis] = 1.0f32.copysign(local[axis]);
+        let mut face_point = local;
+        face_point[axis] = half_extents[axis].copysign(local[axis]);
+        (face_point, normal)
+    };
+
+    // The normal found points from the box to the sphere, the sphere comes first
+    let normal = iso_box.transform_vector(-normal);
+    Some(RawContact {
+        point_a: center + normal * radius,
+        point_b: iso_box.transform_point(box_point),
+        normal,
+    })
+}
+
+/// Separating axis test between two boxes, clipping the touching faces for a full manifold
+#[allow(clippy::needless_range_loop)]
+fn box_box(
+    ha: Vector3<f32>,
+    iso_a: &Isometry,
+    hb: Vector3<f32>,
+    iso_b: &Isometry,
+    margin: f32,
+) -> Vec<RawContact> {
+    let ma = Matrix3::from(iso_a.rotation);
+    let mb = Matrix3::from(iso_b.rotation);
+    let axes_a = [ma.x, ma.y, ma.z];
+    let axes_b = [mb.x, mb.y, mb.z];
+    let d = iso_b.translation - iso_a.translation;
+
+    let separation = |axis: Vector3<f32>| {
+        let pa: f32 = (0..3).map(|i| ha[i] * axes_a[i].dot(axis).abs()).sum();
+        let pb: f32 = (0..3).map(|i| hb[i] * axes_b[i].dot(axis).abs()).sum();
+        d.dot(axis).abs() - pa - pb
+    };
+
+    #[derive(Copy, Clone)]
+    enum Feature {
+        FaceA(usize),
+        FaceB(usize),
+        Edges(usize, usize),
+    }
+
+    let mut best_face = (f32::NEG_INFINITY, Feature::FaceA(0), axes_a[0]);
+    for i in 0..3 {
+        let s = separation(axes_a[i]);
+        if s > margin {
+            return vec![];
+        }
+        if s > best_face.0 {
+            best_face = (s, Feature::FaceA(i), axes_a[i]);
+        }
+    }
+    for j in 0..3 {
+        let s = separation(axes_b[j]);
+        if s > margin {
+            return vec![];
+        }
+        // Faces of B need to be clearly better so the choice does not flip every step
+        if s > best_face.0 * 0.95 + 0.005 {
+            best_face = (s, Feature::FaceB(j), axes_b[j]);
+        }
+    }
+
+    let mut best = best_face;
+    for i in 0..3 {
+        for j in 0..3 {
+            let axis = axes_a[i].cross(axes_b[j]);
+            let length = axis.magnitude();
+            if length < 1e-4 {
+                continue;
+            }
+            let axis = axis / length;
+            let s = separation(axis);
+            if s > margin {
+                return vec![];
+            }
+            if s > best.0 * 0.95 + 0.005 {
+                best = (s, Feature::Edges(i, j), axis);
+            }
+        }
+    }
+
+    let mut normal = best.2;
+    if d.dot(normal) < 0.0 {
+        normal = -normal;
+    }
+
+    match best.1 {
+        Feature::FaceA(i) => clip_box_faces(
+            (iso_a.translation, &axes_a, ha),
+            i,
+            normal,
+            (iso_b.translation, &axes_b, hb),
+            margin,
+        )
+        .into_iter()
+        .map(|(on_incident, on_reference)| RawContact {
+            point_a: on_reference,
+            point_b: on_incident,
+            normal,
+        })
+        .collect(),
+        Feature::FaceB(j) => clip_box_faces(
+            (iso_b.translation, &axes_b, hb),
+            j,
+            -normal,
+            (iso_a.translation, &axes_a, ha),
+            margin,
+        )
+        .into_iter()
+        .map(|(on_incident, on_reference)| RawContact {
+            point_a: on_incident,
+            point_b: on_reference,
+            normal,
+        })
+        .collect(),
+        Feature::Edges(i, j) => {
+            let edge = |center: Vector3<f32>,
+                        axes: &[Vector3<f32>; 3],
+                        h: Vector3<f32>,
+                        along: usize,
+                        toward: Vector3<f32>| {
+                let mut mid = center;
+                for k in 0..3 {
+                    if k != along {
+                        mid += axes[k] * h[k].copysign(axes[k].dot(toward));
+                    }
+                }
+                (mid - axes[along] * h[along], mid + axes[along] * h[along])
+            };
+            let (a0, a1) = edge(iso_a.translation, &axes_a, ha, i, normal);
+            let (b0, b1) = edge(iso_b.translation, &axes_b, hb, j, -normal);
+            let (pa, pb) = closest_segment_points(a0, a1, b0, b1);
+            vec![RawContact {
+                point_a: pa,
+                point_b: pb,
+                normal,
+            }]
+        }
+    }
+}
+
+type BoxFrame<'a> = (Vector3<f32>, &'a [Vector3<f32>; 3], Vector3<f32>);
+
+/// Clips the incident box's face against the reference face, `normal` points from the reference
+/// box to the incident box. Returns pairs of points on the incident and reference surfaces
+#[allow(clippy::needless_range_loop)]
+fn clip_box_faces(
+    reference: BoxFrame,
+    reference_axis: usize,
+    normal: Vector3<f32>,
+    incident: BoxFrame,
+    margin: f32,
+) -> Vec<(Vector3<f32>, Vector3<f32>)> {
+    let (ref_center, ref_axes, ref_half) = reference;
+    let (inc_center, inc_axes, inc_half) = incident;
+
+    // The incident face is the one facing most against the normal
+    let mut inc_axis = 0;
+    let mut best = f32::NEG_INFINITY;
+    for k in 0..3 {
+        let d = inc_axes[k].dot(normal).abs();
+        if d > best {
+            best = d;
+            inc_axis = k;
+        }
+    }
+    let inc_normal = -inc_axes[inc_axis] * inc_axes[inc_axis].dot(normal).signum();
+    let inc_face_center = inc_center + inc_normal * inc_half[inc_axis];
+    let (u, v) = ((inc_axis + 1) % 3, (inc_axis + 2) % 3);
+    let eu = inc_axes[u] * inc_half[u];
+    let ev = inc_axes[v] * inc_half[v];
+    let mut polygon = vec![
+        inc_face_center + eu + ev,
+        inc_face_center - eu + ev,
+        inc_face_center - eu - ev,
+        inc_face_center + eu - ev,
+    ];
+
+    for k in 0..3 {
+        if k == reference_axis {
+            continue;
+        }
+        let axis = ref_axes[k];
+        let offset = axis.dot(ref_center);
+        polygon = clip_polygon(&polygon, axis, offset + ref_half[k]);
+        polygon = clip_polygon(&polygon, -axis, -offset + ref_half[k]);
+        if polygon.is_empty() {
+            return vec![];
+        }
+    }
+
+    let face_offset = normal.dot(ref_center) + ref_half[reference_axis];
+    polygon
+        .into_iter()
+        .filter_map(|p| {
+            let depth = face_offset - normal.dot(p);
+            if depth < -margin {
+                return None;
+            }
+            Some((p, p + normal * depth))
+        })
+        .collect()
+}
+
+/// Keeps the part of the polygon where `normal . p <= offset`
+fn clip_polygon(polygon: &[Vector3<f32>], normal: Vector3<f32>, offset: f32) -> Vec<Vector3<f32>> {
+    let mut clipped = Vec::with_capacity(polygon.len() + 2);
+    for i in 0..polygon.len() {
+        let a = polygon[i];
+        let b = polygon[(i + 1) % polygon.len()];
+        let da = normal.dot(a) - offset;
+        let db = normal.dot(b) - offset;
+        if da <= 0.0 {
+            clipped.push(a);
+        }
+        if (da < 0.0 && db > 0.0) || (da > 0.0 && db < 0.0) {
+            clipped.push(a + (b - a) * (da / (da - db)));
+        }
+    }
+    clipped
+}
+
+struct PointSupport(Vector3<f32>);
+
+impl SupportMap for PointSupport {
+    fn support(&self, _direction: Vector3<f32>) -> Vector3<f32> {
+        self.0
+    }
+
+    fn center(&self) -> Vector3<f32> {
+        self.0
+    }
+}
+
+/// GJK when the cores are apart, EPA when they overlap
+fn support_contact<A: SupportMap, B: SupportMap>(
+    a: &A,
+    radius_a: f32,
+    b: &B,
+    radius_b: f32,
+    margin: f32,
+) -> Option<RawContact> {
+    if let (
+        GjkResult::Separated {
+            distance,
+            point_a,
+            point_b,
+        },
+        _,
+    ) = gjk(a, b)
+    {
+        if distance > radius_a + radius_b + margin {
+            return None;
+        }
+        if distance > 1e-5 {
+            let normal = (point_b - point_a) / distance;
+            return Some(RawContact {
+                point_a: point_a + normal * radius_a,
+                point_b: point_b - normal * radius_b,
+                normal,
+            });
+        }
+    }
+
+    let inflated_a = Inflated {
+        inner: a,
+        radius: radius_a,
+    };
+    let inflated_b = Inflated {
+        inner: b,
+        radius: radius_b,
+    };
+    let (_, simplex) = gjk(&inflated_a, &inflated_b);
+    let penetration = epa(&inflated_a, &inflated_b, simplex)?;
+    Some(RawContact {
+        point_a: penetration.point_a,
+        point_b: penetration.point_b,
+        normal: penetration.normal,
+    })
+}
+
+/// What GJK needs on top of a shape's core: its radius and, for capsules, the segment ends
+#[derive(Copy, Clone)]
+struct Rounding {
+    radius: f32,
+    ends: Option<(Vector3<f32>, Vector3<f32>)>,
+}
+
+impl Rounding {
+    const SHARP: Rounding = Rounding {
+        radius: 0.0,
+        ends: None,
+    };
+
+    fn of(shape: &Shape, iso: &Isometry) -> Rounding {
+        Rounding {
+            radius: shape.radius(),
+            ends: match shape {
+                Shape::Capsule { .. } => Some(core_segment(shape, iso)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Any convex pair without a dedicated routine. Capsules add extra points from their ends,
+/// a single GJK point would let them rock on flat ground
+fn convex_contacts<A: SupportMap, B: SupportMap>(
+    core_a: &A,
+    a: Rounding,
+    core_b: &B,
+    b: Rounding,
+    margin: f32,
+) -> Vec<RawContact> {
+    let mut contacts: Vec<RawContact> = support_contact(core_a, a.radius, core_b, b.radius, margin)
+        .into_iter()
+        .collect();
+    if contacts.is_empty() {
+        return contacts;
+    }
+
+    if let Some((e0, e1)) = a.ends {
+        for &end in [e0, e1].iter() {
+            if let Some(c) = support_contact(&PointSupport(end), a.radius, core_b, b.radius, margin)
+            {
+                add_unique(&mut contacts, c);
+            }
+        }
+    }
+    if let Some((e0, e1)) = b.ends {
+        for &end in [e0, e1].iter() {
+            if let Some(c) = support_contact(core_a, a.radius, &PointSupport(end), b.radius, margin)
+            {
+                add_unique(&mut contacts, c);
+            }
+        }
+    }
+    contacts
+}
+
+/// Adds `contact` unless there is one in the same spot already, keeping the deeper of the two.
+/// On a mesh a corner near a shared edge is close to both triangles, and the one it has sunk
+/// into is the one that should push it out
+fn add_unique(contacts: &mut Vec<RawContact>, contact: RawContact) {
+    let position = (contact.point_a + contact.point_b) * 0.5;
+    let duplicate = contacts.iter_mut().find(|c| {
+        ((c.point_a + c.point_b) * 0.5 - position).magnitude2() < CONTACT_DRIFT * CONTACT_DRIFT
+    });
+    match duplicate {
+        Some(c) if contact.penetration() > c.penetration() => *c = contact,
+        Some(_) => {}
+        None => contacts.push(contact),
+    }
+}
+
+/// Contacts between every nearby triangle of a mesh or height field and a convex shape, the
+/// convex shape is treated as the first shape so normals point from it into the mesh
+fn mesh_contacts<M: TriangleSource>(
+    mesh: &M,
+    iso_mesh: &Isometry,
+    shape: &Shape,
+    iso_shape: &Isometry,
+    margin: f32,
+) -> Vec<RawContact> {
+    let relative = Isometry::new(
+        iso_mesh.inverse_transform_point(iso_shape.translation),
+        iso_mesh.rotation.conjugate() * iso_shape.rotation,
+    );
+    let query = shape.aabb(&relative).expanded(margin);
+    let core = ShapeCore {
+        shape,
+        iso: iso_shape,
+    };
+    let rounding = Rounding::of(shape, iso_shape);
+    // A single GJK or EPA point per triangle lets flat sides rock, so the corners of boxes and
+    // hulls that sink in add their own points
+    let corners = match shape {
+        Shape::Cuboid { half_extents } => box_corners(*half_extents, iso_shape).to_vec(),
+        Shape::ConvexHull(hull) => hull
+            .points()
+            .iter()
+            .map(|&p| iso_shape.transform_point(p))
+            .collect(),
+        _ => vec![],
+    };
+
+    let mut contacts = vec![];
+    mesh.visit_triangles(&query, &mut |triangle| {
+        let world = Triangle {
+            a: iso_mesh.transform_point(triangle.a),
+            b: iso_mesh.transform_point(triangle.b),
+            c: iso_mesh.transform_point(triangle.c),
+        };
+        let mut found = convex_contacts(&core, rounding, &world, Rounding::SHARP, margin);
+        if !found.is_empty() {
+            // Triangles are two sided, corners are pushed back out of whichever side the
+            // shape's centre is on, however the triangle is wound
+            let mut facing = world.normal();
+            if (iso_shape.translation - world.a).dot(facing) < 0.0 {
+                facing = -facing;
+            }
+            for &corner in &corners {
+                if let Some(c) = point_triangle_contact(corner, &world, facing, margin) {
+                    add_unique(&mut found, c);
+                }
+            }
+        }
+        for contact in found {
+            add_unique(&mut contacts, contact);
+        }
+    });
+    contacts
+}
+
+fn box_corners(half_extents: Vector3<f32>, iso: &Isometry) -> [Vector3<f32>; 8] {
+    let mut corners = [Vector3::new(0.0, 0.0, 0.0); 8];
+    for (i, corner) in corners.iter_mut().enumerate() {
+        let local = Vector3::new(
+            if i & 1 == 0 {
+                -half_extents.x
+            } else {
+                half_extents.x
+            },
+            if i & 2 == 0 {
+                -half_extents.y
+            } else {
+                half_extents.y
+            },
+            if i & 4 == 0 {
+                -half_extents.z
+            } else {
+                half_extents.z
+            },
+        );
+        *corner = iso.transform_point(local);
+    }
+    corners
+}
+
+/// A corner resting on or sunk into the side of a triangle that `normal` points out of
+fn point_triangle_contact(
+    point: Vector3<f32>,
+    triangle: &Triangle,
+    normal: Vector3<f32>,
+    margin: f32,
+) -> Option<RawContact> {
+    let distance = (point - triangle.a).dot(normal);
+    if distance > margin {
+        return None;
+    }
+    let projected = point - normal * distance;
+    // Inside when the point is on the same side of all three edges, whichever way they wind
+    let side = |a: Vector3<f32>, b: Vector3<f32>| (b - a).cross(projected - a).dot(normal);
+    let sides = [
+        side(triangle.a, triangle.b),
+        side(triangle.b, triangle.c),
+        side(triangle.c, triangle.a),
+    ];
+    if !(sides.iter().all(|&s| s >= 0.0) || sides.iter().all(|&s| s <= 0.0)) {
+        return None;
+    }
+    Some(RawContact {
+        point_a: point,
+        point_b: projected,
+        normal: -normal,
+    })
+}
+
+/// Cuts a manifold down to the four points that cover the most area. The choice is made on
+/// shape alone so it does not flip between steps on nearly level contacts, a clearly deeper
+/// point is then swapped back in
+pub(crate) fn reduce<P: HasPosition>(points: &mut Vec<P>) {
+    if points.len() <= MAX_MANIFOLD_POINTS {
+        return;
+    }
+
+    let centroid = points
+        .iter()
+        .fold(Vector3::zero(), |sum, p| sum + p.position())
+        / points.len() as f32;
+    let first = (0..points.len())
+        .max_by(|&i, &j| {
+            let di = (points[i].position() - centroid).magnitude2();
+            let dj = (points[j].position() - centroid).magnitude2();
+            di.partial_cmp(&dj).unwrap()
+        })
+        .unwrap();
+    let p0 = points[first].position();
+
+    let furthest = (0..points.len())
+        .max_by(|&i, &j| {
+            let di = (points[i].position() - p0).magnitude2();
+            let dj = (points[j].position() - p0).magnitude2();
+            di.partial_cmp(&dj).unwrap()
+        })
+        .unwrap();
+    let p1 = points[furthest].position();
+
+    let area =
+        |a: Vector3<f32>, b: Vector3<f32>, c: Vector3<f32>| (b - a).cross(c - a).magnitude2();
+    let third = (0..points.len())
+        .max_by(|&i, &j| {
+            let ai = area(p0, p1, points[i].position());
+            let aj = area(p0, p1, points[j].position());
+            ai.partial_cmp(&aj).unwrap()
+        })
+        .unwrap();
+    let p2 = points[third].position();
+
+    let fourth = (0..points.len())
+        .filter(|&i| i != first && i != furthest && i != third)
+        .max_by(|&i, &j| {
+            let score = |p: Vector3<f32>| area(p0, p1, p) + area(p1, p2, p) + area(p2, p0, p);
+            score(points[i].position())
+                .partial_cmp(&score(points[j].position()))
+                .unwrap()
+        });
+
+    let mut keep = vec![first, furthest, third];
+    keep.extend(fourth);
+
+    let deepest = (0..points.len())
+        .max_by(|&i, &j| points[i].depth().partial_cmp(&points[j].depth()).unwrap())
+        .unwrap();
+    let shallowest_kept = keep
+        .iter()
+        .map(|&i| points[i].depth())
+        .fold(f32::INFINITY, f32::min);
+    if !keep.contains(&deepest) && points[deepest].depth() > shallowest_kept + DEPTH_TOLERANCE {
+        let p = points[deepest].position();
+        let nearest = (0..keep.len())
+            .min_by(|&i, &j| {
+                let di = (points[keep[i]].position() - p).magnitude2();
+                let dj = (points[keep[j]].position() - p).magnitude2();
+                di.partial_cmp(&dj).unwrap()
+            })
+            .unwrap();
+        keep[nearest] = deepest;
+    }
+    keep.sort_unstable();
+    keep.dedup();
+    let mut index = 0;
+    points.retain(|_| {
+        let kept = keep.contains(&index);
+        index += 1;
+        kept
+    });
+}
+
+pub(crate) trait HasPosition {
+    fn position(&self) -> Vector3<f32>;
+    fn depth(&self) -> f32;
+}
+
+impl HasPosition for RawContact {
+    fn position(&self) -> Vector3<f32> {
+        (self.point_a + self.point_b) * 0.5
+    }
+
+    fn depth(&self) -> f32 {
+        self.penetration()
+    }
+}
+
+impl HasPosition for ContactPoint {
+    fn position(&self) -> Vector3<f32> {
+        self.position
+    }
+
+    fn depth(&self) -> f32 {
+        self.penetration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::shape::{ConvexHull, TriMesh};
+    use super::super::{Collider, MassProperties, PhysicsWorld, RigidBody, FIXED_TIMESTEP};
+    use super::*;
+    use cgmath::{Deg, One, Quaternion, Rotation3};
+    use std::sync::Arc;
+
+    #[allow(dead_code)]
+    mod teapot {
+        include!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/renderer/shaders/examples/teapot/lib.rs"
+        ));
+    }
+
+    /// The renderer's teapot at the size it is drawn, not quite two metres spout to handle
+    fn teapot() -> Arc<TriMesh> {
+        let positions = teapot::VERTICES.iter().map(|v| {
+            let p = v.position();
+            [p[0] * 0.01, p[1] * 0.01, p[2] * 0.01]
+        });
+        Arc::new(TriMesh::from_indexed(positions, &teapot::INDICES[..]).unwrap())
+    }
+
+    /// Two triangles on y = 0, facing up or, `flipped`, down
+    fn plane(flipped: bool) -> Arc<TriMesh> {
+        let vertices = vec![
+            Vector3::new(-5.0, 0.0, -5.0),
+            Vector3::new(5.0, 0.0, -5.0),
+            Vector3::new(5.0, 0.0, 5.0),
+            Vector3::new(-5.0, 0.0, 5.0),
+        ];
+        let indices: &[u32] = if flipped {
+            &[0, 1, 2, 0, 2, 3]
+        } else {
+            &[0, 2, 1, 0, 3, 2]
+        };
+        Arc::new(TriMesh::new(vertices, indices).unwrap())
+    }
+
+    /// Octagonal slab 0.2 wide and 0.1 tall
+    fn slab() -> Arc<ConvexHull> {
+        let points = (0..16)
+            .map(|i| {
+                let angle = (i / 2) as f32 * std::f32::consts::FRAC_PI_4;
+                let y = if i % 2 == 0 { -0.05 } else { 0.05 };
+                Vector3::new(0.1 * angle.cos(), y, 0.1 * angle.sin())
+            })
+            .collect();
+        Arc::new(ConvexHull::new(points).unwrap())
+    }
+
+    /// Each shape with its rotation, how far it reaches below its centre and how many points
+    /// it should at least touch a flat floor with
+    fn shapes() -> Vec<(Shape, Quaternion<f32>, f32, usize)> {
+        let lying = Quaternion::from_angle_z(Deg(90.0));
+        vec![
+            (
+                Shape::Cuboid {
+                    half_extents: Vector3::new(0.2, 0.1, 0.3),
+                },
+                Quaternion::one(),
+                0.1,
+                4,
+            ),
+            (Shape::Sphere { radius: 0.15 }, Quaternion::one(), 0.15, 1),
+            (
+                Shape::Capsule {
+                    radius: 0.1,
+                    half_height: 0.2,
+                },
+                lying,
+                0.1,
+                2,
+            ),
+            (Shape::ConvexHull(slab()), Quaternion::one(), 0.05, 4),
+        ]
+    }
+
+    #[test]
+    fn meshes_push_shapes_out_of_either_side_however_they_are_wound() {
+        let depth = 0.01;
+        for flipped in [false, true].iter().copied() {
+            let mesh = Shape::TriMesh(plane(flipped));
+            let iso_mesh = Isometry::new(Vector3::zero(), Quaternion::one());
+            for (shape, rotation, reach, points) in shapes() {
+                // Straddling the edge between the two triangles, from above and then below
+                for side in [1.0f32, -1.0].iter().copied() {
+                    let position = Vector3::new(0.3, side * (reach - depth), 0.2);
+                    let iso = Isometry::new(position, rotation);
+                    // Round shapes also get a point just apart from the far triangle's edge
+                    let contacts: Vec<_> = collide(&shape, &iso, &mesh, &iso_mesh, CONTACT_MARGIN)
+                        .into_iter()
+                        .filter(|c| c.penetration() > 0.0)
+                        .collect();
+                    assert!(
+                        contacts.len() >= points,
+                        "{:?} {} {}: {:?}",
+                        shape,
+                        flipped,
+                        side,
+                        contacts
+                    );
+                    for c in &contacts {
+                        assert!(
+                            c.normal.y * -side > 0.999,
+                            "{:?} {} {}: {:?}",
+                            shape,
+                            flipped,
+                            side,
+                            c
+                        );
+                        assert!((c.penetration() - depth).abs() < 1e-3, "{:?}", c);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn deeply_sunk_boxes_keep_their_corners() {
+        let mesh = Shape::TriMesh(plane(false));
+        let iso_mesh = Isometry::new(Vector3::zero(), Quaternion::one());
+        let shape = Shape::Cuboid {
+            half_extents: Vector3::new(0.5, 0.5, 0.5),
+        };
+        let iso = Isometry::new(Vector3::new(0.3, 0.2, 0.2), Quaternion::one());
+        let contacts = collide(&shape, &iso, &mesh, &iso_mesh, CONTACT_MARGIN);
+        assert_eq!(contacts.len(), 4);
+        for c in &contacts {
+            assert!(c.normal.y < -0.999, "{:?}", c);
+            assert!((c.penetration() - 0.3).abs() < 1e-3, "{:?}", c);
+        }
+    }
+
+    #[test]
+    fn shapes_on_the_teapot_lid_are_pushed_up() {
+        let mesh = teapot();
+        let top = mesh
+            .vertices()
+            .iter()
+            .copied()
+            .max_by(|a, b| a.y.total_cmp(&b.y))
+            .unwrap();
+        let iso_mesh = Isometry::new(Vector3::zero(), Quaternion::one());
+        let mesh = Shape::TriMesh(mesh);
+        for (shape, rotation, reach, _) in shapes() {
+            let position = Vector3::new(top.x, top.y + reach - 0.01, top.z);
+            let iso = Isometry::new(position, rotation);
+            let contacts = collide(&shape, &iso, &mesh, &iso_mesh, CONTACT_MARGIN);
+            assert!(!contacts.is_empty(), "{:?}", shape);
+            for c in &contacts {
+                assert!(c.normal.y < -0.7, "{:?}: {:?}", shape, c);
+                assert!(c.penetration() < 0.02, "{:?}: {:?}", shape, c);
+            }
+        }
+    }
+
+    /// Drops a box on the middle of `mesh` with the ground underneath, returns where it came to
+    /// rest
+    fn drop_box_on(mesh: Arc<TriMesh>) -> Vector3<f32> {
+        let mut world = PhysicsWorld::new();
+        let ground = world.add_body(RigidBody::fixed(Vector3::new(0.0, -0.9, 0.0)));
+        world
+            .add_collider(ground, Collider::cuboid(Vector3::new(5.0, 0.5, 5.0)))
+            .unwrap();
+        let fixed = world.add_body(RigidBody::fixed(Vector3::zero()));
+        world.add_collider(fixed, Collider::trimesh(mesh)).unwrap();
+
+        let half = Vector3::new(0.1, 0.1, 0.1);
+        let body = world.add_body(RigidBody::dynamic(
+            Vector3::new(0.0, 1.0, 0.0),
+            MassProperties::cuboid(1.0, half),
+        ));
+        world.add_collider(body, Collider::cuboid(half)).unwrap();
+        for _ in 0..300 {
+            world.step(FIXED_TIMESTEP);
+        }
+        let body = world.body(body).unwrap();
+        assert!(body.is_sleeping(), "{:?}", body.position);
+        body.position
+    }
+
+    #[test]
+    fn boxes_come_to_rest_on_meshes() {
+        for flipped in [false, true].iter().copied() {
+            let position = drop_box_on(plane(flipped));
+            assert!((position.y - 0.1).abs() < 0.005, "{:?}", position);
+        }
+
+        // Balanced on the knob of the lid, not fallen in through the top of the pot
+        let position = drop_box_on(teapot());
+        assert!(position.y > 0.55, "{:?}", position);
+    }
+}
@@ -0,0 +1,39 @@
+use super::collider::ColliderHandle;
+use crate::EntityIndex;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CollisionEventKind {
+    /// A collider started overlapping a sensor
+    TriggerEnter,
+    /// Sent every step after the enter that the overlap lasts
+    TriggerStay,
+    TriggerExit,
+    /// Two solid colliders started pushing on each other
+    ContactBegin,
+    ContactEnd,
+}
+
+/// Something that happened between two colliders during a step. For trigger events `collider_a`
+/// is always the sensor
+#[derive(Debug, Copy, Clone)]
+pub struct CollisionEvent {
+    pub kind: CollisionEventKind,
+    pub collider_a: ColliderHandle,
+    pub collider_b: ColliderHandle,
+    /// Entities driven by the colliders' bodies, if any
+    pub entity_a: Option<EntityIndex>,
+    pub entity_b: Option<EntityIndex>,
+    /// How hard the colliders hit, summed over the contact points. Only set on `ContactBegin`
+    pub impulse: f32,
+}
+
+impl CollisionEvent {
+    pub fn is_trigger(&self) -> bool {
+        matches!(
+            self.kind,
+            CollisionEventKind::TriggerEnter
+                | CollisionEventKind::TriggerStay
+                | CollisionEventKind::TriggerExit
+        )
+    }
+}
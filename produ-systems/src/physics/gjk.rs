@@ -0,0 +1,496 @@
+//! GJK for the distance between two convex shapes and EPA for how far they overlap.
+//! Both work on the Minkowski difference `A - B`, which contains the origin when the shapes touch
+use super::shape::{Isometry, Shape, Triangle};
+use cgmath::{InnerSpace, Quaternion, Rotation, Vector3, Zero};
+
+const MAX_ITERATIONS: usize = 64;
+const EPA_TOLERANCE: f32 = 1e-4;
+
+/// Anything GJK can ask for its furthest point in a direction, in world space
+pub(crate) trait SupportMap {
+    fn support(&self, direction: Vector3<f32>) -> Vector3<f32>;
+
+    fn center(&self) -> Vector3<f32>;
+}
+
+/// A convex shape without its radius, so a sphere is its centre and a capsule its segment
+pub(crate) struct ShapeCore<'a> {
+    pub shape: &'a Shape,
+    pub iso: &'a Isometry,
+}
+
+impl<'a> SupportMap for ShapeCore<'a> {
+    fn support(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        let local = self.iso.inverse_transform_vector(direction);
+        self.iso.transform_point(self.shape.core_support(local))
+    }
+
+    fn center(&self) -> Vector3<f32> {
+        self.iso.translation
+    }
+}
+
+impl SupportMap for Triangle {
+    fn support(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        Triangle::support(self, direction)
+    }
+
+    fn center(&self) -> Vector3<f32> {
+        (self.a + self.b + self.c) / 3.0
+    }
+}
+
+/// Grows another support map by a radius, turning a segment into a capsule
+pub(crate) struct Inflated<'a, S: SupportMap> {
+    pub inner: &'a S,
+    pub radius: f32,
+}
+
+impl<'a, S: SupportMap> SupportMap for Inflated<'a, S> {
+    fn support(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        let length = direction.magnitude();
+        let point = self.inner.support(direction);
+        if length > 1e-12 {
+            point + direction * (self.radius / length)
+        } else {
+            point
+        }
+    }
+
+    fn center(&self) -> Vector3<f32> {
+        self.inner.center()
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct SimplexVertex {
+    /// `a - b`
+    w: Vector3<f32>,
+    a: Vector3<f32>,
+    b: Vector3<f32>,
+}
+
+fn support_vertex<A: SupportMap, B: SupportMap>(
+    a: &A,
+    b: &B,
+    direction: Vector3<f32>,
+) -> SimplexVertex {
+    let pa = a.support(direction);
+    let pb = b.support(-direction);
+    SimplexVertex {
+        w: pa - pb,
+        a: pa,
+        b: pb,
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum GjkResult {
+    /// The closest points on each shape
+    Separated {
+        distance: f32,
+        point_a: Vector3<f32>,
+        point_b: Vector3<f32>,
+    },
+    Overlapping,
+}
+
+pub(crate) struct Simplex {
+    vertices: Vec<SimplexVertex>,
+    weights: Vec<f32>,
+}
+
+pub(crate) fn gjk<A: SupportMap, B: SupportMap>(a: &A, b: &B) -> (GjkResult, Simplex) {
+    let mut direction = b.center() - a.center();
+    if direction.magnitude2() < 1e-12 {
+        direction = Vector3::unit_x();
+    }
+
+    let first = support_vertex(a, b, -direction);
+    let mut simplex = Simplex {
+        vertices: vec![first],
+        weights: vec![1.0],
+    };
+    let mut v = first.w;
+    let mut best_distance2 = v.magnitude2();
+
+    for _ in 0..MAX_ITERATIONS {
+        let distance2 = v.magnitude2();
+        if distance2 < 1e-12 {
+            return (GjkResult::Overlapping, simplex);
+        }
+
+        let p = support_vertex(a, b, -v);
+        // The new point gets no closer to the origin than what we already have. Rounding in `v`
+        // grows with the size of the shapes, so the tolerance does too
+        if distance2 - v.dot(p.w) <= 1e-6 * distance2.max(p.w.magnitude2()) + 1e-12 {
+            break;
+        }
+        if simplex
+            .vertices
+            .iter()
+            .any(|s| (s.w - p.w).magnitude2() < 1e-12)
+        {
+            break;
+        }
+
+        simplex.vertices.push(p);
+        match closest_to_origin(&mut simplex) {
+            Some(closest) => {
+                let closest_distance2 = closest.magnitude2();
+                if closest_distance2 >= best_distance2 {
+                    break;
+                }
+                best_distance2 = closest_distance2;
+                v = closest;
+            }
+            None => return (GjkResult::Overlapping, simplex),
+        }
+    }
+
+    let mut point_a = Vector3::zero();
+    let mut point_b = Vector3::zero();
+    for (vertex, &weight) in simplex.vertices.iter().zip(&simplex.weights) {
+        point_a += vertex.a * weight;
+        point_b += vertex.b * weight;
+    }
+    let result = GjkResult::Separated {
+        distance: v.magnitude(),
+        point_a,
+        point_b,
+    };
+    (result, simplex)
+}
+
+/// Shrinks the simplex to the feature nearest the origin and returns the nearest point,
+/// or `None` if the simplex is a tetrahedron around the origin
+fn closest_to_origin(simplex: &mut Simplex) -> Option<Vector3<f32>> {
+    let v = &simplex.vertices;
+    let (kept, weights): (Vec<usize>, Vec<f32>) = match v.len() {
+        1 => (vec![0], vec![1.0]),
+        2 => closest_on_segment(v[0].w, v[1].w, [0, 1]),
+        3 => closest_on_triangle(v[0].w, v[1].w, v[2].w, [0, 1, 2]),
+        _ => {
+            let faces = [[0, 1, 2, 3], [0, 3, 1, 2], [0, 2, 3, 1], [1, 3, 2, 0]];
+            let mut best: Option<(f32, Vec<usize>, Vec<f32>)> = None;
+            for face in faces.iter() {
+                let (a, b, c, d) = (v[face[0]].w, v[face[1]].w, v[face[2]].w, v[face[3]].w);
+                let normal = (b - a).cross(c - a);
+                let origin_side = normal.dot(-a);
+                let opposite_side = normal.dot(d - a);
+                // Only faces with the origin on the other side from the fourth vertex can be closest
+                if origin_side * opposite_side >= 0.0 {
+                    continue;
+                }
+                let (kept, weights) = closest_on_triangle(a, b, c, [face[0], face[1], face[2]]);
+                let point: Vector3<f32> = kept
+                    .iter()
+                    .zip(&weights)
+                    .map(|(&i, &w)| v[i].w * w)
+                    .fold(Vector3::zero(), |acc, p| acc + p);
+                let distance2 = point.magnitude2();
+                if !matches!(best, Some((closest, ..)) if closest <= distance2) {
+                    best = Some((distance2, kept, weights));
+                }
+            }
+            let (_, kept, weights) = match best {
+                Some(best) => best,
+                None => flat_tetrahedron(v)?,
+            };
+            (kept, weights)
+        }
+    };
+
+    let vertices: Vec<SimplexVertex> = kept.iter().map(|&i| simplex.vertices[i]).collect();
+    let closest = vertices
+        .iter()
+        .zip(&weights)
+        .fold(Vector3::zero(), |acc, (vertex, &w)| acc + vertex.w * w);
+    simplex.vertices = vertices;
+    simplex.weights = weights;
+    Some(closest)
+}
+
+/// Closest face of a tetrahedron squashed flat, which has no inside for the origin to be in.
+/// Returns `None` if it really does have volume, and so contains the origin
+fn flat_tetrahedron(v: &[SimplexVertex]) -> Option<(f32, Vec<usize>, Vec<f32>)> {
+    let (ab, ac, ad) = (v[1].w - v[0].w, v[2].w - v[0].w, v[3].w - v[0].w);
+    let volume = ab.cross(ac).dot(ad).abs();
+    if volume > 1e-6 * ab.magnitude() * ac.magnitude() * ad.magnitude() {
+        return None;
+    }
+    let faces = [[0, 1, 2], [0, 1, 3], [0, 2, 3], [1, 2, 3]];
+    faces
+        .iter()
+        .map(|face| {
+            let (kept, weights) =
+                closest_on_triangle(v[face[0]].w, v[face[1]].w, v[face[2]].w, *face);
+            let point = kept
+                .iter()
+                .zip(&weights)
+                .fold(Vector3::zero(), |acc, (&i, &w)| acc + v[i].w * w);
+            (point.magnitude2(), kept, weights)
+        })
+        .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap())
+}
+
+fn closest_on_segment(a: Vector3<f32>, b: Vector3<f32>, ids: [usize; 2]) -> (Vec<usize>, Vec<f32>) {
+    let ab = b - a;
+    let t = -a.dot(ab) / ab.magnitude2().max(1e-12);
+    if t <= 0.0 {
+        (vec![ids[0]], vec![1.0])
+    } else if t >= 1.0 {
+        (vec![ids[1]], vec![1.0])
+    } else {
+        (vec![ids[0], ids[1]], vec![1.0 - t, t])
+    }
+}
+
+/// Closest point on a triangle to the origin, from Real-Time Collision Detection 5.1.5
+fn closest_on_triangle(
+    a: Vector3<f32>,
+    b: Vector3<f32>,
+    c: Vector3<f32>,
+    ids: [usize; 3],
+) -> (Vec<usize>, Vec<f32>) {
+    let ab = b - a;
+    let ac = c - a;
+    let d1 = ab.dot(-a);
+    let d2 = ac.dot(-a);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return (vec![ids[0]], vec![1.0]);
+    }
+
+    let d3 = ab.dot(-b);
+    let d4 = ac.dot(-b);
+    if d3 >= 0.0 && d4 <= d3 {
+        return (vec![ids[1]], vec![1.0]);
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let v = d1 / (d1 - d3);
+        return (vec![ids[0], ids[1]], vec![1.0 - v, v]);
+    }
+
+    let d5 = ab.dot(-c);
+    let d6 = ac.dot(-c);
+    if d6 >= 0.0 && d5 <= d6 {
+        return (vec![ids[2]], vec![1.0]);
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let w = d2 / (d2 - d6);
+        return (vec![ids[0], ids[2]], vec![1.0 - w, w]);
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return (vec![ids[1], ids[2]], vec![1.0 - w, w]);
+    }
+
+    let denom = 1.0 / (va + vb + vc);
+    let v = vb * denom;
+    let w = vc * denom;
+    (ids.to_vec(), vec![1.0 - v - w, v, w])
+}
+
+/// How to push two overlapping shapes apart
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Penetration {
+    /// Points from the first shape to the second
+    pub normal: Vector3<f32>,
+    pub point_a: Vector3<f32>,
+    pub point_b: Vector3<f32>,
+}
+
+struct Face {
+    indices: [usize; 3],
+    normal: Vector3<f32>,
+    distance: f32,
+}
+
+pub(crate) fn epa<A: SupportMap, B: SupportMap>(
+    a: &A,
+    b: &B,
+    simplex: Simplex,
+) -> Option<Penetration> {
+    let mut vertices = simplex.vertices;
+    blow_up(a, b, &mut vertices)?;
+
+    let interior = vertices.iter().fold(Vector3::zero(), |acc, v| acc + v.w) / 4.0;
+    let make_face = |vertices: &[SimplexVertex], mut indices: [usize; 3]| -> Option<Face> {
+        let (p0, p1, p2) = (
+            vertices[indices[0]].w,
+            vertices[indices[1]].w,
+            vertices[indices[2]].w,
+        );
+        let mut normal = (p1 - p0).cross(p2 - p0);
+        let length = normal.magnitude();
+        if length < 1e-12 {
+            return None;
+        }
+        normal /= length;
+        if normal.dot(p0 - interior) < 0.0 {
+            normal = -normal;
+            indices.swap(1, 2);
+        }
+        Some(Face {
+            indices,
+            normal,
+            distance: normal.dot(p0),
+        })
+    };
+
+    let mut faces: Vec<Face> = [[0, 1, 2], [0, 3, 1], [0, 2, 3], [1, 3, 2]]
+        .iter()
+        .filter_map(|&f| make_face(&vertices, f))
+        .collect();
+    if faces.len() < 4 {
+        return None;
+    }
+
+    for _ in 0..MAX_ITERATIONS {
+        let closest = faces
+            .iter()
+            .enumerate()
+            .min_by(|x, y| x.1.distance.partial_cmp(&y.1.distance).unwrap())
+            .map(|(i, _)| i)?;
+        let normal = faces[closest].normal;
+        let distance = faces[closest].distance;
+
+        let p = support_vertex(a, b, normal);
+        if p.w.dot(normal) - distance < EPA_TOLERANCE {
+            break;
+        }
+
+        // Cut away every face the new point can see and stitch the hole shut around it
+        let new_index = vertices.len();
+        vertices.push(p);
+        let mut horizon: Vec<(usize, usize)> = vec![];
+        faces.retain(|face| {
+            if face.normal.dot(p.w - vertices[face.indices[0]].w) <= 0.0 {
+                return true;
+            }
+            for k in 0..3 {
+                let edge = (face.indices[k], face.indices[(k + 1) % 3]);
+                if let Some(shared) = horizon.iter().position(|&e| e == (edge.1, edge.0)) {
+                    horizon.swap_remove(shared);
+                } else {
+                    horizon.push(edge);
+                }
+            }
+            false
+        });
+        for (from, to) in horizon {
+            if let Some(face) = make_face(&vertices, [from, to, new_index]) {
+                faces.push(face);
+            }
+        }
+        if faces.is_empty() {
+            return None;
+        }
+    }
+
+    let face = faces
+        .iter()
+        .min_by(|x, y| x.distance.partial_cmp(&y.distance).unwrap())?;
+    let [i0, i1, i2] = face.indices;
+    let projected = face.normal * face.distance;
+    let (u, v, w) = barycentric(projected, vertices[i0].w, vertices[i1].w, vertices[i2].w);
+    Some(Penetration {
+        normal: face.normal,
+        point_a: vertices[i0].a * u + vertices[i1].a * v + vertices[i2].a * w,
+        point_b: vertices[i0].b * u + vertices[i1].b * v + vertices[i2].b * w,
+    })
+}
+
+/// Adds support points until the simplex is a tetrahedron with some volume
+fn blow_up<A: SupportMap, B: SupportMap>(
+    a: &A,
+    b: &B,
+    vertices: &mut Vec<SimplexVertex>,
+) -> Option<()> {
+    let axes = [
+        Vector3::unit_x(),
+        -Vector3::unit_x(),
+        Vector3::unit_y(),
+        -Vector3::unit_y(),
+        Vector3::unit_z(),
+        -Vector3::unit_z(),
+    ];
+
+    if vertices.len() == 1 {
+        for &axis in axes.iter() {
+            let p = support_vertex(a, b, axis);
+            if (p.w - vertices[0].w).magnitude2() > 1e-8 {
+                vertices.push(p);
+                break;
+            }
+        }
+    }
+
+    if vertices.len() == 2 {
+        let line = (vertices[1].w - vertices[0].w).normalize();
+        let least_aligned = if line.x.abs() < 0.57 {
+            Vector3::unit_x()
+        } else if line.y.abs() < 0.57 {
+            Vector3::unit_y()
+        } else {
+            Vector3::unit_z()
+        };
+        let mut direction = line.cross(least_aligned).normalize();
+        // A sixth of a turn about the line, spelled out so no platform sin or cos is involved
+        let turn = Quaternion::from_sv(0.866_025_4, line * 0.5);
+        for _ in 0..6 {
+            let p = support_vertex(a, b, direction);
+            if (p.w - vertices[0].w).cross(line).magnitude2() > 1e-8 {
+                vertices.push(p);
+                break;
+            }
+            direction = turn.rotate_vector(direction);
+        }
+    }
+
+    if vertices.len() == 3 {
+        let normal = (vertices[1].w - vertices[0].w).cross(vertices[2].w - vertices[0].w);
+        for &direction in [normal, -normal].iter() {
+            let p = support_vertex(a, b, direction);
+            if normal.dot(p.w - vertices[0].w).abs() > 1e-8 {
+                vertices.push(p);
+                break;
+            }
+        }
+    }
+
+    if vertices.len() == 4 {
+        Some(())
+    } else {
+        None
+    }
+}
+
+fn barycentric(
+    p: Vector3<f32>,
+    a: Vector3<f32>,
+    b: Vector3<f32>,
+    c: Vector3<f32>,
+) -> (f32, f32, f32) {
+    let v0 = b - a;
+    let v1 = c - a;
+    let v2 = p - a;
+    let d00 = v0.dot(v0);
+    let d01 = v0.dot(v1);
+    let d11 = v1.dot(v1);
+    let d20 = v2.dot(v0);
+    let d21 = v2.dot(v1);
+    let denom = d00 * d11 - d01 * d01;
+    if denom.abs() < 1e-12 {
+        return (1.0, 0.0, 0.0);
+    }
+    let v = (d11 * d20 - d01 * d21) / denom;
+    let w = (d00 * d21 - d01 * d20) / denom;
+    (1.0 - v - w, v, w)
+}
@@ -0,0 +1,167 @@
+use super::body::RigidBody;
+use super::contact::ContactManifold;
+use super::joint::Joint;
+use cgmath::InnerSpace;
+
+#[derive(Debug, Copy, Clone)]
+pub struct SleepConfig {
+    pub enabled: bool,
+    /// Bodies moving slower than this count as resting
+    pub linear_threshold: f32,
+    pub angular_threshold: f32,
+    /// How long a whole island has to rest before it goes to sleep
+    pub time_to_sleep: f32,
+}
+
+impl Default for SleepConfig {
+    fn default() -> Self {
+        SleepConfig {
+            enabled: true,
+            linear_threshold: 0.05,
+            angular_threshold: 0.05,
+            time_to_sleep: 0.5,
+        }
+    }
+}
+
+/// What the last step simulated
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PhysicsStats {
+    pub active_bodies: usize,
+    pub sleeping_bodies: usize,
+    /// Groups of dynamic bodies tied together by contacts and joints, each sleeps and wakes as one
+    pub active_islands: usize,
+    pub sleeping_islands: usize,
+    pub contacts: usize,
+    pub joints: usize,
+}
+
+/// Union find over body slots, with dynamic bodies joined when something ties them together
+struct Islands {
+    parent: Vec<usize>,
+}
+
+impl Islands {
+    fn new(count: usize) -> Islands {
+        Islands {
+            parent: (0..count).collect(),
+        }
+    }
+
+    fn root(&mut self, mut slot: usize) -> usize {
+        while self.parent[slot] != slot {
+            self.parent[slot] = self.parent[self.parent[slot]];
+            slot = self.parent[slot];
+        }
+        slot
+    }
+
+    fn join(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.root(a), self.root(b));
+        self.parent[a] = b;
+    }
+}
+
+/// Groups the dynamic bodies into islands, wakes every island with something awake or moving
+/// against it and puts islands that have rested long enough to sleep
+pub(crate) fn update_islands(
+    bodies: &mut [Option<RigidBody>],
+    manifolds: &[ContactManifold],
+    joints: &[Option<Joint>],
+    config: &SleepConfig,
+    dt: f32,
+) -> PhysicsStats {
+    let mut islands = Islands::new(bodies.len());
+    // A kinematic body pushing on an island wakes it, static bodies never do
+    let mut disturbed = vec![false; bodies.len()];
+    let pairs = manifolds
+        .iter()
+        .filter(|m| !m.points.is_empty())
+        .map(|m| (m.body_a.0, m.body_b.0))
+        .chain(
+            joints
+                .iter()
+                .flatten()
+                .filter(|j| !j.is_broken())
+                .map(|j| (j.body_a.0, j.body_b.0)),
+        );
+    for (a, b) in pairs {
+        let (body_a, body_b) = match (&bodies[a], &bodies[b]) {
+            (Some(body_a), Some(body_b)) => (body_a, body_b),
+            _ => continue,
+        };
+        match (body_a.is_dynamic(), body_b.is_dynamic()) {
+            (true, true) => islands.join(a, b),
+            (true, false) => disturbed[a] |= is_moving(body_b),
+            (false, true) => disturbed[b] |= is_moving(body_a),
+            (false, false) => {}
+        }
+    }
+
+    // Per island root: whether anything in it is awake or asleep, and the shortest time any of it
+    // has rested
+    let mut awake = vec![false; bodies.len()];
+    let mut asleep = vec![false; bodies.len()];
+    let mut rested = vec![f32::INFINITY; bodies.len()];
+    for (slot, body) in bodies.iter_mut().enumerate() {
+        let body = match body {
+            Some(body) if body.is_dynamic() => body,
+            _ => continue,
+        };
+        if !body.is_sleeping() {
+            let slow = body.linear_velocity.magnitude2() < config.linear_threshold.powi(2)
+                && body.angular_velocity.magnitude2() < config.angular_threshold.powi(2);
+            body.sleep_time = if slow && body.can_sleep && config.enabled {
+                body.sleep_time + dt
+            } else {
+                0.0
+            };
+        }
+        let root = islands.root(slot);
+        awake[root] |= !body.is_sleeping() || disturbed[slot];
+        asleep[root] |= body.is_sleeping();
+        if disturbed[slot] {
+            rested[root] = 0.0;
+        } else if !body.is_sleeping() {
+            rested[root] = rested[root].min(body.sleep_time);
+        }
+    }
+
+    let mut stats = PhysicsStats {
+        contacts: manifolds.len(),
+        joints: joints.iter().flatten().count(),
+        ..PhysicsStats::default()
+    };
+    let mut counted = vec![false; bodies.len()];
+    for (slot, body) in bodies.iter_mut().enumerate() {
+        let body = match body {
+            Some(body) if body.is_dynamic() => body,
+            _ => continue,
+        };
+        let root = islands.root(slot);
+        if awake[root] && asleep[root] {
+            // Part of the island is moving, so all of it has to
+            body.wake_up();
+        } else if awake[root] && rested[root] >= config.time_to_sleep {
+            body.sleep();
+        }
+        if body.is_sleeping() {
+            stats.sleeping_bodies += 1;
+        } else {
+            stats.active_bodies += 1;
+        }
+        if !counted[root] {
+            counted[root] = true;
+            if body.is_sleeping() {
+                stats.sleeping_islands += 1;
+            } else {
+                stats.active_islands += 1;
+            }
+        }
+    }
+    stats
+}
+
+fn is_moving(body: &RigidBody) -> bool {
+    body.linear_velocity.magnitude2() > 0.0 || body.angular_velocity.magnitude2() > 0.0
+}
@@ -0,0 +1,673 @@
+use super::body::{BodyHandle, RigidBody};
+use super::solver::tangent_basis;
+use crate::math;
+use cgmath::{InnerSpace, One, Quaternion, Vector3, Zero};
+use std::f32::consts::PI;
+
+/// Limits closer together than this hold the joint in place
+const LIMIT_SLOP: f32 = 0.02;
+
+/// Rows a joint can solve at most, their impulses are kept between steps for warm starting
+pub(crate) const MAX_JOINT_ROWS: usize = 8;
+
+const LINEAR_ROWS: usize = 0;
+const ANGULAR_ROWS: usize = 3;
+const LIMIT_ROW: usize = 6;
+const MOTOR_ROW: usize = 7;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct JointHandle(pub(crate) usize);
+
+/// Drives a hinge or slider towards a speed, using no more than `max_force` to get there
+#[derive(Debug, Copy, Clone)]
+pub struct JointMotor {
+    /// Radians per second for hinges, units per second for sliders
+    pub speed: f32,
+    /// Torque for hinges, force for sliders
+    pub max_force: f32,
+}
+
+/// Axes are in body A's space, limits are radians for angles and world units for distances
+#[derive(Debug, Copy, Clone)]
+pub enum JointKind {
+    /// Glues the bodies together
+    Fixed,
+    /// Turns about `axis` only, like a door
+    Hinge {
+        axis: Vector3<f32>,
+        limits: Option<(f32, f32)>,
+        motor: Option<JointMotor>,
+    },
+    /// Turns any way, optionally keeping B's copy of `axis` within `cone` radians of A's
+    Ball {
+        axis: Vector3<f32>,
+        cone: Option<f32>,
+    },
+    /// Keeps the anchors between `min` and `max` apart, a rope has `min` at zero and a rod has
+    /// them equal
+    Distance { min: f32, max: f32 },
+    /// Slides along `axis` only, without turning
+    Prismatic {
+        axis: Vector3<f32>,
+        limits: Option<(f32, f32)>,
+        motor: Option<JointMotor>,
+    },
+    /// Pulls the anchors towards `rest_length` apart, soft where the others are rigid
+    Spring {
+        rest_length: f32,
+        stiffness: f32,
+        damping: f32,
+    },
+}
+
+/// Ties two bodies together, added with `PhysicsWorld::add_joint`. Body B may be static to pin
+/// body A to the world
+#[derive(Debug, Clone)]
+pub struct Joint {
+    pub kind: JointKind,
+    pub body_a: BodyHandle,
+    pub body_b: BodyHandle,
+    /// Where the joint sits on each body, in body space
+    pub anchor_a: Vector3<f32>,
+    pub anchor_b: Vector3<f32>,
+    /// The joint breaks once holding the bodies together takes more force than this
+    pub break_force: f32,
+    /// Whether the colliders of the two bodies still collide with each other
+    pub collide_connected: bool,
+    /// B's rotation relative to A when the joint was added, the rest pose of fixed and hinge joints
+    pub(crate) reference: Quaternion<f32>,
+    pub(crate) impulses: [f32; MAX_JOINT_ROWS],
+    force: f32,
+    broken: bool,
+}
+
+/// One direction the solver keeps the bodies' relative velocity in. The velocity along the row is
+/// `linear` against the velocity difference of the anchors plus the `angular` parts against each
+/// body's spin
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct JointRow {
+    pub(crate) slot: usize,
+    pub(crate) linear: Vector3<f32>,
+    pub(crate) angular_a: Vector3<f32>,
+    pub(crate) angular_b: Vector3<f32>,
+    /// The row aims for minus this velocity
+    pub(crate) bias: f32,
+    /// Bounds on the row's total impulse for the step
+    pub(crate) lower: f32,
+    pub(crate) upper: f32,
+    /// Softness of a spring row, zero for rigid ones
+    pub(crate) gamma: f32,
+}
+
+impl JointRow {
+    fn new(
+        slot: usize,
+        linear: Vector3<f32>,
+        angular_a: Vector3<f32>,
+        angular_b: Vector3<f32>,
+    ) -> JointRow {
+        JointRow {
+            slot,
+            linear,
+            angular_a,
+            angular_b,
+            bias: 0.0,
+            lower: f32::NEG_INFINITY,
+            upper: f32::INFINITY,
+            gamma: 0.0,
+        }
+    }
+
+    /// Keeps the anchors at `r_a` and `r_b` from moving apart along `direction`
+    fn linear(
+        slot: usize,
+        r_a: Vector3<f32>,
+        r_b: Vector3<f32>,
+        direction: Vector3<f32>,
+    ) -> JointRow {
+        JointRow::new(slot, direction, r_a.cross(direction), r_b.cross(direction))
+    }
+
+    fn angular(slot: usize, axis: Vector3<f32>) -> JointRow {
+        JointRow::new(slot, Vector3::zero(), axis, axis)
+    }
+
+    /// Drives the row back to zero error, by `factor` of the error each step
+    fn with_error(mut self, error: f32, factor: f32, dt: f32) -> JointRow {
+        self.bias = factor * error / dt;
+        self
+    }
+
+    /// Keeps `value`, which the row's velocity changes, between `lower` and `upper` by pushing
+    /// against whichever end is closer
+    fn limit(mut self, value: f32, lower: f32, upper: f32, factor: f32, dt: f32) -> JointRow {
+        if upper - lower < LIMIT_SLOP {
+            return self.with_error(value - lower, factor, dt);
+        }
+        let (error, below) = if value - lower < upper - value {
+            (value - lower, true)
+        } else {
+            (upper - value, false)
+        };
+        // Room left before the limit may be used up this step, so fast joints stop right at it
+        // instead of overshooting. Past it the joint is pushed back gently
+        let correction = if error > 0.0 {
+            error / dt
+        } else {
+            factor * error / dt
+        };
+        if below {
+            self.bias = correction;
+            self.lower = 0.0;
+        } else {
+            self.bias = -correction;
+            self.upper = 0.0;
+        }
+        self
+    }
+
+    fn motor(mut self, motor: JointMotor, dt: f32) -> JointRow {
+        self.bias = -motor.speed;
+        self.lower = -motor.max_force * dt;
+        self.upper = motor.max_force * dt;
+        self
+    }
+}
+
+impl Joint {
+    pub fn new(kind: JointKind, body_a: BodyHandle, body_b: BodyHandle) -> Joint {
+        Joint {
+            kind,
+            body_a,
+            body_b,
+            anchor_a: Vector3::zero(),
+            anchor_b: Vector3::zero(),
+            break_force: f32::INFINITY,
+            collide_connected: false,
+            reference: Quaternion::one(),
+            impulses: [0.0; MAX_JOINT_ROWS],
+            force: 0.0,
+            broken: false,
+        }
+    }
+
+    pub fn fixed(body_a: BodyHandle, body_b: BodyHandle) -> Joint {
+        Joint::new(JointKind::Fixed, body_a, body_b)
+    }
+
+    pub fn hinge(body_a: BodyHandle, body_b: BodyHandle, axis: Vector3<f32>) -> Joint {
+        let kind = JointKind::Hinge {
+            axis: axis.normalize(),
+            limits: None,
+            motor: None,
+        };
+        Joint::new(kind, body_a, body_b)
+    }
+
+    pub fn ball(body_a: BodyHandle, body_b: BodyHandle) -> Joint {
+        let kind = JointKind::Ball {
+            axis: Vector3::unit_y(),
+            cone: None,
+        };
+        Joint::new(kind, body_a, body_b)
+    }
+
+    pub fn distance(body_a: BodyHandle, body_b: BodyHandle, min: f32, max: f32) -> Joint {
+        Joint::new(JointKind::Distance { min, max }, body_a, body_b)
+    }
+
+    /// Stops the anchors getting further than `length` apart but lets them come together
+    pub fn rope(body_a: BodyHandle, body_b: BodyHandle, length: f32) -> Joint {
+        Joint::distance(body_a, body_b, 0.0, length)
+    }
+
+    pub fn prismatic(body_a: BodyHandle, body_b: BodyHandle, axis: Vector3<f32>) -> Joint {
+        let kind = JointKind::Prismatic {
+            axis: axis.normalize(),
+            limits: None,
+            motor: None,
+        };
+        Joint::new(kind, body_a, body_b)
+    }
+
+    /// `stiffness` is force per unit of stretch and `damping` force per unit of stretching speed
+    pub fn spring(
+        body_a: BodyHandle,
+        body_b: BodyHandle,
+        rest_length: f32,
+        stiffness: f32,
+        damping: f32,
+    ) -> Joint {
+        let kind = JointKind::Spring {
+            rest_length,
+            stiffness,
+            damping,
+        };
+        Joint::new(kind, body_a, body_b)
+    }
+
+    pub fn with_anchors(mut self, anchor_a: Vector3<f32>, anchor_b: Vector3<f32>) -> Joint {
+        self.anchor_a = anchor_a;
+        self.anchor_b = anchor_b;
+        self
+    }
+
+    /// Angle limits for hinges, distance limits for sliders, other joints ignore them
+    pub fn with_limits(mut self, lower: f32, upper: f32) -> Joint {
+        match &mut self.kind {
+            JointKind::Hinge { limits, .. } | JointKind::Prismatic { limits, .. } => {
+                *limits = Some((lower, upper))
+            }
+            _ => {}
+        }
+        self
+    }
+
+    /// Only hinges and sliders have motors
+    pub fn with_motor(mut self, speed: f32, max_force: f32) -> Joint {
+        match &mut self.kind {
+            JointKind::Hinge { motor, .. } | JointKind::Prismatic { motor, .. } => {
+                *motor = Some(JointMotor { speed, max_force })
+            }
+            _ => {}
+        }
+        self
+    }
+
+    /// Keeps a ball joint's B within `angle` radians of `axis`, which is in A's space
+    pub fn with_cone(mut self, axis: Vector3<f32>, angle: f32) -> Joint {
+        if let JointKind::Ball { .. } = self.kind {
+            self.kind = JointKind::Ball {
+                axis: axis.normalize(),
+                cone: Some(angle),
+            };
+        }
+        self
+    }
+
+    pub fn with_break_force(mut self, break_force: f32) -> Joint {
+        self.break_force = break_force;
+        self
+    }
+
+    pub fn with_collide_connected(mut self, collide_connected: bool) -> Joint {
+        self.collide_connected = collide_connected;
+        self
+    }
+
+    /// Broken joints stay in the world doing nothing until removed
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Force the joint used to hold the bodies together during the last step
+    pub fn force(&self) -> f32 {
+        self.force
+    }
+
+    /// Hinge angle or slider offset from where the joint was added
+    pub fn position(&self, a: &RigidBody, b: &RigidBody) -> f32 {
+        match self.kind {
+            JointKind::Hinge { axis, .. } => self.twist(a, b, a.rotation * axis),
+            JointKind::Prismatic { axis, .. } => {
+                (b.to_world(self.anchor_b) - a.to_world(self.anchor_a)).dot(a.rotation * axis)
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Called by the solver with the force it just used, breaks the joint past `break_force`
+    pub(crate) fn set_force(&mut self, force: f32) {
+        self.force = force;
+        if force > self.break_force {
+            self.broken = true;
+            self.impulses = [0.0; MAX_JOINT_ROWS];
+        }
+    }
+
+    /// How far B has turned away from its rest pose relative to A, in world space
+    fn rotation_error(&self, a: &RigidBody, b: &RigidBody) -> Quaternion<f32> {
+        let error = b.rotation * (a.rotation * self.reference).conjugate();
+        // Both signs are the same rotation, the positive one is the short way round
+        if error.s < 0.0 {
+            -error
+        } else {
+            error
+        }
+    }
+
+    /// Angle B has turned about `axis` away from its rest pose
+    fn twist(&self, a: &RigidBody, b: &RigidBody, axis: Vector3<f32>) -> f32 {
+        let error = self.rotation_error(a, b);
+        let angle = 2.0 * math::atan2(error.v.dot(axis), error.s);
+        if angle > PI {
+            angle - 2.0 * PI
+        } else if angle < -PI {
+            angle + 2.0 * PI
+        } else {
+            angle
+        }
+    }
+
+    /// The rows the solver works through this step, `factor` is the fraction of any drift fixed
+    pub(crate) fn rows(&self, a: &RigidBody, b: &RigidBody, factor: f32, dt: f32) -> Vec<JointRow> {
+        let r_a = a.rotation * self.anchor_a;
+        let r_b = b.rotation * self.anchor_b;
+        let separation = (b.position + r_b) - (a.position + r_a);
+        let mut rows = vec![];
+
+        let point = |rows: &mut Vec<JointRow>| {
+            let axes = [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()];
+            for (i, &axis) in axes.iter().enumerate() {
+                rows.push(
+                    JointRow::linear(LINEAR_ROWS + i, r_a, r_b, axis).with_error(
+                        separation.dot(axis),
+                        factor,
+                        dt,
+                    ),
+                );
+            }
+        };
+        let lock_rotation = |rows: &mut Vec<JointRow>| {
+            let error = rotation_vector(self.rotation_error(a, b));
+            let axes = [Vector3::unit_x(), Vector3::unit_y(), Vector3::unit_z()];
+            for (i, &axis) in axes.iter().enumerate() {
+                rows.push(JointRow::angular(ANGULAR_ROWS + i, axis).with_error(
+                    error.dot(axis),
+                    factor,
+                    dt,
+                ));
+            }
+        };
+
+        match self.kind {
+            JointKind::Fixed => {
+                point(&mut rows);
+                lock_rotation(&mut rows);
+            }
+            JointKind::Hinge {
+                axis,
+                limits,
+                motor,
+            } => {
+                point(&mut rows);
+                let axis_a = a.rotation * axis;
+                let axis_b = b.rotation * (self.reference.conjugate() * axis);
+                let drift = axis_a.cross(axis_b);
+                for (i, &tangent) in tangent_basis(axis_a).iter().enumerate() {
+                    rows.push(JointRow::angular(ANGULAR_ROWS + i, tangent).with_error(
+                        drift.dot(tangent),
+                        factor,
+                        dt,
+                    ));
+                }
+                if let Some((lower, upper)) = limits {
+                    let angle = self.twist(a, b, axis_a);
+                    rows.push(
+                        JointRow::angular(LIMIT_ROW, axis_a).limit(angle, lower, upper, factor, dt),
+                    );
+                }
+                if let Some(motor) = motor {
+                    rows.push(JointRow::angular(MOTOR_ROW, axis_a).motor(motor, dt));
+                }
+            }
+            JointKind::Ball { axis, cone } => {
+                point(&mut rows);
+                if let Some(cone) = cone {
+                    let axis_a = a.rotation * axis;
+                    let axis_b = b.rotation * (self.reference.conjugate() * axis);
+                    let bend = axis_a.cross(axis_b);
+                    let angle = math::atan2(bend.magnitude(), axis_a.dot(axis_b));
+                    // Straight down the axis there is no way to bend, and nothing to limit yet
+                    if bend.magnitude2() > 1e-12 {
+                        let row = JointRow::angular(LIMIT_ROW, bend.normalize());
+                        rows.push(row.limit(angle, f32::NEG_INFINITY, cone, factor, dt));
+                    }
+                }
+            }
+            JointKind::Distance { min, max } => {
+                let (direction, length) = direction_and_length(separation);
+                let row = JointRow::linear(LINEAR_ROWS, r_a, r_b, direction);
+                rows.push(row.limit(length, min, max, factor, dt));
+            }
+            JointKind::Prismatic {
+                axis,
+                limits,
+                motor,
+            } => {
+                // A turns about its centre, so for A the anchors meet at B's anchor
+                let r_a = r_a + separation;
+                let axis_a = a.rotation * axis;
+                for (i, &tangent) in tangent_basis(axis_a).iter().enumerate() {
+                    rows.push(
+                        JointRow::linear(LINEAR_ROWS + i, r_a, r_b, tangent).with_error(
+                            separation.dot(tangent),
+                            factor,
+                            dt,
+                        ),
+                    );
+                }
+                lock_rotation(&mut rows);
+                let slide = JointRow::linear(LIMIT_ROW, r_a, r_b, axis_a);
+                if let Some((lower, upper)) = limits {
+                    let offset = separation.dot(axis_a);
+                    rows.push(slide.limit(offset, lower, upper, factor, dt));
+                }
+                if let Some(motor) = motor {
+                    rows.push(
+                        JointRow {
+                            slot: MOTOR_ROW,
+                            ..slide
+                        }
+                        .motor(motor, dt),
+                    );
+                }
+            }
+            JointKind::Spring {
+                rest_length,
+                stiffness,
+                damping,
+            } => {
+                // Soft constraint: the spring and damper become a softness and a bias, which
+                // stays stable however stiff the spring is
+                let softness = dt * (damping + dt * stiffness);
+                if softness > 0.0 {
+                    let (direction, length) = direction_and_length(separation);
+                    let mut row = JointRow::linear(LINEAR_ROWS, r_a, r_b, direction);
+                    row.gamma = 1.0 / softness;
+                    row.bias = (length - rest_length) * dt * stiffness * row.gamma;
+                    rows.push(row);
+                }
+            }
+        }
+        rows
+    }
+}
+
+fn direction_and_length(separation: Vector3<f32>) -> (Vector3<f32>, f32) {
+    let length = separation.magnitude();
+    if length > 1e-6 {
+        (separation / length, length)
+    } else {
+        (Vector3::unit_y(), length)
+    }
+}
+
+/// Axis times angle of a rotation
+fn rotation_vector(rotation: Quaternion<f32>) -> Vector3<f32> {
+    let sin = rotation.v.magnitude();
+    if sin < 1e-6 {
+        return rotation.v * 2.0;
+    }
+    rotation.v * (2.0 * math::atan2(sin, rotation.s) / sin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::MassProperties;
+    use super::*;
+    use cgmath::{Rad, Rotation3};
+
+    const FACTOR: f32 = 0.2;
+    const DT: f32 = 1.0 / 60.0;
+
+    fn body(position: Vector3<f32>, rotation: Quaternion<f32>) -> RigidBody {
+        RigidBody::dynamic(position, MassProperties::sphere(1.0, 0.5)).with_rotation(rotation)
+    }
+
+    fn turned(axis: Vector3<f32>, angle: f32) -> Quaternion<f32> {
+        Quaternion::from_axis_angle(axis.normalize(), Rad(angle))
+    }
+
+    fn find_row(rows: &[JointRow], slot: usize) -> JointRow {
+        *rows.iter().find(|row| row.slot == slot).unwrap()
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn rotation_vectors_are_axis_times_angle() {
+        let axis = Vector3::new(1.0, -2.0, 0.5).normalize();
+        for &angle in [1e-4, 0.3, 2.0, 3.1].iter() {
+            let vector = rotation_vector(turned(axis, angle));
+            assert!(
+                (vector - axis * angle).magnitude() < 1e-4,
+                "{} radians",
+                angle
+            );
+        }
+        assert_eq!(rotation_vector(Quaternion::one()), Vector3::zero());
+    }
+
+    #[test]
+    fn positions_measure_from_the_rest_pose() {
+        let (a, b) = (BodyHandle(0), BodyHandle(1));
+        let axis = Vector3::new(0.0, 0.0, 1.0);
+        let base = body(Vector3::zero(), turned(Vector3::unit_x(), 0.4));
+
+        let hinge = Joint::hinge(a, b, axis);
+        for &angle in [-2.5, -0.7, 0.0, 0.7, 2.5].iter() {
+            let door = body(
+                Vector3::zero(),
+                turned(base.rotation * axis, angle) * base.rotation,
+            );
+            assert!(
+                close(hinge.position(&base, &door), angle),
+                "{} radians",
+                angle
+            );
+        }
+        // Past half a turn it is the short way round from the other side
+        let door = body(
+            Vector3::zero(),
+            turned(base.rotation * axis, 4.0) * base.rotation,
+        );
+        assert!(close(hinge.position(&base, &door), 4.0 - 2.0 * PI));
+
+        let slider = Joint::prismatic(a, b, axis);
+        let carriage = body(base.rotation * axis * -0.3, base.rotation);
+        assert!(close(slider.position(&base, &carriage), -0.3));
+    }
+
+    #[test]
+    fn rows_push_back_against_the_error() {
+        let (a, b) = (BodyHandle(0), BodyHandle(1));
+        let still = body(Vector3::zero(), Quaternion::one());
+        let drifted = body(
+            Vector3::new(0.1, -0.05, 0.0),
+            turned(Vector3::unit_z(), 0.2),
+        );
+
+        let rows = Joint::fixed(a, b).rows(&still, &drifted, FACTOR, DT);
+        assert_eq!(rows.len(), 6);
+        let expected = [0.1, -0.05, 0.0, 0.0, 0.0, 0.2];
+        for (slot, &error) in expected.iter().enumerate() {
+            let row = find_row(&rows, slot);
+            assert!(close(row.bias, FACTOR * error / DT), "slot {}", slot);
+            assert_eq!((row.lower, row.upper), (f32::NEG_INFINITY, f32::INFINITY));
+        }
+
+        // A hinge about z lets the turn through, only the anchors are pulled together
+        let hinge = Joint::hinge(a, b, Vector3::unit_z());
+        let rows = hinge.rows(&still, &drifted, FACTOR, DT);
+        assert_eq!(rows.len(), 5);
+        for slot in ANGULAR_ROWS..ANGULAR_ROWS + 2 {
+            assert!(find_row(&rows, slot).bias.abs() < 1e-4);
+        }
+
+        // At rest there is nothing to fix
+        let rows = Joint::fixed(a, b).rows(&still, &still, FACTOR, DT);
+        assert!(rows.iter().all(|row| row.bias == 0.0));
+    }
+
+    #[test]
+    fn limits_stop_at_the_nearer_end() {
+        let (a, b) = (BodyHandle(0), BodyHandle(1));
+        let base = body(Vector3::zero(), Quaternion::one());
+        let hinge = Joint::hinge(a, b, Vector3::unit_y()).with_limits(-0.2, 0.3);
+        let limit = |angle: f32| {
+            let door = body(Vector3::zero(), turned(Vector3::unit_y(), angle));
+            find_row(&hinge.rows(&base, &door, FACTOR, DT), LIMIT_ROW)
+        };
+
+        // Inside, the room left can all be used up in one step
+        let row = limit(-0.1);
+        assert!(close(row.bias, 0.1 / DT));
+        assert_eq!((row.lower, row.upper), (0.0, f32::INFINITY));
+        let row = limit(0.25);
+        assert!(close(row.bias, -0.05 / DT));
+        assert_eq!((row.lower, row.upper), (f32::NEG_INFINITY, 0.0));
+
+        // Outside, only a fraction of the overshoot is pushed back each step
+        let row = limit(-0.5);
+        assert!(close(row.bias, FACTOR * -0.3 / DT));
+        assert_eq!(row.lower, 0.0);
+        let row = limit(0.5);
+        assert!(close(row.bias, FACTOR * 0.2 / DT));
+        assert_eq!(row.upper, 0.0);
+
+        // Limits all but on top of each other hold the joint in both directions
+        let locked = Joint::hinge(a, b, Vector3::unit_y()).with_limits(0.1, 0.11);
+        let door = body(Vector3::zero(), turned(Vector3::unit_y(), 0.3));
+        let row = find_row(&locked.rows(&base, &door, FACTOR, DT), LIMIT_ROW);
+        assert!(close(row.bias, FACTOR * 0.2 / DT));
+        assert_eq!((row.lower, row.upper), (f32::NEG_INFINITY, f32::INFINITY));
+    }
+
+    #[test]
+    fn ropes_only_pull_once_taut_and_cones_only_once_bent() {
+        let (a, b) = (BodyHandle(0), BodyHandle(1));
+        let base = body(Vector3::zero(), Quaternion::one());
+        let rope = Joint::rope(a, b, 1.5);
+        let end = |x: f32| body(Vector3::new(x, 0.0, 0.0), Quaternion::one());
+
+        let slack = rope.rows(&base, &end(1.0), FACTOR, DT)[0];
+        assert!(close(slack.bias, -0.5 / DT));
+        assert_eq!(slack.upper, 0.0);
+        let stretched = rope.rows(&base, &end(2.0), FACTOR, DT)[0];
+        assert!(close(stretched.bias, FACTOR * 0.5 / DT));
+        assert_eq!(stretched.linear, Vector3::unit_x());
+
+        let ball = Joint::ball(a, b).with_cone(Vector3::unit_y(), 0.5);
+        assert_eq!(ball.rows(&base, &base, FACTOR, DT).len(), 3);
+        let bent = body(Vector3::zero(), turned(Vector3::unit_z(), 0.8));
+        let row = find_row(&ball.rows(&base, &bent, FACTOR, DT), LIMIT_ROW);
+        assert!(close(row.bias, FACTOR * 0.3 / DT));
+        assert_eq!(row.upper, 0.0);
+        assert!((row.angular_a - Vector3::unit_z()).magnitude() < 1e-4);
+    }
+
+    #[test]
+    fn joints_break_past_their_break_force() {
+        let mut joint = Joint::fixed(BodyHandle(0), BodyHandle(1)).with_break_force(100.0);
+        joint.impulses = [1.0; MAX_JOINT_ROWS];
+        joint.set_force(100.0);
+        assert!(!joint.is_broken());
+        assert_eq!(joint.force(), 100.0);
+        joint.set_force(100.5);
+        assert!(joint.is_broken());
+        assert_eq!(joint.impulses, [0.0; MAX_JOINT_ROWS]);
+    }
+}
@@ -0,0 +1,242 @@
+use super::body::BodyHandle;
+use super::collider::{Collider, ColliderHandle};
+use super::contact;
+use super::gjk::{gjk, GjkResult, ShapeCore, SupportMap};
+use super::shape::{Isometry, Shape, Triangle, TriangleSource};
+use crate::EntityIndex;
+use cgmath::{InnerSpace, Vector3};
+
+/// A cast counts as touching once the gap is this small
+const CAST_TOLERANCE: f32 = 1e-3;
+
+/// Casts aim to stop this far short of the surface. Landing exactly on it would leave GJK
+/// with overlapping shapes and no closest points to report
+const CAST_TARGET: f32 = 0.5 * CAST_TOLERANCE;
+
+/// Grazing casts creep towards the surface forever, give up on them after this many steps
+const MAX_CAST_ITERATIONS: usize = 32;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Ray {
+    pub origin: Vector3<f32>,
+    /// Always unit length, so distances along the ray are in world units
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    pub fn new(origin: Vector3<f32>, direction: Vector3<f32>) -> Ray {
+        Ray {
+            origin,
+            direction: direction.normalize(),
+        }
+    }
+
+    pub fn at(&self, distance: f32) -> Vector3<f32> {
+        self.origin + self.direction * distance
+    }
+}
+
+/// Decides which colliders a query can see
+#[derive(Debug, Copy, Clone)]
+pub struct QueryFilter<'a> {
+    /// Only colliders on at least one of these layers are hit
+    pub mask: u32,
+    /// Colliders on these bodies are skipped, such as the body asking the question
+    pub exclude: &'a [BodyHandle],
+    /// Sensors have no surface to stand on or block a view, so they are only seen when asked for
+    pub include_sensors: bool,
+}
+
+impl Default for QueryFilter<'static> {
+    fn default() -> Self {
+        QueryFilter::new()
+    }
+}
+
+impl QueryFilter<'static> {
+    pub fn new() -> QueryFilter<'static> {
+        QueryFilter {
+            mask: u32::MAX,
+            exclude: &[],
+            include_sensors: false,
+        }
+    }
+}
+
+impl<'a> QueryFilter<'a> {
+    pub fn with_mask(mut self, mask: u32) -> QueryFilter<'a> {
+        self.mask = mask;
+        self
+    }
+
+    pub fn excluding<'b>(self, bodies: &'b [BodyHandle]) -> QueryFilter<'b> {
+        QueryFilter {
+            mask: self.mask,
+            exclude: bodies,
+            include_sensors: self.include_sensors,
+        }
+    }
+
+    pub fn with_sensors(mut self, include_sensors: bool) -> QueryFilter<'a> {
+        self.include_sensors = include_sensors;
+        self
+    }
+
+    pub(crate) fn accepts(&self, collider: &Collider) -> bool {
+        (self.include_sensors || !collider.sensor)
+            && collider.layers & self.mask != 0
+            && !self.exclude.contains(&collider.body)
+    }
+}
+
+/// Where a query touched a collider
+#[derive(Debug, Copy, Clone)]
+pub struct QueryHit {
+    pub collider: ColliderHandle,
+    pub body: BodyHandle,
+    /// The entity the body drives, if any
+    pub entity: Option<EntityIndex>,
+    /// On the surface of the collider that was hit
+    pub position: Vector3<f32>,
+    /// Points out of the collider that was hit
+    pub normal: Vector3<f32>,
+    /// How far the ray or shape travelled before it hit, overlap tests report how deep they
+    /// overlap as a negative distance
+    pub distance: f32,
+}
+
+/// The distance `a` can move along `direction` before it touches `b`, along with the point and
+/// normal on `b` where it touches. Only `a` has to be convex
+pub(crate) fn cast_shape(
+    a: &Shape,
+    iso_a: &Isometry,
+    direction: Vector3<f32>,
+    max_distance: f32,
+    b: &Shape,
+    iso_b: &Isometry,
+) -> Option<(f32, Vector3<f32>, Vector3<f32>)> {
+    if !a.is_convex() {
+        return None;
+    }
+    match b {
+        Shape::TriMesh(mesh) => {
+            cast_against_mesh(a, iso_a, direction, max_distance, &**mesh, iso_b)
+        }
+        Shape::HeightField(field) => match a {
+            // Rays walk the cells under them instead of testing every cell their bounds cover
+            Shape::Sphere { radius } if *radius == 0.0 => {
+                let origin = iso_b.inverse_transform_point(iso_a.translation);
+                let local = iso_b.inverse_transform_vector(direction);
+                field
+                    .cast_ray(origin, local, max_distance)
+                    .map(|(distance, normal)| {
+                        let point = iso_a.translation + direction * distance;
+                        (distance, point, iso_b.transform_vector(normal))
+                    })
+            }
+            _ => cast_against_mesh(a, iso_a, direction, max_distance, &**field, iso_b),
+        },
+        _ => {
+            let core_b = ShapeCore {
+                shape: b,
+                iso: iso_b,
+            };
+            cast_convex(a, iso_a, direction, max_distance, &core_b, b.radius())
+        }
+    }
+}
+
+fn cast_against_mesh<M: TriangleSource>(
+    a: &Shape,
+    iso_a: &Isometry,
+    direction: Vector3<f32>,
+    max_distance: f32,
+    mesh: &M,
+    iso_mesh: &Isometry,
+) -> Option<(f32, Vector3<f32>, Vector3<f32>)> {
+    let relative = Isometry::new(
+        iso_mesh.inverse_transform_point(iso_a.translation),
+        iso_mesh.rotation.conjugate() * iso_a.rotation,
+    );
+    let query = a
+        .aabb(&relative)
+        .swept(iso_mesh.inverse_transform_vector(direction) * max_distance)
+        .expanded(CAST_TOLERANCE);
+
+    let mut best: Option<(f32, Vector3<f32>, Vector3<f32>)> = None;
+    mesh.visit_triangles(&query, &mut |triangle| {
+        let world = Triangle {
+            a: iso_mesh.transform_point(triangle.a),
+            b: iso_mesh.transform_point(triangle.b),
+            c: iso_mesh.transform_point(triangle.c),
+        };
+        let limit = best.map_or(max_distance, |(distance, _, _)| distance);
+        if let Some(hit) = cast_convex(a, iso_a, direction, limit, &world, 0.0) {
+            best = Some(hit);
+        }
+    });
+    best
+}
+
+/// Conservative advancement: GJK gives the gap between the shapes, and `a` can safely move by
+/// the gap divided by how fast it closes in. Repeats until the gap is all but gone
+fn cast_convex<B: SupportMap>(
+    a: &Shape,
+    iso_a: &Isometry,
+    direction: Vector3<f32>,
+    max_distance: f32,
+    b: &B,
+    radius_b: f32,
+) -> Option<(f32, Vector3<f32>, Vector3<f32>)> {
+    let radius = a.radius() + radius_b;
+    let mut distance = 0.0;
+    // Where `b` was last closest, flat faces this close together can look overlapping to GJK
+    let mut closest = (iso_a.translation, -direction);
+    for _ in 0..MAX_CAST_ITERATIONS {
+        let moved = Isometry::new(iso_a.translation + direction * distance, iso_a.rotation);
+        let core_a = ShapeCore {
+            shape: a,
+            iso: &moved,
+        };
+        let (gap, point_a, point_b) = match gjk(&core_a, b).0 {
+            GjkResult::Separated {
+                distance: gap,
+                point_a,
+                point_b,
+            } => (gap, point_a, point_b),
+            GjkResult::Overlapping => return Some((distance, closest.0, closest.1)),
+        };
+
+        let normal = (point_a - point_b) / gap;
+        closest = (point_b + normal * radius_b, normal);
+        let clearance = gap - radius;
+        let closing = -direction.dot(normal);
+        if closing <= 1e-6 {
+            // Moving away or along the surface, even if it already touches
+            return None;
+        }
+        if clearance <= CAST_TOLERANCE {
+            return Some((distance, closest.0, closest.1));
+        }
+        distance += (clearance - CAST_TARGET) / closing;
+        if distance > max_distance {
+            return None;
+        }
+    }
+    None
+}
+
+/// The deepest point where `a` overlaps `b`, with the normal out of `b` and the depth.
+/// Meshes do not overlap each other
+pub(crate) fn overlap_shape(
+    a: &Shape,
+    iso_a: &Isometry,
+    b: &Shape,
+    iso_b: &Isometry,
+) -> Option<(Vector3<f32>, Vector3<f32>, f32)> {
+    contact::collide(a, iso_a, b, iso_b, 0.0)
+        .into_iter()
+        .map(|c| (c.point_b, -c.normal, c.penetration()))
+        .filter(|&(_, _, penetration)| penetration >= 0.0)
+        .max_by(|a, b| a.2.partial_cmp(&b.2).unwrap())
+}
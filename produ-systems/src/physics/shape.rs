@@ -0,0 +1,572 @@
+use super::aabb::Aabb;
+use super::broad_phase::DynamicTree;
+use cgmath::{InnerSpace, Quaternion, Rotation, Vector3, Zero};
+use std::sync::Arc;
+
+/// Position and rotation of a shape in world space
+#[derive(Debug, Copy, Clone)]
+pub struct Isometry {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+}
+
+impl Isometry {
+    pub fn new(translation: Vector3<f32>, rotation: Quaternion<f32>) -> Isometry {
+        Isometry {
+            translation,
+            rotation,
+        }
+    }
+
+    pub fn transform_point(&self, point: Vector3<f32>) -> Vector3<f32> {
+        self.translation + self.rotation.rotate_vector(point)
+    }
+
+    pub fn transform_vector(&self, vector: Vector3<f32>) -> Vector3<f32> {
+        self.rotation.rotate_vector(vector)
+    }
+
+    pub fn inverse_transform_point(&self, point: Vector3<f32>) -> Vector3<f32> {
+        self.rotation
+            .conjugate()
+            .rotate_vector(point - self.translation)
+    }
+
+    pub fn inverse_transform_vector(&self, vector: Vector3<f32>) -> Vector3<f32> {
+        self.rotation.conjugate().rotate_vector(vector)
+    }
+}
+
+/// Convex cloud of points, only the outermost ones ever matter so they do not have to form a hull
+#[derive(Debug, Clone)]
+pub struct ConvexHull {
+    points: Vec<Vector3<f32>>,
+    bounds: Aabb,
+}
+
+impl ConvexHull {
+    /// Returns `None` for an empty point set
+    pub fn new(points: Vec<Vector3<f32>>) -> Option<ConvexHull> {
+        let first = *points.first()?;
+        let bounds = points.iter().fold(Aabb::new(first, first), |aabb, &p| {
+            aabb.union(&Aabb::new(p, p))
+        });
+        Some(ConvexHull { points, bounds })
+    }
+
+    pub fn points(&self) -> &[Vector3<f32>] {
+        &self.points
+    }
+
+    pub fn support(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        let mut best = self.points[0];
+        let mut best_dot = best.dot(direction);
+        for &p in &self.points[1..] {
+            let d = p.dot(direction);
+            if d > best_dot {
+                best = p;
+                best_dot = d;
+            }
+        }
+        best
+    }
+}
+
+/// Triangle soup with its own tree so only triangles near the other shape get tested
+#[derive(Debug, Clone)]
+pub struct TriMesh {
+    vertices: Vec<Vector3<f32>>,
+    triangles: Vec<[u32; 3]>,
+    tree: DynamicTree<usize>,
+    bounds: Aabb,
+}
+
+impl TriMesh {
+    /// Degenerate triangles and triangles pointing outside `vertices` are dropped.
+    /// Returns `None` if no triangles are left
+    pub fn new(vertices: Vec<Vector3<f32>>, indices: &[u32]) -> Option<TriMesh> {
+        let triangles: Vec<[u32; 3]> = indices
+            .chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]])
+            .filter(|t| {
+                if t.iter().any(|&i| i as usize >= vertices.len()) {
+                    return false;
+                }
+                let [a, b, c] = [
+                    vertices[t[0] as usize],
+                    vertices[t[1] as usize],
+                    vertices[t[2] as usize],
+                ];
+                (b - a).cross(c - a).magnitude2() > 1e-12
+            })
+            .collect();
+
+        let mut tree = DynamicTree::new();
+        let mut bounds: Option<Aabb> = None;
+        for (i, t) in triangles.iter().enumerate() {
+            let aabb = Triangle::from_mesh(&vertices, t).aabb();
+            bounds = Some(bounds.map_or(aabb, |b| b.union(&aabb)));
+            tree.create_proxy(aabb, i);
+        }
+
+        Some(TriMesh {
+            vertices,
+            triangles,
+            tree,
+            bounds: bounds?,
+        })
+    }
+
+    /// Builds a mesh straight from render data, such as the teapot's `VERTICES` and `INDICES`
+    pub fn from_indexed<P, I>(positions: P, indices: &[I]) -> Option<TriMesh>
+    where
+        P: IntoIterator<Item = [f32; 3]>,
+        I: Copy + Into<u32>,
+    {
+        let vertices = positions.into_iter().map(Vector3::from).collect();
+        let indices: Vec<u32> = indices.iter().map(|&i| i.into()).collect();
+        TriMesh::new(vertices, &indices)
+    }
+
+    pub fn vertices(&self) -> &[Vector3<f32>] {
+        &self.vertices
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    pub fn triangle(&self, index: usize) -> Triangle {
+        Triangle::from_mesh(&self.vertices, &self.triangles[index])
+    }
+
+    /// Calls `callback` with every triangle whose bounds overlap `aabb`, given in mesh space
+    pub fn triangles_in<F>(&self, aabb: &Aabb, mut callback: F)
+    where
+        F: FnMut(usize, Triangle),
+    {
+        self.tree.query(aabb, |proxy| {
+            if let Some(index) = self.tree.data(proxy) {
+                callback(index, self.triangle(index));
+            }
+            true
+        });
+    }
+}
+
+/// Grid of heights with two triangles per cell. The first sample sits at the origin and the
+/// field spreads along +X and +Z, `scale` spaces the samples out and multiplies the heights
+#[derive(Debug, Clone)]
+pub struct HeightField {
+    columns: usize,
+    rows: usize,
+    /// Row by row along +Z, each row running along +X, already scaled
+    heights: Vec<f32>,
+    scale: Vector3<f32>,
+    bounds: Aabb,
+}
+
+impl HeightField {
+    /// `heights` holds `columns` samples for each of `rows` rows. Returns `None` if it does not,
+    /// or if the grid is smaller than one cell
+    pub fn new(
+        columns: usize,
+        rows: usize,
+        heights: Vec<f32>,
+        scale: Vector3<f32>,
+    ) -> Option<HeightField> {
+        if columns < 2 || rows < 2 || heights.len() != columns * rows {
+            return None;
+        }
+        let heights: Vec<f32> = heights.into_iter().map(|h| h * scale.y).collect();
+        let (low, high) = heights
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(low, high), &h| {
+                (low.min(h), high.max(h))
+            });
+        let bounds = Aabb::new(
+            Vector3::new(0.0, low, 0.0),
+            Vector3::new(
+                (columns - 1) as f32 * scale.x,
+                high,
+                (rows - 1) as f32 * scale.z,
+            ),
+        );
+        Some(HeightField {
+            columns,
+            rows,
+            heights,
+            scale,
+            bounds,
+        })
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn scale(&self) -> Vector3<f32> {
+        self.scale
+    }
+
+    /// Scaled height of a sample, samples past the edges take the height of the nearest edge
+    pub fn height(&self, column: usize, row: usize) -> f32 {
+        let column = column.min(self.columns - 1);
+        let row = row.min(self.rows - 1);
+        self.heights[row * self.columns + column]
+    }
+
+    fn point(&self, column: usize, row: usize) -> Vector3<f32> {
+        Vector3::new(
+            column as f32 * self.scale.x,
+            self.height(column, row),
+            row as f32 * self.scale.z,
+        )
+    }
+
+    /// The two triangles of a cell, split along the diagonal from its first corner
+    pub fn cell_triangles(&self, column: usize, row: usize) -> [Triangle; 2] {
+        let p00 = self.point(column, row);
+        let p10 = self.point(column + 1, row);
+        let p01 = self.point(column, row + 1);
+        let p11 = self.point(column + 1, row + 1);
+        [
+            Triangle {
+                a: p00,
+                b: p01,
+                c: p11,
+            },
+            Triangle {
+                a: p00,
+                b: p11,
+                c: p10,
+            },
+        ]
+    }
+
+    /// Range of cells, first and last inclusive, whose columns or rows overlap `low..high` along
+    /// an axis spaced by `spacing`. `None` if it misses the field
+    fn cell_range(low: f32, high: f32, spacing: f32, cells: usize) -> Option<(usize, usize)> {
+        let (first, last) = ((low / spacing).floor(), (high / spacing).floor());
+        if last < 0.0 || first >= cells as f32 {
+            return None;
+        }
+        Some((first.max(0.0) as usize, (last as usize).min(cells - 1)))
+    }
+
+    /// Calls `callback` with the index and triangle of every cell overlapping `aabb`, given in
+    /// field space. Only the cells under the box get looked at
+    pub fn triangles_in<F>(&self, aabb: &Aabb, mut callback: F)
+    where
+        F: FnMut(usize, Triangle),
+    {
+        if !aabb.overlaps(&self.bounds) {
+            return;
+        }
+        let columns =
+            HeightField::cell_range(aabb.min.x, aabb.max.x, self.scale.x, self.columns - 1);
+        let rows = HeightField::cell_range(aabb.min.z, aabb.max.z, self.scale.z, self.rows - 1);
+        let ((first_column, last_column), (first_row, last_row)) = match (columns, rows) {
+            (Some(columns), Some(rows)) => (columns, rows),
+            _ => return,
+        };
+        for row in first_row..=last_row {
+            for column in first_column..=last_column {
+                let corners = [
+                    self.height(column, row),
+                    self.height(column + 1, row),
+                    self.height(column, row + 1),
+                    self.height(column + 1, row + 1),
+                ];
+                let low = corners.iter().cloned().fold(f32::INFINITY, f32::min);
+                let high = corners.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                if high < aabb.min.y || low > aabb.max.y {
+                    continue;
+                }
+                let cell = row * (self.columns - 1) + column;
+                let [first, second] = self.cell_triangles(column, row);
+                callback(cell * 2, first);
+                callback(cell * 2 + 1, second);
+            }
+        }
+    }
+
+    /// First hit of a ray given in field space, walking only the cells under it. The ray
+    /// direction has to be unit length. Returns the distance and the triangle normal, facing
+    /// back along the ray
+    pub fn cast_ray(
+        &self,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        max_distance: f32,
+    ) -> Option<(f32, Vector3<f32>)> {
+        let (mut t, end) = self
+            .bounds
+            .expanded(1e-4)
+            .ray_distance(origin, direction, max_distance)
+            .map(|enter| (enter, self.exit_distance(origin, direction, max_distance)))?;
+        let cells = (self.columns - 1, self.rows - 1);
+        let start = origin + direction * t;
+        let mut column = ((start.x / self.scale.x).floor().max(0.0) as usize).min(cells.0 - 1);
+        let mut row = ((start.z / self.scale.z).floor().max(0.0) as usize).min(cells.1 - 1);
+
+        // Distance along the ray between crossing cell edges on each axis, and to the next one
+        let step = |d: f32, spacing: f32| {
+            if d.abs() < 1e-9 {
+                f32::INFINITY
+            } else {
+                spacing / d.abs()
+            }
+        };
+        let (delta_x, delta_z) = (
+            step(direction.x, self.scale.x),
+            step(direction.z, self.scale.z),
+        );
+        let next = |position: f32, cell: usize, d: f32, spacing: f32, delta: f32| {
+            if delta.is_infinite() {
+                return f32::INFINITY;
+            }
+            let edge = if d > 0.0 { cell + 1 } else { cell } as f32 * spacing;
+            (edge - position) / d
+        };
+        let mut next_x = t + next(start.x, column, direction.x, self.scale.x, delta_x);
+        let mut next_z = t + next(start.z, row, direction.z, self.scale.z, delta_z);
+
+        while t <= end + 1e-3 {
+            let mut best: Option<(f32, Vector3<f32>)> = None;
+            for triangle in self.cell_triangles(column, row).iter() {
+                if let Some(distance) = ray_triangle(origin, direction, triangle) {
+                    if distance <= max_distance && !matches!(best, Some((b, _)) if b <= distance) {
+                        let normal = triangle.normal();
+                        let facing = if normal.dot(direction) > 0.0 {
+                            -normal
+                        } else {
+                            normal
+                        };
+                        best = Some((distance, facing));
+                    }
+                }
+            }
+            if best.is_some() {
+                return best;
+            }
+            if next_x < next_z {
+                t = next_x;
+                next_x += delta_x;
+                if direction.x > 0.0 && column + 1 < cells.0 {
+                    column += 1;
+                } else if direction.x < 0.0 && column > 0 {
+                    column -= 1;
+                } else {
+                    break;
+                }
+            } else {
+                t = next_z;
+                next_z += delta_z;
+                if direction.z > 0.0 && row + 1 < cells.1 {
+                    row += 1;
+                } else if direction.z < 0.0 && row > 0 {
+                    row -= 1;
+                } else {
+                    break;
+                }
+            }
+        }
+        None
+    }
+
+    /// How far along the ray it leaves the field's bounds, or `max_distance` if it does not
+    fn exit_distance(
+        &self,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        max_distance: f32,
+    ) -> f32 {
+        let mut exit = max_distance;
+        for axis in 0..3 {
+            if direction[axis].abs() < 1e-9 {
+                continue;
+            }
+            let far = if direction[axis] > 0.0 {
+                self.bounds.max[axis]
+            } else {
+                self.bounds.min[axis]
+            };
+            exit = exit.min((far - origin[axis]) / direction[axis]);
+        }
+        exit
+    }
+}
+
+/// Distance along the ray to where it crosses the triangle, from either side
+fn ray_triangle(origin: Vector3<f32>, direction: Vector3<f32>, triangle: &Triangle) -> Option<f32> {
+    let (edge_1, edge_2) = (triangle.b - triangle.a, triangle.c - triangle.a);
+    let p = direction.cross(edge_2);
+    let determinant = edge_1.dot(p);
+    if determinant.abs() < 1e-12 {
+        return None;
+    }
+    let inverse = 1.0 / determinant;
+    let offset = origin - triangle.a;
+    let u = offset.dot(p) * inverse;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = offset.cross(edge_1);
+    let v = direction.dot(q) * inverse;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let distance = edge_2.dot(q) * inverse;
+    if distance >= 0.0 {
+        Some(distance)
+    } else {
+        None
+    }
+}
+
+/// Shapes made of triangles that are looked at a small area at a time, so contacts and casts
+/// treat meshes and height fields alike
+pub(crate) trait TriangleSource {
+    fn visit_triangles(&self, aabb: &Aabb, callback: &mut dyn FnMut(Triangle));
+}
+
+impl TriangleSource for TriMesh {
+    fn visit_triangles(&self, aabb: &Aabb, callback: &mut dyn FnMut(Triangle)) {
+        self.triangles_in(aabb, |_, triangle| callback(triangle));
+    }
+}
+
+impl TriangleSource for HeightField {
+    fn visit_triangles(&self, aabb: &Aabb, callback: &mut dyn FnMut(Triangle)) {
+        self.triangles_in(aabb, |_, triangle| callback(triangle));
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Triangle {
+    pub a: Vector3<f32>,
+    pub b: Vector3<f32>,
+    pub c: Vector3<f32>,
+}
+
+impl Triangle {
+    fn from_mesh(vertices: &[Vector3<f32>], indices: &[u32; 3]) -> Triangle {
+        Triangle {
+            a: vertices[indices[0] as usize],
+            b: vertices[indices[1] as usize],
+            c: vertices[indices[2] as usize],
+        }
+    }
+
+    pub fn normal(&self) -> Vector3<f32> {
+        (self.b - self.a).cross(self.c - self.a).normalize()
+    }
+
+    pub fn aabb(&self) -> Aabb {
+        Aabb::new(self.a, self.a)
+            .union(&Aabb::new(self.b, self.b))
+            .union(&Aabb::new(self.c, self.c))
+    }
+
+    pub fn support(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        let (da, db, dc) = (
+            self.a.dot(direction),
+            self.b.dot(direction),
+            self.c.dot(direction),
+        );
+        if da >= db && da >= dc {
+            self.a
+        } else if db >= dc {
+            self.b
+        } else {
+            self.c
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Shape {
+    Sphere {
+        radius: f32,
+    },
+    /// Box centred on the body, rotated with it
+    Cuboid {
+        half_extents: Vector3<f32>,
+    },
+    /// Runs along the body's Y axis, `half_height` is half the length of the straight part
+    Capsule {
+        radius: f32,
+        half_height: f32,
+    },
+    ConvexHull(Arc<ConvexHull>),
+    TriMesh(Arc<TriMesh>),
+    HeightField(Arc<HeightField>),
+}
+
+impl Shape {
+    pub fn aabb(&self, iso: &Isometry) -> Aabb {
+        match self {
+            Shape::Sphere { radius } => {
+                Aabb::from_center(iso.translation, Vector3::new(*radius, *radius, *radius))
+            }
+            Shape::Cuboid { half_extents } => {
+                Aabb::from_oriented_box(iso.translation, iso.rotation, *half_extents)
+            }
+            Shape::Capsule {
+                radius,
+                half_height,
+            } => {
+                let axis = iso.transform_vector(Vector3::new(0.0, *half_height, 0.0));
+                let r = Vector3::new(*radius, *radius, *radius);
+                let extents = Vector3::new(axis.x.abs(), axis.y.abs(), axis.z.abs()) + r;
+                Aabb::from_center(iso.translation, extents)
+            }
+            Shape::ConvexHull(hull) => local_bounds_to_world(&hull.bounds, iso),
+            Shape::TriMesh(mesh) => local_bounds_to_world(&mesh.bounds, iso),
+            Shape::HeightField(field) => local_bounds_to_world(&field.bounds, iso),
+        }
+    }
+
+    pub fn is_convex(&self) -> bool {
+        !matches!(self, Shape::TriMesh(_) | Shape::HeightField(_))
+    }
+
+    /// Spheres and capsules are a point or segment grown by this much, everything else is sharp
+    pub(crate) fn radius(&self) -> f32 {
+        match self {
+            Shape::Sphere { radius } => *radius,
+            Shape::Capsule { radius, .. } => *radius,
+            _ => 0.0,
+        }
+    }
+
+    /// Furthest point of the shape without its radius in `direction`, both in shape space
+    pub(crate) fn core_support(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        match self {
+            Shape::Sphere { .. } => Vector3::zero(),
+            Shape::Cuboid { half_extents } => Vector3::new(
+                half_extents.x.copysign(direction.x),
+                half_extents.y.copysign(direction.y),
+                half_extents.z.copysign(direction.z),
+            ),
+            Shape::Capsule { half_height, .. } => {
+                Vector3::new(0.0, half_height.copysign(direction.y), 0.0)
+            }
+            Shape::ConvexHull(hull) => hull.support(direction),
+            Shape::TriMesh(_) | Shape::HeightField(_) => Vector3::zero(),
+        }
+    }
+}
+
+fn local_bounds_to_world(bounds: &Aabb, iso: &Isometry) -> Aabb {
+    Aabb::from_oriented_box(
+        iso.transform_point(bounds.center()),
+        iso.rotation,
+        bounds.half_extents(),
+    )
+}
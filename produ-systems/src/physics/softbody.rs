@@ -0,0 +1,615 @@
+use super::body::BodyHandle;
+use super::query::QueryFilter;
+use super::solver::tangent_basis;
+use super::PhysicsWorld;
+use cgmath::{InnerSpace, Vector3, Zero};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct SoftBodyHandle(pub(crate) usize);
+
+#[derive(Debug, Copy, Clone)]
+pub struct SoftBodyConfig {
+    /// Radius of each particle, both for collisions and for how thick ropes are drawn
+    pub thickness: f32,
+    /// More iterations make long ropes and big cloths less stretchy
+    pub iterations: u32,
+    /// How much of an edge's stretch is undone each iteration, from 0 to 1
+    pub stretch_stiffness: f32,
+    /// How much the cloth or rope resists folding, from 0 to 1
+    pub bend_stiffness: f32,
+    /// Fraction of velocity lost per second
+    pub damping: f32,
+    /// Fraction of the speed along a collider's surface lost each step a particle touches it
+    pub friction: f32,
+    /// Edges stretched past this multiple of their rest length tear, `None` never tears
+    pub tear_ratio: Option<f32>,
+    /// Layers of colliders the particles bump into
+    pub mask: u32,
+}
+
+impl Default for SoftBodyConfig {
+    fn default() -> Self {
+        SoftBodyConfig {
+            thickness: 0.02,
+            iterations: 8,
+            stretch_stiffness: 1.0,
+            bend_stiffness: 0.2,
+            damping: 0.1,
+            friction: 0.3,
+            tear_ratio: None,
+            mask: u32::MAX,
+        }
+    }
+}
+
+/// What a pinned particle is held to
+#[derive(Debug, Copy, Clone)]
+pub enum PinTarget {
+    World(Vector3<f32>),
+    /// A point in the body's space, so the particle follows the entity the body drives
+    Body(BodyHandle, Vector3<f32>),
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Particle {
+    position: Vector3<f32>,
+    /// Where the particle was last step, Verlet integration gets the velocity from the difference
+    previous: Vector3<f32>,
+    pinned: bool,
+}
+
+/// Keeps two particles `rest` apart. Bends span two edges, so they resist folding at the particle
+/// in between
+#[derive(Debug, Copy, Clone)]
+struct Link {
+    a: usize,
+    b: usize,
+    rest: f32,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Bend {
+    link: Link,
+    /// The particle the bend folds about
+    middle: usize,
+}
+
+/// Triangles and vertices ready to copy into the renderer's vertex, normal and index buffers
+#[derive(Debug, Clone, Default)]
+pub struct SoftMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub indices: Vec<u32>,
+}
+
+/// A rope or cloth made of particles held together by distance constraints, simulated with
+/// position based dynamics. Colliders push it around but it never pushes back on them
+#[derive(Debug, Clone)]
+pub struct SoftBody {
+    pub config: SoftBodyConfig,
+    particles: Vec<Particle>,
+    edges: Vec<Link>,
+    bends: Vec<Bend>,
+    /// Cloth surface, ropes have none and are drawn as tubes along their edges instead
+    triangles: Vec<[usize; 3]>,
+    pins: Vec<(usize, PinTarget)>,
+}
+
+impl SoftBody {
+    /// A straight rope from `start` to `end` made of `segments` pieces
+    pub fn rope(
+        start: Vector3<f32>,
+        end: Vector3<f32>,
+        segments: usize,
+        config: SoftBodyConfig,
+    ) -> SoftBody {
+        let segments = segments.max(1);
+        let positions = (0..=segments)
+            .map(|i| start + (end - start) * (i as f32 / segments as f32))
+            .collect();
+        let mut rope = SoftBody::new(positions, config);
+        for i in 0..segments {
+            rope.add_edge(i, i + 1);
+        }
+        for i in 1..segments {
+            rope.add_bend(i - 1, i, i + 1);
+        }
+        rope
+    }
+
+    /// A flat sheet of `columns` by `rows` particles, with `origin` at its first corner and the
+    /// sheet spanning `across` along each row and `down` along each column
+    pub fn cloth(
+        origin: Vector3<f32>,
+        across: Vector3<f32>,
+        down: Vector3<f32>,
+        columns: usize,
+        rows: usize,
+        config: SoftBodyConfig,
+    ) -> SoftBody {
+        let (columns, rows) = (columns.max(2), rows.max(2));
+        let index = |column: usize, row: usize| row * columns + column;
+        let mut positions = Vec::with_capacity(columns * rows);
+        for row in 0..rows {
+            for column in 0..columns {
+                let u = column as f32 / (columns - 1) as f32;
+                let v = row as f32 / (rows - 1) as f32;
+                positions.push(origin + across * u + down * v);
+            }
+        }
+
+        let mut cloth = SoftBody::new(positions, config);
+        for row in 0..rows {
+            for column in 0..columns {
+                let here = index(column, row);
+                if column + 1 < columns {
+                    cloth.add_edge(here, index(column + 1, row));
+                }
+                if row + 1 < rows {
+                    cloth.add_edge(here, index(column, row + 1));
+                }
+                if column + 1 < columns && row + 1 < rows {
+                    // Diagonals keep the squares from shearing into diamonds
+                    let right = index(column + 1, row);
+                    let below = index(column, row + 1);
+                    let across = index(column + 1, row + 1);
+                    cloth.add_edge(here, across);
+                    cloth.add_edge(right, below);
+                    cloth.triangles.push([here, below, across]);
+                    cloth.triangles.push([here, across, right]);
+                }
+                if column + 2 < columns {
+                    cloth.add_bend(here, index(column + 1, row), index(column + 2, row));
+                }
+                if row + 2 < rows {
+                    cloth.add_bend(here, index(column, row + 1), index(column, row + 2));
+                }
+            }
+        }
+        cloth
+    }
+
+    fn new(positions: Vec<Vector3<f32>>, config: SoftBodyConfig) -> SoftBody {
+        SoftBody {
+            config,
+            particles: positions
+                .into_iter()
+                .map(|position| Particle {
+                    position,
+                    previous: position,
+                    pinned: false,
+                })
+                .collect(),
+            edges: vec![],
+            bends: vec![],
+            triangles: vec![],
+            pins: vec![],
+        }
+    }
+
+    fn link(&self, a: usize, b: usize) -> Link {
+        let rest = (self.particles[b].position - self.particles[a].position).magnitude();
+        Link { a, b, rest }
+    }
+
+    fn add_edge(&mut self, a: usize, b: usize) {
+        let link = self.link(a, b);
+        self.edges.push(link);
+    }
+
+    fn add_bend(&mut self, a: usize, middle: usize, b: usize) {
+        let link = self.link(a, b);
+        self.bends.push(Bend { link, middle });
+    }
+
+    pub fn particle_count(&self) -> usize {
+        self.particles.len()
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item = Vector3<f32>> + '_ {
+        self.particles.iter().map(|p| p.position)
+    }
+
+    /// Holds a particle to `target` until unpinned, replacing any pin it already had
+    pub fn pin(&mut self, particle: usize, target: PinTarget) {
+        if particle >= self.particles.len() {
+            return;
+        }
+        self.unpin(particle);
+        self.particles[particle].pinned = true;
+        self.pins.push((particle, target));
+    }
+
+    pub fn unpin(&mut self, particle: usize) {
+        self.pins.retain(|&(p, _)| p != particle);
+        if let Some(p) = self.particles.get_mut(particle) {
+            p.pinned = false;
+        }
+    }
+
+    /// Pulls a particle to a new position, it keeps the implied velocity
+    pub fn move_particle(&mut self, particle: usize, position: Vector3<f32>) {
+        if let Some(p) = self.particles.get_mut(particle) {
+            p.position = position;
+        }
+    }
+
+    /// Edges still holding the particles together, tearing removes them
+    pub fn edge_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    pub(crate) fn step(&mut self, world: &PhysicsWorld, dt: f32) {
+        let keep = 1.0 / (1.0 + dt * self.config.damping);
+        let fall = world.gravity * dt * dt;
+        for p in self.particles.iter_mut().filter(|p| !p.pinned) {
+            let velocity = (p.position - p.previous) * keep;
+            p.previous = p.position;
+            p.position += velocity + fall;
+        }
+        self.apply_pins(world);
+
+        for _ in 0..self.config.iterations {
+            for i in 0..self.edges.len() {
+                let edge = self.edges[i];
+                self.satisfy(edge, self.config.stretch_stiffness);
+            }
+            for i in 0..self.bends.len() {
+                let bend = self.bends[i];
+                self.satisfy(bend.link, self.config.bend_stiffness);
+            }
+        }
+
+        self.collide(world);
+        self.tear();
+    }
+
+    fn apply_pins(&mut self, world: &PhysicsWorld) {
+        for &(particle, target) in &self.pins {
+            let position = match target {
+                PinTarget::World(position) => position,
+                PinTarget::Body(body, local) => match world.body(body) {
+                    Some(body) => body.to_world(local),
+                    None => continue,
+                },
+            };
+            let p = &mut self.particles[particle];
+            p.previous = p.position;
+            p.position = position;
+        }
+    }
+
+    /// Moves both ends of the link towards its rest length, pinned ends stay put
+    fn satisfy(&mut self, link: Link, stiffness: f32) {
+        let (a, b) = (self.particles[link.a], self.particles[link.b]);
+        let weight_a = if a.pinned { 0.0 } else { 1.0 };
+        let weight_b = if b.pinned { 0.0 } else { 1.0 };
+        let total = weight_a + weight_b;
+        let delta = b.position - a.position;
+        let length = delta.magnitude();
+        if total == 0.0 || length < 1e-6 {
+            return;
+        }
+        let correction = delta * ((length - link.rest) / length * stiffness / total);
+        self.particles[link.a].position += correction * weight_a;
+        self.particles[link.b].position -= correction * weight_b;
+    }
+
+    /// Pushes particles out of colliders, taking speed along the surface away for friction
+    fn collide(&mut self, world: &PhysicsWorld) {
+        let filter = QueryFilter::new().with_mask(self.config.mask);
+        for p in self.particles.iter_mut().filter(|p| !p.pinned) {
+            let hits = world.overlap_sphere(p.position, self.config.thickness, &filter);
+            for hit in hits {
+                p.position -= hit.normal * hit.distance;
+                let moved = p.position - p.previous;
+                let along = moved - hit.normal * moved.dot(hit.normal);
+                p.previous += along * self.config.friction;
+            }
+        }
+    }
+
+    /// Removes overstretched edges along with the bends and triangles that used them
+    fn tear(&mut self) {
+        let ratio = match self.config.tear_ratio {
+            Some(ratio) => ratio,
+            None => return,
+        };
+        let particles = &self.particles;
+        let stretched = |link: &Link| {
+            let length = (particles[link.b].position - particles[link.a].position).magnitude();
+            length > link.rest * ratio
+        };
+        let torn: Vec<(usize, usize)> = self
+            .edges
+            .iter()
+            .filter(|e| stretched(e))
+            .map(|e| (e.a, e.b))
+            .collect();
+        if torn.is_empty() {
+            return;
+        }
+
+        let is_torn = |a: usize, b: usize| torn.iter().any(|&e| e == (a, b) || e == (b, a));
+        self.edges.retain(|e| !is_torn(e.a, e.b));
+        self.bends.retain(|bend| {
+            !is_torn(bend.link.a, bend.middle) && !is_torn(bend.middle, bend.link.b)
+        });
+        self.triangles.retain(|&[a, b, c]| {
+            // A torn diagonal leaves its triangles' other edges to hold the cloth, so it takes
+            // the pair of triangles with it
+            !is_torn(a, b) && !is_torn(b, c) && !is_torn(c, a)
+        });
+    }
+
+    /// Fills `mesh` with the surface to draw, reusing its allocations. Cloth uses the particles
+    /// as vertices, ropes become four sided tubes `thickness` wide
+    pub fn write_mesh(&self, mesh: &mut SoftMesh) {
+        mesh.positions.clear();
+        mesh.normals.clear();
+        mesh.indices.clear();
+        if self.triangles.is_empty() {
+            self.write_tubes(mesh);
+        } else {
+            self.write_surface(mesh);
+        }
+    }
+
+    fn write_surface(&self, mesh: &mut SoftMesh) {
+        let mut normals = vec![Vector3::zero(); self.particles.len()];
+        for &[a, b, c] in &self.triangles {
+            let (pa, pb, pc) = (
+                self.particles[a].position,
+                self.particles[b].position,
+                self.particles[c].position,
+            );
+            // Left unnormalised, bigger triangles get more say in the shared vertices
+            let normal = (pb - pa).cross(pc - pa);
+            normals[a] += normal;
+            normals[b] += normal;
+            normals[c] += normal;
+            mesh.indices
+                .extend_from_slice(&[a as u32, b as u32, c as u32]);
+        }
+        for (p, normal) in self.particles.iter().zip(normals) {
+            let normal = if normal.magnitude2() > 1e-12 {
+                normal.normalize()
+            } else {
+                Vector3::unit_y()
+            };
+            mesh.positions.push(p.position.into());
+            mesh.normals.push(normal.into());
+        }
+    }
+
+    fn write_tubes(&self, mesh: &mut SoftMesh) {
+        let radius = self.config.thickness;
+        for edge in &self.edges {
+            let (a, b) = (
+                self.particles[edge.a].position,
+                self.particles[edge.b].position,
+            );
+            let direction = b - a;
+            if direction.magnitude2() < 1e-12 {
+                continue;
+            }
+            let [side, up] = tangent_basis(direction.normalize());
+            let around = [side, up, -side, -up];
+            let first = mesh.positions.len() as u32;
+            for &offset in &around {
+                for &end in &[a, b] {
+                    mesh.positions.push((end + offset * radius).into());
+                    mesh.normals.push(offset.into());
+                }
+            }
+            for i in 0..4u32 {
+                let this = first + i * 2;
+                let next = first + ((i + 1) % 4) * 2;
+                mesh.indices
+                    .extend_from_slice(&[this, this + 1, next + 1, this, next + 1, next]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::ground;
+    use super::super::{Collider, RigidBody, FIXED_TIMESTEP};
+    use super::*;
+
+    const TOP: Vector3<f32> = Vector3::new(0.0, 5.0, 0.0);
+
+    fn run(world: &mut PhysicsWorld, ticks: usize) {
+        for _ in 0..ticks {
+            world.step(FIXED_TIMESTEP);
+        }
+    }
+
+    /// Total length of the edges over their total rest length
+    fn stretch(soft_body: &SoftBody) -> f32 {
+        let p = &soft_body.particles;
+        let (length, rest) = soft_body
+            .edges
+            .iter()
+            .fold((0.0, 0.0), |(length, rest), e| {
+                (
+                    length + (p[e.b].position - p[e.a].position).magnitude(),
+                    rest + e.rest,
+                )
+            });
+        length / rest
+    }
+
+    /// Two metres of rope held out sideways from `TOP` by its first particle
+    fn rope(world: &mut PhysicsWorld, config: SoftBodyConfig) -> SoftBodyHandle {
+        let end = TOP + Vector3::unit_x() * 2.0;
+        let mut rope = SoftBody::rope(TOP, end, 20, config);
+        rope.pin(0, PinTarget::World(TOP));
+        world.add_soft_body(rope)
+    }
+
+    fn damped() -> SoftBodyConfig {
+        SoftBodyConfig {
+            damping: 5.0,
+            ..SoftBodyConfig::default()
+        }
+    }
+
+    #[test]
+    fn pinned_particles_hold_and_follow_their_bodies() {
+        let mut world = PhysicsWorld::new();
+        let handle = rope(&mut world, damped());
+        run(&mut world, 300);
+        let positions: Vec<_> = world.soft_body(handle).unwrap().positions().collect();
+        assert_eq!(positions[0], TOP);
+        // Hanging straight down, a little longer than at rest
+        let end = positions[20];
+        assert!(end.x.abs() < 0.01, "{:?}", end);
+        let hang = TOP.y - end.y;
+        assert!(hang > 2.0 && hang < 2.05, "{:?}", end);
+
+        // Pinned to a moving body instead, the end goes along with it
+        let mut cart = RigidBody::kinematic(TOP);
+        cart.linear_velocity = Vector3::new(1.0, 0.0, 0.0);
+        let cart = world.add_body(cart);
+        let offset = Vector3::new(0.0, -0.5, 0.0);
+        let rope = world.soft_body_mut(handle).unwrap();
+        rope.pin(0, PinTarget::Body(cart, offset));
+        assert_eq!(rope.pins.len(), 1);
+        run(&mut world, 120);
+        let held = world.body(cart).unwrap().to_world(offset);
+        let first = world.soft_body(handle).unwrap().positions().next().unwrap();
+        assert_eq!(first, held);
+
+        // Let go, it falls
+        world.soft_body_mut(handle).unwrap().unpin(0);
+        run(&mut world, 60);
+        let first = world.soft_body(handle).unwrap().positions().next().unwrap();
+        assert!(first.y < held.y - 1.0, "{:?}", first);
+    }
+
+    #[test]
+    fn stiff_edges_keep_their_length() {
+        let mut world = PhysicsWorld::new();
+        let mut stretches = vec![];
+        for &iterations in [2, 8, 32].iter() {
+            let config = SoftBodyConfig {
+                iterations,
+                ..damped()
+            };
+            let handle = rope(&mut world, config);
+            run(&mut world, 300);
+            stretches.push(stretch(world.soft_body(handle).unwrap()));
+            world.remove_soft_body(handle);
+        }
+        // More iterations undo more of the sag, the default is within a few percent
+        assert!(stretches[0] > stretches[1] && stretches[1] > stretches[2]);
+        assert!(stretches[1] < 1.03, "{:?}", stretches);
+        assert!(stretches[2] < 1.01, "{:?}", stretches);
+    }
+
+    #[test]
+    fn overstretched_edges_tear() {
+        for &tear_ratio in [None, Some(1.5)].iter() {
+            let mut world = PhysicsWorld::new();
+            let config = SoftBodyConfig {
+                tear_ratio,
+                ..damped()
+            };
+            let mut cloth =
+                SoftBody::cloth(TOP, Vector3::unit_x(), -Vector3::unit_y(), 5, 5, config);
+            cloth.pin(0, PinTarget::World(TOP));
+            cloth.pin(4, PinTarget::World(TOP + Vector3::unit_x()));
+            let edges = cloth.edge_count();
+            let handle = world.add_soft_body(cloth);
+            // Its own weight is nowhere near enough
+            run(&mut world, 60);
+            assert_eq!(world.soft_body(handle).unwrap().edge_count(), edges);
+
+            // Yanking one corner far out is
+            world
+                .soft_body_mut(handle)
+                .unwrap()
+                .pin(4, PinTarget::World(TOP + Vector3::unit_x() * 5.0));
+            run(&mut world, 60);
+            let cloth = world.soft_body(handle).unwrap();
+            let mut mesh = SoftMesh::default();
+            cloth.write_mesh(&mut mesh);
+            if tear_ratio.is_some() {
+                assert!(cloth.edge_count() < edges);
+                assert!(mesh.indices.len() < 16 * 2 * 3);
+            } else {
+                assert_eq!(cloth.edge_count(), edges);
+                assert_eq!(mesh.indices.len(), 16 * 2 * 3);
+            }
+        }
+    }
+
+    #[test]
+    fn colliders_push_particles_out() {
+        let mut world = PhysicsWorld::new();
+        ground(&mut world);
+        let center = Vector3::new(0.0, 0.5, 0.0);
+        let ball = world.add_body(RigidBody::fixed(center));
+        world.add_collider(ball, Collider::sphere(0.5)).unwrap();
+        // Sensors have no surface to catch on
+        let zone = world.add_body(RigidBody::fixed(Vector3::new(0.0, 2.0, 0.0)));
+        let sensor = Collider::cuboid(Vector3::new(2.0, 0.5, 2.0)).with_sensor(true);
+        world.add_collider(zone, sensor).unwrap();
+
+        let config = SoftBodyConfig::default();
+        let cloth = SoftBody::cloth(
+            Vector3::new(-1.0, 3.0, -1.0),
+            Vector3::unit_x() * 2.0,
+            Vector3::unit_z() * 2.0,
+            11,
+            11,
+            config,
+        );
+        let handle = world.add_soft_body(cloth);
+        let touching = config.thickness - 1e-3;
+        for _ in 0..300 {
+            world.step(FIXED_TIMESTEP);
+            for p in world.soft_body(handle).unwrap().positions() {
+                assert!(p.y >= touching, "{:?} in the ground", p);
+                assert!(
+                    (p - center).magnitude() >= 0.5 + touching,
+                    "{:?} in the ball",
+                    p
+                );
+            }
+        }
+        let highest = world
+            .soft_body(handle)
+            .unwrap()
+            .positions()
+            .map(|p| p.y)
+            .fold(f32::NEG_INFINITY, f32::max);
+        assert!(highest < 1.5, "held up at {}", highest);
+    }
+
+    #[test]
+    fn meshes_index_their_own_vertices() {
+        let config = SoftBodyConfig::default();
+        let mut mesh = SoftMesh::default();
+        let cloth = SoftBody::cloth(TOP, Vector3::unit_x(), Vector3::unit_z(), 4, 3, config);
+        cloth.write_mesh(&mut mesh);
+        assert_eq!(mesh.positions.len(), 12);
+        assert_eq!(mesh.indices.len(), 3 * 2 * 6);
+        assert!(mesh
+            .indices
+            .iter()
+            .all(|&i| (i as usize) < mesh.positions.len()));
+
+        let rope = SoftBody::rope(TOP, TOP + Vector3::unit_x(), 5, config);
+        rope.write_mesh(&mut mesh);
+        assert_eq!(mesh.positions.len(), 5 * 8);
+        assert_eq!(mesh.normals.len(), 5 * 8);
+        assert_eq!(mesh.indices.len(), 5 * 4 * 6);
+        assert!(mesh
+            .indices
+            .iter()
+            .all(|&i| (i as usize) < mesh.positions.len()));
+    }
+}
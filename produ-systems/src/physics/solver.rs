@@ -0,0 +1,610 @@
+use super::body::RigidBody;
+use super::contact::ContactManifold;
+use super::joint::{Joint, JointRow, MAX_JOINT_ROWS};
+use cgmath::{InnerSpace, Matrix3, Vector3, Zero};
+
+/// How the solver pushes overlapping bodies back apart
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PositionCorrection {
+    /// Feeds the overlap back into the contact velocity, cheap but adds a little bounce
+    Baumgarte,
+    /// Solves the overlap with its own velocities that move the bodies but are then thrown away
+    SplitImpulse,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct SolverConfig {
+    pub velocity_iterations: u32,
+    /// Only used by `PositionCorrection::SplitImpulse`
+    pub position_iterations: u32,
+    pub position_correction: PositionCorrection,
+    /// Fraction of the overlap fixed each step
+    pub correction_factor: f32,
+    /// Overlap left alone so resting contacts stay touching
+    pub allowed_penetration: f32,
+    /// Bodies hitting slower than this do not bounce, which keeps resting contacts quiet
+    pub restitution_threshold: f32,
+    /// Starts each step from last step's impulses so stacks settle in few iterations
+    pub warm_starting: bool,
+}
+
+impl Default for SolverConfig {
+    fn default() -> Self {
+        SolverConfig {
+            velocity_iterations: 8,
+            position_iterations: 3,
+            position_correction: PositionCorrection::SplitImpulse,
+            correction_factor: 0.2,
+            allowed_penetration: 0.005,
+            restitution_threshold: 1.0,
+            warm_starting: true,
+        }
+    }
+}
+
+/// A body's state copied out for the solver, static and kinematic bodies have no inverse mass
+struct SolverBody {
+    inv_mass: f32,
+    inv_inertia: Matrix3<f32>,
+    linear_velocity: Vector3<f32>,
+    angular_velocity: Vector3<f32>,
+    pseudo_linear: Vector3<f32>,
+    pseudo_angular: Vector3<f32>,
+}
+
+impl SolverBody {
+    fn new(body: &RigidBody) -> SolverBody {
+        SolverBody {
+            inv_mass: body.inv_mass(),
+            inv_inertia: body.inv_inertia_world(),
+            linear_velocity: body.linear_velocity,
+            angular_velocity: body.angular_velocity,
+            pseudo_linear: Vector3::zero(),
+            pseudo_angular: Vector3::zero(),
+        }
+    }
+
+    fn velocity_at(&self, r: Vector3<f32>) -> Vector3<f32> {
+        self.linear_velocity + self.angular_velocity.cross(r)
+    }
+
+    fn pseudo_velocity_at(&self, r: Vector3<f32>) -> Vector3<f32> {
+        self.pseudo_linear + self.pseudo_angular.cross(r)
+    }
+
+    fn apply_impulse(&mut self, impulse: Vector3<f32>, r: Vector3<f32>) {
+        self.linear_velocity += impulse * self.inv_mass;
+        self.angular_velocity += self.inv_inertia * r.cross(impulse);
+    }
+
+    fn apply_pseudo_impulse(&mut self, impulse: Vector3<f32>, r: Vector3<f32>) {
+        self.pseudo_linear += impulse * self.inv_mass;
+        self.pseudo_angular += self.inv_inertia * r.cross(impulse);
+    }
+
+    /// How hard it is to change the velocity of the point at `r` along `direction`
+    fn inv_effective_mass(&self, r: Vector3<f32>, direction: Vector3<f32>) -> f32 {
+        let rn = r.cross(direction);
+        self.inv_mass + (self.inv_inertia * rn).cross(r).dot(direction)
+    }
+}
+
+struct PointConstraint {
+    point: usize,
+    r_a: Vector3<f32>,
+    r_b: Vector3<f32>,
+    normal_mass: f32,
+    /// Separating speed the contact aims for, from restitution or closing a speculative gap
+    velocity_bias: f32,
+    /// Separating speed the split impulse aims for to fix the overlap
+    position_bias: f32,
+    normal_impulse: f32,
+    pseudo_impulse: f32,
+}
+
+/// One manifold's contacts. Friction acts once at the centre of the points plus a twist about
+/// the normal, friction per point fights itself and slowly shakes stacks apart
+struct ManifoldConstraint {
+    body_a: usize,
+    body_b: usize,
+    manifold: usize,
+    normal: Vector3<f32>,
+    tangents: [Vector3<f32>; 2],
+    points: Vec<PointConstraint>,
+    friction: f32,
+    /// Average distance of the points from the centre, turns the twist into a friction limit
+    twist_radius: f32,
+    center_a: Vector3<f32>,
+    center_b: Vector3<f32>,
+    tangent_mass: [f32; 2],
+    twist_mass: f32,
+    tangent_impulse: [f32; 2],
+    twist_impulse: f32,
+}
+
+struct JointRowConstraint {
+    row: JointRow,
+    mass: f32,
+    impulse: f32,
+}
+
+struct JointConstraint {
+    body_a: usize,
+    body_b: usize,
+    joint: usize,
+    rows: Vec<JointRowConstraint>,
+}
+
+/// Sequential impulse solver, set up fresh every step from the contact manifolds and joints
+pub(crate) struct ContactSolver {
+    config: SolverConfig,
+    dt: f32,
+    bodies: Vec<SolverBody>,
+    /// Maps a slot in `PhysicsWorld::bodies` to its index in `bodies`
+    body_slots: Vec<Option<usize>>,
+    constraints: Vec<ManifoldConstraint>,
+    joints: Vec<JointConstraint>,
+}
+
+impl ContactSolver {
+    pub(crate) fn new(
+        config: SolverConfig,
+        bodies: &[Option<RigidBody>],
+        manifolds: &[ContactManifold],
+        joints: &[Option<Joint>],
+        dt: f32,
+    ) -> ContactSolver {
+        let mut solver = ContactSolver {
+            config,
+            dt,
+            bodies: vec![],
+            body_slots: vec![None; bodies.len()],
+            constraints: vec![],
+            joints: vec![],
+        };
+
+        for (j, joint) in joints.iter().enumerate() {
+            let joint = match joint {
+                Some(joint) if !joint.is_broken() => joint,
+                _ => continue,
+            };
+            let (slot_a, slot_b) = (joint.body_a.0, joint.body_b.0);
+            let (body_a, body_b) = match (&bodies[slot_a], &bodies[slot_b]) {
+                (Some(a), Some(b)) if a.is_active() || b.is_active() => (a, b),
+                _ => continue,
+            };
+            let a = solver.solver_body(slot_a, body_a);
+            let b = solver.solver_body(slot_b, body_b);
+            let (sa, sb) = (&solver.bodies[a], &solver.bodies[b]);
+            let rows = joint
+                .rows(body_a, body_b, config.correction_factor, dt)
+                .into_iter()
+                .map(|row| {
+                    let k = (sa.inv_mass + sb.inv_mass) * row.linear.magnitude2()
+                        + (sa.inv_inertia * row.angular_a).dot(row.angular_a)
+                        + (sb.inv_inertia * row.angular_b).dot(row.angular_b)
+                        + row.gamma;
+                    JointRowConstraint {
+                        row,
+                        mass: inverse_or_zero(k),
+                        impulse: if config.warm_starting {
+                            joint.impulses[row.slot]
+                        } else {
+                            0.0
+                        },
+                    }
+                })
+                .collect();
+            solver.joints.push(JointConstraint {
+                body_a: a,
+                body_b: b,
+                joint: j,
+                rows,
+            });
+        }
+
+        for (m, manifold) in manifolds.iter().enumerate() {
+            let (slot_a, slot_b) = (manifold.body_a.0, manifold.body_b.0);
+            let (body_a, body_b) = match (&bodies[slot_a], &bodies[slot_b]) {
+                (Some(a), Some(b)) if a.is_active() || b.is_active() => (a, b),
+                _ => continue,
+            };
+            if manifold.points.is_empty() {
+                continue;
+            }
+            let a = solver.solver_body(slot_a, body_a);
+            let b = solver.solver_body(slot_b, body_b);
+            let (sa, sb) = (&solver.bodies[a], &solver.bodies[b]);
+            let mass = |r_a: Vector3<f32>, r_b: Vector3<f32>, d: Vector3<f32>| {
+                inverse_or_zero(sa.inv_effective_mass(r_a, d) + sb.inv_effective_mass(r_b, d))
+            };
+
+            let normal = manifold
+                .points
+                .iter()
+                .fold(Vector3::zero(), |sum, p| sum + p.normal)
+                .normalize();
+            let tangents = tangent_basis(normal);
+            let center = manifold
+                .points
+                .iter()
+                .fold(Vector3::zero(), |sum, p| sum + p.position)
+                / manifold.points.len() as f32;
+            let twist_radius = manifold
+                .points
+                .iter()
+                .map(|p| (p.position - center).magnitude())
+                .sum::<f32>()
+                / manifold.points.len() as f32;
+            let center_a = center - body_a.position;
+            let center_b = center - body_b.position;
+
+            let mut points = Vec::with_capacity(manifold.points.len());
+            for (p, point) in manifold.points.iter().enumerate() {
+                let r_a = point.position - body_a.position;
+                let r_b = point.position - body_b.position;
+
+                let relative_normal_velocity =
+                    (sb.velocity_at(r_b) - sa.velocity_at(r_a)).dot(point.normal);
+                let mut velocity_bias = 0.0;
+                if point.penetration < 0.0 {
+                    // Still apart, let the bodies close the gap this step but no further
+                    velocity_bias = point.penetration / dt;
+                }
+                if manifold.restitution > 0.0
+                    && relative_normal_velocity < -config.restitution_threshold
+                    && relative_normal_velocity * dt <= point.penetration
+                {
+                    // Bounce as soon as the gap would close this step, waiting a step loses it
+                    velocity_bias = -manifold.restitution * relative_normal_velocity;
+                }
+                let mut position_bias = 0.0;
+                let overlap = point.penetration - config.allowed_penetration;
+                if overlap > 0.0 {
+                    let correction = config.correction_factor * overlap / dt;
+                    match config.position_correction {
+                        PositionCorrection::Baumgarte => {
+                            velocity_bias = velocity_bias.max(correction)
+                        }
+                        PositionCorrection::SplitImpulse => position_bias = correction,
+                    }
+                }
+
+                points.push(PointConstraint {
+                    point: p,
+                    r_a,
+                    r_b,
+                    normal_mass: mass(r_a, r_b, point.normal),
+                    velocity_bias,
+                    position_bias,
+                    normal_impulse: if config.warm_starting {
+                        point.normal_impulse
+                    } else {
+                        0.0
+                    },
+                    pseudo_impulse: 0.0,
+                });
+            }
+
+            let (tangent_impulse, twist_impulse) = if config.warm_starting {
+                (
+                    [
+                        manifold.friction_impulse.dot(tangents[0]),
+                        manifold.friction_impulse.dot(tangents[1]),
+                    ],
+                    manifold.twist_impulse,
+                )
+            } else {
+                ([0.0, 0.0], 0.0)
+            };
+
+            solver.constraints.push(ManifoldConstraint {
+                body_a: a,
+                body_b: b,
+                manifold: m,
+                normal,
+                tangents,
+                points,
+                friction: manifold.friction,
+                twist_radius,
+                center_a,
+                center_b,
+                tangent_mass: [
+                    mass(center_a, center_b, tangents[0]),
+                    mass(center_a, center_b, tangents[1]),
+                ],
+                twist_mass: inverse_or_zero(
+                    (sa.inv_inertia * normal).dot(normal) + (sb.inv_inertia * normal).dot(normal),
+                ),
+                tangent_impulse,
+                twist_impulse,
+            });
+        }
+        solver
+    }
+
+    fn solver_body(&mut self, slot: usize, body: &RigidBody) -> usize {
+        if let Some(index) = self.body_slots[slot] {
+            return index;
+        }
+        self.bodies.push(SolverBody::new(body));
+        self.body_slots[slot] = Some(self.bodies.len() - 1);
+        self.bodies.len() - 1
+    }
+
+    /// Applies last step's impulses before iterating
+    pub(crate) fn warm_start(&mut self) {
+        for j in &self.joints {
+            let (a, b) = pair_mut(&mut self.bodies, j.body_a, j.body_b);
+            for r in &j.rows {
+                apply_row_impulse(a, b, &r.row, r.impulse);
+            }
+        }
+        for c in &self.constraints {
+            let (a, b) = pair_mut(&mut self.bodies, c.body_a, c.body_b);
+            for p in &c.points {
+                let impulse = c.normal * p.normal_impulse;
+                a.apply_impulse(-impulse, p.r_a);
+                b.apply_impulse(impulse, p.r_b);
+            }
+            let friction =
+                c.tangents[0] * c.tangent_impulse[0] + c.tangents[1] * c.tangent_impulse[1];
+            a.apply_impulse(-friction, c.center_a);
+            b.apply_impulse(friction, c.center_b);
+            let twist = c.normal * c.twist_impulse;
+            a.angular_velocity -= a.inv_inertia * twist;
+            b.angular_velocity += b.inv_inertia * twist;
+        }
+    }
+
+    pub(crate) fn solve_velocities(&mut self) {
+        for _ in 0..self.config.velocity_iterations {
+            // Joints first, so contacts have the last word and jointed bodies do not sink
+            for j in &mut self.joints {
+                let (a, b) = pair_mut(&mut self.bodies, j.body_a, j.body_b);
+                for r in &mut j.rows {
+                    let row = &r.row;
+                    let velocity = row.linear.dot(b.linear_velocity - a.linear_velocity)
+                        + row.angular_b.dot(b.angular_velocity)
+                        - row.angular_a.dot(a.angular_velocity);
+                    let lambda = -r.mass * (velocity + row.bias + row.gamma * r.impulse);
+                    let old = r.impulse;
+                    r.impulse = (old + lambda).max(row.lower).min(row.upper);
+                    apply_row_impulse(a, b, row, r.impulse - old);
+                }
+            }
+            for c in &mut self.constraints {
+                let (a, b) = pair_mut(&mut self.bodies, c.body_a, c.body_b);
+                let total_normal: f32 = c.points.iter().map(|p| p.normal_impulse).sum();
+
+                // Friction first, it is less important than not sinking so normal gets the last word
+                let max_twist = c.friction * total_normal * c.twist_radius;
+                let twist_velocity = (b.angular_velocity - a.angular_velocity).dot(c.normal);
+                let old = c.twist_impulse;
+                c.twist_impulse =
+                    (old - twist_velocity * c.twist_mass).clamp(-max_twist, max_twist);
+                let twist = c.normal * (c.twist_impulse - old);
+                a.angular_velocity -= a.inv_inertia * twist;
+                b.angular_velocity += b.inv_inertia * twist;
+
+                let max_friction = c.friction * total_normal;
+                let dv = b.velocity_at(c.center_b) - a.velocity_at(c.center_a);
+                let old = c.tangent_impulse;
+                let mut new = [
+                    old[0] - dv.dot(c.tangents[0]) * c.tangent_mass[0],
+                    old[1] - dv.dot(c.tangents[1]) * c.tangent_mass[1],
+                ];
+                let length = (new[0] * new[0] + new[1] * new[1]).sqrt();
+                if length > max_friction {
+                    let scale = max_friction / length;
+                    new = [new[0] * scale, new[1] * scale];
+                }
+                c.tangent_impulse = new;
+                let friction =
+                    c.tangents[0] * (new[0] - old[0]) + c.tangents[1] * (new[1] - old[1]);
+                a.apply_impulse(-friction, c.center_a);
+                b.apply_impulse(friction, c.center_b);
+
+                for p in &mut c.points {
+                    let dv = b.velocity_at(p.r_b) - a.velocity_at(p.r_a);
+                    let lambda = -(dv.dot(c.normal) - p.velocity_bias) * p.normal_mass;
+                    let old = p.normal_impulse;
+                    p.normal_impulse = (old + lambda).max(0.0);
+                    let impulse = c.normal * (p.normal_impulse - old);
+                    a.apply_impulse(-impulse, p.r_a);
+                    b.apply_impulse(impulse, p.r_b);
+                }
+            }
+        }
+    }
+
+    /// Split impulse pass, the pseudo velocities it builds only ever move positions
+    pub(crate) fn solve_positions(&mut self) {
+        if self.config.position_correction != PositionCorrection::SplitImpulse {
+            return;
+        }
+        for _ in 0..self.config.position_iterations {
+            for c in &mut self.constraints {
+                let (a, b) = pair_mut(&mut self.bodies, c.body_a, c.body_b);
+                for p in &mut c.points {
+                    if p.position_bias <= 0.0 {
+                        continue;
+                    }
+                    let dv = b.pseudo_velocity_at(p.r_b) - a.pseudo_velocity_at(p.r_a);
+                    let lambda = -(dv.dot(c.normal) - p.position_bias) * p.normal_mass;
+                    let old = p.pseudo_impulse;
+                    p.pseudo_impulse = (old + lambda).max(0.0);
+                    let impulse = c.normal * (p.pseudo_impulse - old);
+                    a.apply_pseudo_impulse(-impulse, p.r_a);
+                    b.apply_pseudo_impulse(impulse, p.r_b);
+                }
+            }
+        }
+    }
+
+    /// Copies the new velocities back to the dynamic bodies and the impulses to the contacts and
+    /// joints, breaking joints that took too much force. Returns the split impulse velocities for
+    /// `RigidBody::integrate_position`
+    pub(crate) fn finish(
+        self,
+        bodies: &mut [Option<RigidBody>],
+        manifolds: &mut [ContactManifold],
+        joints: &mut [Option<Joint>],
+    ) -> Vec<Option<(Vector3<f32>, Vector3<f32>)>> {
+        let mut pseudo_velocities = vec![None; bodies.len()];
+        for (slot, index) in self.body_slots.iter().enumerate() {
+            let (body, solved) = match (&mut bodies[slot], index) {
+                (Some(body), Some(index)) => (body, &self.bodies[*index]),
+                _ => continue,
+            };
+            if !body.is_dynamic() {
+                continue;
+            }
+            body.linear_velocity = solved.linear_velocity;
+            body.angular_velocity = solved.angular_velocity;
+            pseudo_velocities[slot] = Some((solved.pseudo_linear, solved.pseudo_angular));
+        }
+        for c in &self.constraints {
+            let manifold = &mut manifolds[c.manifold];
+            for p in &c.points {
+                manifold.points[p.point].normal_impulse = p.normal_impulse;
+            }
+            manifold.friction_impulse =
+                c.tangents[0] * c.tangent_impulse[0] + c.tangents[1] * c.tangent_impulse[1];
+            manifold.twist_impulse = c.twist_impulse;
+        }
+        for j in &self.joints {
+            let joint = match &mut joints[j.joint] {
+                Some(joint) => joint,
+                None => continue,
+            };
+            joint.impulses = [0.0; MAX_JOINT_ROWS];
+            let mut reaction = Vector3::zero();
+            for r in &j.rows {
+                joint.impulses[r.row.slot] = r.impulse;
+                reaction += r.row.linear * r.impulse;
+            }
+            joint.set_force(reaction.magnitude() / self.dt);
+        }
+        pseudo_velocities
+    }
+}
+
+fn inverse_or_zero(k: f32) -> f32 {
+    if k > 0.0 {
+        1.0 / k
+    } else {
+        0.0
+    }
+}
+
+/// Two directions at right angles to `normal` and each other
+pub(crate) fn tangent_basis(normal: Vector3<f32>) -> [Vector3<f32>; 2] {
+    // Build from the normal's largest components so the cross product never gets tiny
+    let t1 = if normal.x.abs() >= 0.57735 {
+        Vector3::new(normal.y, -normal.x, 0.0)
+    } else {
+        Vector3::new(0.0, normal.z, -normal.y)
+    }
+    .normalize();
+    [t1, normal.cross(t1)]
+}
+
+fn apply_row_impulse(a: &mut SolverBody, b: &mut SolverBody, row: &JointRow, impulse: f32) {
+    a.linear_velocity -= row.linear * (a.inv_mass * impulse);
+    a.angular_velocity -= a.inv_inertia * row.angular_a * impulse;
+    b.linear_velocity += row.linear * (b.inv_mass * impulse);
+    b.angular_velocity += b.inv_inertia * row.angular_b * impulse;
+}
+
+/// Borrows two different bodies at once, contacts and joints never join a body to itself
+fn pair_mut(bodies: &mut [SolverBody], a: usize, b: usize) -> (&mut SolverBody, &mut SolverBody) {
+    if a < b {
+        let (low, high) = bodies.split_at_mut(b);
+        (&mut low[a], &mut high[0])
+    } else {
+        let (low, high) = bodies.split_at_mut(a);
+        (&mut high[0], &mut low[b])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{box_stack, ground};
+    use super::super::{PhysicsWorld, FIXED_TIMESTEP};
+    use super::PositionCorrection;
+    use cgmath::{InnerSpace, Vector3};
+
+    const CORRECTIONS: [PositionCorrection; 2] = [
+        PositionCorrection::SplitImpulse,
+        PositionCorrection::Baumgarte,
+    ];
+
+    /// Drops a stack of boxes that never sleep and gives it two seconds to settle. Returns the
+    /// furthest any box got from its place in the stack and the fastest any moved, over the
+    /// following `seconds`
+    fn settle(boxes: usize, correction: PositionCorrection, seconds: u32) -> (f32, f32) {
+        let mut world = PhysicsWorld::new();
+        world.solver.position_correction = correction;
+        ground(&mut world);
+        let stack = box_stack(&mut world, boxes);
+        for &body in &stack {
+            world.body_mut(body).unwrap().can_sleep = false;
+        }
+        for _ in 0..120 {
+            world.step(FIXED_TIMESTEP);
+        }
+        let (mut error, mut speed) = (0.0f32, 0.0f32);
+        for _ in 0..seconds * 60 {
+            world.step(FIXED_TIMESTEP);
+            for (i, &handle) in stack.iter().enumerate() {
+                let body = world.body(handle).unwrap();
+                let offset = body.position - Vector3::new(0.0, 0.5 + i as f32, 0.0);
+                error = error.max(offset.magnitude());
+                speed = speed
+                    .max(body.linear_velocity.magnitude())
+                    .max(body.angular_velocity.magnitude());
+            }
+        }
+        (error, speed)
+    }
+
+    #[test]
+    fn box_resting_on_the_ground_stays_put() {
+        for &correction in CORRECTIONS.iter() {
+            let (error, speed) = settle(1, correction, 10);
+            assert!(error < 0.001, "{:?} moved {} m", correction, error);
+            assert!(speed < 0.001, "{:?} moved at {} m/s", correction, speed);
+        }
+    }
+
+    #[test]
+    fn box_stack_settles_and_stays_at_rest() {
+        for &correction in CORRECTIONS.iter() {
+            let (error, speed) = settle(4, correction, 10);
+            assert!(error < 0.01, "{:?} moved {} m", correction, error);
+            assert!(speed < 0.01, "{:?} moved at {} m/s", correction, speed);
+        }
+    }
+
+    #[test]
+    fn tall_stack_falls_asleep_in_place() {
+        let mut world = PhysicsWorld::new();
+        ground(&mut world);
+        let stack = box_stack(&mut world, 8);
+        for _ in 0..600 {
+            world.step(FIXED_TIMESTEP);
+        }
+        for (i, &handle) in stack.iter().enumerate() {
+            let body = world.body(handle).unwrap();
+            let offset = body.position - Vector3::new(0.0, 0.5 + i as f32, 0.0);
+            assert!(body.is_sleeping(), "box {} is still awake", i);
+            assert!(
+                offset.magnitude() < 0.02,
+                "box {} moved {} m",
+                i,
+                offset.magnitude()
+            );
+        }
+    }
+}
@@ -0,0 +1,722 @@
+use super::body::{BodyHandle, RigidBody};
+use super::character::Ground;
+use super::query::{QueryFilter, Ray};
+use super::shape::Isometry;
+use super::PhysicsWorld;
+use crate::math;
+use cgmath::{InnerSpace, Rad, Vector3};
+
+/// Below this speed slip is measured against it instead, so a car at rest does not see huge slip
+/// from tiny velocities
+const LOW_SPEED: f32 = 1.0;
+
+const RADIANS_PER_SECOND_TO_RPM: f32 = 60.0 / (2.0 * std::f32::consts::PI);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct VehicleHandle(pub(crate) usize);
+
+/// What the driver asks for, mapped from input actions every frame
+#[derive(Debug, Copy, Clone, Default)]
+pub struct VehicleControls {
+    /// From 0 to 1
+    pub throttle: f32,
+    /// From 0 to 1. Held at a standstill it puts an automatic gearbox in reverse, where it drives
+    /// the car backwards and the throttle brakes instead
+    pub brake: f32,
+    /// From -1 for full lock left to 1 for full lock right
+    pub steering: f32,
+    pub handbrake: bool,
+}
+
+/// How hard a tire grips for how much it slips. Grip rises in a straight line to its peak, then
+/// falls off to the sliding grip by twice the peak slip
+#[derive(Debug, Copy, Clone)]
+pub struct SlipCurve {
+    pub peak_slip: f32,
+    /// Friction coefficient at the peak, the force is this times the load on the wheel
+    pub peak_grip: f32,
+    pub sliding_grip: f32,
+}
+
+impl SlipCurve {
+    /// Friction coefficient for `slip`, with the same sign
+    pub fn grip(&self, slip: f32) -> f32 {
+        let over = slip.abs() / self.peak_slip;
+        let grip = if over < 1.0 {
+            over * self.peak_grip
+        } else {
+            self.peak_grip + (self.sliding_grip - self.peak_grip) * (over - 1.0).min(1.0)
+        };
+        grip.copysign(slip)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Tire {
+    /// Against the slip ratio, how much faster the tread moves than the ground under it
+    pub longitudinal: SlipCurve,
+    /// Against the slip angle in radians, between where the wheel points and where it goes
+    pub lateral: SlipCurve,
+}
+
+impl Default for Tire {
+    fn default() -> Self {
+        Tire {
+            longitudinal: SlipCurve {
+                peak_slip: 0.1,
+                peak_grip: 1.0,
+                sliding_grip: 0.75,
+            },
+            lateral: SlipCurve {
+                peak_slip: 0.15,
+                peak_grip: 1.0,
+                sliding_grip: 0.7,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct WheelConfig {
+    /// Top of the suspension in the chassis body's space. Vehicles face -Z with +Y up
+    pub mount: Vector3<f32>,
+    pub radius: f32,
+    /// Moment of inertia about the axle
+    pub inertia: f32,
+    /// How far the wheel hangs below the mount with the spring relaxed
+    pub suspension_length: f32,
+    /// Spring force per unit of compression
+    pub stiffness: f32,
+    /// Force per unit of compression speed, stops the spring bouncing
+    pub damping: f32,
+    pub steered: bool,
+    /// Gets torque from the engine through the differential
+    pub driven: bool,
+    /// Torque the brake applies at full press
+    pub brake_torque: f32,
+    pub handbrake_torque: f32,
+    pub tire: Tire,
+}
+
+impl WheelConfig {
+    /// An undriven, unsteered wheel of a mid sized car
+    pub fn new(mount: Vector3<f32>) -> WheelConfig {
+        WheelConfig {
+            mount,
+            radius: 0.35,
+            inertia: 1.2,
+            suspension_length: 0.35,
+            stiffness: 35_000.0,
+            damping: 4_000.0,
+            steered: false,
+            driven: false,
+            brake_torque: 2_000.0,
+            handbrake_torque: 0.0,
+            tire: Tire::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Engine {
+    /// Torque at full throttle against rpm, sorted by rpm. It is interpolated between points and
+    /// held flat past either end
+    pub torque_curve: Vec<(f32, f32)>,
+    /// The engine never turns slower than this, the clutch slips to keep it running
+    pub idle_rpm: f32,
+    /// The rev limiter cuts the torque past this
+    pub max_rpm: f32,
+}
+
+impl Engine {
+    pub fn torque(&self, rpm: f32) -> f32 {
+        if rpm >= self.max_rpm {
+            return 0.0;
+        }
+        let curve = &self.torque_curve;
+        match curve.iter().position(|&(point, _)| point > rpm) {
+            None => curve.last().map_or(0.0, |&(_, torque)| torque),
+            Some(0) => curve[0].1,
+            Some(i) => {
+                let ((rpm_a, torque_a), (rpm_b, torque_b)) = (curve[i - 1], curve[i]);
+                torque_a + (torque_b - torque_a) * (rpm - rpm_a) / (rpm_b - rpm_a)
+            }
+        }
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine {
+            torque_curve: vec![
+                (1_000.0, 200.0),
+                (3_000.0, 300.0),
+                (5_000.0, 320.0),
+                (6_500.0, 260.0),
+            ],
+            idle_rpm: 900.0,
+            max_rpm: 7_000.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Gearbox {
+    /// Forward gear ratios, first gear first
+    pub ratios: Vec<f32>,
+    pub reverse_ratio: f32,
+    /// Applied on top of every gear
+    pub final_drive: f32,
+    /// Shifts up past `shift_up_rpm` and down below `shift_down_rpm` by itself, and into reverse
+    /// when braking at a standstill. A manual gearbox only changes gear through `Vehicle::shift`
+    pub automatic: bool,
+    pub shift_up_rpm: f32,
+    pub shift_down_rpm: f32,
+}
+
+impl Default for Gearbox {
+    fn default() -> Self {
+        Gearbox {
+            ratios: vec![3.2, 2.0, 1.4, 1.05, 0.85, 0.7],
+            reverse_ratio: 3.4,
+            final_drive: 3.4,
+            automatic: true,
+            shift_up_rpm: 6_000.0,
+            shift_down_rpm: 2_500.0,
+        }
+    }
+}
+
+/// How the driven wheels share the engine's torque. It is always split evenly, the differential
+/// decides how far the wheels may spin at different speeds
+#[derive(Debug, Copy, Clone)]
+pub enum Differential {
+    /// Wheels spin freely, an unloaded wheel can spin up while the others get nowhere
+    Open,
+    /// Wheels always spin together
+    Locked,
+    /// Each step pulls the wheels this fraction of the way towards spinning together
+    LimitedSlip(f32),
+}
+
+impl Differential {
+    fn lock(&self) -> f32 {
+        match *self {
+            Differential::Open => 0.0,
+            Differential::Locked => 1.0,
+            Differential::LimitedSlip(lock) => lock.clamp(0.0, 1.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VehicleConfig {
+    pub wheels: Vec<WheelConfig>,
+    pub engine: Engine,
+    pub gearbox: Gearbox,
+    pub differential: Differential,
+    /// How far the steered wheels turn at full lock
+    pub max_steer: Rad<f32>,
+    /// Full lock halves by this speed, and keeps shrinking faster, so steering stays calm at speed
+    pub steer_half_speed: f32,
+    /// Air resistance, the force is this times the speed squared
+    pub drag: f32,
+    /// Layers the wheels drive on
+    pub mask: u32,
+}
+
+impl VehicleConfig {
+    /// Four wheels on the corners of a `track` wide and `wheelbase` long rectangle, centred
+    /// under the chassis origin with the mounts `mount_height` above it. The front wheels steer
+    /// and the rear wheels drive
+    pub fn car(track: f32, wheelbase: f32, mount_height: f32) -> VehicleConfig {
+        let (x, z) = (track * 0.5, wheelbase * 0.5);
+        let corners = [(-x, -z, true), (x, -z, true), (-x, z, false), (x, z, false)];
+        let wheels = corners
+            .iter()
+            .map(|&(x, z, front)| WheelConfig {
+                steered: front,
+                driven: !front,
+                handbrake_torque: if front { 0.0 } else { 3_000.0 },
+                ..WheelConfig::new(Vector3::new(x, mount_height, z))
+            })
+            .collect();
+        VehicleConfig {
+            wheels,
+            engine: Engine::default(),
+            gearbox: Gearbox::default(),
+            differential: Differential::LimitedSlip(0.5),
+            max_steer: Rad(0.6),
+            steer_half_speed: 8.0,
+            drag: 0.4,
+            mask: u32::MAX,
+        }
+    }
+}
+
+/// One wheel's state as of the last step
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Wheel {
+    ground: Option<Ground>,
+    compression: f32,
+    spin: f32,
+    rotation: f32,
+    steer: f32,
+    load: f32,
+    slip_ratio: f32,
+    slip_angle: f32,
+}
+
+impl Wheel {
+    pub fn ground(&self) -> Option<&Ground> {
+        self.ground.as_ref()
+    }
+
+    pub fn is_grounded(&self) -> bool {
+        self.ground.is_some()
+    }
+
+    /// How far the spring is pushed in
+    pub fn compression(&self) -> f32 {
+        self.compression
+    }
+
+    /// Radians per second about the axle, positive when rolling forwards
+    pub fn spin(&self) -> f32 {
+        self.spin
+    }
+
+    /// Steering angle, positive turning left like a rotation about the chassis' up axis
+    pub fn steer(&self) -> f32 {
+        self.steer
+    }
+
+    /// Force the wheel presses into the ground with
+    pub fn load(&self) -> f32 {
+        self.load
+    }
+
+    pub fn slip_ratio(&self) -> f32 {
+        self.slip_ratio
+    }
+
+    pub fn slip_angle(&self) -> f32 {
+        self.slip_angle
+    }
+}
+
+/// A car driving on raycast wheels. The chassis is an ordinary dynamic body that collides like
+/// any other, every step the world pushes it with the suspension and tire forces
+#[derive(Debug, Clone)]
+pub struct Vehicle {
+    pub config: VehicleConfig,
+    pub controls: VehicleControls,
+    chassis: BodyHandle,
+    wheels: Vec<Wheel>,
+    /// Negative for reverse, zero for neutral
+    gear: i32,
+    rpm: f32,
+    speed: f32,
+}
+
+impl Vehicle {
+    /// Drives `chassis`, which should be a dynamic body with its colliders already attached
+    pub fn new(chassis: BodyHandle, config: VehicleConfig) -> Vehicle {
+        Vehicle {
+            wheels: vec![Wheel::default(); config.wheels.len()],
+            rpm: config.engine.idle_rpm,
+            config,
+            controls: VehicleControls::default(),
+            chassis,
+            gear: 1,
+            speed: 0.0,
+        }
+    }
+
+    pub fn chassis(&self) -> BodyHandle {
+        self.chassis
+    }
+
+    pub fn wheels(&self) -> &[Wheel] {
+        &self.wheels
+    }
+
+    pub fn gear(&self) -> i32 {
+        self.gear
+    }
+
+    pub fn rpm(&self) -> f32 {
+        self.rpm
+    }
+
+    /// Speed along the way the chassis faces, negative when reversing
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Puts the gearbox in `gear`, clamped to the gears it has
+    pub fn shift(&mut self, gear: i32) {
+        self.gear = gear.clamp(-1, self.config.gearbox.ratios.len() as i32);
+    }
+
+    /// Where to draw a wheel, given the chassis body
+    pub fn wheel_isometry(&self, index: usize, chassis: &RigidBody) -> Option<Isometry> {
+        let (wheel, config) = (self.wheels.get(index)?, self.config.wheels.get(index)?);
+        let hang = config.suspension_length - wheel.compression;
+        let centre = config.mount - Vector3::unit_y() * hang;
+        // Rolling forwards down -Z turns the wheel backwards about +X
+        let rotation = chassis.rotation
+            * math::axis_angle(Vector3::unit_y(), wheel.steer)
+            * math::axis_angle(Vector3::unit_x(), -wheel.rotation);
+        Some(Isometry::new(chassis.to_world(centre), rotation))
+    }
+
+    pub(crate) fn update(&mut self, world: &mut PhysicsWorld, dt: f32) {
+        let chassis = match world.body(self.chassis) {
+            Some(body) if body.is_dynamic() => body.clone(),
+            _ => return,
+        };
+        let up = chassis.rotation * Vector3::unit_y();
+        let forward = chassis.rotation * -Vector3::unit_z();
+        self.speed = chassis.linear_velocity.dot(forward);
+        let controls = self.clamped_controls();
+
+        // Wheels only turn as far as still feels sensible at this speed
+        let lock =
+            self.config.max_steer.0 / (1.0 + (self.speed / self.config.steer_half_speed).powi(2));
+        let steer = -controls.steering * lock;
+        self.touch_ground(world, &chassis, up, steer);
+
+        let (throttle, brake) = self.drive_train(&controls);
+        let torques = self.wheel_torques(throttle);
+
+        let grounded = self.wheels.iter().filter(|w| w.is_grounded()).count();
+        // Tire forces are capped at what stops the wheel's share of the chassis slipping this
+        // step, otherwise stiff tires overshoot back and forth at low speeds
+        let share = chassis.mass() / grounded.max(1) as f32;
+        let mut forces = vec![];
+        for ((wheel, config), torque) in
+            self.wheels.iter_mut().zip(&self.config.wheels).zip(torques)
+        {
+            let brake_torque = brake * config.brake_torque
+                + if controls.handbrake {
+                    config.handbrake_torque
+                } else {
+                    0.0
+                };
+            let ground = match wheel.ground {
+                Some(ground) => ground,
+                None => {
+                    wheel.spin += torque / config.inertia * dt;
+                    apply_brake(wheel, brake_torque / config.inertia * dt);
+                    wheel.rotation =
+                        (wheel.rotation + wheel.spin * dt) % (2.0 * std::f32::consts::PI);
+                    continue;
+                }
+            };
+            let normal = ground.normal;
+            let heading = math::axis_angle(up, wheel.steer) * forward;
+            let along = (heading - normal * heading.dot(normal)).normalize();
+            let side = along.cross(normal);
+
+            let mut velocity = chassis.velocity_at_point(ground.position);
+            if let Some(body) = world.body(ground.body) {
+                velocity -= body.velocity_at_point(ground.position);
+            }
+            let (v_along, v_side) = (velocity.dot(along), velocity.dot(side));
+            let reference = v_along.abs().max(LOW_SPEED);
+            let slip_speed = wheel.spin * config.radius - v_along;
+            wheel.slip_ratio = slip_speed / reference;
+            wheel.slip_angle = math::atan2(v_side, reference);
+
+            let tire = &config.tire;
+            let mut long = tire.longitudinal.grip(wheel.slip_ratio) * wheel.load;
+            let mut lat = -tire.lateral.grip(wheel.slip_angle) * wheel.load;
+            // Both directions share one friction circle
+            let used = ((long / tire.longitudinal.peak_grip).powi(2)
+                + (lat / tire.lateral.peak_grip).powi(2))
+            .sqrt();
+            if used > wheel.load && used > 0.0 {
+                long *= wheel.load / used;
+                lat *= wheel.load / used;
+            }
+            let (long_cap, lat_cap) = (share / dt * slip_speed.abs(), share / dt * v_side.abs());
+            long = long.clamp(-long_cap, long_cap);
+            lat = lat.clamp(-lat_cap, lat_cap);
+
+            // The tread pulls back on the wheel, but never past rolling freely
+            let rolling = v_along / config.radius;
+            let spin = wheel.spin + (torque - long * config.radius) / config.inertia * dt;
+            wheel.spin = if (wheel.spin - rolling) * (spin - rolling) < 0.0 {
+                rolling
+            } else {
+                spin
+            };
+            apply_brake(wheel, brake_torque / config.inertia * dt);
+            wheel.rotation = (wheel.rotation + wheel.spin * dt) % (2.0 * std::f32::consts::PI);
+
+            // The spring pushes where the wheel touches, but the tire forces push at the axle. Real
+            // suspension geometry keeps cars from rolling over as easily as they would if the
+            // tires pulled sideways at ground level
+            let axle = ground.position + up * config.radius;
+            forces.push((ground.body, up * wheel.load, ground.position));
+            forces.push((ground.body, along * long + side * lat, axle));
+        }
+        self.lock_differential();
+
+        let drag =
+            -chassis.linear_velocity * chassis.linear_velocity.magnitude() * self.config.drag;
+        if let Some(body) = world.body_mut(self.chassis) {
+            body.apply_force(drag);
+            for &(_, force, point) in &forces {
+                body.apply_force_at_point(force, point);
+            }
+        }
+        // Whatever the wheels stand on gets pushed back
+        for (ground, force, point) in forces {
+            if let Some(body) = world.body_mut(ground).filter(|b| b.is_dynamic()) {
+                body.apply_force_at_point(-force, point);
+            }
+        }
+    }
+
+    fn clamped_controls(&self) -> VehicleControls {
+        let c = self.controls;
+        VehicleControls {
+            throttle: c.throttle.clamp(0.0, 1.0),
+            brake: c.brake.clamp(0.0, 1.0),
+            steering: c.steering.clamp(-1.0, 1.0),
+            handbrake: c.handbrake,
+        }
+    }
+
+    /// Casts each wheel down from its mount and works out how hard its spring pushes
+    fn touch_ground(
+        &mut self,
+        world: &PhysicsWorld,
+        chassis: &RigidBody,
+        up: Vector3<f32>,
+        steer: f32,
+    ) {
+        let exclude = [self.chassis];
+        let filter = QueryFilter::new()
+            .with_mask(self.config.mask)
+            .excluding(&exclude);
+        for (wheel, config) in self.wheels.iter_mut().zip(&self.config.wheels) {
+            wheel.steer = if config.steered { steer } else { 0.0 };
+            let reach = config.suspension_length + config.radius;
+            let mount = chassis.to_world(config.mount);
+            match world.cast_ray(&Ray::new(mount, -up), reach, &filter) {
+                Some(hit) => {
+                    wheel.compression = (reach - hit.distance).min(config.suspension_length);
+                    // Damped by how fast the chassis moves towards the ground rather than by how
+                    // the compression changed, which jumps when the wheel rolls onto a ledge
+                    let mut velocity = chassis.velocity_at_point(mount);
+                    if let Some(body) = world.body(hit.body) {
+                        velocity -= body.velocity_at_point(hit.position);
+                    }
+                    let compression_speed = -velocity.dot(up);
+                    wheel.load = (config.stiffness * wheel.compression
+                        + config.damping * compression_speed)
+                        .max(0.0);
+                    wheel.ground = Some(Ground::from(hit));
+                }
+                None => {
+                    wheel.compression = 0.0;
+                    wheel.load = 0.0;
+                    wheel.slip_ratio = 0.0;
+                    wheel.slip_angle = 0.0;
+                    wheel.ground = None;
+                }
+            }
+        }
+    }
+
+    /// Shifts gear if the gearbox is automatic and works out the engine speed. Returns how far
+    /// the throttle and brake are pressed, swapped while reversing
+    fn drive_train(&mut self, controls: &VehicleControls) -> (f32, f32) {
+        if self.config.gearbox.automatic {
+            let stopped = self.speed.abs() < LOW_SPEED;
+            if self.gear >= 0 && stopped && controls.brake > 0.0 && controls.throttle == 0.0 {
+                self.gear = -1;
+            } else if self.gear <= 0 && stopped && controls.throttle > 0.0 && controls.brake == 0.0
+            {
+                self.gear = 1;
+            }
+        }
+
+        let gearbox = &self.config.gearbox;
+        if gearbox.automatic && self.gear >= 1 {
+            // Shifts go by how fast the car moves rather than how fast the wheels spin, so
+            // wheelspin does not run it up through the gears
+            let speed = self.speed;
+            let rolling = self.driven_average(|_, config| speed / config.radius);
+            let rpm = (rolling * self.ratio()).abs() * RADIANS_PER_SECOND_TO_RPM;
+            let top = gearbox.ratios.len() as i32;
+            if rpm > gearbox.shift_up_rpm && self.gear < top {
+                self.gear += 1;
+            } else if rpm < gearbox.shift_down_rpm && self.gear > 1 {
+                self.gear -= 1;
+            }
+        }
+        let spin = self.driven_average(|wheel, _| wheel.spin);
+        self.rpm = ((spin * self.ratio()).abs() * RADIANS_PER_SECOND_TO_RPM)
+            .max(self.config.engine.idle_rpm);
+
+        if self.gear < 0 && self.config.gearbox.automatic {
+            (controls.brake, controls.throttle)
+        } else {
+            (controls.throttle, controls.brake)
+        }
+    }
+
+    /// Overall ratio from the engine to the driven wheels, negative in reverse
+    fn ratio(&self) -> f32 {
+        let gearbox = &self.config.gearbox;
+        let gear = match self.gear {
+            g if g < 0 => -gearbox.reverse_ratio,
+            0 => 0.0,
+            g => gearbox.ratios.get(g as usize - 1).cloned().unwrap_or(0.0),
+        };
+        gear * gearbox.final_drive
+    }
+
+    /// Average of `value` over the driven wheels
+    fn driven_average(&self, value: impl Fn(&Wheel, &WheelConfig) -> f32) -> f32 {
+        let (sum, count) = self
+            .wheels
+            .iter()
+            .zip(&self.config.wheels)
+            .filter(|(_, config)| config.driven)
+            .fold((0.0, 0), |(sum, count), (wheel, config)| {
+                (sum + value(wheel, config), count + 1)
+            });
+        if count == 0 {
+            0.0
+        } else {
+            sum / count as f32
+        }
+    }
+
+    /// Engine torque at the current rpm, split evenly over the driven wheels
+    fn wheel_torques(&self, throttle: f32) -> Vec<f32> {
+        let driven = self
+            .config
+            .wheels
+            .iter()
+            .filter(|w| w.driven)
+            .count()
+            .max(1);
+        let total = self.config.engine.torque(self.rpm) * throttle * self.ratio();
+        self.config
+            .wheels
+            .iter()
+            .map(|w| if w.driven { total / driven as f32 } else { 0.0 })
+            .collect()
+    }
+
+    fn lock_differential(&mut self) {
+        let lock = self.config.differential.lock();
+        let average = self.driven_average(|wheel, _| wheel.spin);
+        for (wheel, config) in self.wheels.iter_mut().zip(&self.config.wheels) {
+            if config.driven {
+                wheel.spin += (average - wheel.spin) * lock;
+            }
+        }
+    }
+}
+
+/// Slows the wheel's spin by `amount`, stopping at zero
+fn apply_brake(wheel: &mut Wheel, amount: f32) {
+    if wheel.spin.abs() <= amount {
+        wheel.spin = 0.0;
+    } else {
+        wheel.spin -= amount.copysign(wheel.spin);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Collider, MassProperties, FIXED_TIMESTEP};
+    use super::*;
+
+    const CHASSIS_HALF_EXTENTS: Vector3<f32> = Vector3::new(0.9, 0.4, 2.2);
+
+    /// A long flat road with speed bumps across it every 100m, and a car at the start facing
+    /// down it
+    fn test_track() -> (PhysicsWorld, VehicleHandle) {
+        let mut world = PhysicsWorld::new();
+        let ground = world.add_body(RigidBody::fixed(Vector3::new(0.0, -0.5, -900.0)));
+        world
+            .add_collider(ground, Collider::cuboid(Vector3::new(400.0, 0.5, 1000.0)))
+            .unwrap();
+        for i in 1..10 {
+            let bump = world.add_body(RigidBody::fixed(Vector3::new(0.0, 0.0, i as f32 * -100.0)));
+            world
+                .add_collider(bump, Collider::cuboid(Vector3::new(20.0, 0.04, 0.3)))
+                .unwrap();
+        }
+
+        let chassis = world.add_body(RigidBody::dynamic(
+            Vector3::new(0.0, 1.0, 0.0),
+            MassProperties::cuboid(1_200.0, CHASSIS_HALF_EXTENTS),
+        ));
+        world
+            .add_collider(chassis, Collider::cuboid(CHASSIS_HALF_EXTENTS))
+            .unwrap();
+        let vehicle = Vehicle::new(chassis, VehicleConfig::car(1.6, 2.8, -0.2));
+        let handle = world.add_vehicle(vehicle);
+        (world, handle)
+    }
+
+    /// Flat out down the straight, a long sweeping turn at speed, then braking to a stop
+    #[test]
+    fn car_drives_the_test_track_without_tipping_over() {
+        let (mut world, handle) = test_track();
+        let chassis = world.vehicle(handle).unwrap().chassis();
+        let mut top_speed: f32 = 0.0;
+        for step in 0..(30.0 / FIXED_TIMESTEP) as usize {
+            let time = step as f32 * FIXED_TIMESTEP;
+            let speed = world.vehicle(handle).unwrap().speed();
+            let controls = match time {
+                t if t < 14.0 => VehicleControls {
+                    throttle: 1.0,
+                    ..VehicleControls::default()
+                },
+                t if t < 20.0 => VehicleControls {
+                    throttle: 1.0,
+                    steering: 0.3,
+                    ..VehicleControls::default()
+                },
+                _ if speed.abs() < 0.5 => return,
+                _ => VehicleControls {
+                    brake: 1.0,
+                    ..VehicleControls::default()
+                },
+            };
+            world.vehicle_mut(handle).unwrap().controls = controls;
+            world.step(FIXED_TIMESTEP);
+
+            let body = world.body(chassis).unwrap();
+            let up = body.rotation * Vector3::unit_y();
+            assert!(
+                body.position.magnitude().is_finite()
+                    && body.linear_velocity.magnitude().is_finite(),
+                "the car blew up at {}s",
+                time
+            );
+            assert!(
+                up.y > 0.9,
+                "the car tipped over at {}s, up is {:?}",
+                time,
+                up
+            );
+            top_speed = top_speed.max(world.vehicle(handle).unwrap().speed());
+            if time > 14.0 {
+                assert!(top_speed > 30.0, "the car only got up to {}m/s", top_speed);
+            }
+        }
+        let speed = world.vehicle(handle).unwrap().speed();
+        panic!("the car was still going {}m/s after 10s of braking", speed);
+    }
+}
@@ -14,6 +14,18 @@ pub struct Vertex {
 
 vulkano::impl_vertex!(Vertex, position);
 
+impl Vertex {
+    pub fn new(position: [f32; 3]) -> Vertex {
+        Vertex {
+            position: (position[0], position[1], position[2]),
+        }
+    }
+
+    pub fn position(&self) -> [f32; 3] {
+        [self.position.0, self.position.1, self.position.2]
+    }
+}
+
 pub const VERTICES: [Vertex; 531] = [
     Vertex {
         position: (0.0, 0.0, 0.0),
@@ -1618,6 +1630,14 @@ pub struct Normal {
 
 vulkano::impl_vertex!(Normal, normal);
 
+impl Normal {
+    pub fn new(normal: [f32; 3]) -> Normal {
+        Normal {
+            normal: (normal[0], normal[1], normal[2]),
+        }
+    }
+}
+
 pub const NORMALS: [Normal; 531] = [
     Normal {
         normal: (0.0, 0.0, 0.0),
@@ -7,407 +7,865 @@
 // notice may not be copied, modified, or distributed except
 // according to those terms.
 
-use vulkano::buffer::cpu_pool::CpuBufferPool;
-use vulkano::buffer::{BufferUsage, CpuAccessibleBuffer};
+use vulkano::buffer::cpu_pool::{CpuBufferPool, CpuBufferPoolChunk};
+use vulkano::buffer::{BufferAccess, BufferUsage, CpuAccessibleBuffer};
 use vulkano::command_buffer::{AutoCommandBufferBuilder, DynamicState, SubpassContents};
 use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
-use vulkano::device::{Device, DeviceExtensions};
+use vulkano::device::{Device, DeviceExtensions, Features, Queue};
 use vulkano::format::Format;
 use vulkano::framebuffer::{Framebuffer, FramebufferAbstract, RenderPassAbstract, Subpass};
 use vulkano::image::attachment::AttachmentImage;
-use vulkano::image::{ImageUsage, SwapchainImage};
-use vulkano::instance::Instance;
+use vulkano::image::{ImageAccess, ImageUsage, ImageViewAccess};
 use vulkano::instance::PhysicalDevice;
+use vulkano::instance::{Instance, InstanceExtensions};
+use vulkano::memory::pool::StdMemoryPool;
+use vulkano::memory::DeviceMemoryAllocError;
 use vulkano::pipeline::vertex::TwoBuffersDefinition;
 use vulkano::pipeline::viewport::Viewport;
 use vulkano::pipeline::{GraphicsPipeline, GraphicsPipelineAbstract};
 use vulkano::swapchain;
 use vulkano::swapchain::{
-   AcquireError, ColorSpace, FullscreenExclusive, PresentMode, SurfaceTransform, Swapchain,
-   SwapchainCreationError,
+    AcquireError, ColorSpace, FullscreenExclusive, PresentMode, SurfaceTransform, Swapchain,
+    SwapchainCreationError,
 };
 use vulkano::sync;
 use vulkano::sync::{FlushError, GpuFuture};
 
-use vulkano_win::VkSurfaceBuild;
-use winit::event::{Event, WindowEvent};
-use winit::event_loop::{ControlFlow, EventLoop};
-use winit::window::{Window, WindowBuilder};
+use winit::window::Window;
 
-use cgmath::{Matrix3, Matrix4, Point3, Rad, Vector3};
+use cgmath::{EuclideanSpace, Matrix3, Matrix4, Point3, Rad, Rotation, SquareMatrix, Vector3};
 
 #[path = "./shaders/examples/teapot/lib.rs"]
-mod lib;
+pub mod lib;
 use lib::{Normal, Vertex, INDICES, NORMALS, VERTICES};
 
+use crate::physics::SoftMesh;
+use crate::terrain::{ChunkCoord, Terrain};
+use crate::{GameState, EYE_HEIGHT};
+
+use std::collections::HashMap;
+use std::fmt;
 use std::iter;
 use std::sync::Arc;
-use std::time::Instant;
-
-pub fn create_vulkan_instance() {
-   // The start of this example is exactly the same as `triangle`. You should read the
-   // `triangle` example if you haven't done so yet.
-
-   let required_extensions = vulkano_win::required_extensions();
-   let instance = Instance::new(None, &required_extensions, None).unwrap();
-   let physical = PhysicalDevice::enumerate(&instance).next().unwrap();
-   println!(
-      "Using device: {} (type: {:?})",
-      physical.name(),
-      physical.ty()
-   );
-
-   let event_loop = EventLoop::new();
-   let surface = WindowBuilder::new()
-      .build_vk_surface(&event_loop, instance.clone())
-      .unwrap();
-   let dimensions: [u32; 2] = surface.window().inner_size().into();
-
-   let queue_family = physical
-      .queue_families()
-      .find(|&q| q.supports_graphics() && surface.is_supported(q).unwrap_or(false))
-      .unwrap();
-
-   let device_ext = DeviceExtensions {
-      khr_swapchain: true,
-      ..DeviceExtensions::none()
-   };
-
-   let (device, mut queues) = Device::new(
-      physical,
-      physical.supported_features(),
-      &device_ext,
-      [(queue_family, 0.5)].iter().cloned(),
-   )
-   .unwrap();
-
-   let queue = queues.next().unwrap();
-
-   let (mut swapchain, images) = {
-      let caps = surface.capabilities(physical).unwrap();
-      let format = caps.supported_formats[0].0;
-      let alpha = caps.supported_composite_alpha.iter().next().unwrap();
-      Swapchain::new(
-         device.clone(),
-         surface.clone(),
-         caps.min_image_count,
-         format,
-         dimensions,
-         1,
-         ImageUsage::color_attachment(),
-         &queue,
-         SurfaceTransform::Identity,
-         alpha,
-         PresentMode::Fifo,
-         FullscreenExclusive::Default,
-         true,
-         ColorSpace::SrgbNonLinear,
-      )
-      .unwrap()
-   };
-
-   let vertices = VERTICES.iter().cloned();
-   let vertex_buffer =
-      CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), false, vertices).unwrap();
-
-   let normals = NORMALS.iter().cloned();
-   let normals_buffer =
-      CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), false, normals).unwrap();
-
-   let indices = INDICES.iter().cloned();
-   let index_buffer =
-      CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), false, indices).unwrap();
-
-   let uniform_buffer = CpuBufferPool::<vs::ty::Data>::new(device.clone(), BufferUsage::all());
-
-   let vs = vs::Shader::load(device.clone()).unwrap();
-   let fs = fs::Shader::load(device.clone()).unwrap();
-
-   let render_pass = Arc::new(
-      vulkano::single_pass_renderpass!(device.clone(),
-          attachments: {
-              color: {
-                  load: Clear,
-                  store: Store,
-                  format: swapchain.format(),
-                  samples: 1,
-              },
-              depth: {
-                  load: Clear,
-                  store: DontCare,
-                  format: Format::D16Unorm,
-                  samples: 1,
-              }
-          },
-          pass: {
-              color: [color],
-              depth_stencil: {depth}
-          }
-      )
-      .unwrap(),
-   );
-
-   let (mut pipeline, mut framebuffers) =
-      window_size_dependent_setup(device.clone(), &vs, &fs, &images, render_pass.clone());
-   let mut recreate_swapchain = false;
-
-   let mut previous_frame_end = Some(sync::now(device.clone()).boxed());
-   let rotation_start = Instant::now();
-
-   event_loop.run(move |event, _, control_flow| {
-      match event {
-         Event::WindowEvent {
-            event: WindowEvent::CloseRequested,
-            ..
-         } => {
-            *control_flow = ControlFlow::Exit;
-         }
-         Event::WindowEvent {
-            event: WindowEvent::Resized(_),
-            ..
-         } => {
-            recreate_swapchain = true;
-         }
-         Event::RedrawEventsCleared => {
-            previous_frame_end.as_mut().unwrap().cleanup_finished();
-
-            if recreate_swapchain {
-               let dimensions: [u32; 2] = surface.window().inner_size().into();
-               let (new_swapchain, new_images) =
-                  match swapchain.recreate_with_dimensions(dimensions) {
-                     Ok(r) => r,
-                     Err(SwapchainCreationError::UnsupportedDimensions) => return,
-                     Err(e) => panic!("Failed to recreate swapchain: {:?}", e),
-                  };
-
-               swapchain = new_swapchain;
-               let (new_pipeline, new_framebuffers) = window_size_dependent_setup(
-                  device.clone(),
-                  &vs,
-                  &fs,
-                  &new_images,
-                  render_pass.clone(),
-               );
-               pipeline = new_pipeline;
-               framebuffers = new_framebuffers;
-               recreate_swapchain = false;
-            }
 
-            let uniform_buffer_subbuffer = {
-               let elapsed = rotation_start.elapsed();
-               let rotation =
-                  elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
-               let rotation = Matrix3::from_angle_y(Rad(rotation as f32));
-
-               // note: this teapot was meant for OpenGL where the origin is at the lower left
-               //       instead the origin is at the upper left in Vulkan, so we reverse the Y axis
-               let aspect_ratio = dimensions[0] as f32 / dimensions[1] as f32;
-               let proj =
-                  cgmath::perspective(Rad(std::f32::consts::FRAC_PI_2), aspect_ratio, 0.01, 100.0);
-               let view = Matrix4::look_at(
-                  Point3::new(0.3, 0.3, 1.0),
-                  Point3::new(0.0, 0.0, 0.0),
-                  Vector3::new(0.0, -1.0, 0.0),
-               );
-               let scale = Matrix4::from_scale(0.01);
-
-               let uniform_data = vs::ty::Data {
-                  world: Matrix4::from(rotation).into(),
-                  view: (view * scale).into(),
-                  proj: proj.into(),
-               };
-
-               uniform_buffer.next(uniform_data).unwrap()
-            };
+/// cgmath's projections are made for OpenGL, this flips Y and squeezes depth into the 0 to 1
+/// range Vulkan uses
+#[rustfmt::skip]
+const OPENGL_TO_VULKAN: Matrix4<f32> = Matrix4::new(
+   1.0, 0.0, 0.0, 0.0,
+   0.0, -1.0, 0.0, 0.0,
+   0.0, 0.0, 0.5, 0.0,
+   0.0, 0.0, 0.5, 1.0,
+);
+
+/// Teapot model units per world unit
+const TEAPOT_SCALE: f32 = 0.01;
+
+/// Format of offscreen frames, the same sRGB encoding a window shows
+const OFFSCREEN_FORMAT: Format = Format::R8G8B8A8Srgb;
+
+#[derive(Debug)]
+pub enum RendererError {
+    /// No GPU, or software implementation, can draw where the frames need to go
+    NoDevice,
+    /// Vulkan failed to set something up or to draw a frame
+    Vulkan(String),
+    /// Only offscreen frames can be read back
+    NotOffscreen,
+}
 
-            let layout = pipeline.descriptor_set_layout(0).unwrap();
-            let set = Arc::new(
-               PersistentDescriptorSet::start(layout.clone())
-                  .add_buffer(uniform_buffer_subbuffer)
-                  .unwrap()
-                  .build()
-                  .unwrap(),
-            );
-
-            let (image_num, suboptimal, acquire_future) =
-               match swapchain::acquire_next_image(swapchain.clone(), None) {
-                  Ok(r) => r,
-                  Err(AcquireError::OutOfDate) => {
-                     recreate_swapchain = true;
-                     return;
-                  }
-                  Err(e) => panic!("Failed to acquire next image: {:?}", e),
-               };
-
-            if suboptimal {
-               recreate_swapchain = true;
-            }
+impl fmt::Display for RendererError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RendererError::NoDevice => write!(f, "no vulkan device can draw the frames"),
+            RendererError::Vulkan(e) => write!(f, "vulkan failed: {}", e),
+            RendererError::NotOffscreen => write!(f, "only offscreen frames can be read back"),
+        }
+    }
+}
+
+impl std::error::Error for RendererError {}
+
+fn vulkan_error<E: std::error::Error>(error: E) -> RendererError {
+    RendererError::Vulkan(error.to_string())
+}
+
+/// Where frames get drawn
+enum Target {
+    /// Presented in a window through its swapchain
+    Window {
+        swapchain: Arc<Swapchain<Arc<Window>>>,
+        framebuffers: Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
+    },
+    /// Kept in an image for `read_pixels`, for machines without a display
+    Offscreen {
+        color: Arc<AttachmentImage>,
+        framebuffer: Arc<dyn FramebufferAbstract + Send + Sync>,
+    },
+}
 
-            let mut builder =
-               AutoCommandBufferBuilder::primary_one_time_submit(device.clone(), queue.family())
-                  .unwrap();
+/// Draws the game into a window, or offscreen without one. The engine owns the window and its
+/// event loop, it passes resizes on and asks for a frame whenever it has updated the game
+pub struct Renderer {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    target: Target,
+    render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    vs: vs::Shader,
+    fs: fs::Shader,
+    pipeline: Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
+    /// Size of the frames in pixels, as of the last `resize`
+    dimensions: [u32; 2],
+    /// Set when the frame changed size or the swapchain no longer matches the window
+    recreate_target: bool,
+    previous_frame_end: Option<Box<dyn GpuFuture>>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    normals_buffer: Arc<CpuAccessibleBuffer<[Normal]>>,
+    index_buffer: Arc<CpuAccessibleBuffer<[u16]>>,
+    uniform_buffer: CpuBufferPool<vs::ty::Data>,
+    terrain: TerrainBuffers,
+    soft_bodies: DynamicMesh,
+    /// Where soft bodies are written before uploading, kept to reuse its allocations
+    soft_mesh: SoftMesh,
+}
+
+impl Renderer {
+    /// Sets up Vulkan on the first GPU that can draw into `window`
+    pub fn new(window: Arc<Window>) -> Result<Renderer, RendererError> {
+        let required_extensions = vulkano_win::required_extensions();
+        let instance = Instance::new(None, &required_extensions, None).map_err(vulkan_error)?;
+        let dimensions: [u32; 2] = window.inner_size().into();
+        let surface =
+            vulkano_win::create_vk_surface(window, instance.clone()).map_err(vulkan_error)?;
+
+        let queue_family = PhysicalDevice::enumerate(&instance)
+            .flat_map(|physical| physical.queue_families())
+            .find(|&q| q.supports_graphics() && surface.is_supported(q).unwrap_or(false))
+            .ok_or(RendererError::NoDevice)?;
+        let physical = queue_family.physical_device();
+
+        let device_ext = DeviceExtensions {
+            khr_swapchain: true,
+            ..DeviceExtensions::none()
+        };
+
+        // The pipeline sticks to core Vulkan, it needs no optional features
+        let (device, mut queues) = Device::new(
+            physical,
+            &Features::none(),
+            &device_ext,
+            [(queue_family, 0.5)].iter().cloned(),
+        )
+        .map_err(vulkan_error)?;
+
+        let queue = queues.next().ok_or(RendererError::NoDevice)?;
+
+        let (swapchain, images) = {
+            let caps = surface.capabilities(physical).map_err(vulkan_error)?;
+            let format = caps.supported_formats[0].0;
+            let alpha = caps
+                .supported_composite_alpha
+                .iter()
+                .next()
+                .ok_or(RendererError::NoDevice)?;
+            Swapchain::new(
+                device.clone(),
+                surface.clone(),
+                caps.min_image_count,
+                format,
+                dimensions,
+                1,
+                ImageUsage::color_attachment(),
+                &queue,
+                SurfaceTransform::Identity,
+                alpha,
+                PresentMode::Fifo,
+                FullscreenExclusive::Default,
+                true,
+                ColorSpace::SrgbNonLinear,
+            )
+            .map_err(vulkan_error)?
+        };
+
+        let render_pass = create_render_pass(device.clone(), swapchain.format())?;
+        let framebuffers = create_framebuffers(device.clone(), render_pass.clone(), images)?;
+        let target = Target::Window {
+            swapchain,
+            framebuffers,
+        };
+        Renderer::with_target(device, queue, render_pass, target, dimensions)
+    }
+
+    /// Sets up Vulkan without a window or surface, drawing `dimensions` sized frames into an
+    /// image instead. Any device that can draw will do, software ones such as lavapipe included
+    pub fn headless(dimensions: [u32; 2]) -> Result<Renderer, RendererError> {
+        let instance =
+            Instance::new(None, &InstanceExtensions::none(), None).map_err(vulkan_error)?;
+
+        let queue_family = PhysicalDevice::enumerate(&instance)
+            .flat_map(|physical| physical.queue_families())
+            .find(|&q| q.supports_graphics())
+            .ok_or(RendererError::NoDevice)?;
+        let physical = queue_family.physical_device();
+
+        let (device, mut queues) = Device::new(
+            physical,
+            &Features::none(),
+            &DeviceExtensions::none(),
+            [(queue_family, 0.5)].iter().cloned(),
+        )
+        .map_err(vulkan_error)?;
+
+        let queue = queues.next().ok_or(RendererError::NoDevice)?;
+
+        let render_pass = create_render_pass(device.clone(), OFFSCREEN_FORMAT)?;
+        let color = create_offscreen_image(device.clone(), dimensions)?;
+        let framebuffer =
+            create_framebuffers(device.clone(), render_pass.clone(), vec![color.clone()])?
+                .remove(0);
+        let target = Target::Offscreen { color, framebuffer };
+        Renderer::with_target(device, queue, render_pass, target, dimensions)
+    }
+
+    /// The parts windowed and offscreen renderers share
+    fn with_target(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+        target: Target,
+        dimensions: [u32; 2],
+    ) -> Result<Renderer, RendererError> {
+        let vertices = VERTICES.iter().cloned();
+        let vertex_buffer =
+            CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), false, vertices)
+                .map_err(vulkan_error)?;
+
+        let normals = NORMALS.iter().cloned();
+        let normals_buffer =
+            CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), false, normals)
+                .map_err(vulkan_error)?;
+
+        let indices = INDICES.iter().cloned();
+        let index_buffer =
+            CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::all(), false, indices)
+                .map_err(vulkan_error)?;
+
+        let uniform_buffer = CpuBufferPool::<vs::ty::Data>::new(device.clone(), BufferUsage::all());
+
+        let vs = vs::Shader::load(device.clone()).map_err(vulkan_error)?;
+        let fs = fs::Shader::load(device.clone()).map_err(vulkan_error)?;
+
+        let pipeline = create_pipeline(device.clone(), &vs, &fs, render_pass.clone(), dimensions)?;
+
+        Ok(Renderer {
+            previous_frame_end: Some(sync::now(device.clone()).boxed()),
+            terrain: TerrainBuffers::new(device.clone()),
+            soft_bodies: DynamicMesh::new(device.clone()),
+            soft_mesh: SoftMesh::default(),
+            device,
+            queue,
+            target,
+            render_pass,
+            vs,
+            fs,
+            pipeline,
+            dimensions,
+            recreate_target: false,
+            vertex_buffer,
+            normals_buffer,
+            index_buffer,
+            uniform_buffer,
+        })
+    }
+
+    /// Size of the frames being drawn, in pixels
+    pub fn dimensions(&self) -> [u32; 2] {
+        self.dimensions
+    }
+
+    /// Call when the window's drawable area changes size, or to draw offscreen frames at
+    /// another size. Everything that depends on it is rebuilt before the next frame
+    pub fn resize(&mut self, dimensions: [u32; 2]) {
+        self.dimensions = dimensions;
+        self.recreate_target = true;
+    }
+
+    /// Draws the world as `game_state` has it and presents it, or keeps it for `read_pixels`
+    /// when offscreen. A minimized window draws nothing
+    pub fn render_frame(&mut self, game_state: &GameState) -> Result<(), RendererError> {
+        if let Some(previous_frame_end) = self.previous_frame_end.as_mut() {
+            previous_frame_end.cleanup_finished();
+        }
+        if self.dimensions[0] == 0 || self.dimensions[1] == 0 {
+            return Ok(());
+        }
+
+        if self.recreate_target {
+            self.recreate_target()?;
+            if self.recreate_target {
+                return Ok(());
+            }
+        }
+
+        let (framebuffer, present) = match &self.target {
+            Target::Window {
+                swapchain,
+                framebuffers,
+            } => {
+                let (image_num, suboptimal, acquire_future) =
+                    match swapchain::acquire_next_image(swapchain.clone(), None) {
+                        Ok(r) => r,
+                        Err(AcquireError::OutOfDate) => {
+                            self.recreate_target = true;
+                            return Ok(());
+                        }
+                        Err(e) => return Err(vulkan_error(e)),
+                    };
+
+                if suboptimal {
+                    self.recreate_target = true;
+                }
+                let present = (swapchain.clone(), image_num, acquire_future);
+                (framebuffers[image_num].clone(), Some(present))
+            }
+            Target::Offscreen { framebuffer, .. } => (framebuffer.clone(), None),
+        };
+
+        let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(
+            self.device.clone(),
+            self.queue.family(),
+        )
+        .map_err(vulkan_error)?;
+        builder
+            .begin_render_pass(
+                framebuffer,
+                SubpassContents::Inline,
+                vec![[0.0, 0.0, 1.0, 1.0].into(), 1f32.into()],
+            )
+            .map_err(vulkan_error)?;
+        self.draw_scene(&mut builder, game_state)?;
+        builder.end_render_pass().map_err(vulkan_error)?;
+        let command_buffer = builder.build().map_err(vulkan_error)?;
+
+        let device = self.device.clone();
+        let previous_frame_end = self
+            .previous_frame_end
+            .take()
+            .unwrap_or_else(|| sync::now(device.clone()).boxed());
+        let future = match present {
+            Some((swapchain, image_num, acquire_future)) => previous_frame_end
+                .join(acquire_future)
+                .then_execute(self.queue.clone(), command_buffer)
+                .map_err(vulkan_error)?
+                .then_swapchain_present(self.queue.clone(), swapchain, image_num)
+                .then_signal_fence_and_flush()
+                .map(|future| future.boxed()),
+            None => previous_frame_end
+                .then_execute(self.queue.clone(), command_buffer)
+                .map_err(vulkan_error)?
+                .then_signal_fence_and_flush()
+                .map(|future| future.boxed()),
+        };
+
+        match future {
+            Ok(future) => {
+                self.previous_frame_end = Some(future);
+                Ok(())
+            }
+            Err(FlushError::OutOfDate) => {
+                self.recreate_target = true;
+                self.previous_frame_end = Some(sync::now(device).boxed());
+                Ok(())
+            }
+            Err(e) => {
+                self.previous_frame_end = Some(sync::now(device).boxed());
+                Err(vulkan_error(e))
+            }
+        }
+    }
+
+    /// Copies the last offscreen frame into memory as RGBA bytes, sRGB encoded, a row at a time
+    /// from the top. Waits for the GPU to finish drawing it
+    pub fn read_pixels(&mut self) -> Result<Vec<u8>, RendererError> {
+        let color = match &self.target {
+            Target::Offscreen { color, .. } => color.clone(),
+            Target::Window { .. } => return Err(RendererError::NotOffscreen),
+        };
+        let [width, height] = ImageAccess::dimensions(&color).width_height();
+        let pixels = (0..width as usize * height as usize * 4).map(|_| 0u8);
+        let buffer =
+            CpuAccessibleBuffer::from_iter(self.device.clone(), BufferUsage::all(), false, pixels)
+                .map_err(vulkan_error)?;
+
+        let mut builder = AutoCommandBufferBuilder::primary_one_time_submit(
+            self.device.clone(),
+            self.queue.family(),
+        )
+        .map_err(vulkan_error)?;
+        builder
+            .copy_image_to_buffer(color, buffer.clone())
+            .map_err(vulkan_error)?;
+        let command_buffer = builder.build().map_err(vulkan_error)?;
+
+        let device = self.device.clone();
+        let future = self
+            .previous_frame_end
+            .take()
+            .unwrap_or_else(|| sync::now(device.clone()).boxed())
+            .then_execute(self.queue.clone(), command_buffer)
+            .map_err(vulkan_error)?
+            .then_signal_fence_and_flush()
+            .map_err(vulkan_error)?;
+        future.wait(None).map_err(vulkan_error)?;
+        self.previous_frame_end = Some(sync::now(device).boxed());
+
+        let pixels = buffer.read().map_err(vulkan_error)?;
+        Ok(pixels.to_vec())
+    }
+
+    /// Waits for the frames still on the GPU, call it before the window goes away
+    pub fn shutdown(&mut self) {
+        if let Some(mut previous_frame_end) = self.previous_frame_end.take() {
+            previous_frame_end.cleanup_finished();
+        }
+        // Nothing left to do about a GPU that cannot even wait
+        let _ = self.queue.wait();
+    }
+
+    /// Rebuilds everything sized to the frame. Leaves `recreate_target` set if the window is
+    /// in the middle of changing size, to try again next frame
+    fn recreate_target(&mut self) -> Result<(), RendererError> {
+        match &mut self.target {
+            Target::Window {
+                swapchain,
+                framebuffers,
+            } => {
+                let (new_swapchain, new_images) =
+                    match swapchain.recreate_with_dimensions(self.dimensions) {
+                        Ok(r) => r,
+                        // The window changed size again meanwhile, there will be another resize
+                        Err(SwapchainCreationError::UnsupportedDimensions) => return Ok(()),
+                        Err(e) => return Err(vulkan_error(e)),
+                    };
+                *swapchain = new_swapchain;
+                *framebuffers =
+                    create_framebuffers(self.device.clone(), self.render_pass.clone(), new_images)?;
+            }
+            Target::Offscreen { color, framebuffer } => {
+                *color = create_offscreen_image(self.device.clone(), self.dimensions)?;
+                *framebuffer = create_framebuffers(
+                    self.device.clone(),
+                    self.render_pass.clone(),
+                    vec![color.clone()],
+                )?
+                .remove(0);
+            }
+        }
+        self.pipeline = create_pipeline(
+            self.device.clone(),
+            &self.vs,
+            &self.fs,
+            self.render_pass.clone(),
+            self.dimensions,
+        )?;
+        self.recreate_target = false;
+        Ok(())
+    }
+
+    /// Records the terrain, the teapot and any soft bodies, seen from the first player's eyes.
+    /// Without a player the camera looks at the teapot
+    fn draw_scene(
+        &mut self,
+        builder: &mut AutoCommandBufferBuilder,
+        game_state: &GameState,
+    ) -> Result<(), RendererError> {
+        let player = game_state
+            .players
+            .first()
+            .and_then(|&index| game_state.transforms[index as usize]);
+        let (eye, view) = match player {
+            Some(transform) => {
+                let eye = transform.position + Vector3::unit_y() * EYE_HEIGHT;
+                let forward = transform.rotation.rotate_vector(-Vector3::unit_z());
+                let view = Matrix4::look_at_dir(Point3::from_vec(eye), forward, Vector3::unit_y());
+                (eye, view)
+            }
+            None => {
+                let eye = Point3::new(0.3, 0.3, 1.0);
+                let view = Matrix4::look_at(eye, Point3::new(0.0, 0.0, 0.0), Vector3::unit_y());
+                (eye.to_vec(), view)
+            }
+        };
+        let aspect_ratio = self.dimensions[0] as f32 / self.dimensions[1] as f32;
+        let proj = OPENGL_TO_VULKAN
+            * cgmath::perspective(Rad(std::f32::consts::FRAC_PI_2), aspect_ratio, 0.01, 500.0);
+
+        let rotation = Matrix3::from_angle_y(Rad(game_state.counter as f32));
+        let teapot = Matrix4::from(rotation) * Matrix4::from_scale(TEAPOT_SCALE);
+        let mut draws = vec![(
+            teapot,
+            self.vertex_buffer.clone(),
+            self.normals_buffer.clone(),
+            self.index_buffer.clone(),
+        )];
+        let chunks = self
+            .terrain
+            .visible(&game_state.terrain, eye)
+            .map_err(vulkan_error)?;
+        for chunk in chunks {
+            draws.push((
+                Matrix4::identity(),
+                chunk.vertices.clone(),
+                chunk.normals.clone(),
+                chunk.indices.clone(),
+            ));
+        }
+
+        let layout = self
+            .pipeline
+            .descriptor_set_layout(0)
+            .expect("the vertex shader takes its uniforms in set 0")
+            .clone();
+        let uniform_buffer = &self.uniform_buffer;
+        let uniforms = |world: Matrix4<f32>| -> Result<_, RendererError> {
+            let uniform_data = vs::ty::Data {
+                world: world.into(),
+                view: view.into(),
+                proj: proj.into(),
+            };
+            let uniform_buffer_subbuffer =
+                uniform_buffer.next(uniform_data).map_err(vulkan_error)?;
+            let set = PersistentDescriptorSet::start(layout.clone())
+                .add_buffer(uniform_buffer_subbuffer)
+                .map_err(vulkan_error)?
+                .build()
+                .map_err(vulkan_error)?;
+            Ok(Arc::new(set))
+        };
+        for (world, vertices, normals, indices) in draws {
             builder
-               .begin_render_pass(
-                  framebuffers[image_num].clone(),
-                  SubpassContents::Inline,
-                  vec![[0.0, 0.0, 1.0, 1.0].into(), 1f32.into()],
-               )
-               .unwrap()
-               .draw_indexed(
-                  pipeline.clone(),
-                  &DynamicState::none(),
-                  vec![vertex_buffer.clone(), normals_buffer.clone()],
-                  index_buffer.clone(),
-                  set.clone(),
-                  (),
-               )
-               .unwrap()
-               .end_render_pass()
-               .unwrap();
-            let command_buffer = builder.build().unwrap();
-
-            let future = previous_frame_end
-               .take()
-               .unwrap()
-               .join(acquire_future)
-               .then_execute(queue.clone(), command_buffer)
-               .unwrap()
-               .then_swapchain_present(queue.clone(), swapchain.clone(), image_num)
-               .then_signal_fence_and_flush();
-
-            match future {
-               Ok(future) => {
-                  previous_frame_end = Some(future.boxed());
-               }
-               Err(FlushError::OutOfDate) => {
-                  recreate_swapchain = true;
-                  previous_frame_end = Some(sync::now(device.clone()).boxed());
-               }
-               Err(e) => {
-                  println!("Failed to flush future: {:?}", e);
-                  previous_frame_end = Some(sync::now(device.clone()).boxed());
-               }
+                .draw_indexed(
+                    self.pipeline.clone(),
+                    &DynamicState::none(),
+                    vec![vertices, normals],
+                    indices,
+                    uniforms(world)?,
+                    (),
+                )
+                .map_err(vulkan_error)?;
+        }
+
+        // Soft bodies are simulated in world space, and reshaped every frame
+        for (_, soft_body) in game_state.physics.soft_bodies() {
+            soft_body.write_mesh(&mut self.soft_mesh);
+            let (vertices, normals, indices) = match self
+                .soft_bodies
+                .upload(&self.soft_mesh)
+                .map_err(vulkan_error)?
+            {
+                Some(buffers) => buffers,
+                None => continue,
+            };
+            let vertex_buffers: Vec<Arc<dyn BufferAccess + Send + Sync>> =
+                vec![Arc::new(vertices), Arc::new(normals)];
+            builder
+                .draw_indexed(
+                    self.pipeline.clone(),
+                    &DynamicState::none(),
+                    vertex_buffers,
+                    indices,
+                    uniforms(Matrix4::identity())?,
+                    (),
+                )
+                .map_err(vulkan_error)?;
+        }
+        Ok(())
+    }
+}
+
+/// One pass drawing into a `format` color image, depth tested
+fn create_render_pass(
+    device: Arc<Device>,
+    format: Format,
+) -> Result<Arc<dyn RenderPassAbstract + Send + Sync>, RendererError> {
+    let render_pass = vulkano::single_pass_renderpass!(device,
+        attachments: {
+            color: {
+                load: Clear,
+                store: Store,
+                format: format,
+                samples: 1,
+            },
+            depth: {
+                load: Clear,
+                store: DontCare,
+                format: Format::D16Unorm,
+                samples: 1,
             }
-         }
-         _ => (),
-      }
-   });
+        },
+        pass: {
+            color: [color],
+            depth_stencil: {depth}
+        }
+    )
+    .map_err(vulkan_error)?;
+    Ok(Arc::new(render_pass))
+}
+
+/// Color image offscreen frames are drawn into and copied out of
+fn create_offscreen_image(
+    device: Arc<Device>,
+    dimensions: [u32; 2],
+) -> Result<Arc<AttachmentImage>, RendererError> {
+    let usage = ImageUsage {
+        color_attachment: true,
+        transfer_source: true,
+        ..ImageUsage::none()
+    };
+    AttachmentImage::with_usage(device, dimensions, OFFSCREEN_FORMAT, usage).map_err(vulkan_error)
+}
+
+/// A framebuffer for each of `images`, sharing one depth buffer. Called once during
+/// initialization, then again whenever the frames change size
+fn create_framebuffers<I>(
+    device: Arc<Device>,
+    render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    images: Vec<Arc<I>>,
+) -> Result<Vec<Arc<dyn FramebufferAbstract + Send + Sync>>, RendererError>
+where
+    I: ImageViewAccess + Send + Sync + 'static,
+{
+    let dimensions = ImageViewAccess::dimensions(&images[0]).width_height();
+
+    let depth_buffer =
+        AttachmentImage::transient(device, dimensions, Format::D16Unorm).map_err(vulkan_error)?;
+
+    images
+        .into_iter()
+        .map(|image| {
+            let framebuffer = Framebuffer::start(render_pass.clone())
+                .add(image)
+                .map_err(vulkan_error)?
+                .add(depth_buffer.clone())
+                .map_err(vulkan_error)?
+                .build()
+                .map_err(vulkan_error)?;
+            Ok(Arc::new(framebuffer) as Arc<dyn FramebufferAbstract + Send + Sync>)
+        })
+        .collect()
 }
 
-/// This method is called once during initialization, then again whenever the window is resized
-fn window_size_dependent_setup(
-   device: Arc<Device>,
-   vs: &vs::Shader,
-   fs: &fs::Shader,
-   images: &[Arc<SwapchainImage<Window>>],
-   render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
-) -> (
-   Arc<dyn GraphicsPipelineAbstract + Send + Sync>,
-   Vec<Arc<dyn FramebufferAbstract + Send + Sync>>,
-) {
-   let dimensions = images[0].dimensions();
-
-   let depth_buffer =
-      AttachmentImage::transient(device.clone(), dimensions, Format::D16Unorm).unwrap();
-
-   let framebuffers = images
-      .iter()
-      .map(|image| {
-         Arc::new(
-            Framebuffer::start(render_pass.clone())
-               .add(image.clone())
-               .unwrap()
-               .add(depth_buffer.clone())
-               .unwrap()
-               .build()
-               .unwrap(),
-         ) as Arc<dyn FramebufferAbstract + Send + Sync>
-      })
-      .collect::<Vec<_>>();
-
-   // In the triangle example we use a dynamic viewport, as its a simple example.
-   // However in the teapot example, we recreate the pipelines with a hardcoded viewport instead.
-   // This allows the driver to optimize things, at the cost of slower window resizes.
-   // https://computergraphics.stackexchange.com/questions/5742/vulkan-best-way-of-updating-pipeline-viewport
-   let pipeline = Arc::new(
-      GraphicsPipeline::start()
-         .vertex_input(TwoBuffersDefinition::<Vertex, Normal>::new())
-         .vertex_shader(vs.main_entry_point(), ())
-         .triangle_list()
-         .viewports_dynamic_scissors_irrelevant(1)
-         .viewports(iter::once(Viewport {
+/// Rebuilt along with the framebuffers, as the viewport is part of it
+fn create_pipeline(
+    device: Arc<Device>,
+    vs: &vs::Shader,
+    fs: &fs::Shader,
+    render_pass: Arc<dyn RenderPassAbstract + Send + Sync>,
+    dimensions: [u32; 2],
+) -> Result<Arc<dyn GraphicsPipelineAbstract + Send + Sync>, RendererError> {
+    // In the triangle example we use a dynamic viewport, as its a simple example.
+    // However in the teapot example, we recreate the pipelines with a hardcoded viewport instead.
+    // This allows the driver to optimize things, at the cost of slower window resizes.
+    // https://computergraphics.stackexchange.com/questions/5742/vulkan-best-way-of-updating-pipeline-viewport
+    let subpass = Subpass::from(render_pass, 0).expect("the render pass has one subpass");
+    let pipeline = GraphicsPipeline::start()
+        .vertex_input(TwoBuffersDefinition::<Vertex, Normal>::new())
+        .vertex_shader(vs.main_entry_point(), ())
+        .triangle_list()
+        .viewports_dynamic_scissors_irrelevant(1)
+        .viewports(iter::once(Viewport {
             origin: [0.0, 0.0],
             dimensions: [dimensions[0] as f32, dimensions[1] as f32],
             depth_range: 0.0..1.0,
-         }))
-         .fragment_shader(fs.main_entry_point(), ())
-         .depth_stencil_simple_depth()
-         .render_pass(Subpass::from(render_pass.clone(), 0).unwrap())
-         .build(device.clone())
-         .unwrap(),
-   );
-
-   (pipeline, framebuffers)
+        }))
+        .fragment_shader(fs.main_entry_point(), ())
+        .depth_stencil_simple_depth()
+        .render_pass(subpass)
+        .build(device)
+        .map_err(vulkan_error)?;
+    Ok(Arc::new(pipeline))
 }
 
 mod vs {
-   vulkano_shaders::shader! {
-       ty: "vertex",
-       path: "src/renderer/shaders/examples/teapot/vert.glsl"
-   }
+    vulkano_shaders::shader! {
+        ty: "vertex",
+        path: "src/renderer/shaders/examples/teapot/vert.glsl"
+    }
 }
 
 mod fs {
-   vulkano_shaders::shader! {
-       ty: "fragment",
-       path: "src/renderer/shaders/examples/teapot/frag.glsl"
-   }
+    vulkano_shaders::shader! {
+        ty: "fragment",
+        path: "src/renderer/shaders/examples/teapot/frag.glsl"
+    }
 }
 
-pub struct Object3D {
-   fragment_shader: Result<Shader, OomError>,
-   vertex_shader: Arc<ShaderModule>,
+/// One upload's vertices, normals and indices
+pub type MeshChunks = (
+    CpuBufferPoolChunk<Vertex, Arc<StdMemoryPool>>,
+    CpuBufferPoolChunk<Normal, Arc<StdMemoryPool>>,
+    CpuBufferPoolChunk<u32, Arc<StdMemoryPool>>,
+);
+
+/// Vertex, normal and index buffers refilled every frame, for meshes the CPU reshapes like cloth
+/// and ropes. Each upload takes fresh chunks from the pools, chunks still used by frames in
+/// flight stay alive until those frames finish
+pub struct DynamicMesh {
+    vertices: CpuBufferPool<Vertex>,
+    normals: CpuBufferPool<Normal>,
+    indices: CpuBufferPool<u32>,
 }
 
-// pub fn addObject(object:Object3D) {
-//    mod vs {
-//      object::vertex_shader!
-//    }
-//    mod fs {
-//       object::fragment_shader!
-//    }
-// }
-
-
- # fn main() {}
- # use std::sync::Arc;
- # use vulkano::OomError;
- # use vulkano::device::Device;
- #
- # mod vs {
- #     vulkano_shaders::shader!{
- #         ty: "vertex",
- #         src: "
- # #version 450
- #
- # layout(location = 0) in vec3 position;
- #
- # void main() {
- #     gl_Position = vec4(position, 1.0);
- # }"
- #     }
- # }
- // various use statements
- // `vertex_shader` module with shader derive
-
- pub struct Shaders {
-     pub vs: vs::Shader
- }
-
- impl Shaders {
-     pub fn load(device: Arc<Device>) -> Result<Self, OomError> {
-         Ok(Self {
-             vs: vs::Shader::load(device)?,
-         })
-     }
- }
\ No newline at end of file
+impl DynamicMesh {
+    pub fn new(device: Arc<Device>) -> DynamicMesh {
+        DynamicMesh {
+            vertices: CpuBufferPool::vertex_buffer(device.clone()),
+            normals: CpuBufferPool::vertex_buffer(device.clone()),
+            indices: CpuBufferPool::new(device, BufferUsage::index_buffer()),
+        }
+    }
+
+    /// Copies the mesh into the buffers, ready to hand to `draw_indexed` like the teapot's.
+    /// Returns `None` for an empty mesh, which has nothing to draw
+    pub fn upload(&self, mesh: &SoftMesh) -> Result<Option<MeshChunks>, DeviceMemoryAllocError> {
+        if mesh.indices.is_empty() {
+            return Ok(None);
+        }
+        let vertices = self
+            .vertices
+            .chunk(mesh.positions.iter().map(|&p| Vertex::new(p)))?;
+        let normals = self
+            .normals
+            .chunk(mesh.normals.iter().map(|&n| Normal::new(n)))?;
+        let indices = self.indices.chunk(mesh.indices.iter().cloned())?;
+        Ok(Some((vertices, normals, indices)))
+    }
+}
+
+/// Buffers of one terrain chunk at one detail level
+pub struct ChunkBuffers {
+    pub vertices: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pub normals: Arc<CpuAccessibleBuffer<[Normal]>>,
+    pub indices: Arc<CpuAccessibleBuffer<[u16]>>,
+}
+
+/// Terrain chunk buffers, built the first time a chunk is drawn at a detail level and kept
+/// around so moving back and forth between levels does not rebuild them
+pub struct TerrainBuffers {
+    device: Arc<Device>,
+    chunks: HashMap<(ChunkCoord, usize), ChunkBuffers>,
+}
+
+impl TerrainBuffers {
+    pub fn new(device: Arc<Device>) -> TerrainBuffers {
+        TerrainBuffers {
+            device,
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Buffers of every chunk at the detail level `eye` sees it at, each one drawn with
+    /// `draw_indexed` like the teapot
+    pub fn visible(
+        &mut self,
+        terrain: &Terrain,
+        eye: Vector3<f32>,
+    ) -> Result<Vec<&ChunkBuffers>, DeviceMemoryAllocError> {
+        let lods = terrain.chunk_lods(eye);
+        for &(chunk, lod) in &lods {
+            if !self.chunks.contains_key(&(chunk, lod)) {
+                let mesh = terrain.chunk_mesh(chunk, lod);
+                let buffers = ChunkBuffers {
+                    vertices: CpuAccessibleBuffer::from_iter(
+                        self.device.clone(),
+                        BufferUsage::all(),
+                        false,
+                        mesh.positions.iter().map(|&p| Vertex::new(p)),
+                    )?,
+                    normals: CpuAccessibleBuffer::from_iter(
+                        self.device.clone(),
+                        BufferUsage::all(),
+                        false,
+                        mesh.normals.iter().map(|&n| Normal::new(n)),
+                    )?,
+                    indices: CpuAccessibleBuffer::from_iter(
+                        self.device.clone(),
+                        BufferUsage::all(),
+                        false,
+                        mesh.indices.iter().cloned(),
+                    )?,
+                };
+                self.chunks.insert((chunk, lod), buffers);
+            }
+        }
+        let chunks = &self.chunks;
+        Ok(lods.iter().map(|key| &chunks[key]).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::{Audio, MixerConfig, NullSink, Sound};
+    use crate::physics::PhysicsWorld;
+    use crate::spawner::Spawner;
+    use crate::terrain::{Heightmap, TerrainConfig};
+
+    /// Nothing but the teapot in the middle, the terrain is out past the far plane
+    fn empty_game_state() -> GameState {
+        let heightmap = Heightmap::new(2, 2, vec![0.0; 4]).unwrap();
+        let terrain_config = TerrainConfig {
+            origin: Vector3::new(1000.0, 0.0, 1000.0),
+            ..TerrainConfig::default()
+        };
+        let (audio, mixer) = Audio::new(MixerConfig::default());
+        GameState {
+            entities: vec![],
+            transforms: vec![],
+            players: vec![],
+            spawner: Spawner::from_file("assets/spawner/waves.ron").unwrap(),
+            physics: PhysicsWorld::new(),
+            terrain: Terrain::new(&heightmap, terrain_config),
+            audio,
+            audio_output: Box::new(NullSink::new(mixer)),
+            acoustic_materials: HashMap::new(),
+            impact_sound: Sound::mono(48_000, vec![]),
+            collision_events: vec![],
+            combat_intensity: 0.0,
+            counter: 0.0,
+        }
+    }
+
+    /// Whether a Vulkan driver is installed, software ones included
+    fn have_vulkan() -> bool {
+        match Instance::new(None, &InstanceExtensions::none(), None) {
+            Ok(instance) => PhysicalDevice::enumerate(&instance).next().is_some(),
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn headless_frames_read_back_the_scene() {
+        if !have_vulkan() {
+            eprintln!("skipped, no Vulkan driver found");
+            return;
+        }
+        let mut renderer = Renderer::headless([64, 48]).unwrap();
+        renderer.render_frame(&empty_game_state()).unwrap();
+        let pixels = renderer.read_pixels().unwrap();
+        assert_eq!(pixels.len(), 64 * 48 * 4);
+
+        let pixel = |x: usize, y: usize| &pixels[(y * 64 + x) * 4..(y * 64 + x) * 4 + 4];
+        // Cleared to blue around the edges, the red teapot in the middle
+        for &(x, y) in [(0, 0), (63, 0), (0, 47), (63, 47)].iter() {
+            assert_eq!(pixel(x, y), [0, 0, 255, 255], "pixel {}, {}", x, y);
+        }
+        let middle = pixel(32, 24);
+        assert!(
+            middle[0] > 100 && middle[1] == 0 && middle[2] == 0,
+            "{:?}",
+            middle
+        );
+        assert_eq!(middle[3], 255);
+    }
+}
@@ -0,0 +1,203 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Everything a designer can tune about spawning, loaded from a `.ron` file
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpawnerConfig {
+    /// Seed for the spawn rng, so a level plays out the same way for the same inputs
+    #[serde(default)]
+    pub seed: u64,
+    /// Most monsters the spawner will keep alive at once
+    pub population_cap: usize,
+    /// Seconds between spawns at normal intensity
+    pub spawn_interval: f32,
+    /// Spawn points closer than this to any player are never used
+    pub min_spawn_distance: f32,
+    /// Spawn points are only used if a player is within this distance
+    pub max_spawn_distance: f32,
+    /// Players can not see spawn points further away than this, even with a clear line of sight
+    pub sight_range: f32,
+    pub spawn_points: Vec<SpawnPointConfig>,
+    pub tables: HashMap<String, Vec<SpawnEntryConfig>>,
+    /// Played in order, the last wave repeats once the list runs out
+    pub waves: Vec<WaveConfig>,
+    pub director: DirectorConfig,
+    /// How each kind of monster behaves, ones missing here get `ArchetypeConfig::default`
+    #[serde(default)]
+    pub archetypes: HashMap<String, ArchetypeConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpawnPointConfig {
+    pub position: [f32; 3],
+    /// Monsters are scattered on the ground plane within this radius of `position`
+    #[serde(default)]
+    pub radius: f32,
+    /// Name of the entry in `SpawnerConfig::tables` this point spawns from
+    pub table: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpawnEntryConfig {
+    pub archetype: String,
+    pub weight: u32,
+    /// How much of the wave budget one of these uses up
+    pub cost: u32,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default)]
+pub struct ArchetypeConfig {
+    /// How fast the monster walks towards the player it hunts, in units per second
+    pub speed: f32,
+    /// Health per second the monster takes off a player it has caught up with
+    pub damage: f32,
+}
+
+impl Default for ArchetypeConfig {
+    fn default() -> ArchetypeConfig {
+        ArchetypeConfig {
+            speed: 3.0,
+            damage: 5.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WaveConfig {
+    pub budget: u32,
+    /// Seconds before the wave ends even if its budget is not spent
+    pub duration: f32,
+    /// Seconds of quiet before the next wave
+    pub rest: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DirectorConfig {
+    /// Wave budgets and spawn rates get scaled between these two by the director's intensity
+    pub min_intensity: f32,
+    pub max_intensity: f32,
+    /// Intensity gained per second while building up
+    pub build_up_rate: f32,
+    /// Intensity lost per second while relaxing
+    pub relax_rate: f32,
+    /// Stress that tips the director from building up into a peak
+    pub peak_stress: f32,
+    pub peak_duration: f32,
+    /// Stress has to drop under this before building up again
+    pub relax_stress: f32,
+    pub relax_duration: f32,
+    /// How much missing health adds to stress
+    pub health_weight: f32,
+    /// How much each recent kill adds to stress
+    pub kill_weight: f32,
+    /// Seconds a kill counts as recent
+    pub kill_window: f32,
+    /// How quickly stress follows the player's state, per second
+    pub stress_response: f32,
+}
+
+#[derive(Debug)]
+pub enum SpawnerConfigError {
+    Io(io::Error),
+    Parse(ron::Error),
+    UnknownTable {
+        spawn_point: usize,
+        table: String,
+    },
+    NoWaves,
+    /// Spawning would never wait between monsters
+    BadSpawnInterval(f32),
+    /// A monster that uses none of the budget could be spawned forever
+    FreeEntry {
+        table: String,
+        archetype: String,
+    },
+}
+
+impl fmt::Display for SpawnerConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpawnerConfigError::Io(e) => write!(f, "failed to read spawner config: {}", e),
+            SpawnerConfigError::Parse(e) => write!(f, "failed to parse spawner config: {}", e),
+            SpawnerConfigError::UnknownTable { spawn_point, table } => write!(
+                f,
+                "spawn point {} uses table \"{}\" which does not exist",
+                spawn_point, table
+            ),
+            SpawnerConfigError::NoWaves => write!(f, "spawner config has no waves"),
+            SpawnerConfigError::BadSpawnInterval(interval) => write!(
+                f,
+                "spawn interval is {} but has to be more than zero",
+                interval
+            ),
+            SpawnerConfigError::FreeEntry { table, archetype } => write!(
+                f,
+                "\"{}\" in table \"{}\" costs nothing, every entry has to cost at least 1",
+                archetype, table
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SpawnerConfigError {}
+
+impl From<io::Error> for SpawnerConfigError {
+    fn from(e: io::Error) -> Self {
+        SpawnerConfigError::Io(e)
+    }
+}
+
+impl From<ron::Error> for SpawnerConfigError {
+    fn from(e: ron::Error) -> Self {
+        SpawnerConfigError::Parse(e)
+    }
+}
+
+impl FromStr for SpawnerConfig {
+    type Err = SpawnerConfigError;
+
+    fn from_str(source: &str) -> Result<SpawnerConfig, SpawnerConfigError> {
+        let config: SpawnerConfig = ron::de::from_str(source)?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl SpawnerConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<SpawnerConfig, SpawnerConfigError> {
+        let source = fs::read_to_string(path)?;
+        source.parse()
+    }
+
+    fn validate(&self) -> Result<(), SpawnerConfigError> {
+        if self.waves.is_empty() {
+            return Err(SpawnerConfigError::NoWaves);
+        }
+        if self.spawn_interval.is_nan() || self.spawn_interval <= 0.0 {
+            return Err(SpawnerConfigError::BadSpawnInterval(self.spawn_interval));
+        }
+        for (name, entries) in &self.tables {
+            if let Some(entry) = entries.iter().find(|e| e.cost == 0) {
+                return Err(SpawnerConfigError::FreeEntry {
+                    table: name.clone(),
+                    archetype: entry.archetype.clone(),
+                });
+            }
+        }
+        for (i, point) in self.spawn_points.iter().enumerate() {
+            if !self.tables.contains_key(&point.table) {
+                return Err(SpawnerConfigError::UnknownTable {
+                    spawn_point: i,
+                    table: point.table.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use super::config::DirectorConfig;
+use std::collections::VecDeque;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DirectorPhase {
+    /// Intensity climbs until the players are stressed enough
+    BuildUp,
+    /// Full intensity for a while
+    Peak,
+    /// No spawning, give the players room to breathe
+    Relax,
+}
+
+/// Paces the spawner off how the players are doing, in the spirit of Left 4 Dead's AI director
+#[derive(Debug, Clone)]
+pub struct Director {
+    config: DirectorConfig,
+    phase: DirectorPhase,
+    phase_timer: f32,
+    time: f32,
+    stress: f32,
+    intensity: f32,
+    recent_kills: VecDeque<f32>,
+}
+
+impl Director {
+    pub fn new(config: DirectorConfig) -> Director {
+        Director {
+            config,
+            phase: DirectorPhase::BuildUp,
+            phase_timer: 0.0,
+            time: 0.0,
+            stress: 0.0,
+            intensity: 0.0,
+            recent_kills: VecDeque::new(),
+        }
+    }
+
+    pub fn record_kill(&mut self) {
+        self.recent_kills.push_back(self.time);
+    }
+
+    /// `health_fraction` is the health of the worst off player, from `0.0` dead to `1.0` full
+    pub fn update(&mut self, dt: f32, health_fraction: f32) {
+        self.time += dt;
+        while let Some(&kill_time) = self.recent_kills.front() {
+            if self.time - kill_time <= self.config.kill_window {
+                break;
+            }
+            self.recent_kills.pop_front();
+        }
+
+        let target_stress = (self.config.health_weight * (1.0 - health_fraction.clamp(0.0, 1.0))
+            + self.config.kill_weight * self.recent_kills.len() as f32)
+            .clamp(0.0, 1.0);
+        let response = (self.config.stress_response * dt).min(1.0);
+        self.stress += (target_stress - self.stress) * response;
+
+        match self.phase {
+            DirectorPhase::BuildUp => {
+                self.intensity = (self.intensity + self.config.build_up_rate * dt).min(1.0);
+                if self.stress >= self.config.peak_stress {
+                    self.phase = DirectorPhase::Peak;
+                    self.phase_timer = self.config.peak_duration;
+                }
+            }
+            DirectorPhase::Peak => {
+                self.intensity = 1.0;
+                self.phase_timer -= dt;
+                if self.phase_timer <= 0.0 {
+                    self.phase = DirectorPhase::Relax;
+                    self.phase_timer = self.config.relax_duration;
+                }
+            }
+            DirectorPhase::Relax => {
+                self.intensity = (self.intensity - self.config.relax_rate * dt).max(0.0);
+                self.phase_timer -= dt;
+                if self.phase_timer <= 0.0 && self.stress < self.config.relax_stress {
+                    self.phase = DirectorPhase::BuildUp;
+                }
+            }
+        }
+    }
+
+    pub fn phase(&self) -> DirectorPhase {
+        self.phase
+    }
+
+    pub fn stress(&self) -> f32 {
+        self.stress
+    }
+
+    pub fn intensity(&self) -> f32 {
+        self.intensity
+    }
+
+    pub fn allows_spawning(&self) -> bool {
+        self.phase != DirectorPhase::Relax
+    }
+
+    /// What to scale wave budgets and spawn rates by right now
+    pub fn multiplier(&self) -> f32 {
+        self.config.min_intensity
+            + (self.config.max_intensity - self.config.min_intensity) * self.intensity
+    }
+}
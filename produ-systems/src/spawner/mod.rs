@@ -0,0 +1,478 @@
+mod config;
+mod director;
+mod table;
+
+use config::WaveConfig;
+pub use config::{ArchetypeConfig, SpawnerConfig, SpawnerConfigError};
+pub use director::Director;
+pub use table::{SpawnRng, SpawnTable};
+
+use crate::math;
+use crate::EntityIndex;
+use cgmath::{InnerSpace, Vector3};
+use std::path::Path;
+
+/// Answers whether a player standing at `eye` could see `target`
+pub trait LineOfSight {
+    fn is_visible(&self, eye: Vector3<f32>, target: Vector3<f32>) -> bool;
+}
+
+impl<F> LineOfSight for F
+where
+    F: Fn(Vector3<f32>, Vector3<f32>) -> bool,
+{
+    fn is_visible(&self, eye: Vector3<f32>, target: Vector3<f32>) -> bool {
+        self(eye, target)
+    }
+}
+
+/// Height of the ground under a point on the XZ plane, `None` off the edge of the level
+pub trait GroundHeight {
+    fn height_at(&self, x: f32, z: f32) -> Option<f32>;
+}
+
+impl<F> GroundHeight for F
+where
+    F: Fn(f32, f32) -> Option<f32>,
+{
+    fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        self(x, z)
+    }
+}
+
+/// The parts of a player the spawner cares about
+#[derive(Debug, Copy, Clone)]
+pub struct PlayerView {
+    pub position: Vector3<f32>,
+    /// From `0.0` dead to `1.0` full health
+    pub health_fraction: f32,
+}
+
+/// A monster the spawner wants in the world, `GameState` does the actual spawning
+#[derive(Debug, Clone)]
+pub struct SpawnRequest {
+    pub archetype: String,
+    pub position: Vector3<f32>,
+}
+
+struct SpawnPoint {
+    position: Vector3<f32>,
+    radius: f32,
+    table: usize,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum WavePhase {
+    Active,
+    Resting,
+}
+
+pub struct Spawner {
+    config: SpawnerConfig,
+    points: Vec<SpawnPoint>,
+    tables: Vec<SpawnTable>,
+    director: Director,
+    rng: SpawnRng,
+    alive: Vec<EntityIndex>,
+    wave: usize,
+    wave_phase: WavePhase,
+    wave_timer: f32,
+    budget: u32,
+    spawn_timer: f32,
+}
+
+impl Spawner {
+    pub fn new(config: SpawnerConfig) -> Spawner {
+        let mut table_names: Vec<&String> = config.tables.keys().collect();
+        // HashMap order is random, sort so the same seed picks the same tables
+        table_names.sort();
+        let tables = table_names
+            .iter()
+            .map(|name| SpawnTable::new(&config.tables[*name]))
+            .collect();
+        let points = config
+            .spawn_points
+            .iter()
+            .map(|p| SpawnPoint {
+                position: p.position.into(),
+                radius: p.radius,
+                table: table_names.iter().position(|&n| *n == p.table).unwrap(),
+            })
+            .collect();
+
+        let mut spawner = Spawner {
+            director: Director::new(config.director.clone()),
+            rng: SpawnRng::new(config.seed),
+            points,
+            tables,
+            alive: vec![],
+            wave: 0,
+            wave_phase: WavePhase::Resting,
+            wave_timer: 0.0,
+            budget: 0,
+            spawn_timer: 0.0,
+            config,
+        };
+        spawner.start_wave(0);
+        spawner
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Spawner, SpawnerConfigError> {
+        Ok(Spawner::new(SpawnerConfig::from_file(path)?))
+    }
+
+    pub fn director(&self) -> &Director {
+        &self.director
+    }
+
+    pub fn wave(&self) -> usize {
+        self.wave
+    }
+
+    pub fn population(&self) -> usize {
+        self.alive.len()
+    }
+
+    /// How monsters of `archetype` behave once spawned
+    pub fn archetype(&self, archetype: &str) -> ArchetypeConfig {
+        self.config
+            .archetypes
+            .get(archetype)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Starts counting a spawned monster against the population cap
+    pub fn track(&mut self, index: EntityIndex) {
+        self.alive.push(index);
+    }
+
+    /// Forgets monsters that are no longer alive, each one counts as a kill for the director
+    pub fn retain_alive<F>(&mut self, is_alive: F)
+    where
+        F: Fn(EntityIndex) -> bool,
+    {
+        let before = self.alive.len();
+        self.alive.retain(|&index| is_alive(index));
+        for _ in self.alive.len()..before {
+            self.director.record_kill();
+        }
+    }
+
+    /// Spawns land on `ground` where it has a height, otherwise at the spawn point's height
+    pub fn update<L: LineOfSight, G: GroundHeight>(
+        &mut self,
+        dt: f32,
+        players: &[PlayerView],
+        line_of_sight: &L,
+        ground: &G,
+    ) -> Vec<SpawnRequest> {
+        let health_fraction = players
+            .iter()
+            .map(|p| p.health_fraction)
+            .fold(1.0, f32::min);
+        self.director.update(dt, health_fraction);
+
+        self.wave_timer += dt;
+        match self.wave_phase {
+            WavePhase::Active => {
+                let spent = self.budget == 0 && self.alive.is_empty();
+                if spent || self.wave_timer >= self.wave_config().duration {
+                    self.wave_phase = WavePhase::Resting;
+                    self.wave_timer = 0.0;
+                }
+            }
+            WavePhase::Resting => {
+                if self.wave_timer >= self.wave_config().rest {
+                    self.start_wave(self.wave + 1);
+                }
+            }
+        }
+
+        let mut requests = vec![];
+        if self.wave_phase != WavePhase::Active || !self.director.allows_spawning() {
+            return requests;
+        }
+
+        self.spawn_timer -= dt * self.director.multiplier();
+        while self.spawn_timer <= 0.0 {
+            self.spawn_timer += self.config.spawn_interval;
+            if self.alive.len() + requests.len() >= self.config.population_cap {
+                break;
+            }
+            match self.pick_spawn(players, line_of_sight, ground) {
+                Some(request) => requests.push(request),
+                None => break,
+            }
+        }
+        requests
+    }
+
+    fn wave_config(&self) -> &WaveConfig {
+        let last = self.config.waves.len() - 1;
+        &self.config.waves[self.wave.min(last)]
+    }
+
+    fn start_wave(&mut self, wave: usize) {
+        self.wave = wave;
+        self.wave_phase = WavePhase::Active;
+        self.wave_timer = 0.0;
+        self.spawn_timer = 0.0;
+        self.budget =
+            (self.wave_config().budget as f32 * self.director.multiplier()).round() as u32;
+    }
+
+    fn pick_spawn<L: LineOfSight, G: GroundHeight>(
+        &mut self,
+        players: &[PlayerView],
+        line_of_sight: &L,
+        ground: &G,
+    ) -> Option<SpawnRequest> {
+        let config = &self.config;
+        let candidates: Vec<usize> = self
+            .points
+            .iter()
+            .enumerate()
+            .filter(|(_, point)| {
+                let mut in_range = false;
+                for player in players {
+                    let distance = (point.position - player.position).magnitude();
+                    if distance < config.min_spawn_distance {
+                        return false;
+                    }
+                    if distance <= config.sight_range
+                        && line_of_sight.is_visible(player.position, point.position)
+                    {
+                        return false;
+                    }
+                    in_range |= distance <= config.max_spawn_distance;
+                }
+                in_range
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let point = &self.points[candidates[self.rng.below(candidates.len() as u64) as usize]];
+        let table = &self.tables[point.table];
+        let entry = match table.pick(self.budget, &mut self.rng) {
+            Some(entry) => entry,
+            None => {
+                // Nothing left that the wave can afford
+                self.budget = 0;
+                return None;
+            }
+        };
+        self.budget -= entry.cost;
+
+        let angle = self.rng.next_f32() * std::f32::consts::PI * 2.0;
+        let distance = self.rng.next_f32().sqrt() * point.radius;
+        let offset = Vector3::new(
+            math::cos(angle) * distance,
+            0.0,
+            math::sin(angle) * distance,
+        );
+        let mut position = point.position + offset;
+        if let Some(height) = ground.height_at(position.x, position.z) {
+            position.y = height;
+        }
+        Some(SpawnRequest {
+            archetype: entry.archetype.clone(),
+            position,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One graveyard 30m east of the origin, full intensity from the start so budgets are
+    /// exactly what the waves say
+    const CONFIG: &str = r#"(
+        seed: 7,
+        population_cap: 100,
+        spawn_interval: 0.5,
+        min_spawn_distance: 10.0,
+        max_spawn_distance: 50.0,
+        sight_range: 40.0,
+        spawn_points: [(position: (30.0, 0.0, 0.0), radius: 3.0, table: "graveyard")],
+        tables: {"graveyard": [(archetype: "zombie", weight: 1, cost: 1)]},
+        waves: [
+            (budget: 4, duration: 60.0, rest: 5.0),
+            (budget: 6, duration: 60.0, rest: 5.0),
+        ],
+        director: (
+            min_intensity: 1.0,
+            max_intensity: 1.0,
+            build_up_rate: 0.1,
+            relax_rate: 0.2,
+            peak_stress: 0.8,
+            peak_duration: 2.0,
+            relax_stress: 0.3,
+            relax_duration: 3.0,
+            health_weight: 1.0,
+            kill_weight: 0.0,
+            kill_window: 10.0,
+            stress_response: 2.0,
+        ),
+    )"#;
+
+    fn config() -> SpawnerConfig {
+        CONFIG.parse().unwrap()
+    }
+
+    fn player_at(x: f32) -> PlayerView {
+        PlayerView {
+            position: Vector3::new(x, 0.0, 0.0),
+            health_fraction: 1.0,
+        }
+    }
+
+    fn hidden(_: Vector3<f32>, _: Vector3<f32>) -> bool {
+        false
+    }
+
+    fn no_ground(_: f32, _: f32) -> Option<f32> {
+        None
+    }
+
+    /// Steps the spawner for `seconds`, tracking every monster it asks for
+    fn run(spawner: &mut Spawner, seconds: f32, players: &[PlayerView]) -> Vec<SpawnRequest> {
+        let mut spawned = vec![];
+        for _ in 0..(seconds * 10.0) as usize {
+            for request in spawner.update(0.1, players, &hidden, &no_ground) {
+                spawner.track(spawned.len() as EntityIndex);
+                spawned.push(request);
+            }
+        }
+        spawned
+    }
+
+    #[test]
+    fn waves_spend_their_budget_then_rest() {
+        let mut spawner = Spawner::new(config());
+        let players = [player_at(0.0)];
+        assert_eq!(run(&mut spawner, 10.0, &players).len(), 4);
+        assert_eq!(spawner.wave(), 0);
+
+        // Killing the wave ends it, the next starts after the rest with a bigger budget
+        spawner.retain_alive(|_| false);
+        assert!(run(&mut spawner, 4.0, &players).is_empty());
+        assert_eq!(spawner.wave(), 0);
+        assert_eq!(run(&mut spawner, 10.0, &players).len(), 6);
+        assert_eq!(spawner.wave(), 1);
+    }
+
+    #[test]
+    fn population_cap_holds_spawns_back() {
+        let mut config = config();
+        config.population_cap = 3;
+        let mut spawner = Spawner::new(config);
+        let players = [player_at(0.0)];
+        assert_eq!(run(&mut spawner, 10.0, &players).len(), 3);
+        spawner.retain_alive(|index| index != 0);
+        assert_eq!(run(&mut spawner, 10.0, &players).len(), 1);
+    }
+
+    #[test]
+    fn spawn_points_too_close_far_or_in_sight_are_skipped() {
+        let players = [player_at(25.0)];
+        assert!(run(&mut Spawner::new(config()), 10.0, &players).is_empty());
+        let players = [player_at(-30.0)];
+        assert!(run(&mut Spawner::new(config()), 10.0, &players).is_empty());
+
+        let mut spawner = Spawner::new(config());
+        let seen = |_: Vector3<f32>, _: Vector3<f32>| true;
+        let players = [player_at(0.0)];
+        assert!(spawner.update(0.1, &players, &seen, &no_ground).is_empty());
+    }
+
+    #[test]
+    fn spawns_scatter_around_the_point_on_the_ground() {
+        let mut spawner = Spawner::new(config());
+        let slope = |x: f32, z: f32| Some(x * 0.5 + z * 0.25);
+        let players = [player_at(0.0)];
+        let mut spawned = vec![];
+        for _ in 0..30 {
+            spawned.extend(spawner.update(0.1, &players, &hidden, &slope));
+        }
+        assert_eq!(spawned.len(), 4);
+        for request in &spawned {
+            let offset = request.position - Vector3::new(30.0, 0.0, 0.0);
+            assert!(Vector3::new(offset.x, 0.0, offset.z).magnitude() <= 3.0);
+            let ground = slope(request.position.x, request.position.z).unwrap();
+            assert_eq!(request.position.y, ground);
+        }
+        assert_eq!(spawned[0].archetype, "zombie");
+    }
+
+    #[test]
+    fn director_peaks_under_stress_then_relaxes() {
+        let mut director = Director::new(config().director);
+        director.update(1.0, 1.0);
+        assert_eq!(director.phase(), director::DirectorPhase::BuildUp);
+        assert!(director.allows_spawning());
+
+        // Badly hurt players push stress up to a peak
+        for _ in 0..20 {
+            director.update(0.1, 0.0);
+        }
+        assert_eq!(director.phase(), director::DirectorPhase::Peak);
+        assert_eq!(director.intensity(), 1.0);
+
+        for _ in 0..25 {
+            director.update(0.1, 0.0);
+        }
+        assert_eq!(director.phase(), director::DirectorPhase::Relax);
+        assert!(!director.allows_spawning());
+
+        // Only builds up again once the relax time is over and the players have recovered
+        for _ in 0..40 {
+            director.update(0.1, 0.0);
+        }
+        assert_eq!(director.phase(), director::DirectorPhase::Relax);
+        for _ in 0..20 {
+            director.update(0.1, 1.0);
+        }
+        assert_eq!(director.phase(), director::DirectorPhase::BuildUp);
+    }
+
+    #[test]
+    fn configs_that_would_spawn_forever_are_rejected() {
+        let zero_interval = CONFIG.replace("spawn_interval: 0.5", "spawn_interval: 0.0");
+        assert!(matches!(
+            zero_interval.parse::<SpawnerConfig>(),
+            Err(SpawnerConfigError::BadSpawnInterval(_))
+        ));
+        let free = CONFIG.replace("cost: 1", "cost: 0");
+        assert!(matches!(
+            free.parse::<SpawnerConfig>(),
+            Err(SpawnerConfigError::FreeEntry { .. })
+        ));
+    }
+
+    #[test]
+    fn archetypes_missing_from_the_config_get_defaults() {
+        let mut config = config();
+        let fast = ArchetypeConfig {
+            speed: 9.0,
+            damage: 1.0,
+        };
+        config.archetypes.insert("ghoul".to_string(), fast);
+        let spawner = Spawner::new(config);
+        assert_eq!(spawner.archetype("ghoul").speed, 9.0);
+        assert_eq!(
+            spawner.archetype("zombie").speed,
+            ArchetypeConfig::default().speed
+        );
+    }
+
+    #[test]
+    fn shipped_config_loads() {
+        let spawner = Spawner::from_file("assets/spawner/waves.ron").unwrap();
+        assert!(spawner.archetype("bog_troll").damage > spawner.archetype("slime").damage);
+    }
+}
@@ -0,0 +1,119 @@
+use super::config::SpawnEntryConfig;
+
+/// Small xorshift rng, spawning only needs something cheap that repeats for the same seed
+#[derive(Debug, Clone)]
+pub struct SpawnRng {
+    state: u64,
+}
+
+impl SpawnRng {
+    pub fn new(seed: u64) -> SpawnRng {
+        // splitmix64's finalizer, so nearby seeds start far apart. It is a bijection, so exactly
+        // one seed lands on zero, where xorshift would get stuck
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        SpawnRng {
+            state: if z == 0 { 0x9E37_79B9_7F4A_7C15 } else { z },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A number in `0..bound`, `bound` must not be zero
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+
+    /// A number in `0.0..1.0`
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SpawnEntry {
+    pub archetype: String,
+    pub weight: u32,
+    pub cost: u32,
+}
+
+/// Monsters a spawn point can produce, picked with a chance proportional to their weight
+#[derive(Debug, Clone)]
+pub struct SpawnTable {
+    entries: Vec<SpawnEntry>,
+}
+
+impl SpawnTable {
+    pub fn new(entries: &[SpawnEntryConfig]) -> SpawnTable {
+        SpawnTable {
+            entries: entries
+                .iter()
+                .filter(|e| e.weight > 0)
+                .map(|e| SpawnEntry {
+                    archetype: e.archetype.clone(),
+                    weight: e.weight,
+                    cost: e.cost,
+                })
+                .collect(),
+        }
+    }
+
+    /// Picks an entry that costs no more than `budget`, or `None` if nothing is affordable
+    pub fn pick(&self, budget: u32, rng: &mut SpawnRng) -> Option<&SpawnEntry> {
+        let total: u64 = self
+            .entries
+            .iter()
+            .filter(|e| e.cost <= budget)
+            .map(|e| e.weight as u64)
+            .sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut roll = rng.below(total);
+        for entry in self.entries.iter().filter(|e| e.cost <= budget) {
+            if roll < entry.weight as u64 {
+                return Some(entry);
+            }
+            roll -= entry.weight as u64;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_seed_gets_a_working_rng() {
+        // The last one is the seed splitmix64 maps to zero, its finalizer leaves zero alone
+        let seeds = [0, 1, u64::MAX, 0x9E37_79B9_7F4A_7C15, 0x61C8_8646_80B5_83EB];
+        for &seed in seeds.iter() {
+            let mut rng = SpawnRng::new(seed);
+            assert_ne!(rng.state, 0, "seed {:#x}", seed);
+            assert!((0..8).any(|_| rng.next_u64() != 0), "seed {:#x}", seed);
+        }
+    }
+
+    #[test]
+    fn same_seed_same_numbers() {
+        let mut a = SpawnRng::new(42);
+        let mut b = SpawnRng::new(42);
+        let mut c = SpawnRng::new(43);
+        let a: Vec<u64> = (0..16).map(|_| a.next_u64()).collect();
+        let b: Vec<u64> = (0..16).map(|_| b.next_u64()).collect();
+        let c: Vec<u64> = (0..16).map(|_| c.next_u64()).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+}
@@ -0,0 +1,408 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum HeightmapError {
+    Io(io::Error),
+    /// Only `.pgm` greyscale images and `.raw`, `.r8` and `.r16` raw files are understood
+    UnknownFormat(String),
+    /// The PGM header is missing or broken
+    BadHeader(&'static str),
+    /// Raw files carry no size, so they have to be square
+    NotSquare {
+        bytes: usize,
+    },
+    /// Fewer samples than the header promised
+    Truncated {
+        expected: usize,
+        found: usize,
+    },
+    /// A height field needs at least two samples each way
+    TooSmall {
+        columns: usize,
+        rows: usize,
+    },
+    /// More samples than can be counted
+    TooLarge {
+        columns: usize,
+        rows: usize,
+    },
+}
+
+impl fmt::Display for HeightmapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeightmapError::Io(e) => write!(f, "failed to read heightmap: {}", e),
+            HeightmapError::UnknownFormat(extension) => {
+                write!(
+                    f,
+                    "heightmaps can not be loaded from \".{}\" files",
+                    extension
+                )
+            }
+            HeightmapError::BadHeader(problem) => write!(f, "bad PGM header: {}", problem),
+            HeightmapError::NotSquare { bytes } => {
+                write!(f, "raw heightmap of {} bytes is not square", bytes)
+            }
+            HeightmapError::Truncated { expected, found } => write!(
+                f,
+                "heightmap should have {} samples but only has {}",
+                expected, found
+            ),
+            HeightmapError::TooSmall { columns, rows } => write!(
+                f,
+                "heightmap is {}x{} but needs to be at least 2x2",
+                columns, rows
+            ),
+            HeightmapError::TooLarge { columns, rows } => {
+                write!(f, "heightmap of {}x{} samples is too large", columns, rows)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HeightmapError {}
+
+impl From<io::Error> for HeightmapError {
+    fn from(e: io::Error) -> Self {
+        HeightmapError::Io(e)
+    }
+}
+
+/// Bytes per sample of a raw heightmap
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RawDepth {
+    Eight,
+    /// Little endian, as terrain tools export them
+    Sixteen,
+}
+
+/// Grid of heights from 0 to 1, row by row along +Z with each row running along +X
+#[derive(Debug, Clone)]
+pub struct Heightmap {
+    columns: usize,
+    rows: usize,
+    heights: Vec<f32>,
+}
+
+impl Heightmap {
+    pub fn new(
+        columns: usize,
+        rows: usize,
+        heights: Vec<f32>,
+    ) -> Result<Heightmap, HeightmapError> {
+        if columns < 2 || rows < 2 {
+            return Err(HeightmapError::TooSmall { columns, rows });
+        }
+        let count = columns
+            .checked_mul(rows)
+            .ok_or(HeightmapError::TooLarge { columns, rows })?;
+        if heights.len() < count {
+            return Err(HeightmapError::Truncated {
+                expected: count,
+                found: heights.len(),
+            });
+        }
+        Ok(Heightmap {
+            columns,
+            rows,
+            heights: heights[..count].to_vec(),
+        })
+    }
+
+    /// Picks the format from the extension. `.raw` files are taken as 16 bit when their size
+    /// makes a square that way, and 8 bit otherwise
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Heightmap, HeightmapError> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let bytes = fs::read(path)?;
+        match extension.as_str() {
+            "pgm" => Heightmap::from_pgm(&bytes),
+            "r8" => Heightmap::from_raw(&bytes, RawDepth::Eight),
+            "r16" => Heightmap::from_raw(&bytes, RawDepth::Sixteen),
+            "raw" if square_side(bytes.len() / 2).is_some() && bytes.len() % 2 == 0 => {
+                Heightmap::from_raw(&bytes, RawDepth::Sixteen)
+            }
+            "raw" => Heightmap::from_raw(&bytes, RawDepth::Eight),
+            _ => Err(HeightmapError::UnknownFormat(extension)),
+        }
+    }
+
+    /// Reads a binary (P5) or plain text (P2) greyscale PGM image, 8 or 16 bits deep
+    pub fn from_pgm(bytes: &[u8]) -> Result<Heightmap, HeightmapError> {
+        let mut header = PgmHeader { bytes, at: 0 };
+        let binary = match header.token() {
+            Some(b"P5") => true,
+            Some(b"P2") => false,
+            _ => return Err(HeightmapError::BadHeader("not a greyscale PGM image")),
+        };
+        let columns = header.number("missing width")?;
+        let rows = header.number("missing height")?;
+        let max = header.number("missing maximum value")?;
+        if max == 0 || max > u16::MAX as usize {
+            return Err(HeightmapError::BadHeader("maximum value out of range"));
+        }
+        let count = columns
+            .checked_mul(rows)
+            .ok_or(HeightmapError::TooLarge { columns, rows })?;
+        let scale = 1.0 / max as f32;
+
+        let heights: Vec<f32> = if binary {
+            // Exactly one whitespace byte separates the header from the samples
+            let data = &bytes[(header.at + 1).min(bytes.len())..];
+            let sample_bytes = if max < 256 { 1 } else { 2 };
+            if data.len() / sample_bytes < count {
+                return Err(HeightmapError::Truncated {
+                    expected: count,
+                    found: data.len() / sample_bytes,
+                });
+            }
+            if max < 256 {
+                data.iter().take(count).map(|&b| b as f32 * scale).collect()
+            } else {
+                data.chunks_exact(2)
+                    .take(count)
+                    .map(|b| u16::from_be_bytes([b[0], b[1]]) as f32 * scale)
+                    .collect()
+            }
+        } else {
+            // Every sample takes at least two bytes of text, a header promising more than that
+            // is truncated and should not get to reserve the memory
+            let mut heights = Vec::with_capacity(count.min(bytes.len() / 2));
+            while heights.len() < count {
+                match header.token().and_then(parse_number) {
+                    Some(sample) => heights.push(sample as f32 * scale),
+                    None => break,
+                }
+            }
+            heights
+        };
+        Heightmap::new(columns, rows, heights)
+    }
+
+    /// Reads a headerless square grid of unsigned samples
+    pub fn from_raw(bytes: &[u8], depth: RawDepth) -> Result<Heightmap, HeightmapError> {
+        let heights: Vec<f32> = match depth {
+            RawDepth::Eight => bytes.iter().map(|&b| b as f32 / 255.0).collect(),
+            RawDepth::Sixteen => bytes
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as f32 / 65535.0)
+                .collect(),
+        };
+        let side =
+            square_side(heights.len()).ok_or(HeightmapError::NotSquare { bytes: bytes.len() })?;
+        Heightmap::new(side, side, heights)
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn heights(&self) -> &[f32] {
+        &self.heights
+    }
+
+    /// Height of a sample, samples past the edges take the height of the nearest edge
+    pub fn height(&self, column: usize, row: usize) -> f32 {
+        let column = column.min(self.columns - 1);
+        let row = row.min(self.rows - 1);
+        self.heights[row * self.columns + column]
+    }
+}
+
+fn square_side(count: usize) -> Option<usize> {
+    let side = (count as f64).sqrt().round() as usize;
+    if side * side == count {
+        Some(side)
+    } else {
+        None
+    }
+}
+
+fn parse_number(token: &[u8]) -> Option<usize> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+/// Whitespace separated tokens of a PGM header, skipping `#` comments
+struct PgmHeader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> PgmHeader<'a> {
+    fn token(&mut self) -> Option<&'a [u8]> {
+        loop {
+            match self.bytes.get(self.at)? {
+                b'#' => {
+                    while !matches!(self.bytes.get(self.at), Some(b'\n') | None) {
+                        self.at += 1;
+                    }
+                }
+                b if b.is_ascii_whitespace() => self.at += 1,
+                _ => break,
+            }
+        }
+        let start = self.at;
+        while matches!(self.bytes.get(self.at), Some(b) if !b.is_ascii_whitespace()) {
+            self.at += 1;
+        }
+        Some(&self.bytes[start..self.at])
+    }
+
+    fn number(&mut self, missing: &'static str) -> Result<usize, HeightmapError> {
+        self.token()
+            .and_then(parse_number)
+            .ok_or(HeightmapError::BadHeader(missing))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: &[f32], b: &[f32]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-6)
+    }
+
+    #[test]
+    fn pgm_images_parse_in_every_flavour() {
+        let mut binary = b"P5\n# made by hand\n3 2\n255\n".to_vec();
+        binary.extend_from_slice(&[0, 51, 255, 102, 204, 153]);
+        let map = Heightmap::from_pgm(&binary).unwrap();
+        assert_eq!((map.columns(), map.rows()), (3, 2));
+        assert!(close(map.heights(), &[0.0, 0.2, 1.0, 0.4, 0.8, 0.6]));
+        assert_eq!(map.height(2, 1), 0.6);
+        // Past the edges clamps to them
+        assert_eq!(map.height(7, 0), 1.0);
+
+        let mut wide = b"P5 2 2 1000 ".to_vec();
+        for sample in &[0u16, 250, 500, 1000] {
+            wide.extend_from_slice(&sample.to_be_bytes());
+        }
+        let map = Heightmap::from_pgm(&wide).unwrap();
+        assert!(close(map.heights(), &[0.0, 0.25, 0.5, 1.0]));
+
+        let text = b"P2\n2 2 # size\n4\n0 1\n# second row\n2 4\n";
+        let map = Heightmap::from_pgm(text).unwrap();
+        assert!(close(map.heights(), &[0.0, 0.25, 0.5, 1.0]));
+    }
+
+    #[test]
+    fn raw_files_are_square_grids() {
+        let map = Heightmap::from_raw(&[0, 255, 51, 102], RawDepth::Eight).unwrap();
+        assert_eq!((map.columns(), map.rows()), (2, 2));
+        assert!(close(map.heights(), &[0.0, 1.0, 0.2, 0.4]));
+
+        let bytes: Vec<u8> = [0u16, 65535, 13107, 26214]
+            .iter()
+            .flat_map(|s| s.to_le_bytes().to_vec())
+            .collect();
+        let map = Heightmap::from_raw(&bytes, RawDepth::Sixteen).unwrap();
+        assert!(close(map.heights(), &[0.0, 1.0, 0.2, 0.4]));
+
+        assert!(matches!(
+            Heightmap::from_raw(&[0; 5], RawDepth::Eight),
+            Err(HeightmapError::NotSquare { bytes: 5 })
+        ));
+        assert!(matches!(
+            Heightmap::from_raw(&[0], RawDepth::Eight),
+            Err(HeightmapError::TooSmall {
+                columns: 1,
+                rows: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn broken_files_are_errors() {
+        let bad_header = |bytes: &[u8]| {
+            matches!(
+                Heightmap::from_pgm(bytes),
+                Err(HeightmapError::BadHeader(_))
+            )
+        };
+        assert!(bad_header(b""));
+        assert!(bad_header(b"P6 2 2 255 rgbrgbrgbrgb"));
+        assert!(bad_header(b"P5 2"));
+        assert!(bad_header(b"P5 2 two 255 "));
+        assert!(bad_header(b"P5 2 2 0 ...."));
+        assert!(bad_header(b"P5 2 2 65536 ........"));
+
+        // The header promises more than the file holds
+        assert!(matches!(
+            Heightmap::from_pgm(b"P5 2 2 255 abc"),
+            Err(HeightmapError::Truncated {
+                expected: 4,
+                found: 3
+            })
+        ));
+        assert!(matches!(
+            Heightmap::from_pgm(b"P5 2 2 1000 abcdefg"),
+            Err(HeightmapError::Truncated {
+                expected: 4,
+                found: 3
+            })
+        ));
+        assert!(matches!(
+            Heightmap::from_pgm(b"P2 2 2 255 1 2 3"),
+            Err(HeightmapError::Truncated {
+                expected: 4,
+                found: 3
+            })
+        ));
+        assert!(matches!(
+            Heightmap::from_pgm(b"P5 2 2 255 "),
+            Err(HeightmapError::Truncated { found: 0, .. })
+        ));
+        let huge = format!("P2 {} {} 255 1 2 3 4", usize::MAX / 2, 3);
+        assert!(matches!(
+            Heightmap::from_pgm(huge.as_bytes()),
+            Err(HeightmapError::TooLarge { rows: 3, .. })
+        ));
+        assert!(matches!(
+            Heightmap::from_pgm(b"P5 100000 100000 255 abcd"),
+            Err(HeightmapError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn files_are_read_by_extension() {
+        let dir = std::env::temp_dir().join(format!("heightmap_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, bytes: &[u8]| {
+            let path = dir.join(name);
+            fs::write(&path, bytes).unwrap();
+            path
+        };
+
+        let map = Heightmap::from_file(write("a.PGM", b"P2 2 2 4 0 1 2 4")).unwrap();
+        assert!(close(map.heights(), &[0.0, 0.25, 0.5, 1.0]));
+        // Eight bytes make a 2x2 grid of 16 bit samples but not a square of 8 bit ones
+        let map = Heightmap::from_file(write("b.raw", &[0, 0, 255, 255, 0, 0, 255, 255])).unwrap();
+        assert_eq!((map.columns(), map.rows()), (2, 2));
+        // Nine do the other way round
+        let map = Heightmap::from_file(write("c.raw", &[0; 9])).unwrap();
+        assert_eq!((map.columns(), map.rows()), (3, 3));
+        let map = Heightmap::from_file(write("d.r8", &[0; 16])).unwrap();
+        assert_eq!((map.columns(), map.rows()), (4, 4));
+        assert!(matches!(
+            Heightmap::from_file(write("e.png", &[0; 16])),
+            Err(HeightmapError::UnknownFormat(extension)) if extension == "png"
+        ));
+        assert!(matches!(
+            Heightmap::from_file(dir.join("missing.pgm")),
+            Err(HeightmapError::Io(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,380 @@
+mod heightmap;
+
+pub use heightmap::{Heightmap, HeightmapError, RawDepth};
+
+use crate::physics::{
+    Aabb, BodyHandle, Collider, ColliderHandle, HeightField, PhysicsWorld, RigidBody,
+};
+use cgmath::{InnerSpace, Vector3};
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug, Copy, Clone)]
+pub struct TerrainConfig {
+    /// World position of the heightmap's first sample, at height zero
+    pub origin: Vector3<f32>,
+    /// Distance between neighbouring samples
+    pub cell_size: f32,
+    /// World height of a full white sample
+    pub height_scale: f32,
+    /// Cells along each side of a render chunk, at most 250 so a chunk fits 16 bit indices
+    pub chunk_cells: usize,
+    /// Each level halves the samples along both sides of a chunk
+    pub lod_levels: usize,
+    /// Chunks closer than this get full detail, each doubling of the distance drops a level
+    pub lod_distance: f32,
+}
+
+impl Default for TerrainConfig {
+    fn default() -> TerrainConfig {
+        TerrainConfig {
+            origin: Vector3::new(0.0, 0.0, 0.0),
+            cell_size: 1.0,
+            height_scale: 32.0,
+            chunk_cells: 32,
+            lod_levels: 4,
+            lod_distance: 64.0,
+        }
+    }
+}
+
+/// A render chunk by its column along +X and row along +Z
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ChunkCoord {
+    pub column: usize,
+    pub row: usize,
+}
+
+/// Vertex, normal and index data of a chunk in world space, laid out like the teapot's buffers
+#[derive(Debug, Clone, Default)]
+pub struct ChunkMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub indices: Vec<u16>,
+}
+
+/// Heightmap terrain. Physics collides with the full resolution height field while rendering
+/// draws it in chunks that lose detail with distance
+#[derive(Debug, Clone)]
+pub struct Terrain {
+    config: TerrainConfig,
+    field: Arc<HeightField>,
+    /// One per sample, in the same order as the heights
+    normals: Vec<Vector3<f32>>,
+}
+
+impl Terrain {
+    pub fn new(heightmap: &Heightmap, config: TerrainConfig) -> Terrain {
+        let config = TerrainConfig {
+            chunk_cells: config.chunk_cells.clamp(1, 250),
+            lod_levels: config.lod_levels.max(1),
+            ..config
+        };
+        let scale = Vector3::new(config.cell_size, config.height_scale, config.cell_size);
+        let field = HeightField::new(
+            heightmap.columns(),
+            heightmap.rows(),
+            heightmap.heights().to_vec(),
+            scale,
+        )
+        .expect("heightmaps are always at least 2x2");
+
+        // Central differences, one sided along the edges
+        let (columns, rows) = (field.columns(), field.rows());
+        let mut normals = Vec::with_capacity(columns * rows);
+        for row in 0..rows {
+            for column in 0..columns {
+                let (left, right) = (column.saturating_sub(1), (column + 1).min(columns - 1));
+                let (back, front) = (row.saturating_sub(1), (row + 1).min(rows - 1));
+                let slope_x = (field.height(right, row) - field.height(left, row))
+                    / ((right - left) as f32 * config.cell_size);
+                let slope_z = (field.height(column, front) - field.height(column, back))
+                    / ((front - back) as f32 * config.cell_size);
+                normals.push(Vector3::new(-slope_x, 1.0, -slope_z).normalize());
+            }
+        }
+
+        Terrain {
+            config,
+            field: Arc::new(field),
+            normals,
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        config: TerrainConfig,
+    ) -> Result<Terrain, HeightmapError> {
+        Ok(Terrain::new(&Heightmap::from_file(path)?, config))
+    }
+
+    pub fn config(&self) -> &TerrainConfig {
+        &self.config
+    }
+
+    pub fn height_field(&self) -> &Arc<HeightField> {
+        &self.field
+    }
+
+    /// A collider sharing the terrain's height field, to attach to a body at `origin`
+    pub fn collider(&self) -> Collider {
+        Collider::heightfield(self.field.clone())
+    }
+
+    /// Adds a fixed body at the terrain's origin with the terrain collider on it
+    pub fn add_to(&self, physics: &mut PhysicsWorld) -> (BodyHandle, ColliderHandle) {
+        let body = physics.add_body(RigidBody::fixed(self.config.origin));
+        let collider = physics
+            .add_collider(body, self.collider())
+            .expect("the body was just added");
+        (body, collider)
+    }
+
+    /// World bounds of the whole terrain
+    pub fn bounds(&self) -> Aabb {
+        let (columns, rows) = (self.field.columns(), self.field.rows());
+        self.sample_bounds(0..columns, 0..rows)
+    }
+
+    /// Height of the surface at a world position, as the collider sees it. `None` off the edges
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        let u = (x - self.config.origin.x) / self.config.cell_size;
+        let v = (z - self.config.origin.z) / self.config.cell_size;
+        let (cells_x, cells_z) = (self.field.columns() - 1, self.field.rows() - 1);
+        if !(0.0..=cells_x as f32).contains(&u) || !(0.0..=cells_z as f32).contains(&v) {
+            return None;
+        }
+        let column = (u.floor() as usize).min(cells_x - 1);
+        let row = (v.floor() as usize).min(cells_z - 1);
+        let (fu, fv) = (u - column as f32, v - row as f32);
+        let h = |c: usize, r: usize| self.field.height(column + c, row + r);
+        // Same diagonal split as the height field's cells
+        let height = if fv >= fu {
+            h(0, 0) + fu * (h(1, 1) - h(0, 1)) + fv * (h(0, 1) - h(0, 0))
+        } else {
+            h(0, 0) + fu * (h(1, 0) - h(0, 0)) + fv * (h(1, 1) - h(1, 0))
+        };
+        Some(self.config.origin.y + height)
+    }
+
+    /// Number of chunk columns and rows, the last ones may be narrower than `chunk_cells`
+    pub fn chunk_counts(&self) -> (usize, usize) {
+        let cells = self.config.chunk_cells;
+        (
+            (self.field.columns() - 2) / cells + 1,
+            (self.field.rows() - 2) / cells + 1,
+        )
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = ChunkCoord> {
+        let (columns, rows) = self.chunk_counts();
+        (0..rows).flat_map(move |row| (0..columns).map(move |column| ChunkCoord { column, row }))
+    }
+
+    /// World bounds of a chunk's surface, not counting the skirts
+    pub fn chunk_bounds(&self, chunk: ChunkCoord) -> Aabb {
+        let (columns, rows) = self.chunk_samples(chunk);
+        self.sample_bounds(columns, rows)
+    }
+
+    /// Detail level for a chunk seen from `eye`, by the horizontal distance to its nearest point
+    pub fn lod_for(&self, chunk: ChunkCoord, eye: Vector3<f32>) -> usize {
+        let bounds = self.chunk_bounds(chunk);
+        let dx = (bounds.min.x - eye.x).max(eye.x - bounds.max.x).max(0.0);
+        let dz = (bounds.min.z - eye.z).max(eye.z - bounds.max.z).max(0.0);
+        let distance = (dx * dx + dz * dz).sqrt();
+        let mut lod = 0;
+        let mut reach = self.config.lod_distance;
+        while distance > reach && lod + 1 < self.config.lod_levels {
+            lod += 1;
+            reach *= 2.0;
+        }
+        lod
+    }
+
+    /// Every chunk with the detail level to draw it at from `eye`
+    pub fn chunk_lods(&self, eye: Vector3<f32>) -> Vec<(ChunkCoord, usize)> {
+        self.chunks()
+            .map(|chunk| (chunk, self.lod_for(chunk, eye)))
+            .collect()
+    }
+
+    /// Builds the mesh of a chunk at a detail level. Level `n` keeps every `2^n`th sample plus
+    /// the chunk edges, and skirts hang down from the edges to hide the cracks next to chunks
+    /// drawn at other levels
+    pub fn chunk_mesh(&self, chunk: ChunkCoord, lod: usize) -> ChunkMesh {
+        let (columns, rows) = self.chunk_samples(chunk);
+        let step = (1 << lod.min(self.config.lod_levels - 1)).min(self.config.chunk_cells);
+        let pick = |range: std::ops::Range<usize>| {
+            let last = range.end - 1;
+            let mut samples: Vec<usize> = range.step_by(step).collect();
+            if samples.last() != Some(&last) {
+                samples.push(last);
+            }
+            samples
+        };
+        let (columns, rows) = (pick(columns), pick(rows));
+        let (width, depth) = (columns.len(), rows.len());
+
+        let mut mesh = ChunkMesh::default();
+        for &row in &rows {
+            for &column in &columns {
+                mesh.positions
+                    .push(self.sample_position(column, row).into());
+                mesh.normals
+                    .push(self.normals[row * self.field.columns() + column].into());
+            }
+        }
+        for z in 0..depth - 1 {
+            for x in 0..width - 1 {
+                let p00 = (z * width + x) as u16;
+                let p10 = p00 + 1;
+                let p01 = p00 + width as u16;
+                let p11 = p01 + 1;
+                mesh.indices
+                    .extend_from_slice(&[p00, p01, p11, p00, p11, p10]);
+            }
+        }
+
+        // Skirts, walking the edge loop so every wall faces outwards
+        let bounds = self.chunk_bounds(chunk);
+        let drop = bounds.max.y - bounds.min.y + self.config.cell_size;
+        let mut edge: Vec<usize> = Vec::with_capacity(2 * (width + depth));
+        edge.extend(0..width);
+        edge.extend((1..depth).map(|z| z * width + width - 1));
+        edge.extend((0..width - 1).rev().map(|x| (depth - 1) * width + x));
+        edge.extend((1..depth - 1).rev().map(|z| z * width));
+        edge.push(0);
+        for pair in edge.windows(2) {
+            let (top_a, top_b) = (pair[0] as u16, pair[1] as u16);
+            let bottom_a = mesh.positions.len() as u16;
+            for &top in &[pair[0], pair[1]] {
+                let [x, y, z] = mesh.positions[top];
+                mesh.positions.push([x, y - drop, z]);
+                mesh.normals.push(mesh.normals[top]);
+            }
+            let bottom_b = bottom_a + 1;
+            mesh.indices
+                .extend_from_slice(&[top_a, bottom_b, bottom_a, top_a, top_b, bottom_b]);
+        }
+        mesh
+    }
+
+    /// Sample ranges a chunk covers, sharing its edge samples with the neighbouring chunks
+    fn chunk_samples(&self, chunk: ChunkCoord) -> (std::ops::Range<usize>, std::ops::Range<usize>) {
+        let cells = self.config.chunk_cells;
+        let span = |index: usize, samples: usize| {
+            let first = (index * cells).min(samples - 2);
+            first..(first + cells).min(samples - 1) + 1
+        };
+        (
+            span(chunk.column, self.field.columns()),
+            span(chunk.row, self.field.rows()),
+        )
+    }
+
+    fn sample_position(&self, column: usize, row: usize) -> Vector3<f32> {
+        self.config.origin
+            + Vector3::new(
+                column as f32 * self.config.cell_size,
+                self.field.height(column, row),
+                row as f32 * self.config.cell_size,
+            )
+    }
+
+    fn sample_bounds(&self, columns: std::ops::Range<usize>, rows: std::ops::Range<usize>) -> Aabb {
+        let (mut low, mut high) = (f32::INFINITY, f32::NEG_INFINITY);
+        for row in rows.clone() {
+            for column in columns.clone() {
+                let height = self.field.height(column, row);
+                low = low.min(height);
+                high = high.max(height);
+            }
+        }
+        let origin = self.config.origin;
+        Aabb::new(
+            origin
+                + Vector3::new(
+                    columns.start as f32 * self.config.cell_size,
+                    low,
+                    rows.start as f32 * self.config.cell_size,
+                ),
+            origin
+                + Vector3::new(
+                    (columns.end - 1) as f32 * self.config.cell_size,
+                    high,
+                    (rows.end - 1) as f32 * self.config.cell_size,
+                ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::physics::{QueryFilter, Ray};
+
+    /// Two by two cells, two units wide, over heights 0 to 4
+    fn terrain() -> Terrain {
+        #[rustfmt::skip]
+        let heights = vec![
+            0.0, 0.25, 0.5,
+            0.5, 1.0, 0.5,
+            0.0, 0.0, 0.0,
+        ];
+        let heightmap = Heightmap::new(3, 3, heights).unwrap();
+        let config = TerrainConfig {
+            origin: Vector3::new(-10.0, 1.0, 5.0),
+            cell_size: 2.0,
+            height_scale: 4.0,
+            ..TerrainConfig::default()
+        };
+        Terrain::new(&heightmap, config)
+    }
+
+    #[test]
+    fn heights_interpolate_across_each_triangle() {
+        let terrain = terrain();
+        let close = |x: f32, z: f32, height: f32| {
+            let found = terrain.height_at(x, z).unwrap();
+            assert!((found - height).abs() < 1e-5, "{} at {}, {}", found, x, z);
+        };
+        // On the samples, and the origin lifts them all
+        close(-10.0, 5.0, 1.0);
+        close(-8.0, 5.0, 2.0);
+        close(-8.0, 7.0, 5.0);
+        close(-6.0, 9.0, 1.0);
+        // Halfway along an edge
+        close(-9.0, 5.0, 1.5);
+        close(-10.0, 6.0, 2.0);
+        // Each side of the first cell's diagonal from its first sample to the opposite one.
+        // Above it the plane runs through 0, 2 and 4, below it through 0, 1 and 4
+        close(-9.5, 6.5, 1.0 + 0.25 * 2.0 + 0.75 * 2.0);
+        close(-8.5, 5.5, 1.0 + 0.75 * 1.0 + 0.25 * 3.0);
+        close(-9.0, 6.0, 1.0 + 2.0);
+        // The far edges belong to the last cells
+        close(-6.0, 8.0, 1.0 + 1.0);
+        close(-7.0, 9.0, 1.0);
+
+        for &(x, z) in [(-10.1, 6.0), (-5.9, 6.0), (-8.0, 4.9), (-8.0, 9.1)].iter() {
+            assert_eq!(terrain.height_at(x, z), None);
+        }
+    }
+
+    #[test]
+    fn heights_match_what_the_collider_sees() {
+        let terrain = terrain();
+        let mut physics = PhysicsWorld::new();
+        terrain.add_to(&mut physics);
+        let filter = QueryFilter::new();
+        for i in 0..=16 {
+            for j in 0..=16 {
+                let (x, z) = (-10.0 + i as f32 * 0.25, 5.0 + j as f32 * 0.25);
+                let ray = Ray::new(Vector3::new(x, 20.0, z), -Vector3::unit_y());
+                let hit = physics.cast_ray(&ray, 40.0, &filter).unwrap();
+                let height = terrain.height_at(x, z).unwrap();
+                assert!((hit.position.y - height).abs() < 1e-3, "at {}, {}", x, z);
+            }
+        }
+    }
+}